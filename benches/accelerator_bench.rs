@@ -0,0 +1,150 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use raytrace::{
+    AcceleratorType, Camera, Material, Mesh, NodePrecision, Object3D, RenderOptions, Scene,
+    Transform,
+};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+const SCENE_FILE: &str = "scenes/benchmarks/complex.json";
+const RENDER_SCENE_FILES: [(&str, &str); 2] = [
+    ("Simple scene", "scenes/benchmarks/simple.json"),
+    ("Complex scene", SCENE_FILE),
+];
+const MESH_FILE: &str = "scenes/models/cerberus.obj";
+
+static ACCELERATORS: [Accelerator; 2] = [
+    Accelerator(AcceleratorType::KdTree, NodePrecision::F64),
+    Accelerator(AcceleratorType::Bvh, NodePrecision::F64),
+];
+static NODE_PRECISIONS: [Accelerator; 2] = [
+    Accelerator(AcceleratorType::Bvh, NodePrecision::F64),
+    Accelerator(AcceleratorType::Bvh, NodePrecision::F32),
+];
+
+#[derive(Copy, Clone)]
+struct Accelerator(AcceleratorType, NodePrecision);
+
+impl fmt::Display for Accelerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            AcceleratorType::KdTree => write!(f, "kdtree")?,
+            AcceleratorType::Bvh => write!(f, "bvh")?,
+        }
+        match self.1 {
+            NodePrecision::F64 => write!(f, " f64"),
+            NodePrecision::F32 => write!(f, " f32"),
+        }
+    }
+}
+
+fn load_scene(scene_file: &str, accelerator: Accelerator) -> Scene {
+    let scene_path = Path::new(scene_file);
+    let scene_file = File::open(scene_path).expect("file not found");
+    let mut scene: Scene = serde_json::from_reader(scene_file).expect("failed to parse scene");
+    scene.render_options.accelerator = accelerator.0;
+    scene.render_options.node_precision = accelerator.1;
+    scene.load_assets(scene_path.parent().unwrap_or_else(|| Path::new("")));
+
+    scene
+}
+
+fn load_mesh_scene() -> Scene {
+    let mut scene = Scene::new(RenderOptions::default(), Camera::default());
+    scene.add_object(Object3D::Mesh(Box::new(Mesh::new(
+        MESH_FILE.to_string(),
+        Transform::default(),
+        Material::default(),
+    ))));
+    scene.load_assets(Path::new(""));
+
+    scene
+}
+
+pub fn accelerator_build_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Accelerator build");
+    group.sample_size(10);
+    for &accelerator in &ACCELERATORS {
+        group.bench_with_input(
+            BenchmarkId::new("Complex scene", accelerator),
+            &accelerator,
+            |b, &accelerator| {
+                b.iter_batched(
+                    || load_scene(SCENE_FILE, accelerator),
+                    Scene::build_raytracing_scene,
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+pub fn kd_tree_build_benchmark(c: &mut Criterion) {
+    let mut thread_counts = vec![1, rayon::current_num_threads()];
+    thread_counts.dedup();
+
+    let mut group = c.benchmark_group("Kd-tree build");
+    group.sample_size(10);
+    for num_threads in thread_counts {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .expect("failed to build thread pool");
+        group.bench_with_input(
+            BenchmarkId::new("Mesh", format!("{} threads", num_threads)),
+            &thread_pool,
+            |b, thread_pool| {
+                b.iter_batched(
+                    load_mesh_scene,
+                    |scene| thread_pool.install(|| scene.build_raytracing_scene()),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+pub fn accelerator_render_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Accelerator render");
+    group.sample_size(10);
+    for &(scene_name, scene_file) in &RENDER_SCENE_FILES {
+        for &accelerator in &ACCELERATORS {
+            let raytracing_scene = load_scene(scene_file, accelerator).build_raytracing_scene();
+            group.bench_with_input(
+                BenchmarkId::new(scene_name, accelerator),
+                &raytracing_scene,
+                |b, raytracing_scene| b.iter(|| raytracing_scene.raytrace_to_image(false)),
+            );
+        }
+    }
+    group.finish();
+}
+
+// Double and single precision BVH node bounds on every benchmark scene
+pub fn node_precision_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("BVH node precision");
+    group.sample_size(10);
+    for &(scene_name, scene_file) in &RENDER_SCENE_FILES {
+        for &accelerator in &NODE_PRECISIONS {
+            let raytracing_scene = load_scene(scene_file, accelerator).build_raytracing_scene();
+            group.bench_with_input(
+                BenchmarkId::new(scene_name, accelerator),
+                &raytracing_scene,
+                |b, raytracing_scene| b.iter(|| raytracing_scene.raytrace_to_image(false)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    accelerator_build_benchmark,
+    kd_tree_build_benchmark,
+    accelerator_render_benchmark,
+    node_precision_benchmark
+);
+criterion_main!(benches);
@@ -0,0 +1,158 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nalgebra::{Point3, Unit, Vector2, Vector3};
+use raytrace::{
+    Camera, Group, Material, Mesh, Object3D, RenderOptions, Scene, Transform, Triangle,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tracks the current and peak number of allocated bytes so the memory used by each mesh
+// representation can be reported alongside its build time
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK_ALLOCATED.fetch_max(allocated, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const MESH_FILE: &str = "scenes/models/cerberus.obj";
+
+#[derive(Clone, Copy)]
+enum MeshRepresentation {
+    Triangles,
+    TriangleMesh,
+}
+
+impl std::fmt::Display for MeshRepresentation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MeshRepresentation::Triangles => write!(f, "Triangles"),
+            MeshRepresentation::TriangleMesh => write!(f, "TriangleMesh"),
+        }
+    }
+}
+
+// One `Triangle` object per face, as meshes were represented before `TriangleMesh`
+fn load_triangles() -> Object3D {
+    let (models, _) = tobj::load_obj(Path::new(MESH_FILE), true).expect("failed to load mesh");
+
+    let mut group = Group::new(Transform::default());
+    for model in &models {
+        let mesh = &model.mesh;
+        let position = |index: usize| {
+            Point3::new(
+                f64::from(mesh.positions[index * 3]),
+                f64::from(mesh.positions[index * 3 + 1]),
+                f64::from(mesh.positions[index * 3 + 2]),
+            )
+        };
+        let normal = |index: usize| {
+            Unit::new_normalize(Vector3::new(
+                f64::from(mesh.normals[index * 3]),
+                f64::from(mesh.normals[index * 3 + 1]),
+                f64::from(mesh.normals[index * 3 + 2]),
+            ))
+        };
+        let texcoords = |index: usize| {
+            Vector2::new(
+                f64::from(mesh.texcoords[index * 2]),
+                f64::from(mesh.texcoords[index * 2 + 1]),
+            )
+        };
+
+        for face in mesh.indices.chunks_exact(3) {
+            let face = [face[0] as usize, face[1] as usize, face[2] as usize];
+            let positions = [position(face[0]), position(face[1]), position(face[2])];
+            let normals = if mesh.normals.is_empty() {
+                [Triangle::compute_normal(positions); 3]
+            } else {
+                [normal(face[0]), normal(face[1]), normal(face[2])]
+            };
+            let texcoords = if mesh.texcoords.is_empty() {
+                [Vector2::zeros(); 3]
+            } else {
+                [texcoords(face[0]), texcoords(face[1]), texcoords(face[2])]
+            };
+
+            group.add_child(Object3D::Triangle(Box::new(Triangle::new(
+                positions,
+                normals,
+                texcoords,
+                Transform::default(),
+                Material::default(),
+            ))));
+        }
+    }
+
+    Object3D::Group(Box::new(group))
+}
+
+fn build_scene(representation: MeshRepresentation) -> Scene {
+    let mut scene = Scene::new(RenderOptions::default(), Camera::default());
+    match representation {
+        MeshRepresentation::Triangles => scene.add_object(load_triangles()),
+        MeshRepresentation::TriangleMesh => scene.add_object(Object3D::Mesh(Box::new(Mesh::new(
+            MESH_FILE.to_string(),
+            Transform::default(),
+            Material::default(),
+        )))),
+    }
+    scene.load_assets(Path::new(""));
+
+    scene
+}
+
+fn report_memory(representation: MeshRepresentation) {
+    let baseline = ALLOCATED.load(Ordering::SeqCst);
+    PEAK_ALLOCATED.store(baseline, Ordering::SeqCst);
+
+    let raytracing_scene = build_scene(representation).build_raytracing_scene();
+    let retained = ALLOCATED.load(Ordering::SeqCst) - baseline;
+    let peak = PEAK_ALLOCATED.load(Ordering::SeqCst) - baseline;
+    println!(
+        "{}: {} objects, {:.1} MiB retained, {:.1} MiB peak",
+        representation,
+        raytracing_scene.get_num_objects(),
+        retained as f64 / 1024.0 / 1024.0,
+        peak as f64 / 1024.0 / 1024.0,
+    );
+}
+
+pub fn mesh_build_benchmark(c: &mut Criterion) {
+    let representations = [
+        MeshRepresentation::Triangles,
+        MeshRepresentation::TriangleMesh,
+    ];
+    for &representation in &representations {
+        report_memory(representation);
+    }
+
+    let mut group = c.benchmark_group("Mesh build");
+    group.sample_size(10);
+    for representation in &representations {
+        group.bench_with_input(
+            BenchmarkId::new("Load and build", representation),
+            representation,
+            |b, &representation| b.iter(|| build_scene(representation).build_raytracing_scene()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mesh_build_benchmark);
+criterion_main!(benches);
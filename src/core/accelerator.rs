@@ -0,0 +1,379 @@
+use super::{
+    AcceleratorCache, BoundingVolume, BvhAccelerator, CompactBounds, KdTreeAccelerator,
+    KdTreeConstructionOptions, MediumStack, ParticipatingMedium, RayPacket,
+};
+use crate::primitives::RaytracingObject;
+use crate::ray_intersection::{Intersectable, Intersection, Ray, RayType};
+use nalgebra::Vector3;
+use serde::Deserialize;
+use std::fmt::{self, Debug};
+use std::marker::{Send, Sync};
+
+// A spatial structure over a set of objects which finds the closest object along a ray
+pub trait Accelerator: Send + Sync + Debug + Intersectable {
+    fn get_num_objects(&self) -> usize;
+    // Bounds of every object in the structure, or `None` if any object is unbounded
+    fn get_bounding_volume(&self) -> Option<BoundingVolume>;
+    fn shadow_cast(&self, ray: &Ray, max_distance: f64) -> bool;
+    fn get_stats(&self) -> AcceleratorStats;
+
+    fn raycast(&self, ray: &Ray) -> Option<Intersection> {
+        self.intersect(ray, None)
+    }
+
+    // Fraction of light which makes it along a ray within a maximum distance. Every transmissive
+    // surface crossed filters the light, while anything opaque, or more than `max_transmissions`
+    // transmissive surfaces, blocks it completely. Light is also attenuated by the media it passes
+    // through, with `outer_medium` filling the space outside every object.
+    fn shadow_transmittance(
+        &self,
+        ray: &Ray,
+        max_distance: f64,
+        max_transmissions: u8,
+        outer_medium: Option<&ParticipatingMedium>,
+    ) -> Vector3<f64> {
+        let mut transmittance = Vector3::repeat(1.0);
+        let mut media = MediumStack::default();
+        let mut origin = ray.origin;
+        let mut remaining_distance = max_distance;
+        for _ in 0..=max_transmissions {
+            let segment = Ray {
+                ray_type: RayType::Shadow,
+                origin,
+                direction: ray.direction,
+                media: MediumStack::default(),
+                wavelengths: None,
+                differentials: None,
+            };
+            let mut intersection = self.intersect(&segment, Some(remaining_distance));
+            if let Some(intersection) = &mut intersection {
+                intersection.compute_data(&segment);
+            }
+            let segment_distance = intersection
+                .as_ref()
+                .map_or(remaining_distance, |intersection| intersection.distance);
+
+            // A surface leaving a medium which was never entered, such as when the light is
+            // inside an object, ends a segment through that medium. Thin sheets have no inside.
+            let medium = media.participating_medium(outer_medium).cloned();
+            let medium = match &intersection {
+                Some(intersection) => match intersection.get_material().as_physical() {
+                    Some(material) if !material.thin && !intersection.is_front_face() => {
+                        let exited = intersection.get_medium(material);
+                        if media.contains(&exited) {
+                            medium
+                        } else {
+                            exited.interior().cloned()
+                        }
+                    }
+                    _ => medium,
+                },
+                None => medium,
+            };
+            if let Some(medium) = medium {
+                transmittance
+                    .component_mul_assign(&medium.transmittance(&segment, segment_distance));
+            }
+
+            if let Some(intersection) = intersection {
+                let material = intersection.get_material();
+                transmittance.component_mul_assign(&material.get_transmittance());
+                if transmittance.max() <= 0.0 {
+                    return transmittance;
+                }
+
+                if let Some(material) = material.as_physical().filter(|material| !material.thin) {
+                    let medium = intersection.get_medium(material);
+                    media = if intersection.is_front_face() {
+                        media.entered(medium)
+                    } else {
+                        media.exited(&medium)
+                    };
+                }
+
+                let next_origin = intersection.get_ray_origin(&ray.direction);
+                remaining_distance -= (next_origin - origin).magnitude();
+                origin = next_origin;
+            } else {
+                return transmittance;
+            }
+        }
+
+        Vector3::zeros()
+    }
+
+    // Closest intersection of each ray in a packet. Structures without packet traversal trace
+    // each ray on its own.
+    fn raycast_packet(&self, packet: &RayPacket) -> Vec<Option<Intersection>> {
+        packet.rays().iter().map(|ray| self.raycast(ray)).collect()
+    }
+
+    // Whether each ray in a packet is blocked within its maximum distance
+    fn shadow_cast_packet(&self, packet: &RayPacket, max_distances: &[f64]) -> Vec<bool> {
+        packet
+            .rays()
+            .iter()
+            .zip(max_distances)
+            .map(|(ray, &max_distance)| self.shadow_cast(ray, max_distance))
+            .collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceleratorType {
+    #[default]
+    KdTree,
+    Bvh,
+}
+
+// Precision of the bounds stored in BVH nodes. Single precision bounds halve the size of each node
+// and are tested against four lanes at once, at the cost of slightly looser boxes. Objects are
+// always intersected in double precision, and kd-tree splits are single planes rather than boxes,
+// so they have no compact form.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodePrecision {
+    #[default]
+    F64,
+    F32,
+}
+
+impl AcceleratorType {
+    pub fn supports(self, node_precision: NodePrecision) -> bool {
+        self == AcceleratorType::Bvh || node_precision == NodePrecision::F64
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the accelerator doesn't support the node precision. Scenes with such a pair are
+    /// rejected when they're deserialized.
+    pub fn build(
+        self,
+        objects: Vec<Box<dyn RaytracingObject>>,
+        kd_tree_options: KdTreeConstructionOptions,
+        node_precision: NodePrecision,
+        cache: &mut AcceleratorCache,
+    ) -> Box<dyn Accelerator> {
+        match (self, node_precision) {
+            (AcceleratorType::KdTree, NodePrecision::F64) => {
+                Box::new(KdTreeAccelerator::new(objects, kd_tree_options, cache))
+            }
+            (AcceleratorType::KdTree, NodePrecision::F32) => {
+                panic!("f32 node precision is only supported by the bvh accelerator")
+            }
+            (AcceleratorType::Bvh, NodePrecision::F64) => {
+                Box::new(BvhAccelerator::<BoundingVolume>::new(objects, cache))
+            }
+            (AcceleratorType::Bvh, NodePrecision::F32) => {
+                Box::new(BvhAccelerator::<CompactBounds>::new(objects, cache))
+            }
+        }
+    }
+}
+
+// Shape of an acceleration structure. The SAH cost is the expected cost of tracing a ray through
+// the structure, with each node weighted by its surface area relative to the root.
+#[derive(Debug)]
+pub struct AcceleratorStats {
+    pub num_unbounded_objects: usize,
+    pub num_nodes: usize,
+    pub num_leaves: usize,
+    pub num_leaf_objects: usize,
+    pub max_leaf_objects: usize,
+    // Number of leaves at each depth
+    pub leaf_depths: Vec<usize>,
+    pub sah_cost: f64,
+
+    root_surface_area: f64,
+}
+
+impl AcceleratorStats {
+    pub(super) fn new(num_unbounded_objects: usize, bounds: Option<BoundingVolume>) -> Self {
+        Self {
+            num_unbounded_objects,
+            num_nodes: 0,
+            num_leaves: 0,
+            num_leaf_objects: 0,
+            max_leaf_objects: 0,
+            leaf_depths: Vec::new(),
+            sah_cost: 0.0,
+
+            root_surface_area: bounds.map_or(0.0, |bounds| bounds.surface_area()),
+        }
+    }
+
+    fn area_weight(&self, bounding_volume: &BoundingVolume) -> f64 {
+        if self.root_surface_area > 0.0 {
+            bounding_volume.surface_area() / self.root_surface_area
+        } else {
+            1.0
+        }
+    }
+
+    pub(super) fn add_interior(&mut self, bounding_volume: &BoundingVolume, traversal_cost: f64) {
+        self.num_nodes += 1;
+        self.sah_cost += self.area_weight(bounding_volume) * traversal_cost;
+    }
+
+    pub(super) fn add_leaf(
+        &mut self,
+        bounding_volume: &BoundingVolume,
+        depth: usize,
+        num_objects: usize,
+        intersection_cost: f64,
+    ) {
+        self.num_nodes += 1;
+        self.num_leaves += 1;
+        self.num_leaf_objects += num_objects;
+        self.max_leaf_objects = self.max_leaf_objects.max(num_objects);
+        if self.leaf_depths.len() <= depth {
+            self.leaf_depths.resize(depth + 1, 0);
+        }
+        self.leaf_depths[depth] += 1;
+        self.sah_cost += self.area_weight(bounding_volume) * intersection_cost * num_objects as f64;
+    }
+
+    pub fn average_leaf_objects(&self) -> f64 {
+        if self.num_leaves == 0 {
+            0.0
+        } else {
+            self.num_leaf_objects as f64 / self.num_leaves as f64
+        }
+    }
+}
+
+impl fmt::Display for AcceleratorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Unbounded objects: {}", self.num_unbounded_objects)?;
+        writeln!(
+            f,
+            "Nodes: {} ({} interior, {} leaves)",
+            self.num_nodes,
+            self.num_nodes - self.num_leaves,
+            self.num_leaves
+        )?;
+        writeln!(
+            f,
+            "Objects per leaf: {:.2} average, {} max",
+            self.average_leaf_objects(),
+            self.max_leaf_objects
+        )?;
+        writeln!(f, "SAH cost: {:.2}", self.sah_cost)?;
+        write!(f, "Leaf depths:")?;
+        for (depth, &num_leaves) in self.leaf_depths.iter().enumerate() {
+            if num_leaves > 0 {
+                write!(f, "\n  {:>3}: {}", depth, num_leaves)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{HomogeneousMedium, Material, PhysicalMaterial, Transform};
+    use crate::primitives::{Object3D, Sphere};
+    use nalgebra::Point3;
+
+    fn sphere_row(materials: Vec<Material>) -> Vec<Box<dyn RaytracingObject>> {
+        let mut objects = Vec::new();
+        for (index, material) in materials.into_iter().enumerate() {
+            let sphere = Object3D::Sphere(Box::new(Sphere::new(
+                0.5,
+                Transform::default().translate(Vector3::new(0.0, 0.0, -2.0 * index as f64)),
+                material,
+            )));
+            objects.append(&mut sphere.flatten_to_world(&Transform::default()));
+        }
+
+        objects
+    }
+
+    fn glass() -> Material {
+        Material::Physical(PhysicalMaterial {
+            color: Vector3::new(1.0, 0.5, 0.5),
+            opacity: 0.5,
+            ..PhysicalMaterial::default()
+        })
+    }
+
+    #[test]
+    fn it_filters_shadows_through_transmissive_surfaces() {
+        let ray = Ray {
+            ray_type: RayType::Shadow,
+            origin: Point3::new(0.0, 0.0, 5.0),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+
+        let kd_tree = KdTreeAccelerator::new(
+            sphere_row(vec![glass(), glass()]),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        // Both surfaces of each sphere filter the light
+        let expected = Vector3::new(0.5, 0.25, 0.25).map(|c: f64| c.powi(4));
+        let transmittance = kd_tree.shadow_transmittance(&ray, 10.0, 8, None);
+        assert!((transmittance - expected).magnitude() < 1e-12);
+
+        // Surfaces past the maximum distance don't filter the light
+        let transmittance = kd_tree.shadow_transmittance(&ray, 6.0, 8, None);
+        assert!((transmittance - expected.map(f64::sqrt)).magnitude() < 1e-12);
+
+        assert_eq!(
+            kd_tree.shadow_transmittance(&ray, 10.0, 3, None),
+            Vector3::zeros()
+        );
+
+        let kd_tree = KdTreeAccelerator::new(
+            sphere_row(vec![glass(), Material::default()]),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        assert_eq!(
+            kd_tree.shadow_transmittance(&ray, 10.0, 8, None),
+            Vector3::zeros()
+        );
+    }
+
+    #[test]
+    fn it_attenuates_shadows_through_media() {
+        let ray = Ray {
+            ray_type: RayType::Shadow,
+            origin: Point3::new(0.0, 0.0, 5.0),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+
+        let tinted_glass = Material::Physical(PhysicalMaterial {
+            color: Vector3::repeat(1.0),
+            opacity: 0.0,
+            absorption_color: Vector3::new(1.0, 0.5, 0.5),
+            absorption_density: 1.0,
+            ..PhysicalMaterial::default()
+        });
+        let kd_tree = KdTreeAccelerator::new(
+            sphere_row(vec![tinted_glass]),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        let fog = ParticipatingMedium::Homogeneous(HomogeneousMedium {
+            absorption: Vector3::repeat(0.1),
+            ..HomogeneousMedium::default()
+        });
+
+        // One unit through the sphere, and the other nine through the fog around it
+        let expected = Vector3::new(1.0, 0.5, 0.5) * (-0.9_f64).exp();
+        let transmittance = kd_tree.shadow_transmittance(&ray, 10.0, 8, Some(&fog));
+        assert!((transmittance - expected).magnitude() < 1e-6);
+        let transmittance = kd_tree.shadow_transmittance(&ray, 10.0, 8, None);
+        assert!((transmittance - Vector3::new(1.0, 0.5, 0.5)).magnitude() < 1e-6);
+    }
+}
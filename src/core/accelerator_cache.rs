@@ -0,0 +1,200 @@
+use super::bounds::BoundedObject;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+// Bumped whenever the layout of a cached acceleration structure or mesh changes
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Default, Deserialize, Serialize)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<u64, Vec<u8>>,
+}
+
+// Loaded meshes and built acceleration structures persisted between runs. Meshes are keyed by the
+// contents of their files and the parameters they're processed with, and structures by the bounds
+// of the objects they were built from, so both stay valid while only the camera, lights, materials
+// or render options change.
+#[derive(Debug, Default)]
+pub struct AcceleratorCache {
+    path: Option<PathBuf>,
+    cached_entries: HashMap<u64, Vec<u8>>,
+    used_entries: HashMap<u64, Vec<u8>>,
+    num_hits: usize,
+    num_misses: usize,
+}
+
+impl AcceleratorCache {
+    // A cache which never has any entries and is never saved
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    // Reads the cache file at `path`, starting empty if it's missing, unreadable or out of date
+    pub fn load(path: &Path) -> Self {
+        let cache_file: Option<CacheFile> = File::open(path)
+            .ok()
+            .and_then(|file| bincode::deserialize_from(BufReader::new(file)).ok());
+        let cached_entries = match cache_file {
+            Some(cache_file) if cache_file.version == CACHE_VERSION => cache_file.entries,
+            _ => HashMap::new(),
+        };
+
+        Self {
+            path: Some(path.to_path_buf()),
+            cached_entries,
+            ..AcceleratorCache::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn get_num_hits(&self) -> usize {
+        self.num_hits
+    }
+
+    pub fn get_num_misses(&self) -> usize {
+        self.num_misses
+    }
+
+    pub(super) fn key(
+        accelerator: &str,
+        parameters: &[f64],
+        num_unbounded_objects: usize,
+        bounded_objects: &[BoundedObject],
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        accelerator.hash(&mut hasher);
+        for parameter in parameters {
+            parameter.to_bits().hash(&mut hasher);
+        }
+        num_unbounded_objects.hash(&mut hasher);
+        bounded_objects.len().hash(&mut hasher);
+        for object in bounded_objects {
+            let bounding_volume = &object.bounding_volume;
+            for coordinate in bounding_volume
+                .bounds_min
+                .iter()
+                .chain(bounding_volume.bounds_max.iter())
+            {
+                coordinate.to_bits().hash(&mut hasher);
+            }
+        }
+
+        hasher.finish()
+    }
+
+    pub(crate) fn file_key(kind: &str, contents: &[u8], parameters: &[f64]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        kind.hash(&mut hasher);
+        for parameter in parameters {
+            parameter.to_bits().hash(&mut hasher);
+        }
+        contents.hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    pub(crate) fn get<T: DeserializeOwned>(&mut self, key: u64) -> Option<T> {
+        let bytes = self.cached_entries.remove(&key)?;
+        let value = bincode::deserialize(&bytes).ok()?;
+        self.used_entries.insert(key, bytes);
+        self.num_hits += 1;
+
+        Some(value)
+    }
+
+    pub(crate) fn insert<T: Serialize>(&mut self, key: u64, value: &T) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let bytes = bincode::serialize(value).expect("failed to serialize cache entry");
+        self.used_entries.insert(key, bytes);
+        self.num_misses += 1;
+    }
+
+    // Writes the entries used in this run, dropping any stale ones. Nothing is written if every
+    // entry was reused.
+    pub fn save(self) -> bincode::Result<()> {
+        let path = match self.path {
+            Some(path) if self.num_misses > 0 || !self.cached_entries.is_empty() => path,
+            _ => return Ok(()),
+        };
+
+        let cache_file = CacheFile {
+            version: CACHE_VERSION,
+            entries: self.used_entries,
+        };
+        let writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(writer, &cache_file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        Accelerator, KdTreeAccelerator, KdTreeConstructionOptions, Material, MediumStack, Transform,
+    };
+    use crate::primitives::{Object3D, RaytracingObject, Sphere};
+    use crate::ray_intersection::{Ray, RayType};
+    use nalgebra::{Point3, Vector3};
+
+    fn spheres(radius: f64) -> Vec<Box<dyn RaytracingObject>> {
+        (0..10)
+            .flat_map(|i| {
+                Object3D::Sphere(Box::new(Sphere::new(
+                    radius,
+                    Transform::default().translate(Vector3::new(f64::from(i), 0.0, 0.0)),
+                    Material::default(),
+                )))
+                .flatten_to_world(&Transform::default())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_reuses_cached_trees_until_geometry_changes() {
+        let path = std::env::temp_dir().join(format!(
+            "raytrace_accelerator_cache_test_{}.bin",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let options = KdTreeConstructionOptions::default();
+
+        let mut cache = AcceleratorCache::load(&path);
+        KdTreeAccelerator::new(spheres(0.4), options, &mut cache);
+        assert_eq!((cache.get_num_hits(), cache.get_num_misses()), (0, 1));
+        cache.save().unwrap();
+
+        let mut cache = AcceleratorCache::load(&path);
+        let tree = KdTreeAccelerator::new(spheres(0.4), options, &mut cache);
+        assert_eq!((cache.get_num_hits(), cache.get_num_misses()), (1, 0));
+
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::new(3.0, 0.0, 5.0),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+        let intersection = tree.raycast(&ray).unwrap();
+        assert!((intersection.distance - 4.6).abs() < 1e-10);
+
+        let mut cache = AcceleratorCache::load(&path);
+        KdTreeAccelerator::new(spheres(0.3), options, &mut cache);
+        assert_eq!((cache.get_num_hits(), cache.get_num_misses()), (0, 1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,13 +1,19 @@
-use super::{Axis, Transform};
+use super::packet::masked_rays;
+use super::{Accelerator, AcceleratorCache, AcceleratorStats, Axis, RayPacket, Transform};
 use crate::primitives::RaytracingObject;
 use crate::ray_intersection::{Intersectable, Intersection, Ray};
 use itertools::{Either, Itertools};
 use nalgebra::Point3;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering::{self, Equal};
 use std::f64::EPSILON;
 use std::fmt;
 
-fn build_bounding_volume(bounding_volumes: &[BoundingVolume]) -> BoundingVolume {
+// Nodes with fewer objects than this are built on the current thread
+const PARALLEL_BUILD_THRESHOLD: usize = 1024;
+
+pub(super) fn build_bounding_volume(bounding_volumes: &[BoundingVolume]) -> BoundingVolume {
     if bounding_volumes.is_empty() {
         panic!("trying to build a bounding volume out of nothing")
     }
@@ -19,11 +25,11 @@ fn build_bounding_volume(bounding_volumes: &[BoundingVolume]) -> BoundingVolume
         })
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct BoundingVolume {
-    center: Point3<f64>,
-    bounds_min: Point3<f64>,
-    bounds_max: Point3<f64>,
+    pub(super) center: Point3<f64>,
+    pub(super) bounds_min: Point3<f64>,
+    pub(super) bounds_max: Point3<f64>,
 }
 
 impl BoundingVolume {
@@ -71,6 +77,10 @@ impl BoundingVolume {
         BoundingVolume::from_bounds(min, max)
     }
 
+    pub fn transform(&self, transform: &Transform) -> Self {
+        Self::from_bounds_and_transform(self.bounds_min, self.bounds_max, transform)
+    }
+
     pub fn merge(a: &BoundingVolume, b: &BoundingVolume) -> BoundingVolume {
         let mut min = a.bounds_min;
         let mut max = a.bounds_max;
@@ -152,6 +162,13 @@ impl BoundingVolume {
     }
 }
 
+// Whether a ray passes through the lower side of a split plane before the upper side
+fn is_left_first(ray: &Ray, split_index: usize, split_location: f64) -> bool {
+    ray.origin[split_index] < split_location
+        || ((ray.origin[split_index] - split_location).abs() < EPSILON
+            && ray.direction[split_index] <= 0.0)
+}
+
 #[derive(Debug)]
 pub struct UnboundedObject(Box<dyn RaytracingObject>);
 
@@ -166,7 +183,7 @@ impl Intersectable for UnboundedObject {
 #[derive(Debug)]
 pub struct BoundedObject {
     object: Box<dyn RaytracingObject>,
-    bounding_volume: BoundingVolume,
+    pub(super) bounding_volume: BoundingVolume,
 }
 
 impl Intersectable for BoundedObject {
@@ -199,6 +216,20 @@ impl ObjectWithBounds {
     }
 }
 
+// Splits objects into those without bounds, which must be tested against every ray, and those
+// which can be placed in an acceleration structure
+pub(super) fn partition_objects(
+    objects: Vec<Box<dyn RaytracingObject>>,
+) -> (Vec<UnboundedObject>, Vec<BoundedObject>) {
+    objects
+        .into_iter()
+        .map(|object| object.into_bounded_object())
+        .partition_map(|object| match object {
+            ObjectWithBounds::Unbounded(object) => Either::Left(object),
+            ObjectWithBounds::Bounded(object) => Either::Right(object),
+        })
+}
+
 impl Intersectable for ObjectWithBounds {
     fn intersect(&self, ray: &Ray, max_distance: Option<f64>) -> Option<Intersection> {
         match self {
@@ -208,6 +239,94 @@ impl Intersectable for ObjectWithBounds {
     }
 }
 
+impl Intersectable for KdTreeAccelerator {
+    fn intersect(&self, ray: &Ray, max_distance: Option<f64>) -> Option<Intersection> {
+        self.unbounded_objects
+            .iter()
+            .filter_map(|object| object.intersect(ray, max_distance))
+            .chain(self.raycast_tree(&self.tree, ray, max_distance))
+            .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Equal))
+    }
+}
+
+impl Accelerator for KdTreeAccelerator {
+    fn get_num_objects(&self) -> usize {
+        self.unbounded_objects.len() + self.bounded_objects.len()
+    }
+
+    fn get_bounding_volume(&self) -> Option<BoundingVolume> {
+        self.bounding_volume
+    }
+
+    fn shadow_cast(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.unbounded_objects
+            .iter()
+            .filter_map(|object| object.intersect(ray, Some(max_distance)))
+            .any(|intersection| intersection.distance <= max_distance)
+            || self.shadow_cast_tree(&self.tree, ray, Some(max_distance))
+    }
+
+    fn raycast_packet(&self, packet: &RayPacket) -> Vec<Option<Intersection>> {
+        let mut intersections: Vec<Option<Intersection>> =
+            packet.rays().iter().map(|_| None).collect();
+        self.raycast_packet_tree(&self.tree, packet, packet.all_rays(), &mut intersections);
+
+        packet
+            .rays()
+            .iter()
+            .zip(intersections)
+            .map(|(ray, intersection)| {
+                self.unbounded_objects
+                    .iter()
+                    .filter_map(|object| object.intersect(ray, None))
+                    .chain(intersection)
+                    .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Equal))
+            })
+            .collect()
+    }
+
+    fn shadow_cast_packet(&self, packet: &RayPacket, max_distances: &[f64]) -> Vec<bool> {
+        let mut unoccluded = 0;
+        for (index, (ray, &max_distance)) in packet.rays().iter().zip(max_distances).enumerate() {
+            if !self
+                .unbounded_objects
+                .iter()
+                .filter_map(|object| object.intersect(ray, Some(max_distance)))
+                .any(|intersection| intersection.distance <= max_distance)
+            {
+                unoccluded |= 1 << index;
+            }
+        }
+        let unoccluded =
+            self.shadow_cast_packet_tree(&self.tree, packet, max_distances, unoccluded);
+
+        (0..packet.len())
+            .map(|index| unoccluded & (1 << index) == 0)
+            .collect()
+    }
+
+    fn get_stats(&self) -> AcceleratorStats {
+        let bounds = if self.bounded_objects.is_empty() {
+            None
+        } else {
+            let bounding_volumes: Vec<BoundingVolume> = self
+                .bounded_objects
+                .iter()
+                .map(|object| object.bounding_volume)
+                .collect();
+            Some(build_bounding_volume(&bounding_volumes))
+        };
+
+        let mut stats = AcceleratorStats::new(self.unbounded_objects.len(), bounds);
+        if let Some(bounds) = bounds {
+            self.tree
+                .collect_stats(&self.options, bounds, 0, &mut stats);
+        }
+
+        stats
+    }
+}
+
 enum SplitCandidate {
     Start(f64, usize),
     End(f64, usize),
@@ -244,77 +363,172 @@ impl SplitCandidate {
     }
 }
 
+// Split candidates along one axis of a node, sorted by location, and the index and cost of the
+// cheapest one which lies inside the node
+struct SplitEvaluation {
+    axis: usize,
+    split_candidates: Vec<SplitCandidate>,
+    best_split: Option<(usize, f64)>,
+}
+
+impl SplitEvaluation {
+    fn new(
+        objects: &[BoundedObject],
+        options: &KdTreeConstructionOptions,
+        bounding_volume: &BoundingVolume,
+        indexes: &[usize],
+        axis: usize,
+    ) -> Self {
+        let total_surface_area = bounding_volume.surface_area();
+        let bounds_diagonal = bounding_volume.bounds_max - bounding_volume.bounds_min;
+
+        let mut split_candidates = Vec::with_capacity(2 * indexes.len());
+        for &index in indexes {
+            let object_bounds = objects[index].bounding_volume;
+            split_candidates.push(SplitCandidate::Start(object_bounds.bounds_min[axis], index));
+            split_candidates.push(SplitCandidate::End(object_bounds.bounds_max[axis], index));
+        }
+        split_candidates.sort_by(|a, b| SplitCandidate::cmp(a, b));
+
+        let mut best_split = None;
+        let mut best_cost = f64::INFINITY;
+
+        let mut below = 0;
+        let mut above = indexes.len();
+        for (index, split_candidate) in split_candidates.iter().enumerate() {
+            if let SplitCandidate::End(_, _) = split_candidate {
+                above -= 1;
+            }
+
+            let split = split_candidate.get_split();
+
+            if bounding_volume.bounds_min[axis] < split && split < bounding_volume.bounds_max[axis]
+            {
+                let other_axis0 = (axis + 1) % 3;
+                let other_axis1 = (axis + 2) % 3;
+                let d = bounds_diagonal[other_axis0] * bounds_diagonal[other_axis1];
+                let surface_area_below = 2.0
+                    * (d + (split - bounding_volume.bounds_min[axis])
+                        * (bounds_diagonal[other_axis0] + bounds_diagonal[other_axis1]));
+                let surface_area_above = 2.0
+                    * (d + (bounding_volume.bounds_max[axis] - split)
+                        * (bounds_diagonal[other_axis0] + bounds_diagonal[other_axis1]));
+
+                let area_below = surface_area_below / total_surface_area;
+                let area_above = surface_area_above / total_surface_area;
+                let empty_bonus = if above == 0 || below == 0 {
+                    options.empty_bonus
+                } else {
+                    0.0
+                };
+                let cost = options.traversal_cost
+                    + options.intersection_cost
+                        * (1.0 - empty_bonus)
+                        * (area_below * f64::from(below) + area_above * above as f64);
+
+                if cost < best_cost {
+                    best_cost = cost;
+                    best_split = Some((index, cost));
+                }
+            }
+
+            if let SplitCandidate::Start(_, _) = split_candidate {
+                below += 1;
+            }
+        }
+
+        Self {
+            axis,
+            split_candidates,
+            best_split,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct KdTreeAccelerator {
     unbounded_objects: Vec<UnboundedObject>,
     bounded_objects: Vec<BoundedObject>,
+    bounding_volume: Option<BoundingVolume>,
+    options: KdTreeConstructionOptions,
     tree: KdTree,
 }
 
 impl KdTreeAccelerator {
-    pub fn new(objects: Vec<Box<dyn RaytracingObject>>) -> Self {
-        let (unbounded_objects, bounded_objects): (Vec<UnboundedObject>, Vec<BoundedObject>) =
-            objects
-                .into_iter()
-                .map(|object| object.into_bounded_object())
-                .partition_map(|object| match object {
-                    ObjectWithBounds::Unbounded(object) => Either::Left(object),
-                    ObjectWithBounds::Bounded(object) => Either::Right(object),
-                });
-
-        let (tree, bounded_objects) = if bounded_objects.is_empty() {
-            (KdTree::Leaf(Vec::new()), bounded_objects)
+    pub fn new(
+        objects: Vec<Box<dyn RaytracingObject>>,
+        options: KdTreeConstructionOptions,
+        cache: &mut AcceleratorCache,
+    ) -> Self {
+        let (unbounded_objects, bounded_objects) = partition_objects(objects);
+        let cache_key = if cache.is_enabled() {
+            Some(AcceleratorCache::key(
+                "kdtree",
+                &[
+                    options.max_objects as f64,
+                    options.intersection_cost,
+                    options.traversal_cost,
+                    options.empty_bonus,
+                    options.max_depth.map_or(-1.0, f64::from),
+                    f64::from(options.max_bad_refines),
+                ],
+                unbounded_objects.len(),
+                &bounded_objects,
+            ))
+        } else {
+            None
+        };
+
+        let (tree, bounded_objects, bounds) = if bounded_objects.is_empty() {
+            (KdTree::Leaf(Vec::new()), bounded_objects, None)
         } else {
             let indexes = (0..bounded_objects.len()).collect();
-            let max_depth = (8.0 + 1.3 * (bounded_objects.len() as f64).log2()) as u8;
-            let max_bad_refines = 3;
+            let max_depth = options
+                .max_depth
+                .unwrap_or_else(|| (8.0 + 1.3 * (bounded_objects.len() as f64).log2()) as u8);
 
             let bounding_volumes: Vec<BoundingVolume> = bounded_objects
                 .iter()
                 .map(|object| object.bounding_volume)
                 .collect();
+            let bounds = build_bounding_volume(&bounding_volumes);
 
-            (
-                KdTree::build(
+            let cached_tree = cache_key.and_then(|cache_key| cache.get(cache_key));
+            let tree = cached_tree.unwrap_or_else(|| {
+                let tree = KdTree::build(
                     &bounded_objects,
-                    KdTreeConstructionOptions::default(),
+                    options,
                     max_depth,
-                    max_bad_refines,
-                    build_bounding_volume(&bounding_volumes),
+                    options.max_bad_refines,
+                    bounds,
                     indexes,
+                    PARALLEL_BUILD_THRESHOLD,
                 )
-                .unwrap_or_else(|| KdTree::Leaf(Vec::new())),
-                bounded_objects,
-            )
+                .unwrap_or_else(|| KdTree::Leaf(Vec::new()));
+                if let Some(cache_key) = cache_key {
+                    cache.insert(cache_key, &tree);
+                }
+
+                tree
+            });
+
+            (tree, bounded_objects, Some(bounds))
+        };
+        let bounding_volume = if unbounded_objects.is_empty() {
+            bounds
+        } else {
+            None
         };
 
         Self {
             unbounded_objects,
             bounded_objects,
+            bounding_volume,
+            options,
             tree,
         }
     }
 
-    pub fn get_num_objects(&self) -> usize {
-        self.unbounded_objects.len() + self.bounded_objects.len()
-    }
-
-    pub fn raycast(&self, ray: &Ray) -> Option<Intersection> {
-        self.unbounded_objects
-            .iter()
-            .filter_map(|object| object.intersect(ray, None))
-            .chain(self.raycast_tree(&self.tree, ray, None))
-            .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Equal))
-    }
-
-    pub fn shadow_cast(&self, ray: &Ray, max_distance: f64) -> bool {
-        self.unbounded_objects
-            .iter()
-            .filter_map(|object| object.intersect(ray, Some(max_distance)))
-            .any(|intersection| intersection.distance <= max_distance)
-            || self.shadow_cast_tree(&self.tree, ray, Some(max_distance))
-    }
-
     fn raycast_tree(
         &self,
         tree: &KdTree,
@@ -331,9 +545,7 @@ impl KdTreeAccelerator {
             } => {
                 if bounding_volume.intersect(ray, max_distance) {
                     let split_index = usize::from(split_axis);
-                    let left_first = ray.origin[split_index] < *split_location
-                        || ((ray.origin[split_index] - *split_location).abs() < EPSILON
-                            && ray.direction[split_index] <= 0.0);
+                    let left_first = is_left_first(ray, split_index, *split_location);
 
                     let (first, second) = if left_first {
                         (left, right)
@@ -358,11 +570,159 @@ impl KdTreeAccelerator {
             }
             KdTree::Leaf(object_indexes) => object_indexes
                 .iter()
-                .filter_map(|index| self.bounded_objects[*index].intersect(&ray, max_distance))
+                .filter_map(|index| self.bounded_objects[*index].intersect(ray, max_distance))
                 .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Equal)),
         }
     }
 
+    // Traces the rays of a packet set in `active` together, keeping the closest intersection of
+    // each. Rays which miss a node's bounds are dropped from the packet below that node.
+    fn raycast_packet_tree<'a>(
+        &'a self,
+        tree: &KdTree,
+        packet: &RayPacket,
+        active: u64,
+        intersections: &mut [Option<Intersection<'a>>],
+    ) {
+        let rays = packet.rays();
+        let max_distance = |intersection: &Option<Intersection>| {
+            intersection
+                .as_ref()
+                .map(|intersection| intersection.distance)
+        };
+
+        match tree {
+            KdTree::Node {
+                split_axis,
+                split_location,
+                bounding_volume,
+                left,
+                right,
+            } => {
+                let packet_max_distance = masked_rays(active)
+                    .map(|index| max_distance(&intersections[index]))
+                    .try_fold(f64::NEG_INFINITY, |acc, distance| {
+                        distance.map(|distance| acc.max(distance))
+                    });
+                if !packet.frustum_intersects(bounding_volume, packet_max_distance) {
+                    return;
+                }
+
+                let split_index = usize::from(split_axis);
+                let mut hit: u64 = 0;
+                let mut num_left_first = 0;
+                for index in masked_rays(active) {
+                    let ray = &rays[index];
+                    if bounding_volume.intersect(ray, max_distance(&intersections[index])) {
+                        hit |= 1 << index;
+                        if is_left_first(ray, split_index, *split_location) {
+                            num_left_first += 1;
+                        }
+                    }
+                }
+                if hit == 0 {
+                    return;
+                }
+
+                // Children are visited in the order most rays would visit them. Rays which would
+                // go the other way still find their closest intersection, only with less pruning.
+                let (first, second) = if 2 * num_left_first >= hit.count_ones() {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+
+                self.raycast_packet_tree(first, packet, hit, intersections);
+                self.raycast_packet_tree(second, packet, hit, intersections);
+            }
+            KdTree::Leaf(object_indexes) => {
+                for index in masked_rays(active) {
+                    let ray = &rays[index];
+                    for object_index in object_indexes {
+                        if let Some(intersection) = self.bounded_objects[*object_index]
+                            .intersect(ray, max_distance(&intersections[index]))
+                        {
+                            let is_closer = match max_distance(&intersections[index]) {
+                                Some(distance) => intersection.distance < distance,
+                                None => true,
+                            };
+                            if is_closer {
+                                intersections[index] = Some(intersection);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns the rays set in `active` which aren't blocked by any object in the tree
+    fn shadow_cast_packet_tree(
+        &self,
+        tree: &KdTree,
+        packet: &RayPacket,
+        max_distances: &[f64],
+        active: u64,
+    ) -> u64 {
+        let rays = packet.rays();
+
+        match tree {
+            KdTree::Node {
+                split_axis,
+                split_location,
+                bounding_volume,
+                left,
+                right,
+            } => {
+                let packet_max_distance = masked_rays(active)
+                    .map(|index| max_distances[index])
+                    .fold(f64::NEG_INFINITY, f64::max);
+                if active == 0
+                    || !packet.frustum_intersects(bounding_volume, Some(packet_max_distance))
+                {
+                    return active;
+                }
+
+                let split_index = usize::from(split_axis);
+                let mut hit: u64 = 0;
+                let mut num_left_first = 0;
+                for index in masked_rays(active) {
+                    let ray = &rays[index];
+                    if bounding_volume.intersect(ray, Some(max_distances[index])) {
+                        hit |= 1 << index;
+                        if is_left_first(ray, split_index, *split_location) {
+                            num_left_first += 1;
+                        }
+                    }
+                }
+                if hit == 0 {
+                    return active;
+                }
+
+                let (first, second) = if 2 * num_left_first >= hit.count_ones() {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+
+                let unoccluded = self.shadow_cast_packet_tree(first, packet, max_distances, hit);
+                let unoccluded =
+                    self.shadow_cast_packet_tree(second, packet, max_distances, unoccluded);
+
+                (active & !hit) | unoccluded
+            }
+            KdTree::Leaf(object_indexes) => masked_rays(active)
+                .filter(|&index| {
+                    !object_indexes.iter().any(|object_index| {
+                        self.bounded_objects[*object_index]
+                            .intersect(&rays[index], Some(max_distances[index]))
+                            .is_some()
+                    })
+                })
+                .fold(0, |unoccluded, index| unoccluded | 1 << index),
+        }
+    }
+
     fn shadow_cast_tree(&self, tree: &KdTree, ray: &Ray, max_distance: Option<f64>) -> bool {
         match tree {
             KdTree::Node {
@@ -374,9 +734,7 @@ impl KdTreeAccelerator {
             } => {
                 if bounding_volume.intersect(ray, max_distance) {
                     let split_index = usize::from(split_axis);
-                    let left_first = ray.origin[split_index] < *split_location
-                        || ((ray.origin[split_index] - *split_location).abs() < EPSILON
-                            && ray.direction[split_index] <= 0.0);
+                    let left_first = is_left_first(ray, split_index, *split_location);
 
                     let (first, second) = if left_first {
                         (left, right)
@@ -392,19 +750,23 @@ impl KdTreeAccelerator {
             }
             KdTree::Leaf(object_indexes) => object_indexes.iter().any(|index| {
                 self.bounded_objects[*index]
-                    .intersect(&ray, max_distance)
+                    .intersect(ray, max_distance)
                     .is_some()
             }),
         }
     }
 }
 
-#[derive(Copy, Clone)]
-struct KdTreeConstructionOptions {
-    max_objects: usize,
-    intersection_cost: f64,
-    traversal_cost: f64,
-    empty_bonus: f64,
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KdTreeConstructionOptions {
+    pub max_objects: usize,
+    pub intersection_cost: f64,
+    pub traversal_cost: f64,
+    pub empty_bonus: f64,
+    // Defaults to a depth based on the number of objects in the tree
+    pub max_depth: Option<u8>,
+    pub max_bad_refines: u8,
 }
 
 impl Default for KdTreeConstructionOptions {
@@ -414,10 +776,13 @@ impl Default for KdTreeConstructionOptions {
             intersection_cost: 80.0,
             traversal_cost: 1.0,
             empty_bonus: 0.5,
+            max_depth: None,
+            max_bad_refines: 3,
         }
     }
 }
 
+#[derive(Deserialize, Serialize)]
 enum KdTree {
     Node {
         split_axis: Axis,
@@ -450,6 +815,53 @@ impl fmt::Debug for KdTree {
 }
 
 impl KdTree {
+    // Leaves don't store their bounds, so they're rebuilt from the split planes above them
+    fn collect_stats(
+        &self,
+        options: &KdTreeConstructionOptions,
+        bounding_volume: BoundingVolume,
+        depth: usize,
+        stats: &mut AcceleratorStats,
+    ) {
+        match self {
+            Self::Node {
+                split_axis,
+                split_location,
+                left,
+                right,
+                ..
+            } => {
+                stats.add_interior(&bounding_volume, options.traversal_cost);
+
+                let split_axis = usize::from(split_axis);
+                let mut left_bound = bounding_volume.bounds_max;
+                left_bound[split_axis] = *split_location;
+                let mut right_bound = bounding_volume.bounds_min;
+                right_bound[split_axis] = *split_location;
+
+                left.collect_stats(
+                    options,
+                    BoundingVolume::from_bounds(bounding_volume.bounds_min, left_bound),
+                    depth + 1,
+                    stats,
+                );
+                right.collect_stats(
+                    options,
+                    BoundingVolume::from_bounds(right_bound, bounding_volume.bounds_max),
+                    depth + 1,
+                    stats,
+                );
+            }
+            Self::Leaf(indexes) => stats.add_leaf(
+                &bounding_volume,
+                depth,
+                indexes.len(),
+                options.intersection_cost,
+            ),
+        }
+    }
+
+    // Nodes with at least `parallel_threshold` objects are split and built on multiple threads
     fn build(
         objects: &[BoundedObject],
         options: KdTreeConstructionOptions,
@@ -457,6 +869,7 @@ impl KdTree {
         max_bad_refines: u8,
         bounding_volume: BoundingVolume,
         indexes: Vec<usize>,
+        parallel_threshold: usize,
     ) -> Option<Self> {
         if indexes.is_empty() {
             return None;
@@ -465,91 +878,47 @@ impl KdTree {
         }
 
         let split_axis = bounding_volume.maximum_extent();
-        let total_surface_area = bounding_volume.surface_area();
-        let bounds_diagonal = bounding_volume.bounds_max - bounding_volume.bounds_min;
         let old_cost = options.intersection_cost * indexes.len() as f64;
+        let build_in_parallel = indexes.len() >= parallel_threshold;
+
+        // Axes are tried in order until one has a split. Large nodes evaluate every axis at once
+        // rather than waiting on each one, which gives the same split.
+        let evaluate_axis =
+            |axis| SplitEvaluation::new(objects, &options, &bounding_volume, &indexes, axis);
+        let split_evaluation = if build_in_parallel {
+            let axes: Vec<usize> = Axis::iter(split_axis).collect();
+            let split_evaluations: Vec<SplitEvaluation> =
+                axes.into_par_iter().map(evaluate_axis).collect();
+
+            split_evaluations
+                .into_iter()
+                .find(|split_evaluation| split_evaluation.best_split.is_some())
+        } else {
+            Axis::iter(split_axis)
+                .map(evaluate_axis)
+                .find(|split_evaluation| split_evaluation.best_split.is_some())
+        };
 
-        let mut max_bad_refines = max_bad_refines;
-        let mut split_attempts = 0;
-
-        let mut split_candidates = Vec::new();
-        let mut best_axis_and_split = None;
-        let mut best_cost = f64::INFINITY;
-
-        for axis in Axis::iter(split_axis) {
-            split_candidates.clear();
-            for &index in &indexes {
-                let object_bounds = objects[index].bounding_volume;
-                split_candidates.push(SplitCandidate::Start(object_bounds.bounds_min[axis], index));
-                split_candidates.push(SplitCandidate::End(object_bounds.bounds_max[axis], index));
-            }
-            split_candidates.sort_by(|a, b| SplitCandidate::cmp(a, b));
-
-            let mut below = 0;
-            let mut above = indexes.len();
-            for (index, split_candidate) in split_candidates.iter().enumerate() {
-                if let SplitCandidate::End(_, _) = split_candidate {
-                    above -= 1;
-                }
-
-                let split = split_candidate.get_split();
-
-                if bounding_volume.bounds_min[axis] < split
-                    && split < bounding_volume.bounds_max[axis]
-                {
-                    let other_axis0 = (axis + 1) % 3;
-                    let other_axis1 = (axis + 2) % 3;
-                    let d = bounds_diagonal[other_axis0] * bounds_diagonal[other_axis1];
-                    let surface_area_below = 2.0
-                        * (d + (split - bounding_volume.bounds_min[axis])
-                            * (bounds_diagonal[other_axis0] + bounds_diagonal[other_axis1]));
-                    let surface_area_above = 2.0
-                        * (d + (bounding_volume.bounds_max[axis] - split)
-                            * (bounds_diagonal[other_axis0] + bounds_diagonal[other_axis1]));
-
-                    let area_below = surface_area_below / total_surface_area;
-                    let area_above = surface_area_above / total_surface_area;
-                    let empty_bonus = if above == 0 || below == 0 {
-                        options.empty_bonus
-                    } else {
-                        0.0
-                    };
-                    let cost = options.traversal_cost
-                        + options.intersection_cost
-                            * (1.0 - empty_bonus)
-                            * (area_below * f64::from(below) + area_above * above as f64);
-
-                    if cost < best_cost {
-                        best_cost = cost;
-                        best_axis_and_split = Some((axis, index));
-                    }
-                }
-
-                if let SplitCandidate::Start(_, _) = split_candidate {
-                    below += 1;
-                }
-            }
-
-            if best_axis_and_split.is_none() && split_attempts < 2 {
-                split_attempts += 1;
-                continue;
-            }
+        if split_evaluation.is_none() {
+            return Some(Self::Leaf(indexes));
+        }
 
-            if best_cost > old_cost {
-                max_bad_refines -= 1;
-            }
+        let SplitEvaluation {
+            axis: split_axis,
+            split_candidates,
+            best_split,
+        } = split_evaluation.unwrap();
+        let (split_index, best_cost) = best_split.unwrap();
 
-            if best_axis_and_split.is_none()
-                || max_bad_refines == 0
-                || (best_cost > 4.0 * old_cost && indexes.len() < 16)
-            {
-                return Some(Self::Leaf(indexes));
-            }
+        let mut max_bad_refines = max_bad_refines;
+        if best_cost > old_cost {
+            max_bad_refines = max_bad_refines.saturating_sub(1);
+        }
 
-            break;
+        if max_bad_refines == 0 || (best_cost > 4.0 * old_cost && indexes.len() < 16) {
+            return Some(Self::Leaf(indexes));
         }
 
-        let (split_axis, split_index) = best_axis_and_split.unwrap();
         let split_location = split_candidates[split_index].get_split();
 
         let mut left = Vec::new();
@@ -574,27 +943,39 @@ impl KdTree {
         left_bound[split_axis] = split_location;
         let left_bounding_volume =
             BoundingVolume::from_bounds(bounding_volume.bounds_min, left_bound);
-        let left = Self::build(
-            objects,
-            options,
-            max_depth - 1,
-            max_bad_refines,
-            left_bounding_volume,
-            left,
-        );
+        let build_left = || {
+            Self::build(
+                objects,
+                options,
+                max_depth - 1,
+                max_bad_refines,
+                left_bounding_volume,
+                left,
+                parallel_threshold,
+            )
+        };
 
         let mut right_bound = bounding_volume.bounds_min;
         right_bound[split_axis] = split_location;
         let right_bounding_volume =
             BoundingVolume::from_bounds(right_bound, bounding_volume.bounds_max);
-        let right = Self::build(
-            objects,
-            options,
-            max_depth - 1,
-            max_bad_refines,
-            right_bounding_volume,
-            right,
-        );
+        let build_right = || {
+            Self::build(
+                objects,
+                options,
+                max_depth - 1,
+                max_bad_refines,
+                right_bounding_volume,
+                right,
+                parallel_threshold,
+            )
+        };
+
+        let (left, right) = if build_in_parallel {
+            rayon::join(build_left, build_right)
+        } else {
+            (build_left(), build_right())
+        };
 
         match (left, right) {
             (Some(left), Some(right)) => Some(Self::Node {
@@ -609,3 +990,89 @@ impl KdTree {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{Material, MediumStack, Transform};
+    use crate::primitives::{Object3D, Sphere};
+    use crate::ray_intersection::RayType;
+    use nalgebra::{Point3, Vector3};
+
+    fn sphere_grid() -> Vec<Box<dyn RaytracingObject>> {
+        let mut objects = Vec::new();
+        for x in -5..6 {
+            for y in -5..6 {
+                for z in -5..6 {
+                    let sphere = Object3D::Sphere(Box::new(Sphere::new(
+                        0.3,
+                        Transform::default().translate(Vector3::new(
+                            f64::from(x),
+                            f64::from(y),
+                            f64::from(z),
+                        )),
+                        Material::default(),
+                    )));
+                    objects.append(&mut sphere.flatten_to_world(&Transform::default()));
+                }
+            }
+        }
+
+        objects
+    }
+
+    #[test]
+    fn it_builds_the_same_tree_in_parallel() {
+        let parallel = KdTreeAccelerator::new(
+            sphere_grid(),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        assert!(parallel.bounded_objects.len() > PARALLEL_BUILD_THRESHOLD);
+
+        let mut sequential = KdTreeAccelerator::new(
+            sphere_grid(),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        sequential.tree = KdTree::build(
+            &sequential.bounded_objects,
+            sequential.options,
+            (8.0 + 1.3 * (sequential.bounded_objects.len() as f64).log2()) as u8,
+            sequential.options.max_bad_refines,
+            sequential.bounding_volume.unwrap(),
+            (0..sequential.bounded_objects.len()).collect(),
+            usize::MAX,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", parallel.tree),
+            format!("{:?}", sequential.tree)
+        );
+
+        for i in 0..100 {
+            let angle = f64::from(i) * 0.3;
+            let ray = Ray {
+                ray_type: RayType::Primary,
+                origin: Point3::new(10.0 * angle.cos(), 3.0, 10.0 * angle.sin()),
+                direction: Vector3::new(
+                    -angle.cos() + 0.01 * f64::from(i % 7),
+                    -0.4,
+                    -angle.sin() - 0.01 * f64::from(i % 5),
+                ),
+                media: MediumStack::default(),
+                wavelengths: None,
+                differentials: None,
+            };
+
+            assert_eq!(
+                parallel
+                    .raycast(&ray)
+                    .map(|intersection| intersection.distance),
+                sequential
+                    .raycast(&ray)
+                    .map(|intersection| intersection.distance)
+            );
+        }
+    }
+}
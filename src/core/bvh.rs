@@ -0,0 +1,562 @@
+use super::bounds::{build_bounding_volume, partition_objects, BoundedObject, UnboundedObject};
+use super::{Accelerator, AcceleratorCache, AcceleratorStats, BoundingVolume, NodeBounds};
+use crate::primitives::RaytracingObject;
+use crate::ray_intersection::{Intersectable, Intersection, Ray};
+use nalgebra::Point3;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering::Equal;
+
+const NUM_BINS: usize = 16;
+const MAX_LEAF_OBJECTS: usize = 4;
+// Cost of visiting a node relative to intersecting a single object
+const TRAVERSAL_COST: f64 = 0.125;
+// Subtrees with fewer objects than this are built on the current thread
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+#[derive(Copy, Clone)]
+struct ObjectInfo {
+    index: usize,
+    bounding_volume: BoundingVolume,
+}
+
+enum BvhBuildNode {
+    Interior {
+        bounding_volume: BoundingVolume,
+        split_axis: usize,
+        left: Box<BvhBuildNode>,
+        right: Box<BvhBuildNode>,
+    },
+    Leaf {
+        bounding_volume: BoundingVolume,
+        first_object: usize,
+        num_objects: usize,
+    },
+}
+
+// Nodes are stored depth first, so the left child of an interior node directly follows it
+#[derive(Debug, Deserialize, Serialize)]
+enum BvhNode<B> {
+    Interior {
+        bounding_volume: B,
+        split_axis: usize,
+        second_child: usize,
+    },
+    Leaf {
+        bounding_volume: B,
+        first_object: usize,
+        num_objects: usize,
+    },
+}
+
+impl<B> BvhNode<B> {
+    fn get_bounding_volume(&self) -> &B {
+        match self {
+            BvhNode::Interior {
+                bounding_volume, ..
+            }
+            | BvhNode::Leaf {
+                bounding_volume, ..
+            } => bounding_volume,
+        }
+    }
+}
+
+fn bin_index(centroid: f64, centroid_min: f64, centroid_extent: f64) -> usize {
+    let bin = ((centroid - centroid_min) / centroid_extent * NUM_BINS as f64) as usize;
+
+    bin.min(NUM_BINS - 1)
+}
+
+// Builds a subtree over a slice of objects which starts at `offset` in the final object order,
+// reordering the slice so that every leaf covers a contiguous range of it
+fn build(objects: &mut [ObjectInfo], offset: usize) -> BvhBuildNode {
+    let bounding_volumes: Vec<BoundingVolume> = objects
+        .iter()
+        .map(|object| object.bounding_volume)
+        .collect();
+    let bounding_volume = build_bounding_volume(&bounding_volumes);
+    let leaf = BvhBuildNode::Leaf {
+        bounding_volume,
+        first_object: offset,
+        num_objects: objects.len(),
+    };
+
+    if objects.len() == 1 {
+        return leaf;
+    }
+
+    let mut centroid_min = objects[0].bounding_volume.center;
+    let mut centroid_max = centroid_min;
+    for object in &objects[1..] {
+        let centroid = object.bounding_volume.center;
+        centroid_min = Point3::from(centroid_min.coords.inf(&centroid.coords));
+        centroid_max = Point3::from(centroid_max.coords.sup(&centroid.coords));
+    }
+
+    let split_axis = BoundingVolume::from_bounds(centroid_min, centroid_max)
+        .maximum_extent()
+        .into();
+    let centroid_min = centroid_min[split_axis];
+    let centroid_extent = centroid_max[split_axis] - centroid_min;
+    if centroid_extent <= 0.0 {
+        // Every centroid is at the same point so the objects can't be separated
+        return leaf;
+    }
+
+    let mut bins: [(usize, Option<BoundingVolume>); NUM_BINS] = [(0, None); NUM_BINS];
+    for object in objects.iter() {
+        let bin = &mut bins[bin_index(
+            object.bounding_volume.center[split_axis],
+            centroid_min,
+            centroid_extent,
+        )];
+        bin.0 += 1;
+        bin.1 = Some(bin.1.map_or(object.bounding_volume, |bounding_volume| {
+            BoundingVolume::merge(&bounding_volume, &object.bounding_volume)
+        }));
+    }
+
+    // Costs are left scaled by the node's surface area, which doesn't change which split is best
+    let mut below_costs = [0.0; NUM_BINS];
+    let mut count = 0;
+    let mut bounds: Option<BoundingVolume> = None;
+    for (bin, below_cost) in bins.iter().zip(below_costs.iter_mut()) {
+        count += bin.0;
+        bounds = merge_bins(bounds, bin.1);
+        *below_cost = bounds.map_or(0.0, |bounds| count as f64 * bounds.surface_area());
+    }
+
+    let mut best_split = None;
+    let mut best_cost = f64::INFINITY;
+    let mut count = 0;
+    let mut bounds: Option<BoundingVolume> = None;
+    for split in (0..NUM_BINS - 1).rev() {
+        let bin = &bins[split + 1];
+        count += bin.0;
+        bounds = merge_bins(bounds, bin.1);
+
+        if count == 0 || count == objects.len() {
+            continue;
+        }
+
+        let above_cost = bounds.map_or(0.0, |bounds| count as f64 * bounds.surface_area());
+        let cost =
+            TRAVERSAL_COST * bounding_volume.surface_area() + below_costs[split] + above_cost;
+        if cost < best_cost {
+            best_cost = cost;
+            best_split = Some(split);
+        }
+    }
+
+    let leaf_cost = objects.len() as f64 * bounding_volume.surface_area();
+    if best_split.is_none() || (objects.len() <= MAX_LEAF_OBJECTS && leaf_cost <= best_cost) {
+        return leaf;
+    }
+
+    let best_split = best_split.unwrap();
+
+    let mut mid = 0;
+    for index in 0..objects.len() {
+        let bin = bin_index(
+            objects[index].bounding_volume.center[split_axis],
+            centroid_min,
+            centroid_extent,
+        );
+        if bin <= best_split {
+            objects.swap(index, mid);
+            mid += 1;
+        }
+    }
+
+    let build_in_parallel = objects.len() >= PARALLEL_BUILD_THRESHOLD;
+    let (left_objects, right_objects) = objects.split_at_mut(mid);
+    let (left, right) = if build_in_parallel {
+        rayon::join(
+            || build(left_objects, offset),
+            || build(right_objects, offset + mid),
+        )
+    } else {
+        (
+            build(left_objects, offset),
+            build(right_objects, offset + mid),
+        )
+    };
+
+    BvhBuildNode::Interior {
+        bounding_volume,
+        split_axis,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+fn merge_bins(a: Option<BoundingVolume>, b: Option<BoundingVolume>) -> Option<BoundingVolume> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(BoundingVolume::merge(&a, &b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// Appends a subtree to the flattened node array and returns the index of its root
+fn flatten<B: NodeBounds>(node: BvhBuildNode, nodes: &mut Vec<BvhNode<B>>) -> usize {
+    match node {
+        BvhBuildNode::Leaf {
+            bounding_volume,
+            first_object,
+            num_objects,
+        } => {
+            nodes.push(BvhNode::Leaf {
+                bounding_volume: B::new(&bounding_volume),
+                first_object,
+                num_objects,
+            });
+
+            nodes.len() - 1
+        }
+        BvhBuildNode::Interior {
+            bounding_volume,
+            split_axis,
+            left,
+            right,
+        } => {
+            let index = nodes.len();
+            nodes.push(BvhNode::Interior {
+                bounding_volume: B::new(&bounding_volume),
+                split_axis,
+                second_child: 0,
+            });
+
+            flatten(*left, nodes);
+            let second_child = flatten(*right, nodes);
+            if let BvhNode::Interior {
+                second_child: node_second_child,
+                ..
+            } = &mut nodes[index]
+            {
+                *node_second_child = second_child;
+            }
+
+            index
+        }
+    }
+}
+
+// A bounding volume hierarchy built by binning objects along their largest centroid extent and
+// splitting where the surface area heuristic is lowest. Node bounds are stored as `B`, which is
+// either full precision or compact single precision bounds.
+#[derive(Debug)]
+pub struct BvhAccelerator<B: NodeBounds = BoundingVolume> {
+    unbounded_objects: Vec<UnboundedObject>,
+    bounded_objects: Vec<BoundedObject>,
+    bounding_volume: Option<BoundingVolume>,
+    nodes: Vec<BvhNode<B>>,
+}
+
+impl<B: NodeBounds> BvhAccelerator<B> {
+    pub fn new(objects: Vec<Box<dyn RaytracingObject>>, cache: &mut AcceleratorCache) -> Self {
+        let (unbounded_objects, bounded_objects) = partition_objects(objects);
+        let cache_key = if cache.is_enabled() {
+            Some(AcceleratorCache::key(
+                &format!("bvh-{}", B::NAME),
+                &[],
+                unbounded_objects.len(),
+                &bounded_objects,
+            ))
+        } else {
+            None
+        };
+
+        // The order objects are stored in, so that each leaf covers a contiguous range of them
+        let cached_nodes: Option<(Vec<BvhNode<B>>, Vec<usize>)> =
+            cache_key.and_then(|cache_key| cache.get(cache_key));
+        let (nodes, object_order) = cached_nodes.unwrap_or_else(|| {
+            let mut object_infos: Vec<ObjectInfo> = bounded_objects
+                .iter()
+                .enumerate()
+                .map(|(index, object)| ObjectInfo {
+                    index,
+                    bounding_volume: object.bounding_volume,
+                })
+                .collect();
+
+            let mut nodes = Vec::new();
+            if !object_infos.is_empty() {
+                let root = build(&mut object_infos, 0);
+                nodes.reserve(2 * object_infos.len());
+                flatten(root, &mut nodes);
+            }
+
+            let object_order = object_infos
+                .iter()
+                .map(|object_info| object_info.index)
+                .collect();
+            let nodes_and_order = (nodes, object_order);
+            if let Some(cache_key) = cache_key {
+                cache.insert(cache_key, &nodes_and_order);
+            }
+
+            nodes_and_order
+        });
+
+        let mut bounded_objects: Vec<Option<BoundedObject>> =
+            bounded_objects.into_iter().map(Some).collect();
+        let bounded_objects: Vec<BoundedObject> = object_order
+            .iter()
+            .map(|&index| bounded_objects[index].take().unwrap())
+            .collect();
+
+        let bounding_volume = match nodes.first() {
+            Some(root) if unbounded_objects.is_empty() => {
+                Some(root.get_bounding_volume().to_bounding_volume())
+            }
+            _ => None,
+        };
+
+        Self {
+            unbounded_objects,
+            bounded_objects,
+            bounding_volume,
+            nodes,
+        }
+    }
+
+    // Visits the nodes hit by a ray nearest child first, calling `visit_object` on the objects in
+    // each leaf until it returns true
+    fn traverse<'a>(
+        &'a self,
+        ray: &Ray,
+        max_distance: Option<f64>,
+        mut visit_object: impl FnMut(&'a BoundedObject, &mut Option<f64>) -> bool,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let direction_is_negative = [
+            ray.direction.x < 0.0,
+            ray.direction.y < 0.0,
+            ray.direction.z < 0.0,
+        ];
+        let ray_data = B::ray_data(ray);
+        let mut max_distance = max_distance;
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node
+                .get_bounding_volume()
+                .intersect(ray, &ray_data, max_distance)
+            {
+                continue;
+            }
+
+            match node {
+                BvhNode::Interior {
+                    split_axis,
+                    second_child,
+                    ..
+                } => {
+                    if direction_is_negative[*split_axis] {
+                        stack.push(node_index + 1);
+                        stack.push(*second_child);
+                    } else {
+                        stack.push(*second_child);
+                        stack.push(node_index + 1);
+                    }
+                }
+                BvhNode::Leaf {
+                    first_object,
+                    num_objects,
+                    ..
+                } => {
+                    for object in &self.bounded_objects[*first_object..first_object + num_objects] {
+                        if visit_object(object, &mut max_distance) {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<B: NodeBounds> Intersectable for BvhAccelerator<B> {
+    fn intersect(&self, ray: &Ray, max_distance: Option<f64>) -> Option<Intersection> {
+        let mut closest_intersection: Option<Intersection> = None;
+        self.traverse(ray, max_distance, |object, max_distance| {
+            if let Some(intersection) = object.intersect(ray, *max_distance) {
+                let is_closer = match max_distance {
+                    Some(max_distance) => intersection.distance < *max_distance,
+                    None => true,
+                };
+                if is_closer {
+                    *max_distance = Some(intersection.distance);
+                    closest_intersection = Some(intersection);
+                }
+            }
+
+            false
+        });
+
+        self.unbounded_objects
+            .iter()
+            .filter_map(|object| object.intersect(ray, max_distance))
+            .chain(closest_intersection)
+            .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Equal))
+    }
+}
+
+impl<B: NodeBounds> Accelerator for BvhAccelerator<B> {
+    fn get_num_objects(&self) -> usize {
+        self.unbounded_objects.len() + self.bounded_objects.len()
+    }
+
+    fn get_bounding_volume(&self) -> Option<BoundingVolume> {
+        self.bounding_volume
+    }
+
+    fn shadow_cast(&self, ray: &Ray, max_distance: f64) -> bool {
+        if self
+            .unbounded_objects
+            .iter()
+            .filter_map(|object| object.intersect(ray, Some(max_distance)))
+            .any(|intersection| intersection.distance <= max_distance)
+        {
+            return true;
+        }
+
+        let mut hit = false;
+        self.traverse(ray, Some(max_distance), |object, max_distance| {
+            hit = object.intersect(ray, *max_distance).is_some();
+            hit
+        });
+
+        hit
+    }
+
+    fn get_stats(&self) -> AcceleratorStats {
+        let bounds = self
+            .nodes
+            .first()
+            .map(|root| root.get_bounding_volume().to_bounding_volume());
+        let mut stats = AcceleratorStats::new(self.unbounded_objects.len(), bounds);
+        if self.nodes.is_empty() {
+            return stats;
+        }
+
+        let mut stack = vec![(0, 0)];
+        while let Some((node_index, depth)) = stack.pop() {
+            match &self.nodes[node_index] {
+                BvhNode::Interior {
+                    bounding_volume,
+                    second_child,
+                    ..
+                } => {
+                    stats.add_interior(&bounding_volume.to_bounding_volume(), TRAVERSAL_COST);
+                    stack.push((node_index + 1, depth + 1));
+                    stack.push((*second_child, depth + 1));
+                }
+                BvhNode::Leaf {
+                    bounding_volume,
+                    num_objects,
+                    ..
+                } => stats.add_leaf(
+                    &bounding_volume.to_bounding_volume(),
+                    depth,
+                    *num_objects,
+                    1.0,
+                ),
+            }
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        CompactBounds, KdTreeAccelerator, KdTreeConstructionOptions, Material, MediumStack,
+        Transform,
+    };
+    use crate::primitives::{Object3D, Plane, Sphere};
+    use crate::ray_intersection::RayType;
+    use nalgebra::Vector3;
+
+    fn sphere_grid() -> Vec<Box<dyn RaytracingObject>> {
+        let mut objects = Vec::new();
+        for x in -5..6 {
+            for y in -5..6 {
+                for z in -5..6 {
+                    let sphere = Object3D::Sphere(Box::new(Sphere::new(
+                        0.3,
+                        Transform::default().translate(Vector3::new(
+                            f64::from(x),
+                            f64::from(y),
+                            f64::from(z),
+                        )),
+                        Material::default(),
+                    )));
+                    objects.append(&mut sphere.flatten_to_world(&Transform::default()));
+                }
+            }
+        }
+        let plane = Object3D::Plane(Box::new(Plane::new(
+            Vector3::y_axis(),
+            Transform::default().translate(Vector3::new(0.0, -6.0, 0.0)),
+            Material::default(),
+        )));
+        objects.append(&mut plane.flatten_to_world(&Transform::default()));
+
+        objects
+    }
+
+    #[test]
+    fn it_finds_the_same_intersections_as_the_kd_tree() {
+        let bvh =
+            BvhAccelerator::<BoundingVolume>::new(sphere_grid(), &mut AcceleratorCache::disabled());
+        let compact_bvh =
+            BvhAccelerator::<CompactBounds>::new(sphere_grid(), &mut AcceleratorCache::disabled());
+        let kd_tree = KdTreeAccelerator::new(
+            sphere_grid(),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        assert_eq!(bvh.get_num_objects(), 1332);
+        assert!(bvh.get_bounding_volume().is_none());
+
+        for i in 0..100 {
+            let angle = f64::from(i) * 0.3;
+            let ray = Ray {
+                ray_type: RayType::Primary,
+                origin: Point3::new(10.0 * angle.cos(), 3.0, 10.0 * angle.sin()),
+                direction: Vector3::new(
+                    -angle.cos() + 0.01 * f64::from(i % 7),
+                    -0.4,
+                    -angle.sin() - 0.01 * f64::from(i % 5),
+                ),
+                media: MediumStack::default(),
+                wavelengths: None,
+                differentials: None,
+            };
+
+            let bvh_distance = bvh.raycast(&ray).map(|intersection| intersection.distance);
+            let kd_tree_distance = kd_tree
+                .raycast(&ray)
+                .map(|intersection| intersection.distance);
+            let compact_bvh_distance = compact_bvh
+                .raycast(&ray)
+                .map(|intersection| intersection.distance);
+            assert_eq!(bvh_distance, kd_tree_distance);
+            assert_eq!(compact_bvh_distance, kd_tree_distance);
+            assert_eq!(bvh.shadow_cast(&ray, 5.0), kd_tree.shadow_cast(&ray, 5.0),);
+            assert_eq!(
+                compact_bvh.shadow_cast(&ray, 5.0),
+                kd_tree.shadow_cast(&ray, 5.0),
+            );
+        }
+    }
+}
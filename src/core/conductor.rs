@@ -0,0 +1,100 @@
+use crate::utils;
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+// Measured metals, with their complex refractive indices sampled at red, green and blue
+// wavelengths of 650, 550 and 450 nanometers
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Metal {
+    Gold,
+    Copper,
+    Aluminium,
+    Silver,
+    Chrome,
+}
+
+impl Metal {
+    fn complex_index(self) -> ComplexIndex {
+        let (eta, k) = match self {
+            Metal::Gold => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+            Metal::Copper => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+            Metal::Aluminium => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+            Metal::Silver => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+            Metal::Chrome => ([3.184, 3.108, 2.365], [3.330, 3.328, 3.186]),
+        };
+
+        ComplexIndex {
+            eta: Vector3::from(eta),
+            k: Vector3::from(k),
+        }
+    }
+}
+
+// Refractive index and extinction coefficient for each color channel
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ComplexIndex {
+    pub eta: Vector3<f64>,
+    pub k: Vector3<f64>,
+}
+
+// Metal reflecting light tinted by its complex refractive index, given either by name or directly
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Conductor {
+    Measured(Metal),
+    Custom(ComplexIndex),
+}
+
+impl Conductor {
+    pub fn complex_index(&self) -> ComplexIndex {
+        match *self {
+            Conductor::Measured(metal) => metal.complex_index(),
+            Conductor::Custom(index) => index,
+        }
+    }
+
+    // Fraction of light reflected at an angle, coming from a medium with the given refractive index
+    pub fn reflectance(&self, cos_theta_i: f64, eta_i: f64) -> Vector3<f64> {
+        let ComplexIndex { eta, k } = self.complex_index();
+
+        utils::fresnel_conductor(cos_theta_i, &(eta / eta_i), &(k / eta_i))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_deserializes_conductors() {
+        assert_eq!(
+            serde_json::from_value::<Conductor>(json!("gold")).unwrap(),
+            Conductor::Measured(Metal::Gold)
+        );
+        assert_eq!(
+            serde_json::from_value::<Conductor>(json!({ "eta": [1, 1, 1], "k": [2, 3, 4] }))
+                .unwrap(),
+            Conductor::Custom(ComplexIndex {
+                eta: Vector3::repeat(1.0),
+                k: Vector3::new(2.0, 3.0, 4.0),
+            })
+        );
+        assert!(serde_json::from_value::<Conductor>(json!("brass")).is_err());
+    }
+
+    #[test]
+    fn it_tints_reflections_of_measured_metals() {
+        // Gold reflects red light more strongly than blue, until everything reflects at grazing
+        // angles
+        let gold = Conductor::Measured(Metal::Gold).reflectance(1.0, 1.0);
+        assert!(gold.x > 0.9 && gold.z < 0.5);
+        let grazing = Conductor::Measured(Metal::Gold).reflectance(0.0, 1.0);
+        assert!((grazing - Vector3::repeat(1.0)).amax() < 1e-9);
+
+        let silver = Conductor::Measured(Metal::Silver).reflectance(1.0, 1.0);
+        assert!(silver.min() > 0.9);
+    }
+}
@@ -0,0 +1,289 @@
+use nalgebra::{Point3, Vector3};
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// Density of a volume at points within the unit cube, which is zero everywhere outside of it
+#[derive(Debug)]
+pub enum DensityField {
+    Grid(DensityGrid),
+    Noise(NoiseDensity),
+}
+
+impl DensityField {
+    pub fn density(&self, point: &Point3<f64>) -> f64 {
+        if point.iter().any(|&c| !(0.0..=1.0).contains(&c)) {
+            return 0.0;
+        }
+
+        match self {
+            DensityField::Grid(grid) => grid.density(point),
+            DensityField::Noise(noise) => noise.density(point),
+        }
+    }
+
+    // Upper bound on the density anywhere in the field
+    pub fn max_density(&self) -> f64 {
+        match self {
+            DensityField::Grid(grid) => grid.max_density,
+            DensityField::Noise(_) => 1.0,
+        }
+    }
+}
+
+// Densities sampled at the centers of the cells of a regular grid over the unit cube, stored with
+// x varying fastest and z slowest
+#[derive(Debug)]
+pub struct DensityGrid {
+    resolution: [usize; 3],
+    densities: Vec<f32>,
+    max_density: f64,
+}
+
+impl DensityGrid {
+    pub fn new(resolution: [usize; 3], densities: Vec<f32>) -> Result<Self> {
+        let num_cells = resolution.iter().product::<usize>();
+        if num_cells == 0 || densities.len() != num_cells {
+            return Err(invalid_data(format!(
+                "expected {} densities for a {}x{}x{} grid but found {}",
+                num_cells,
+                resolution[0],
+                resolution[1],
+                resolution[2],
+                densities.len()
+            )));
+        }
+
+        let max_density = densities.iter().copied().fold(0.0, f32::max);
+        Ok(Self {
+            resolution,
+            densities,
+            max_density: f64::from(max_density),
+        })
+    }
+
+    // Grids are read from Mitsuba's binary .vol format, or otherwise from text holding the
+    // resolution along each axis followed by every density
+    pub fn load(path: &Path) -> Result<Self> {
+        if path.extension().and_then(OsStr::to_str) == Some("vol") {
+            Self::parse_vol(&fs::read(path)?)
+        } else {
+            Self::parse_text(&fs::read_to_string(path)?)
+        }
+    }
+
+    fn parse_text(text: &str) -> Result<Self> {
+        let mut tokens = text.split_whitespace();
+
+        let mut resolution = [0; 3];
+        for size in &mut resolution {
+            let token = tokens
+                .next()
+                .ok_or_else(|| invalid_data("missing density grid resolution".to_owned()))?;
+            *size = token
+                .parse()
+                .map_err(|_| invalid_data(format!("invalid density grid size \"{}\"", token)))?;
+        }
+
+        let densities = tokens
+            .map(|token| {
+                token
+                    .parse()
+                    .map_err(|_| invalid_data(format!("invalid density \"{}\"", token)))
+            })
+            .collect::<Result<Vec<f32>>>()?;
+
+        Self::new(resolution, densities)
+    }
+
+    // A 48 byte header followed by little endian f32 values. Only single channel grids of floats
+    // are supported, and the bounding box in the header is ignored in favor of the volume's
+    // transform.
+    fn parse_vol(bytes: &[u8]) -> Result<Self> {
+        const HEADER_SIZE: usize = 48;
+
+        if bytes.len() < HEADER_SIZE || &bytes[..3] != b"VOL" || bytes[3] != 3 {
+            return Err(invalid_data("missing VOL version 3 header".to_owned()));
+        }
+        let read_i32 = |offset: usize| {
+            i32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 byte slice"))
+        };
+
+        let (encoding, channels) = (read_i32(4), read_i32(20));
+        if encoding != 1 || channels != 1 {
+            return Err(invalid_data(format!(
+                "unsupported VOL encoding {} with {} channels",
+                encoding, channels
+            )));
+        }
+
+        let mut resolution = [0; 3];
+        for (axis, size) in resolution.iter_mut().enumerate() {
+            *size = read_i32(8 + 4 * axis)
+                .try_into()
+                .map_err(|_| invalid_data("negative VOL resolution".to_owned()))?;
+        }
+
+        let densities = bytes[HEADER_SIZE..]
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes(value.try_into().expect("4 byte chunk")))
+            .collect();
+
+        Self::new(resolution, densities)
+    }
+
+    fn cell(&self, x: usize, y: usize, z: usize) -> f64 {
+        let [width, height, _] = self.resolution;
+        f64::from(self.densities[(z * height + y) * width + x])
+    }
+
+    // Trilinearly interpolated density, held constant past the outermost cell centers
+    fn density(&self, point: &Point3<f64>) -> f64 {
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut t = Vector3::zeros();
+        for axis in 0..3 {
+            let size = self.resolution[axis];
+            let position = point[axis] * size as f64 - 0.5;
+            let cell = position.floor();
+
+            lower[axis] = (cell.max(0.0) as usize).min(size - 1);
+            upper[axis] = ((cell + 1.0).max(0.0) as usize).min(size - 1);
+            t[axis] = position - cell;
+        }
+
+        let lerp_x = |y: usize, z: usize| {
+            self.cell(lower[0], y, z) * (1.0 - t.x) + self.cell(upper[0], y, z) * t.x
+        };
+        let lerp_y = |z: usize| lerp_x(lower[1], z) * (1.0 - t.y) + lerp_x(upper[1], z) * t.y;
+
+        lerp_y(lower[2]) * (1.0 - t.z) + lerp_y(upper[2]) * t.z
+    }
+}
+
+// Fractal value noise between 0 and 1. Each octave doubles the frequency and halves the
+// amplitude of the one before it. Noise below the threshold is cut off to leave empty space, and
+// the rest is stretched back out to fill the range.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoiseDensity {
+    pub frequency: f64,
+    pub octaves: u8,
+    pub seed: u32,
+    pub threshold: f64,
+}
+
+impl Default for NoiseDensity {
+    fn default() -> Self {
+        Self {
+            frequency: 4.0,
+            octaves: 4,
+            seed: 0,
+            threshold: 0.0,
+        }
+    }
+}
+
+impl NoiseDensity {
+    // Pseudorandom value in [0, 1) for a lattice point
+    fn lattice_value(&self, x: i64, y: i64, z: i64) -> f64 {
+        let mut hash = (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+            ^ (z as u64).wrapping_mul(0x1656_67b1_9e37_79f9)
+            ^ u64::from(self.seed);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^= hash >> 33;
+
+        (hash >> 11) as f64 / (1_u64 << 53) as f64
+    }
+
+    fn value_noise(&self, point: &Point3<f64>) -> f64 {
+        let cell = point.coords.map(f64::floor);
+        // Smoothstep weights give the noise a continuous gradient across cells
+        let t = (point.coords - cell).map(|t| t * t * (3.0 - 2.0 * t));
+        let (x, y, z) = (cell.x as i64, cell.y as i64, cell.z as i64);
+
+        let lerp_x = |y: i64, z: i64| {
+            self.lattice_value(x, y, z) * (1.0 - t.x) + self.lattice_value(x + 1, y, z) * t.x
+        };
+        let lerp_y = |z: i64| lerp_x(y, z) * (1.0 - t.y) + lerp_x(y + 1, z) * t.y;
+
+        lerp_y(z) * (1.0 - t.z) + lerp_y(z + 1) * t.z
+    }
+
+    fn density(&self, point: &Point3<f64>) -> f64 {
+        let mut noise = 0.0;
+        let mut total_amplitude = 0.0;
+        let (mut frequency, mut amplitude) = (self.frequency, 1.0);
+        for _ in 0..self.octaves.max(1) {
+            noise += amplitude * self.value_noise(&(point * frequency));
+            total_amplitude += amplitude;
+            frequency *= 2.0;
+            amplitude *= 0.5;
+        }
+
+        let noise = noise / total_amplitude;
+        if self.threshold < 1.0 {
+            (noise - self.threshold).max(0.0) / (1.0 - self.threshold)
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_interpolates_grid_densities() {
+        let grid = DensityGrid::parse_text("2 1 1\n0 1\n").unwrap();
+        let field = DensityField::Grid(grid);
+
+        assert!((field.max_density() - 1.0).abs() < 1e-12);
+        assert!((field.density(&Point3::new(0.5, 0.5, 0.5)) - 0.5).abs() < 1e-12);
+        assert!((field.density(&Point3::new(0.375, 0.2, 0.9)) - 0.25).abs() < 1e-12);
+        // Densities are held at the outermost cell centers, and are zero outside the grid
+        assert!(field.density(&Point3::new(0.1, 0.5, 0.5)).abs() < 1e-12);
+        assert!((field.density(&Point3::new(0.9, 0.5, 0.5)) - 1.0).abs() < 1e-12);
+        assert!(field.density(&Point3::new(1.1, 0.5, 0.5)).abs() < 1e-12);
+
+        assert!(DensityGrid::parse_text("2 2 1\n0 1 2\n").is_err());
+    }
+
+    #[test]
+    fn it_parses_vol_grids() {
+        let mut bytes = b"VOL\x03".to_vec();
+        for value in &[1_i32, 1, 2, 1, 1] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in &[0_f32, 0.0, 0.0, 1.0, 1.0, 1.0, 0.25, 0.75] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let grid = DensityGrid::parse_vol(&bytes).unwrap();
+        assert_eq!(grid.resolution, [1, 2, 1]);
+        assert_eq!(grid.densities, vec![0.25, 0.75]);
+        assert!((grid.max_density - 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn it_generates_noise_within_bounds() {
+        let noise = NoiseDensity::default();
+        for i in 0..1000 {
+            let t = f64::from(i) / 1000.0;
+            let density = noise.density(&Point3::new(t, (3.0 * t).fract(), (7.0 * t).fract()));
+            assert!((0.0..1.0).contains(&density));
+        }
+    }
+}
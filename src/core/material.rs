@@ -1,7 +1,13 @@
-use super::Texture;
-use nalgebra::{Vector2, Vector3};
+use super::{
+    Conductor, Dispersion, HomogeneousMedium, ParticipatingMedium, Subsurface, Texture, TextureMap,
+    ThinFilm,
+};
+use crate::ray_intersection::Intersection;
+use crate::utils;
+use nalgebra::{Rotation2, Unit, Vector2, Vector3};
 use num_traits::identities::Zero;
-use serde::Deserialize;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::path::Path;
@@ -28,8 +34,11 @@ pub struct PhongMaterial {
     pub specular: Vector3<f64>,
     pub reflectivity: f64,
     pub shininess: f64,
-    #[serde(rename = "texture")]
-    pub texture_path: Option<String>,
+    pub texture: Option<TextureMap>,
+    pub specular_texture: Option<TextureMap>,
+    pub shininess_texture: Option<TextureMap>,
+    pub bump_texture: Option<TextureMap>,
+    pub bump_scale: f64,
 }
 
 impl Default for PhongMaterial {
@@ -41,18 +50,65 @@ impl Default for PhongMaterial {
             specular: Vector3::zero(),
             reflectivity: 0.0,
             shininess: 30.0,
-            texture_path: None,
+            texture: None,
+            specular_texture: None,
+            shininess_texture: None,
+            bump_texture: None,
+            bump_scale: 1.0,
         }
     }
 }
 
 impl PhongMaterial {
-    pub fn get_color(&self, uv: Vector2<f64>, textures: &HashMap<String, Texture>) -> Vector3<f64> {
-        self.texture_path
+    pub fn get_color(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Vector3<f64> {
+        let color = self.texture.as_ref().map_or(self.color, |texture| {
+            self.color
+                .component_mul(&texture.get_color(intersection, textures))
+        });
+
+        intersection
+            .get_vertex_color()
+            .map_or(color, |vertex_color| color.component_mul(&vertex_color))
+    }
+
+    pub fn get_normal(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Unit<Vector3<f64>> {
+        self.bump_texture
+            .as_ref()
+            .map_or(intersection.get_normal(), |texture| {
+                texture.get_bump_normal(self.bump_scale, intersection, textures)
+            })
+    }
+
+    pub fn get_specular(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Vector3<f64> {
+        self.specular_texture
+            .as_ref()
+            .map_or(self.specular, |texture| {
+                self.specular
+                    .component_mul(&texture.get_color(intersection, textures))
+            })
+    }
+
+    pub fn get_shininess(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> f64 {
+        self.shininess_texture
             .as_ref()
-            .map_or(self.color, |texture_path| {
-                let texture = textures.get(texture_path).expect("texture not loaded");
-                self.color.component_mul(&texture.get_color(uv))
+            .map_or(self.shininess, |texture| {
+                self.shininess * texture.get_value(intersection, textures)
             })
     }
 }
@@ -66,10 +122,50 @@ pub struct PhysicalMaterial {
     pub emissive: Vector3<f64>,
     pub emissive_intensity: f64,
     pub roughness: f64,
+    // How much smoother the surface is along its tangent than across it, as for brushed metals,
+    // and the rotation of the tangent around the normal in degrees
+    pub anisotropy: f64,
+    pub anisotropy_rotation: f64,
     pub metalness: f64,
+    // Metal described by its complex refractive index, which takes the place of the metalness
+    pub conductor: Option<Conductor>,
     pub refractive_index: f64,
-    #[serde(rename = "texture")]
-    pub texture_path: Option<String>,
+    // How the refractive index varies with wavelength, which only bends light apart into colors in
+    // spectral renders
+    pub dispersion: Option<Dispersion>,
+    // Which of several overlapping transmissive objects fills the overlap. Higher priorities win.
+    pub medium_priority: u8,
+    // Color light is tinted toward for every unit of distance traveled inside the object, with the
+    // density scaling how quickly it gets there
+    pub absorption_color: Vector3<f64>,
+    pub absorption_density: f64,
+    // Medium filling the inside of a closed transmissive object
+    pub medium: Option<ParticipatingMedium>,
+    // Scattering beneath the surface of an opaque object, which takes the place of diffuse
+    // reflection
+    pub subsurface: Option<Subsurface>,
+    // Film over the surface whose reflections interfere, coloring them by the viewing angle
+    pub thin_film: Option<ThinFilm>,
+    // Treats the surface as an infinitely thin sheet, like a leaf or a sheet of paper, instead of
+    // the boundary of a solid. Light passes through it without bending, and light from behind
+    // shines through diffusely.
+    pub thin: bool,
+    // Fraction of diffusely scattered light a thin surface lets through to its other side
+    pub diffuse_transmission: f64,
+    pub texture: Option<TextureMap>,
+    pub roughness_texture: Option<TextureMap>,
+    // Scales the Blinn-Phong exponent equivalent to the roughness, as with MTL shininess maps.
+    // Ignored if there's a roughness texture.
+    pub shininess_texture: Option<TextureMap>,
+    pub metalness_texture: Option<TextureMap>,
+    pub emissive_texture: Option<TextureMap>,
+    pub bump_texture: Option<TextureMap>,
+    pub bump_scale: f64,
+    pub normal_texture: Option<TextureMap>,
+    pub normal_scale: f64,
+    // Tangent directions encoded in the red and green channels, relative to the surface's own
+    // tangents
+    pub tangent_texture: Option<TextureMap>,
 }
 
 impl Default for PhysicalMaterial {
@@ -81,29 +177,276 @@ impl Default for PhysicalMaterial {
             emissive: Vector3::zero(),
             emissive_intensity: 0.0,
             roughness: 0.5,
+            anisotropy: 0.0,
+            anisotropy_rotation: 0.0,
             metalness: 0.0,
+            conductor: None,
             refractive_index: 1.0,
-            texture_path: None,
+            dispersion: None,
+            medium_priority: 0,
+            absorption_color: Vector3::repeat(1.0),
+            absorption_density: 0.0,
+            medium: None,
+            subsurface: None,
+            thin_film: None,
+            thin: false,
+            diffuse_transmission: 0.5,
+            texture: None,
+            roughness_texture: None,
+            shininess_texture: None,
+            metalness_texture: None,
+            emissive_texture: None,
+            bump_texture: None,
+            bump_scale: 1.0,
+            normal_texture: None,
+            normal_scale: 1.0,
+            tangent_texture: None,
         }
     }
 }
 
 impl PhysicalMaterial {
-    pub fn get_color(&self, uv: Vector2<f64>, textures: &HashMap<String, Texture>) -> Vector3<f64> {
-        self.texture_path
+    pub fn get_color(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Vector3<f64> {
+        let color = self.texture.as_ref().map_or(self.color, |texture| {
+            self.color
+                .component_mul(&texture.get_color(intersection, textures))
+        });
+
+        intersection
+            .get_vertex_color()
+            .map_or(color, |vertex_color| color.component_mul(&vertex_color))
+    }
+
+    pub fn get_normal(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Unit<Vector3<f64>> {
+        if let Some(texture) = &self.normal_texture {
+            texture.get_mapped_normal(self.normal_scale, intersection, textures)
+        } else if let Some(texture) = &self.bump_texture {
+            texture.get_bump_normal(self.bump_scale, intersection, textures)
+        } else {
+            intersection.get_normal()
+        }
+    }
+
+    // Tangent and bitangent of an anisotropic surface around the given normal. The surface's own
+    // tangent is redirected by the tangent texture, then rotated.
+    pub fn get_tangent_frame(
+        &self,
+        intersection: &Intersection,
+        normal: &Unit<Vector3<f64>>,
+        textures: &HashMap<String, Texture>,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        let (dpdu, dpdv) = intersection.get_tangents();
+        let (tangent, bitangent) = utils::tangent_frame(normal, &dpdu, &dpdv)
+            .unwrap_or_else(|| utils::orthonormal_basis(normal));
+
+        let direction = self
+            .tangent_texture
+            .as_ref()
+            .map_or(Vector2::x(), |texture| {
+                let mapped = texture.get_color(intersection, textures) * 2.0 - Vector3::repeat(1.0);
+                Vector2::new(mapped.x, mapped.y)
+            });
+        let direction = Rotation2::new(self.anisotropy_rotation.to_radians()) * direction;
+        if direction.magnitude_squared() < 1e-12 {
+            return (tangent, bitangent);
+        }
+
+        let tangent = (tangent * direction.x + bitangent * direction.y).normalize();
+        (tangent, normal.cross(&tangent))
+    }
+
+    pub fn get_emissive(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Vector3<f64> {
+        self.emissive_texture
+            .as_ref()
+            .map_or(self.emissive, |texture| {
+                self.emissive
+                    .component_mul(&texture.get_color(intersection, textures))
+            })
+    }
+
+    pub fn get_roughness(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> f64 {
+        if let Some(texture) = &self.roughness_texture {
+            self.roughness * texture.get_value(intersection, textures)
+        } else if let Some(texture) = &self.shininess_texture {
+            let shininess = 2.0 / self.roughness.powi(2) - 2.0;
+            (2.0 / (shininess * texture.get_value(intersection, textures) + 2.0)).sqrt()
+        } else {
+            self.roughness
+        }
+    }
+
+    pub fn get_metalness(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> f64 {
+        self.metalness_texture
             .as_ref()
-            .map_or(self.color, |texture_path| {
-                let texture = textures.get(texture_path).expect("texture not loaded");
-                self.color.component_mul(&texture.get_color(uv))
+            .map_or(self.metalness, |texture| {
+                self.metalness * texture.get_value(intersection, textures)
             })
     }
+
+    // Refractive index for light of a wavelength, or for light without one
+    pub fn get_refractive_index(&self, wavelength: Option<f64>) -> f64 {
+        self.dispersion.map_or(self.refractive_index, |dispersion| {
+            dispersion.refractive_index(self.refractive_index, wavelength)
+        })
+    }
+
+    // Medium filling the object, with the absorption color added on to any attached medium
+    pub fn get_interior_medium(&self) -> Option<ParticipatingMedium> {
+        let absorption = self
+            .absorption_color
+            .map(|c| -c.max(f64::MIN_POSITIVE).ln() * self.absorption_density);
+
+        let medium = self.medium.clone().or_else(|| {
+            if absorption.max() > 0.0 {
+                Some(ParticipatingMedium::Homogeneous(
+                    HomogeneousMedium::default(),
+                ))
+            } else {
+                None
+            }
+        });
+
+        medium.map(|medium| medium.with_added_absorption(&absorption))
+    }
+}
+
+// Physical material with a clear lacquer coat over it and a soft sheen at grazing angles, for
+// things like car paint, lacquered wood and velvet. Light reflected off of the coat never reaches
+// the layers underneath.
+#[derive(Clone, Debug)]
+pub struct PrincipledMaterial {
+    pub base: PhysicalMaterial,
+    // How far the base's dielectric reflections are tinted toward its color
+    pub specular_tint: f64,
+    pub sheen: f64,
+    // How far the sheen is tinted toward the base's color
+    pub sheen_tint: f64,
+    pub clearcoat: f64,
+    pub clearcoat_roughness: f64,
+    pub clearcoat_normal_texture: Option<TextureMap>,
+    pub clearcoat_normal_scale: f64,
+}
+
+impl Default for PrincipledMaterial {
+    fn default() -> Self {
+        Self {
+            base: PhysicalMaterial::default(),
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            clearcoat_normal_texture: None,
+            clearcoat_normal_scale: 1.0,
+        }
+    }
 }
 
+// Serde doesn't deny unknown fields through `flatten`, so whatever the base material leaves over
+// is collected and rejected here
+impl<'de> Deserialize<'de> for PrincipledMaterial {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct PrincipledFields {
+            specular_tint: Option<f64>,
+            sheen: Option<f64>,
+            sheen_tint: Option<f64>,
+            clearcoat: Option<f64>,
+            clearcoat_roughness: Option<f64>,
+            clearcoat_normal_texture: Option<TextureMap>,
+            clearcoat_normal_scale: Option<f64>,
+            #[serde(flatten)]
+            base: PhysicalMaterial,
+            #[serde(flatten)]
+            unknown_fields: HashMap<String, serde_json::Value>,
+        }
+
+        let fields = PrincipledFields::deserialize(deserializer)?;
+        if let Some(field) = fields.unknown_fields.keys().min() {
+            return Err(D::Error::custom(format!("unknown field `{}`", field)));
+        }
+
+        let default = PrincipledMaterial::default();
+        Ok(PrincipledMaterial {
+            base: fields.base,
+            specular_tint: fields.specular_tint.unwrap_or(default.specular_tint),
+            sheen: fields.sheen.unwrap_or(default.sheen),
+            sheen_tint: fields.sheen_tint.unwrap_or(default.sheen_tint),
+            clearcoat: fields.clearcoat.unwrap_or(default.clearcoat),
+            clearcoat_roughness: fields
+                .clearcoat_roughness
+                .unwrap_or(default.clearcoat_roughness),
+            clearcoat_normal_texture: fields.clearcoat_normal_texture,
+            clearcoat_normal_scale: fields
+                .clearcoat_normal_scale
+                .unwrap_or(default.clearcoat_normal_scale),
+        })
+    }
+}
+
+// Hue and saturation of a color at a luminance of 1
+fn tint_color(color: &Vector3<f64>) -> Vector3<f64> {
+    let luminance = color.dot(&Vector3::new(0.3, 0.6, 0.1));
+    if luminance > 0.0 {
+        color / luminance
+    } else {
+        Vector3::repeat(1.0)
+    }
+}
+
+impl PrincipledMaterial {
+    // Color of the base's dielectric reflections at normal incidence
+    pub fn get_specular_color(&self, base_color: &Vector3<f64>) -> Vector3<f64> {
+        Vector3::repeat(1.0).lerp(&tint_color(base_color), self.specular_tint)
+    }
+
+    pub fn get_sheen_color(&self, base_color: &Vector3<f64>) -> Vector3<f64> {
+        self.sheen * Vector3::repeat(1.0).lerp(&tint_color(base_color), self.sheen_tint)
+    }
+
+    // The coat has its own normal, so it stays smooth over bumpy bases
+    pub fn get_clearcoat_normal(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Unit<Vector3<f64>> {
+        self.clearcoat_normal_texture
+            .as_ref()
+            .map_or(intersection.get_normal(), |texture| {
+                texture.get_mapped_normal(self.clearcoat_normal_scale, intersection, textures)
+            })
+    }
+}
+
+// Materials are stored once per object and only borrowed while rendering, so the size of the
+// larger variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag = "type", rename_all(deserialize = "lowercase"))]
 pub enum Material {
     Phong(PhongMaterial),
     Physical(PhysicalMaterial),
+    Principled(PrincipledMaterial),
 }
 
 impl Default for Material {
@@ -113,31 +456,90 @@ impl Default for Material {
 }
 
 impl Material {
-    pub fn load_textures(&self, asset_base: &Path, textures: &mut HashMap<String, Texture>) {
-        let texture_path = match self {
-            Material::Phong(material) => material.texture_path.as_ref(),
-            Material::Physical(material) => material.texture_path.as_ref(),
-        };
+    fn texture_maps(&self) -> Vec<&TextureMap> {
+        match self {
+            Material::Phong(material) => vec![
+                &material.texture,
+                &material.specular_texture,
+                &material.shininess_texture,
+                &material.bump_texture,
+            ],
+            Material::Physical(material) => vec![
+                &material.texture,
+                &material.roughness_texture,
+                &material.shininess_texture,
+                &material.metalness_texture,
+                &material.emissive_texture,
+                &material.bump_texture,
+                &material.normal_texture,
+                &material.tangent_texture,
+            ],
+            Material::Principled(material) => vec![
+                &material.base.texture,
+                &material.base.roughness_texture,
+                &material.base.shininess_texture,
+                &material.base.metalness_texture,
+                &material.base.emissive_texture,
+                &material.base.bump_texture,
+                &material.base.normal_texture,
+                &material.base.tangent_texture,
+                &material.clearcoat_normal_texture,
+            ],
+        }
+        .into_iter()
+        .filter_map(Option::as_ref)
+        .collect()
+    }
 
-        if let Some(texture_path) = texture_path {
+    pub fn load_textures(&self, asset_base: &Path, textures: &mut HashMap<String, Texture>) {
+        for TextureMap {
+            path: texture_path, ..
+        } in self.texture_maps()
+        {
             if !textures.contains_key(texture_path) {
                 let texture_path = texture_path.to_string();
                 let mut texture = Texture::new(&texture_path);
                 texture.load(asset_base).unwrap_or_else(|err| {
-                    panic!(format!(
+                    panic!(
                         "failed to load texture at path \"{}\": {}",
                         texture_path, err
-                    ))
+                    )
                 });
                 textures.insert(texture_path, texture);
             }
         }
     }
 
+    // Physical properties of the material, which principled materials layer on top of
+    pub fn as_physical(&self) -> Option<&PhysicalMaterial> {
+        match self {
+            Material::Phong(_) => None,
+            Material::Physical(material) => Some(material),
+            Material::Principled(material) => Some(&material.base),
+        }
+    }
+
+    // Color of light let through the surface, which is black for opaque surfaces
+    pub fn get_transmittance(&self) -> Vector3<f64> {
+        match self.as_physical() {
+            Some(material) if material.opacity < 1.0 => material.color * (1.0 - material.opacity),
+            _ => Vector3::zeros(),
+        }
+    }
+
     pub fn side(&self) -> MaterialSide {
         match self {
             Material::Phong(material) => material.side,
-            Material::Physical(material) => material.side,
+            Material::Physical(material)
+            | Material::Principled(PrincipledMaterial { base: material, .. }) => {
+                // Rays have to be able to leave anything they can be refracted or scattered into,
+                // and thin sheets are seen from either side
+                if material.opacity < 1.0 || material.subsurface.is_some() || material.thin {
+                    MaterialSide::Both
+                } else {
+                    material.side
+                }
+            }
         }
     }
 }
@@ -145,6 +547,8 @@ impl Material {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::core::texture::{TextureMapping, TriplanarSpace};
+    use nalgebra::Vector2;
     use serde_json::json;
 
     impl PartialEq for PhongMaterial {
@@ -155,6 +559,7 @@ mod test {
                 && self.specular == other.specular
                 && self.reflectivity == other.reflectivity
                 && self.shininess == other.shininess
+                && self.texture == other.texture
         }
     }
 
@@ -167,7 +572,18 @@ mod test {
                 && self.emissive_intensity == other.emissive_intensity
                 && self.roughness == other.roughness
                 && self.metalness == other.metalness
+                && self.conductor == other.conductor
                 && self.refractive_index == other.refractive_index
+                && self.dispersion == other.dispersion
+                && self.medium_priority == other.medium_priority
+                && self.absorption_color == other.absorption_color
+                && self.absorption_density == other.absorption_density
+                && self.medium == other.medium
+                && self.subsurface == other.subsurface
+                && self.thin_film == other.thin_film
+                && self.thin == other.thin
+                && self.diffuse_transmission == other.diffuse_transmission
+                && self.texture == other.texture
         }
     }
 
@@ -176,6 +592,15 @@ mod test {
             match (self, other) {
                 (Material::Phong(a), Material::Phong(b)) => a == b,
                 (Material::Physical(a), Material::Physical(b)) => a == b,
+                (Material::Principled(a), Material::Principled(b)) => {
+                    a.base == b.base
+                        && a.specular_tint == b.specular_tint
+                        && a.sheen == b.sheen
+                        && a.sheen_tint == b.sheen_tint
+                        && a.clearcoat == b.clearcoat
+                        && a.clearcoat_roughness == b.clearcoat_roughness
+                        && a.clearcoat_normal_texture == b.clearcoat_normal_texture
+                }
                 _ => false,
             }
         }
@@ -219,4 +644,137 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn it_deserializes_principled_materials() {
+        let material = serde_json::from_value::<Material>(json!({
+            "type": "principled",
+            "color": [0.6, 0.05, 0.05],
+            "metalness": 0.3,
+            "clearcoat": 1,
+            "clearcoat_roughness": 0.1,
+            "sheen": 0.5
+        }))
+        .unwrap();
+        assert_eq!(
+            material,
+            Material::Principled(PrincipledMaterial {
+                base: PhysicalMaterial {
+                    color: Vector3::from([0.6, 0.05, 0.05]),
+                    metalness: 0.3,
+                    ..PhysicalMaterial::default()
+                },
+                clearcoat: 1.0,
+                clearcoat_roughness: 0.1,
+                sheen: 0.5,
+                ..PrincipledMaterial::default()
+            })
+        );
+        assert_eq!(material.as_physical().unwrap().metalness, 0.3);
+
+        // Tints keep the hue of the base color at full brightness
+        let tinted = PrincipledMaterial {
+            sheen: 1.0,
+            sheen_tint: 1.0,
+            ..PrincipledMaterial::default()
+        }
+        .get_sheen_color(&Vector3::new(0.5, 0.25, 0.0));
+        assert!((tinted.dot(&Vector3::new(0.3, 0.6, 0.1)) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn it_rejects_unknown_principled_fields() {
+        for field in &["roughnes", "clearcoat_roughnes"] {
+            let result = serde_json::from_value::<Material>(json!({
+                "type": "principled",
+                "clearcoat": 1,
+                *field: 0.1
+            }));
+            assert!(result.unwrap_err().to_string().contains(field));
+        }
+    }
+
+    #[test]
+    fn it_deserializes_textures() {
+        assert_eq!(
+            serde_json::from_value::<Material>(json!({
+                "type": "phong",
+                "texture": "textures/test.jpg"
+            }))
+            .unwrap(),
+            Material::Phong(PhongMaterial {
+                texture: Some(TextureMap::new("textures/test.jpg")),
+                ..PhongMaterial::default()
+            })
+        );
+
+        assert_eq!(
+            serde_json::from_value::<Material>(json!({
+                "type": "physical",
+                "texture": {
+                    "path": "textures/checker.png",
+                    "mapping": "triplanar",
+                    "triplanar_space": "world",
+                    "uv_scale": [2, 4],
+                    "uv_rotation": 45
+                }
+            }))
+            .unwrap(),
+            Material::Physical(PhysicalMaterial {
+                texture: Some(TextureMap {
+                    mapping: TextureMapping::Triplanar,
+                    triplanar_space: TriplanarSpace::World,
+                    uv_scale: Vector2::from([2.0, 4.0]),
+                    uv_rotation: 45.0,
+                    ..TextureMap::new("textures/checker.png")
+                }),
+                ..PhysicalMaterial::default()
+            })
+        );
+    }
+
+    #[test]
+    fn it_combines_absorption_with_interior_media() {
+        let material = serde_json::from_value::<Material>(json!({
+            "type": "physical",
+            "opacity": 0,
+            "absorption_color": [1, 0.5, 0.25],
+            "absorption_density": 2,
+            "medium": {
+                "type": "homogeneous",
+                "scattering": [0.1, 0.1, 0.1],
+                "asymmetry": 0.5
+            }
+        }))
+        .unwrap();
+
+        let medium = if let Material::Physical(material) = material {
+            material.get_interior_medium()
+        } else {
+            None
+        };
+
+        if let Some(ParticipatingMedium::Homogeneous(medium)) = medium {
+            let expected = Vector3::new(0.0, 2.0 * 2_f64.ln(), 4.0 * 2_f64.ln());
+            assert!((medium.absorption - expected).magnitude() < 1e-12);
+            assert_eq!(medium.scattering, Vector3::repeat(0.1));
+            assert!((medium.asymmetry - 0.5).abs() < 1e-12);
+        } else {
+            panic!("expected a homogeneous interior medium");
+        }
+        assert_eq!(PhysicalMaterial::default().get_interior_medium(), None);
+    }
+
+    #[test]
+    fn it_transforms_texture_coordinates() {
+        let texture = TextureMap {
+            uv_scale: Vector2::from([2.0, 3.0]),
+            uv_offset: Vector2::from([0.5, 0.25]),
+            uv_rotation: 90.0,
+            ..TextureMap::new("textures/test.jpg")
+        };
+
+        let uv = texture.transform_uv(Vector2::from([1.0, 1.0]));
+        assert!((uv - Vector2::from([-2.5, 2.25])).norm() < 1e-10);
+    }
 }
@@ -0,0 +1,550 @@
+use super::{DensityField, Dispersion, PhysicalMaterial, Transform};
+use crate::ray_intersection::Ray;
+use nalgebra::{Affine3, Translation3, Vector3};
+use rand::Rng;
+use serde::Deserialize;
+use std::ptr;
+use std::sync::Arc;
+
+// Refractive index of the space outside every object
+const VACUUM_REFRACTIVE_INDEX: f64 = 1.0;
+
+// Volume which absorbs and scatters light traveling through it
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all(deserialize = "lowercase"))]
+pub enum ParticipatingMedium {
+    Homogeneous(HomogeneousMedium),
+    // Only built by volume primitives, which place the density in the scene
+    #[serde(skip_deserializing)]
+    Heterogeneous(HeterogeneousMedium),
+}
+
+// Medium with the same density everywhere. Coefficients are per unit distance and per color
+// channel. The asymmetry is the Henyey-Greenstein `g` parameter, from -1 for light scattered back
+// where it came from to 1 for light carrying on forward.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomogeneousMedium {
+    pub absorption: Vector3<f64>,
+    pub scattering: Vector3<f64>,
+    pub asymmetry: f64,
+}
+
+impl Default for HomogeneousMedium {
+    fn default() -> Self {
+        Self {
+            absorption: Vector3::zeros(),
+            scattering: Vector3::zeros(),
+            asymmetry: 0.0,
+        }
+    }
+}
+
+// Medium whose coefficients are scaled by a density field over a box. The box is the unit cube
+// centered on the origin, placed in the scene by a transform.
+#[derive(Clone, Debug)]
+pub struct HeterogeneousMedium {
+    pub absorption: Vector3<f64>,
+    pub scattering: Vector3<f64>,
+    pub asymmetry: f64,
+    density: Arc<DensityField>,
+    // Maps world space points into the unit cube the density field is defined over
+    world_to_density: Affine3<f64>,
+}
+
+impl PartialEq for HeterogeneousMedium {
+    fn eq(&self, other: &Self) -> bool {
+        self.absorption == other.absorption
+            && self.scattering == other.scattering
+            && self.asymmetry == other.asymmetry
+            && Arc::ptr_eq(&self.density, &other.density)
+            && self.world_to_density == other.world_to_density
+    }
+}
+
+// Where along a ray light was scattered, along with the throughput of the path up to that point
+// divided by the probability of sampling it
+#[derive(Copy, Clone, Debug)]
+pub enum MediumSample {
+    Scattered { distance: f64, weight: Vector3<f64> },
+    Passed { weight: Vector3<f64> },
+}
+
+// Fraction of scattering over attenuation in each channel
+fn albedo(absorption: &Vector3<f64>, scattering: &Vector3<f64>) -> Vector3<f64> {
+    scattering.zip_map(&(absorption + scattering), |scattering, attenuation| {
+        if attenuation > 0.0 {
+            scattering / attenuation
+        } else {
+            0.0
+        }
+    })
+}
+
+impl ParticipatingMedium {
+    pub fn asymmetry(&self) -> f64 {
+        match self {
+            ParticipatingMedium::Homogeneous(medium) => medium.asymmetry,
+            ParticipatingMedium::Heterogeneous(medium) => medium.asymmetry,
+        }
+    }
+
+    pub fn albedo(&self) -> Vector3<f64> {
+        match self {
+            ParticipatingMedium::Homogeneous(medium) => {
+                albedo(&medium.absorption, &medium.scattering)
+            }
+            ParticipatingMedium::Heterogeneous(medium) => {
+                albedo(&medium.absorption, &medium.scattering)
+            }
+        }
+    }
+
+    // Density fields scale added absorption along with the rest of the medium
+    pub fn with_added_absorption(mut self, absorption: &Vector3<f64>) -> Self {
+        match &mut self {
+            ParticipatingMedium::Homogeneous(medium) => medium.absorption += absorption,
+            ParticipatingMedium::Heterogeneous(medium) => medium.absorption += absorption,
+        }
+
+        self
+    }
+
+    // Only heterogeneous media vary over space, so only they move with an instance
+    pub fn add_instance(&mut self, transform: &Transform) {
+        if let ParticipatingMedium::Heterogeneous(medium) = self {
+            medium.add_instance(transform);
+        }
+    }
+
+    // Fraction of light which makes it a distance along a ray
+    pub fn transmittance(&self, ray: &Ray, distance: f64) -> Vector3<f64> {
+        match self {
+            ParticipatingMedium::Homogeneous(medium) => medium.transmittance(distance),
+            ParticipatingMedium::Heterogeneous(medium) => medium.transmittance(ray, distance),
+        }
+    }
+
+    // Samples a distance at which light traveling along a ray is scattered, or that it passes
+    // through to `max_distance`
+    pub fn sample_distance(&self, ray: &Ray, max_distance: f64) -> MediumSample {
+        match self {
+            ParticipatingMedium::Homogeneous(medium) => medium.sample_distance(max_distance),
+            ParticipatingMedium::Heterogeneous(medium) => medium.sample_distance(ray, max_distance),
+        }
+    }
+}
+
+impl HomogeneousMedium {
+    fn attenuation(&self) -> Vector3<f64> {
+        self.absorption + self.scattering
+    }
+
+    // Beer-Lambert law
+    fn transmittance(&self, distance: f64) -> Vector3<f64> {
+        // Channels which attenuate nothing let everything through, even over infinite distances
+        self.attenuation().map(|attenuation| {
+            if attenuation > 0.0 {
+                (-attenuation * distance).exp()
+            } else {
+                1.0
+            }
+        })
+    }
+
+    // Each sample picks a color channel to sample distances by, and is weighted by the average
+    // probability over all of the channels
+    fn sample_distance(&self, max_distance: f64) -> MediumSample {
+        // Media which only absorb light never scatter it
+        if self.scattering.max() <= 0.0 {
+            return MediumSample::Passed {
+                weight: self.transmittance(max_distance),
+            };
+        }
+
+        let mut rng = rand::thread_rng();
+        let attenuation = self.attenuation()[rng.gen_range(0, 3)];
+        let distance = if attenuation > 0.0 {
+            -(1.0 - rng.gen::<f64>()).ln() / attenuation
+        } else {
+            f64::INFINITY
+        };
+
+        if distance < max_distance {
+            let transmittance = self.transmittance(distance);
+            let pdf = transmittance.component_mul(&self.attenuation()).mean();
+
+            MediumSample::Scattered {
+                distance,
+                weight: transmittance.component_mul(&self.scattering) / pdf,
+            }
+        } else {
+            let transmittance = self.transmittance(max_distance);
+            let pdf = transmittance.mean();
+
+            MediumSample::Passed {
+                weight: if pdf > 0.0 {
+                    transmittance / pdf
+                } else {
+                    Vector3::zeros()
+                },
+            }
+        }
+    }
+}
+
+impl HeterogeneousMedium {
+    pub fn new(
+        absorption: Vector3<f64>,
+        scattering: Vector3<f64>,
+        asymmetry: f64,
+        density: Arc<DensityField>,
+        transform: &Transform,
+    ) -> Self {
+        Self {
+            absorption,
+            scattering,
+            asymmetry,
+            density,
+            world_to_density: Translation3::new(0.5, 0.5, 0.5) * transform.inverse(),
+        }
+    }
+
+    // Places a medium found in an instance's prototype space into the instance's parent space
+    fn add_instance(&mut self, transform: &Transform) {
+        self.world_to_density *= transform.inverse();
+    }
+
+    // Attenuation coefficient bounding the attenuation of every channel everywhere in the medium
+    fn majorant(&self) -> f64 {
+        self.density.max_density() * (self.absorption + self.scattering).max()
+    }
+
+    fn density_at(&self, ray: &Ray, distance: f64) -> f64 {
+        self.density
+            .density(&(self.world_to_density * (ray.origin + ray.direction * distance)))
+    }
+
+    // Distance along a ray to where it leaves the density field's box, beyond which nothing
+    // attenuates it
+    fn exit_distance(&self, ray: &Ray) -> f64 {
+        let origin = self.world_to_density * ray.origin;
+        let direction = self.world_to_density * ray.direction;
+
+        (0..3)
+            .map(|axis| {
+                let t0 = -origin[axis] / direction[axis];
+                let t1 = (1.0 - origin[axis]) / direction[axis];
+                let far = t0.max(t1);
+                // Rays parallel to a slab are never bounded by it
+                if far.is_nan() {
+                    f64::INFINITY
+                } else {
+                    far
+                }
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    // Estimated with ratio tracking. Tentative collisions are sampled against the majorant, and
+    // each one scales the transmittance by the chance it would have been a null collision.
+    fn transmittance(&self, ray: &Ray, distance: f64) -> Vector3<f64> {
+        let majorant = self.majorant();
+        let mut transmittance = Vector3::repeat(1.0);
+        if majorant <= 0.0 {
+            return transmittance;
+        }
+
+        let max_distance = distance.min(self.exit_distance(ray));
+        let mut rng = rand::thread_rng();
+        let mut distance = 0.0;
+        loop {
+            distance -= (1.0 - rng.gen::<f64>()).ln() / majorant;
+            if distance >= max_distance || transmittance.max() <= 0.0 {
+                return transmittance;
+            }
+
+            let attenuation = (self.absorption + self.scattering) * self.density_at(ray, distance);
+            transmittance.component_mul_assign(&(Vector3::repeat(1.0) - attenuation / majorant));
+        }
+    }
+
+    // Delta tracking with tentative collisions sampled against the majorant. Each collision is
+    // chosen to be a scattering or null collision in proportion to their throughput-weighted
+    // coefficients averaged over the channels, which handles colored media without a
+    // per-channel majorant.
+    fn sample_distance(&self, ray: &Ray, max_distance: f64) -> MediumSample {
+        let majorant = self.majorant();
+        let mut weight = Vector3::repeat(1.0);
+        if majorant <= 0.0 {
+            return MediumSample::Passed { weight };
+        }
+
+        let max_distance = max_distance.min(self.exit_distance(ray));
+        let mut rng = rand::thread_rng();
+        let mut distance = 0.0;
+        loop {
+            distance -= (1.0 - rng.gen::<f64>()).ln() / majorant;
+            if distance >= max_distance {
+                return MediumSample::Passed { weight };
+            }
+
+            let density = self.density_at(ray, distance);
+            let scattering = self.scattering * density;
+            let null = Vector3::repeat(majorant) - (self.absorption + self.scattering) * density;
+
+            let scatter_probability = weight.component_mul(&scattering).mean();
+            let null_probability = weight.component_mul(&null).mean();
+            let total_probability = scatter_probability + null_probability;
+            if total_probability <= 0.0 {
+                return MediumSample::Passed {
+                    weight: Vector3::zeros(),
+                };
+            }
+
+            if rng.gen::<f64>() * total_probability < scatter_probability {
+                return MediumSample::Scattered {
+                    distance,
+                    weight: weight.component_mul(&scattering) * total_probability
+                        / (majorant * scatter_probability),
+                };
+            }
+            weight =
+                weight.component_mul(&null) * total_probability / (majorant * null_probability);
+        }
+    }
+}
+
+// The interior of an object with a transmissive material. Media are told apart by the material
+// they were entered through, so objects sharing a material share a medium.
+#[derive(Clone, Debug)]
+pub struct Medium {
+    id: usize,
+    priority: u8,
+    refractive_index: f64,
+    dispersion: Option<Dispersion>,
+    interior: Option<ParticipatingMedium>,
+}
+
+impl Medium {
+    pub fn new(material: &PhysicalMaterial) -> Self {
+        Self {
+            id: ptr::from_ref(material) as usize,
+            priority: material.medium_priority,
+            refractive_index: material.refractive_index,
+            dispersion: material.dispersion,
+            interior: material.get_interior_medium(),
+        }
+    }
+
+    pub fn refractive_index(&self, wavelength: Option<f64>) -> f64 {
+        self.dispersion.map_or(self.refractive_index, |dispersion| {
+            dispersion.refractive_index(self.refractive_index, wavelength)
+        })
+    }
+
+    pub fn add_instance(&mut self, transform: &Transform) {
+        if let Some(interior) = &mut self.interior {
+            interior.add_instance(transform);
+        }
+    }
+
+    pub fn interior(&self) -> Option<&ParticipatingMedium> {
+        self.interior.as_ref()
+    }
+}
+
+// Media a ray is inside of, in the order they were entered. Where media overlap, the one with the
+// highest priority fills the overlap, with ties going to the most recently entered medium. Entering
+// or leaving any other medium crosses no real surface.
+#[derive(Clone, Debug, Default)]
+pub struct MediumStack {
+    media: Vec<Medium>,
+}
+
+impl MediumStack {
+    // Medium filling the space the ray is in, or `None` outside every object
+    pub fn current(&self) -> Option<&Medium> {
+        // `max_by_key` returns the last of several equal elements
+        self.media.iter().max_by_key(|medium| medium.priority)
+    }
+
+    pub fn refractive_index(&self, wavelength: Option<f64>) -> f64 {
+        self.current().map_or(VACUUM_REFRACTIVE_INDEX, |medium| {
+            medium.refractive_index(wavelength)
+        })
+    }
+
+    // Participating medium filling the space the ray is in. Outside every object, that's the medium
+    // filling the rest of the scene.
+    pub fn participating_medium<'a>(
+        &'a self,
+        outer: Option<&'a ParticipatingMedium>,
+    ) -> Option<&'a ParticipatingMedium> {
+        self.current().map_or(outer, Medium::interior)
+    }
+
+    pub fn contains(&self, medium: &Medium) -> bool {
+        self.media.iter().any(|entered| entered.id == medium.id)
+    }
+
+    pub fn entered(&self, medium: Medium) -> Self {
+        let mut media = self.media.clone();
+        media.push(medium);
+
+        Self { media }
+    }
+
+    pub fn exited(&self, medium: &Medium) -> Self {
+        let mut media = self.media.clone();
+        if let Some(index) = media.iter().rposition(|entered| entered.id == medium.id) {
+            media.remove(index);
+        }
+
+        Self { media }
+    }
+
+    // Whether moving from this stack to another crosses the boundary of the medium filling the
+    // space, rather than the boundary of a medium hidden by a higher priority one
+    pub fn is_interface(&self, other: &MediumStack) -> bool {
+        match (self.current(), other.current()) {
+            (Some(current), Some(other_current)) => current.id != other_current.id,
+            (None, None) => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::DensityGrid;
+    use crate::ray_intersection::RayType;
+    use nalgebra::Point3;
+
+    fn material(refractive_index: f64, medium_priority: u8) -> PhysicalMaterial {
+        PhysicalMaterial {
+            refractive_index,
+            medium_priority,
+            ..PhysicalMaterial::default()
+        }
+    }
+
+    #[test]
+    fn it_tracks_nested_media_by_priority() {
+        let water = material(1.33, 0);
+        let glass = material(1.5, 1);
+        let (water, glass) = (Medium::new(&water), Medium::new(&glass));
+
+        let air = MediumStack::default();
+        let in_water = air.entered(water.clone());
+        assert!(air.is_interface(&in_water));
+        assert!((in_water.refractive_index(None) - 1.33).abs() < 1e-12);
+
+        // Glass takes precedence over the water around it
+        let in_glass = in_water.entered(glass.clone());
+        assert!(in_water.is_interface(&in_glass));
+        assert!((in_glass.refractive_index(None) - 1.5).abs() < 1e-12);
+
+        // Water entered from inside the glass is hidden by it
+        let glass_first = air.entered(glass.clone());
+        let hidden_water = glass_first.entered(water.clone());
+        assert!(!glass_first.is_interface(&hidden_water));
+        assert!((hidden_water.refractive_index(None) - 1.5).abs() < 1e-12);
+
+        // Leaving the glass while still in the water lands back in the water
+        let back_in_water = hidden_water.exited(&glass);
+        assert!(hidden_water.is_interface(&back_in_water));
+        assert!((back_in_water.refractive_index(None) - 1.33).abs() < 1e-12);
+        assert!(back_in_water.contains(&water) && !back_in_water.contains(&glass));
+    }
+
+    #[test]
+    fn it_samples_distances_in_homogeneous_media() {
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::origin(),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+
+        // Absorbing media attenuate exponentially with distance
+        let absorbing = ParticipatingMedium::Homogeneous(HomogeneousMedium {
+            absorption: Vector3::new(0.0, 1.0, 2.0),
+            ..HomogeneousMedium::default()
+        });
+        let expected = Vector3::new(1.0, (-2_f64).exp(), (-4_f64).exp());
+        assert!((absorbing.transmittance(&ray, 2.0) - expected).magnitude() < 1e-12);
+        if let MediumSample::Passed { weight } = absorbing.sample_distance(&ray, 2.0) {
+            assert!((weight - expected).magnitude() < 1e-12);
+        } else {
+            panic!("absorbing media shouldn't scatter");
+        }
+
+        // A gray medium scatters about as often as it attenuates, weighted by its albedo
+        let foggy = ParticipatingMedium::Homogeneous(HomogeneousMedium {
+            absorption: Vector3::repeat(0.25),
+            scattering: Vector3::repeat(0.75),
+            asymmetry: 0.0,
+        });
+        let num_samples = 10_000;
+        let mut num_scattered = 0;
+        for _ in 0..num_samples {
+            match foggy.sample_distance(&ray, 1.0) {
+                MediumSample::Scattered { distance, weight } => {
+                    num_scattered += 1;
+                    assert!(distance < 1.0);
+                    assert!((weight - Vector3::repeat(0.75)).magnitude() < 1e-12);
+                }
+                MediumSample::Passed { weight } => {
+                    assert!((weight - Vector3::repeat(1.0)).magnitude() < 1e-12);
+                }
+            }
+        }
+        let scattered_fraction = f64::from(num_scattered) / f64::from(num_samples);
+        assert!((scattered_fraction - (1.0 - (-1_f64).exp())).abs() < 0.03);
+    }
+
+    #[test]
+    fn it_tracks_through_heterogeneous_media() {
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::new(0.0, 0.0, 2.0),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+        let density = DensityGrid::new([2, 1, 1], vec![0.5, 0.5]).unwrap();
+        let medium = ParticipatingMedium::Heterogeneous(HeterogeneousMedium::new(
+            Vector3::new(0.0, 1.0, 1.0),
+            Vector3::repeat(1.0),
+            0.0,
+            Arc::new(DensityField::Grid(density)),
+            &Transform::default(),
+        ));
+
+        // A uniform density matches the Beer-Lambert law through the one unit of the box the ray
+        // crosses, with nothing attenuating it outside of the box
+        let num_samples = 10_000;
+        let transmittance = (0..num_samples)
+            .map(|_| medium.transmittance(&ray, 10.0))
+            .sum::<Vector3<f64>>()
+            / f64::from(num_samples);
+        let expected = Vector3::new((-0.5_f64).exp(), (-1_f64).exp(), (-1_f64).exp());
+        assert!((transmittance - expected).amax() < 0.02);
+
+        // Light is scattered within the box as often as the red channel is attenuated there
+        let mut num_scattered = 0;
+        for _ in 0..num_samples {
+            if let MediumSample::Scattered { distance, .. } = medium.sample_distance(&ray, 10.0) {
+                num_scattered += 1;
+                assert!((1.5..=2.5).contains(&distance));
+            }
+        }
+        let scattered_fraction = f64::from(num_scattered) / f64::from(num_samples);
+        assert!(scattered_fraction > 0.3 && scattered_fraction < 0.7);
+    }
+}
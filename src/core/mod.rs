@@ -1,14 +1,42 @@
+mod accelerator;
+mod accelerator_cache;
 mod bounds;
+mod bvh;
+mod conductor;
+mod density;
 mod material;
+mod medium;
+mod node_bounds;
+mod packet;
+mod spectrum;
+mod subsurface;
 mod texture;
+mod thin_film;
 mod transform;
 
-pub use bounds::{BoundedObject, BoundingVolume, KdTreeAccelerator, ObjectWithBounds};
-pub use material::{Material, MaterialSide, PhongMaterial, PhysicalMaterial};
-pub use texture::Texture;
+use serde::{Deserialize, Serialize};
+
+pub use accelerator::{Accelerator, AcceleratorStats, AcceleratorType, NodePrecision};
+pub use accelerator_cache::AcceleratorCache;
+pub use bounds::{
+    BoundedObject, BoundingVolume, KdTreeAccelerator, KdTreeConstructionOptions, ObjectWithBounds,
+};
+pub use bvh::BvhAccelerator;
+pub use conductor::Conductor;
+pub use density::{DensityField, DensityGrid, NoiseDensity};
+pub use material::{Material, MaterialSide, PhongMaterial, PhysicalMaterial, PrincipledMaterial};
+pub use medium::{
+    HeterogeneousMedium, HomogeneousMedium, Medium, MediumSample, MediumStack, ParticipatingMedium,
+};
+pub use node_bounds::{CompactBounds, NodeBounds};
+pub use packet::RayPacket;
+pub use spectrum::{Dispersion, Wavelengths};
+pub use subsurface::Subsurface;
+pub use texture::{Texture, TextureChannel, TextureMap};
+pub use thin_film::ThinFilm;
 pub use transform::{Transform, Transformed};
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum Axis {
     X,
     Y,
@@ -0,0 +1,310 @@
+use super::BoundingVolume;
+use crate::ray_intersection::Ray;
+use nalgebra::Point3;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::marker::{Send, Sync};
+
+// Bounds stored in the nodes of an acceleration structure
+pub trait NodeBounds: Copy + Debug + Send + Sync + Serialize + DeserializeOwned {
+    // Name used to tell cached structures with different bounds apart
+    const NAME: &'static str;
+
+    // Values derived from a ray once per traversal rather than once per node
+    type RayData;
+
+    fn new(bounding_volume: &BoundingVolume) -> Self;
+    fn to_bounding_volume(&self) -> BoundingVolume;
+    fn ray_data(ray: &Ray) -> Self::RayData;
+    fn intersect(&self, ray: &Ray, ray_data: &Self::RayData, max_distance: Option<f64>) -> bool;
+}
+
+impl NodeBounds for BoundingVolume {
+    const NAME: &'static str = "f64";
+
+    type RayData = ();
+
+    fn new(bounding_volume: &BoundingVolume) -> Self {
+        *bounding_volume
+    }
+
+    fn to_bounding_volume(&self) -> BoundingVolume {
+        *self
+    }
+
+    fn ray_data(_ray: &Ray) -> Self::RayData {}
+
+    fn intersect(&self, ray: &Ray, _ray_data: &Self::RayData, max_distance: Option<f64>) -> bool {
+        self.intersect(ray, max_distance)
+    }
+}
+
+// Relative error bound of the three rounded operations in a slab test
+const SLAB_ERROR: f32 = 3.0 * (f32::EPSILON / 2.0) / (1.0 - 3.0 * (f32::EPSILON / 2.0));
+// Relative amount boxes are grown by to cover origins rounded onto a face the ray runs parallel to
+const BOUNDS_PADDING: f32 = 8.0 * f32::EPSILON;
+
+fn next_down(value: f32) -> f32 {
+    if value.is_nan() || value == f32::NEG_INFINITY {
+        value
+    } else if value == 0.0 {
+        -f32::from_bits(1)
+    } else if value > 0.0 {
+        f32::from_bits(value.to_bits() - 1)
+    } else {
+        f32::from_bits(value.to_bits() + 1)
+    }
+}
+
+// Closest f32 values which are no greater and no less than `value`
+fn round_down(value: f64) -> f32 {
+    let rounded = value as f32;
+    if f64::from(rounded) > value {
+        next_down(rounded)
+    } else {
+        rounded
+    }
+}
+
+fn round_up(value: f64) -> f32 {
+    -round_down(-value)
+}
+
+// Four f32 lanes aligned for SIMD loads. Only the first three are coordinates.
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+struct Lanes([f32; 4]);
+
+// Single precision bounds, rounded and padded outwards so they always contain the f64 bounds they
+// were made from
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct CompactBounds {
+    min: Lanes,
+    max: Lanes,
+}
+
+#[derive(Debug)]
+pub struct SimdRay {
+    origin: Lanes,
+    inverse_direction: Lanes,
+    // Furthest any slab distance can move from rounding the origin to f32
+    origin_error: f64,
+}
+
+impl NodeBounds for CompactBounds {
+    const NAME: &'static str = "f32";
+
+    type RayData = SimdRay;
+
+    fn new(bounding_volume: &BoundingVolume) -> Self {
+        let mut min = [f32::NEG_INFINITY; 4];
+        let mut max = [f32::INFINITY; 4];
+        for axis in 0..3 {
+            let (bounds_min, bounds_max) = (
+                round_down(bounding_volume.bounds_min[axis]),
+                round_up(bounding_volume.bounds_max[axis]),
+            );
+            let padding = BOUNDS_PADDING * bounds_min.abs().max(bounds_max.abs()).max(1.0);
+            min[axis] = bounds_min - padding;
+            max[axis] = bounds_max + padding;
+        }
+
+        Self {
+            min: Lanes(min),
+            max: Lanes(max),
+        }
+    }
+
+    fn to_bounding_volume(&self) -> BoundingVolume {
+        let [min_x, min_y, min_z, _] = self.min.0;
+        let [max_x, max_y, max_z, _] = self.max.0;
+
+        BoundingVolume::from_bounds(
+            Point3::new(f64::from(min_x), f64::from(min_y), f64::from(min_z)),
+            Point3::new(f64::from(max_x), f64::from(max_y), f64::from(max_z)),
+        )
+    }
+
+    fn ray_data(ray: &Ray) -> Self::RayData {
+        // Zero components are replaced with the smallest normal value so that every slab
+        // distance is finite or infinite rather than NaN
+        let inverse = |component: f64| {
+            let component = component as f32;
+            if component == 0.0 {
+                1.0 / f32::MIN_POSITIVE.copysign(component)
+            } else {
+                1.0 / component
+            }
+        };
+
+        let origin_error = (0..3)
+            .filter(|&axis| ray.direction[axis] as f32 != 0.0)
+            .map(|axis| {
+                let error = (f64::from(ray.origin[axis] as f32) - ray.origin[axis]).abs();
+                error / ray.direction[axis].abs()
+            })
+            .fold(0.0, f64::max);
+
+        SimdRay {
+            origin: Lanes([
+                ray.origin.x as f32,
+                ray.origin.y as f32,
+                ray.origin.z as f32,
+                0.0,
+            ]),
+            inverse_direction: Lanes([
+                inverse(ray.direction.x),
+                inverse(ray.direction.y),
+                inverse(ray.direction.z),
+                1.0,
+            ]),
+            origin_error,
+        }
+    }
+
+    fn intersect(&self, _ray: &Ray, ray_data: &Self::RayData, max_distance: Option<f64>) -> bool {
+        let (near, far) = slab_distances(self, ray_data);
+        // Widened in f64, as the error is often smaller than the spacing of f32 distances
+        let near = f64::from(near) - ray_data.origin_error;
+        let far = f64::from(far * (1.0 + 2.0 * SLAB_ERROR)) + ray_data.origin_error;
+
+        near <= far && far >= 0.0 && (max_distance.is_none() || near <= max_distance.unwrap())
+    }
+}
+
+// Entry and exit distances of a ray through the slabs of a box
+#[cfg(target_arch = "x86_64")]
+fn slab_distances(bounds: &CompactBounds, ray: &SimdRay) -> (f32, f32) {
+    use std::arch::x86_64::{
+        _mm_cvtss_f32, _mm_load_ps, _mm_max_ps, _mm_min_ps, _mm_mul_ps, _mm_shuffle_ps, _mm_sub_ps,
+    };
+
+    // SSE is part of the x86_64 baseline, and `Lanes` is aligned for the loads
+    unsafe {
+        let origin = _mm_load_ps(ray.origin.0.as_ptr());
+        let inverse_direction = _mm_load_ps(ray.inverse_direction.0.as_ptr());
+        let t_min = _mm_mul_ps(
+            _mm_sub_ps(_mm_load_ps(bounds.min.0.as_ptr()), origin),
+            inverse_direction,
+        );
+        let t_max = _mm_mul_ps(
+            _mm_sub_ps(_mm_load_ps(bounds.max.0.as_ptr()), origin),
+            inverse_direction,
+        );
+
+        let near = _mm_min_ps(t_min, t_max);
+        let near = _mm_max_ps(near, _mm_shuffle_ps::<0b01_00_11_10>(near, near));
+        let near = _mm_max_ps(near, _mm_shuffle_ps::<0b10_11_00_01>(near, near));
+
+        let far = _mm_max_ps(t_min, t_max);
+        let far = _mm_min_ps(far, _mm_shuffle_ps::<0b01_00_11_10>(far, far));
+        let far = _mm_min_ps(far, _mm_shuffle_ps::<0b10_11_00_01>(far, far));
+
+        (_mm_cvtss_f32(near), _mm_cvtss_f32(far))
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn slab_distances(bounds: &CompactBounds, ray: &SimdRay) -> (f32, f32) {
+    let mut near = f32::NEG_INFINITY;
+    let mut far = f32::INFINITY;
+    for axis in 0..3 {
+        let t_min = (bounds.min.0[axis] - ray.origin.0[axis]) * ray.inverse_direction.0[axis];
+        let t_max = (bounds.max.0[axis] - ray.origin.0[axis]) * ray.inverse_direction.0[axis];
+        near = near.max(t_min.min(t_max));
+        far = far.min(t_min.max(t_max));
+    }
+
+    (near, far)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::MediumStack;
+    use crate::ray_intersection::RayType;
+    use nalgebra::Vector3;
+
+    #[test]
+    fn it_contains_the_original_bounds() {
+        let bounding_volume = BoundingVolume::from_bounds(
+            Point3::new(-0.1, 1e-9, 12345.678_9),
+            Point3::new(0.1, 0.3, 12345.679),
+        );
+        let compact = CompactBounds::new(&bounding_volume).to_bounding_volume();
+
+        for axis in 0..3 {
+            assert!(compact.bounds_min[axis] <= bounding_volume.bounds_min[axis]);
+            assert!(compact.bounds_max[axis] >= bounding_volume.bounds_max[axis]);
+        }
+    }
+
+    #[test]
+    fn it_matches_the_f64_slab_test() {
+        let bounding_volume =
+            BoundingVolume::from_bounds(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let compact = CompactBounds::new(&bounding_volume);
+
+        let directions = [
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::new(0.3, -0.2, -1.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        ];
+        let origins = [
+            Point3::new(0.0, 0.0, 5.0),
+            Point3::new(0.9, 0.0, 5.0),
+            Point3::new(1.1, 0.0, 5.0),
+            Point3::new(0.5, 0.5, 0.5),
+            Point3::new(3.0, 3.0, 3.0),
+            Point3::new(-5.0, 0.5, 0.0),
+        ];
+        for direction in &directions {
+            for origin in &origins {
+                let ray = Ray {
+                    ray_type: RayType::Primary,
+                    origin: *origin,
+                    direction: *direction,
+                    media: MediumStack::default(),
+                    wavelengths: None,
+                    differentials: None,
+                };
+                let ray_data = CompactBounds::ray_data(&ray);
+
+                for &max_distance in &[None, Some(1.0)] {
+                    assert_eq!(
+                        compact.intersect(&ray, &ray_data, max_distance),
+                        bounding_volume.intersect(&ray, max_distance),
+                        "{:?} {:?} {:?}",
+                        origin,
+                        direction,
+                        max_distance
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_covers_the_rounding_of_distant_origins() {
+        let bounding_volume =
+            BoundingVolume::from_bounds(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+        let compact = CompactBounds::new(&bounding_volume);
+
+        // The origin rounds half a unit away from the box, past the closest hit so far
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::new(1.0e7 + 0.51, 0.5, 0.5),
+            direction: Vector3::new(-1.0, 0.0, 0.0),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+        let ray_data = CompactBounds::ray_data(&ray);
+        let max_distance = Some(1.0e7 - 0.4);
+
+        assert!(bounding_volume.intersect(&ray, max_distance));
+        assert!(compact.intersect(&ray, &ray_data, max_distance));
+    }
+}
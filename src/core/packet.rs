@@ -0,0 +1,430 @@
+use super::BoundingVolume;
+use crate::ray_intersection::Ray;
+use nalgebra::{Point3, Vector3};
+
+// Rays in a packet are tracked with the bits of a mask
+pub const MAX_PACKET_SIZE: usize = 64;
+
+// Relative tolerance on frustum culling so that it never rejects a box which a single ray would
+// graze because of rounding
+const FRUSTUM_TOLERANCE: f64 = 1e-9;
+
+fn with_tolerance(value: f64, scale: f64) -> f64 {
+    let tolerance = FRUSTUM_TOLERANCE * (1.0 + scale.abs());
+    if tolerance.is_finite() {
+        value + tolerance
+    } else {
+        value
+    }
+}
+
+// Conservative bounds on the rays of a packet. Every ray's origin lies within the origin bounds
+// and every inverse direction within the inverse direction bounds, so interval arithmetic on the
+// slab distances gives a lower bound on where any ray can enter a box and an upper bound on where
+// any ray can leave it. Axes along which the rays don't all point the same way give no bounds.
+#[derive(Debug)]
+struct RayBounds {
+    origin_min: Point3<f64>,
+    origin_max: Point3<f64>,
+    inverse_direction_min: Vector3<f64>,
+    inverse_direction_max: Vector3<f64>,
+    has_bounds: [bool; 3],
+}
+
+impl RayBounds {
+    fn new(rays: &[Ray]) -> Self {
+        let mut origin_min = rays[0].origin;
+        let mut origin_max = origin_min;
+        let mut inverse_direction_min = Vector3::repeat(f64::INFINITY);
+        let mut inverse_direction_max = Vector3::repeat(f64::NEG_INFINITY);
+        let mut has_bounds = [true; 3];
+        let signs = rays[0].direction.map(f64::signum);
+        for ray in rays {
+            origin_min = Point3::from(origin_min.coords.inf(&ray.origin.coords));
+            origin_max = Point3::from(origin_max.coords.sup(&ray.origin.coords));
+            for axis in 0..3 {
+                let direction = ray.direction[axis];
+                if direction * signs[axis] <= 0.0 {
+                    has_bounds[axis] = false;
+                    continue;
+                }
+
+                let inverse_direction = 1.0 / direction;
+                inverse_direction_min[axis] = inverse_direction_min[axis].min(inverse_direction);
+                inverse_direction_max[axis] = inverse_direction_max[axis].max(inverse_direction);
+            }
+        }
+
+        Self {
+            origin_min,
+            origin_max,
+            inverse_direction_min,
+            inverse_direction_max,
+            has_bounds,
+        }
+    }
+
+    // Range of `(plane - origin) * inverse_direction` over every origin and direction in the
+    // frustum along an axis
+    fn slab_distances(&self, axis: usize, plane: f64) -> (f64, f64) {
+        let offsets = [plane - self.origin_max[axis], plane - self.origin_min[axis]];
+        let inverse_directions = [
+            self.inverse_direction_min[axis],
+            self.inverse_direction_max[axis],
+        ];
+
+        let mut min = f64::INFINITY;
+        let mut max = f64::NEG_INFINITY;
+        for offset in &offsets {
+            for inverse_direction in &inverse_directions {
+                let distance = offset * inverse_direction;
+                min = min.min(distance);
+                max = max.max(distance);
+            }
+        }
+
+        (min, max)
+    }
+
+    // False only if no ray in the frustum can hit the box within `max_distance`
+    fn intersect(&self, bounding_volume: &BoundingVolume, max_distance: Option<f64>) -> bool {
+        let mut near = f64::NEG_INFINITY;
+        let mut far = f64::INFINITY;
+        for axis in 0..3 {
+            if !self.has_bounds[axis] {
+                continue;
+            }
+
+            let (near_plane, far_plane) = if self.inverse_direction_min[axis] > 0.0 {
+                (
+                    bounding_volume.bounds_min[axis],
+                    bounding_volume.bounds_max[axis],
+                )
+            } else {
+                (
+                    bounding_volume.bounds_max[axis],
+                    bounding_volume.bounds_min[axis],
+                )
+            };
+            near = near.max(self.slab_distances(axis, near_plane).0);
+            far = far.min(self.slab_distances(axis, far_plane).1);
+        }
+
+        let scale = near.abs().max(far.abs());
+        if near > with_tolerance(far, scale) || with_tolerance(far, scale) < 0.0 {
+            return false;
+        }
+
+        max_distance.is_none() || near <= with_tolerance(max_distance.unwrap(), scale)
+    }
+}
+
+// The four planes bounding rays which share an origin. Along the major axis each ray moves out
+// from the origin, and across it each ray's offset grows at a slope between the smallest and
+// largest slope in the packet, so a box entirely outside one of the planes is missed by every ray.
+#[derive(Debug)]
+struct SidePlanes {
+    origin: Point3<f64>,
+    major_axis: usize,
+    major_sign: f64,
+    // Smallest and largest slope across each of the other two axes
+    slopes: [(usize, f64, f64); 2],
+}
+
+impl SidePlanes {
+    fn new(rays: &[Ray]) -> Option<Self> {
+        let origin = rays[0].origin;
+        if rays.iter().any(|ray| ray.origin != origin) {
+            return None;
+        }
+
+        let direction_sum: Vector3<f64> = rays.iter().map(|ray| ray.direction).sum();
+        let major_axis = direction_sum.iamax();
+        let major_sign = direction_sum[major_axis].signum();
+        if rays
+            .iter()
+            .any(|ray| ray.direction[major_axis] * major_sign <= 0.0)
+        {
+            return None;
+        }
+
+        let mut slopes = [
+            ((major_axis + 1) % 3, f64::INFINITY, f64::NEG_INFINITY),
+            ((major_axis + 2) % 3, f64::INFINITY, f64::NEG_INFINITY),
+        ];
+        for ray in rays {
+            let major_direction = ray.direction[major_axis].abs();
+            for (axis, min_slope, max_slope) in &mut slopes {
+                let slope = ray.direction[*axis] / major_direction;
+                *min_slope = min_slope.min(slope);
+                *max_slope = max_slope.max(slope);
+            }
+        }
+
+        Some(Self {
+            origin,
+            major_axis,
+            major_sign,
+            slopes,
+        })
+    }
+
+    // False only if the box is entirely outside one of the planes
+    fn intersect(&self, bounding_volume: &BoundingVolume) -> bool {
+        let major_axis = self.major_axis;
+        let major_min =
+            (bounding_volume.bounds_min[major_axis] - self.origin[major_axis]) * self.major_sign;
+        let major_max =
+            (bounding_volume.bounds_max[major_axis] - self.origin[major_axis]) * self.major_sign;
+        let (major_min, major_max) = (major_min.min(major_max), major_min.max(major_max));
+
+        self.slopes.iter().all(|&(axis, min_slope, max_slope)| {
+            let offset_min = bounding_volume.bounds_min[axis] - self.origin[axis];
+            let offset_max = bounding_volume.bounds_max[axis] - self.origin[axis];
+
+            // Some point of the box needs an offset of at least `min_slope` times its distance
+            // along the major axis, and some point at most `max_slope` times it
+            let above_min_plane = offset_max - (min_slope * major_min).min(min_slope * major_max);
+            let below_max_plane = offset_min - (max_slope * major_min).max(max_slope * major_max);
+            let scale = offset_min.abs().max(offset_max.abs()).max(major_max.abs());
+
+            with_tolerance(above_min_plane, scale) >= 0.0
+                && below_max_plane <= with_tolerance(0.0, scale)
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Frustum {
+    bounds: RayBounds,
+    planes: Option<SidePlanes>,
+}
+
+impl Frustum {
+    fn new(rays: &[Ray]) -> Self {
+        Self {
+            bounds: RayBounds::new(rays),
+            planes: SidePlanes::new(rays),
+        }
+    }
+
+    fn intersect(&self, bounding_volume: &BoundingVolume, max_distance: Option<f64>) -> bool {
+        let within_planes = match &self.planes {
+            Some(planes) => planes.intersect(bounding_volume),
+            None => true,
+        };
+
+        within_planes && self.bounds.intersect(bounding_volume, max_distance)
+    }
+}
+
+// A group of coherent rays traced together, such as primary rays through neighboring pixels or
+// shadow rays toward the same light. Nodes are first tested against the frustum bounding the whole
+// packet, so a node missed by every ray is skipped without testing each ray against it.
+#[derive(Debug)]
+pub struct RayPacket {
+    rays: Vec<Ray>,
+    frustum: Option<Frustum>,
+}
+
+impl RayPacket {
+    pub fn new(rays: Vec<Ray>) -> Self {
+        assert!(
+            rays.len() <= MAX_PACKET_SIZE,
+            "ray packets hold at most {} rays",
+            MAX_PACKET_SIZE
+        );
+
+        let frustum = if rays.is_empty() {
+            None
+        } else {
+            Some(Frustum::new(&rays))
+        };
+
+        Self { rays, frustum }
+    }
+
+    pub fn rays(&self) -> &[Ray] {
+        &self.rays
+    }
+
+    pub fn len(&self) -> usize {
+        self.rays.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rays.is_empty()
+    }
+
+    // Mask with a bit set for every ray in the packet
+    pub(super) fn all_rays(&self) -> u64 {
+        if self.rays.len() == MAX_PACKET_SIZE {
+            u64::MAX
+        } else {
+            (1 << self.rays.len()) - 1
+        }
+    }
+
+    pub(super) fn frustum_intersects(
+        &self,
+        bounding_volume: &BoundingVolume,
+        max_distance: Option<f64>,
+    ) -> bool {
+        self.frustum
+            .as_ref()
+            .is_some_and(|frustum| frustum.intersect(bounding_volume, max_distance))
+    }
+}
+
+// Indexes of the rays in a mask
+pub(super) fn masked_rays(mask: u64) -> impl Iterator<Item = usize> {
+    let mut mask = mask;
+    std::iter::from_fn(move || {
+        if mask == 0 {
+            None
+        } else {
+            let index = mask.trailing_zeros() as usize;
+            mask &= mask - 1;
+            Some(index)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        Accelerator, AcceleratorCache, KdTreeAccelerator, KdTreeConstructionOptions, Material,
+        MediumStack, Transform,
+    };
+    use crate::primitives::{Object3D, Plane, RaytracingObject, Sphere};
+    use crate::ray_intersection::RayType;
+
+    fn sphere_grid() -> Vec<Box<dyn RaytracingObject>> {
+        let mut objects = Vec::new();
+        for x in -4..5 {
+            for y in -4..5 {
+                for z in -4..5 {
+                    let sphere = Object3D::Sphere(Box::new(Sphere::new(
+                        0.35,
+                        Transform::default().translate(Vector3::new(
+                            f64::from(x),
+                            f64::from(y),
+                            f64::from(z),
+                        )),
+                        Material::default(),
+                    )));
+                    objects.append(&mut sphere.flatten_to_world(&Transform::default()));
+                }
+            }
+        }
+        let plane = Object3D::Plane(Box::new(Plane::new(
+            Vector3::y_axis(),
+            Transform::default().translate(Vector3::new(0.0, -5.0, 0.0)),
+            Material::default(),
+        )));
+        objects.append(&mut plane.flatten_to_world(&Transform::default()));
+
+        objects
+    }
+
+    fn ray(ray_type: RayType, origin: Point3<f64>, direction: Vector3<f64>) -> Ray {
+        Ray {
+            ray_type,
+            origin,
+            direction: direction.normalize(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        }
+    }
+
+    // 4x4 grid of rays from a common origin spread over `spread` around `direction`
+    fn camera_packet(origin: Point3<f64>, direction: Vector3<f64>, spread: f64) -> RayPacket {
+        let mut rays = Vec::new();
+        for y in 0..4 {
+            for x in 0..4 {
+                let offset = Vector3::new(f64::from(x) - 1.5, f64::from(y) - 1.5, 0.0) * spread;
+                rays.push(ray(RayType::Primary, origin, direction + offset));
+            }
+        }
+
+        RayPacket::new(rays)
+    }
+
+    #[test]
+    fn it_culls_boxes_outside_the_frustum() {
+        let packet = camera_packet(Point3::new(0.0, 0.0, 5.0), -Vector3::z(), 0.01);
+        let in_front =
+            BoundingVolume::from_bounds(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let to_the_side =
+            BoundingVolume::from_bounds(Point3::new(3.0, -1.0, -1.0), Point3::new(4.0, 1.0, 1.0));
+        let behind =
+            BoundingVolume::from_bounds(Point3::new(-1.0, -1.0, 6.0), Point3::new(1.0, 1.0, 7.0));
+
+        assert!(packet.frustum_intersects(&in_front, None));
+        assert!(!packet.frustum_intersects(&in_front, Some(3.0)));
+        assert!(!packet.frustum_intersects(&to_the_side, None));
+        assert!(!packet.frustum_intersects(&behind, None));
+    }
+
+    #[test]
+    fn it_finds_the_same_intersections_as_single_rays() {
+        let kd_tree = KdTreeAccelerator::new(
+            sphere_grid(),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+
+        for i in 0..20 {
+            let angle = f64::from(i) * 0.35;
+            let origin = Point3::new(9.0 * angle.cos(), 2.0, 9.0 * angle.sin());
+            let direction = Vector3::new(-angle.cos(), -0.3, -angle.sin());
+            let packet = camera_packet(origin, direction, 0.02 * f64::from(i % 4 + 1));
+
+            let intersections = kd_tree.raycast_packet(&packet);
+            assert_eq!(intersections.len(), packet.len());
+            for (ray, intersection) in packet.rays().iter().zip(intersections) {
+                assert_eq!(
+                    intersection.map(|intersection| intersection.distance),
+                    kd_tree
+                        .raycast(ray)
+                        .map(|intersection| intersection.distance)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn it_finds_the_same_shadows_as_single_rays() {
+        let kd_tree = KdTreeAccelerator::new(
+            sphere_grid(),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        let light_position = Point3::new(2.0, 8.0, 3.0);
+
+        for i in 0..20 {
+            let mut rays = Vec::new();
+            let mut max_distances = Vec::new();
+            for j in 0..16 {
+                let target = Point3::new(
+                    f64::from(i % 5) - 2.0 + 0.1 * f64::from(j % 4),
+                    f64::from(i / 5) - 2.5,
+                    0.5 + 0.1 * f64::from(j / 4),
+                );
+                let to_target = target - light_position;
+                rays.push(ray(RayType::Shadow, light_position, to_target));
+                max_distances.push(to_target.magnitude());
+            }
+            let packet = RayPacket::new(rays);
+
+            let shadows = kd_tree.shadow_cast_packet(&packet, &max_distances);
+            for ((ray, max_distance), shadow) in
+                packet.rays().iter().zip(&max_distances).zip(shadows)
+            {
+                assert_eq!(shadow, kd_tree.shadow_cast(ray, *max_distance));
+            }
+        }
+    }
+}
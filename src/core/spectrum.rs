@@ -0,0 +1,240 @@
+use nalgebra::{Matrix3, Vector3};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::Deserialize;
+
+// Range of visible wavelengths in nanometers
+const MIN_WAVELENGTH: f64 = 360.0;
+const MAX_WAVELENGTH: f64 = 830.0;
+const WAVELENGTH_RANGE: f64 = MAX_WAVELENGTH - MIN_WAVELENGTH;
+
+// Number of wavelengths a camera ray carries
+const NUM_HERO_WAVELENGTHS: usize = 4;
+
+// Number of wavelengths spectra are sampled at to find their colors
+const NUM_SPECTRUM_SAMPLES: u32 = 48;
+
+// Wavelengths of the Fraunhofer lines refractive indices and Abbe numbers are given at
+const D_LINE: f64 = 587.6;
+const F_LINE: f64 = 486.1;
+const C_LINE: f64 = 656.3;
+
+#[rustfmt::skip]
+static XYZ_TO_LINEAR_SRGB: Lazy<Matrix3<f64>> = Lazy::new(|| Matrix3::new(
+    3.2406, -1.5372, -0.4986,
+    -0.9689, 1.8758, 0.0415,
+    0.0557, -0.2040, 1.0570,
+));
+
+// Average of the unnormalized color of every visible wavelength, so that colors are scaled to
+// average out to white
+static AVERAGE_WAVELENGTH_COLOR: Lazy<Vector3<f64>> = Lazy::new(|| {
+    let num_steps = 4700;
+    (0..num_steps)
+        .map(|step| {
+            let wavelength =
+                MIN_WAVELENGTH + (f64::from(step) + 0.5) / f64::from(num_steps) * WAVELENGTH_RANGE;
+            unnormalized_wavelength_color(wavelength)
+        })
+        .sum::<Vector3<f64>>()
+        / f64::from(num_steps)
+});
+
+// Piecewise Gaussian with different widths either side of its peak
+fn lobe(wavelength: f64, peak: f64, lower_width: f64, upper_width: f64) -> f64 {
+    let width = if wavelength < peak {
+        lower_width
+    } else {
+        upper_width
+    };
+    let t = (wavelength - peak) / width;
+
+    (-0.5 * t * t).exp()
+}
+
+// Wyman, Sloan and Shirley's multi-lobe fit of the CIE 1931 color matching functions
+fn wavelength_to_xyz(wavelength: f64) -> Vector3<f64> {
+    Vector3::new(
+        1.056 * lobe(wavelength, 599.8, 37.9, 31.0) + 0.362 * lobe(wavelength, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(wavelength, 501.1, 20.4, 26.2),
+        0.821 * lobe(wavelength, 568.8, 46.9, 40.5) + 0.286 * lobe(wavelength, 530.9, 16.3, 31.1),
+        1.217 * lobe(wavelength, 437.0, 11.8, 36.0) + 0.681 * lobe(wavelength, 459.0, 26.0, 13.8),
+    )
+}
+
+// Linear sRGB color of a wavelength, with colors outside of the gamut clipped to it
+fn unnormalized_wavelength_color(wavelength: f64) -> Vector3<f64> {
+    (*XYZ_TO_LINEAR_SRGB * wavelength_to_xyz(wavelength)).map(|c| c.max(0.0))
+}
+
+// Color a wavelength adds to the image, scaled so that the colors of every visible wavelength
+// average out to white
+pub fn wavelength_color(wavelength: f64) -> Vector3<f64> {
+    unnormalized_wavelength_color(wavelength).component_div(&AVERAGE_WAVELENGTH_COLOR)
+}
+
+// Color of light with the given intensity at each wavelength, where an intensity of 1 everywhere
+// is white
+pub fn spectrum_to_rgb(intensity: impl Fn(f64) -> f64) -> Vector3<f64> {
+    (0..NUM_SPECTRUM_SAMPLES)
+        .map(|sample| {
+            let wavelength = MIN_WAVELENGTH
+                + (f64::from(sample) + 0.5) / f64::from(NUM_SPECTRUM_SAMPLES) * WAVELENGTH_RANGE;
+            intensity(wavelength) * wavelength_color(wavelength)
+        })
+        .sum::<Vector3<f64>>()
+        / f64::from(NUM_SPECTRUM_SAMPLES)
+}
+
+// Wavelengths in nanometers carried by a ray in spectral mode. Camera rays carry a hero wavelength
+// along with others spaced evenly across the visible range from it, which are only traced apart
+// where a dispersive surface bends each of them differently. Everywhere else light is carried in
+// RGB as usual.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Wavelengths {
+    Hero(f64),
+    Single(f64),
+}
+
+impl Wavelengths {
+    pub fn sample() -> Self {
+        Wavelengths::Hero(MIN_WAVELENGTH + rand::thread_rng().gen::<f64>() * WAVELENGTH_RANGE)
+    }
+
+    pub fn hero(self) -> f64 {
+        match self {
+            Wavelengths::Hero(wavelength) | Wavelengths::Single(wavelength) => wavelength,
+        }
+    }
+
+    // Wavelengths to trace apart, along with the color each one adds to the image. A single
+    // wavelength was already given its color when it was split off.
+    pub fn split(self) -> Vec<(f64, Vector3<f64>)> {
+        match self {
+            Wavelengths::Hero(hero) => (0..NUM_HERO_WAVELENGTHS)
+                .map(|index| {
+                    let offset = index as f64 * WAVELENGTH_RANGE / NUM_HERO_WAVELENGTHS as f64;
+                    let wavelength =
+                        MIN_WAVELENGTH + (hero - MIN_WAVELENGTH + offset) % WAVELENGTH_RANGE;
+
+                    (
+                        wavelength,
+                        wavelength_color(wavelength) / NUM_HERO_WAVELENGTHS as f64,
+                    )
+                })
+                .collect(),
+            Wavelengths::Single(wavelength) => vec![(wavelength, Vector3::repeat(1.0))],
+        }
+    }
+}
+
+// How the refractive index of a material varies with wavelength
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Dispersion {
+    // n = a + b / λ² + c / λ⁴, with λ in micrometers
+    Cauchy {
+        a: f64,
+        b: f64,
+        #[serde(default)]
+        c: f64,
+    },
+    // n² = 1 + Σ bᵢ λ² / (λ² - cᵢ), with λ in micrometers
+    Sellmeier {
+        b: [f64; 3],
+        c: [f64; 3],
+    },
+    // Spread of the refractive index around the material's own index, which is taken to be the
+    // index at the d line. Lower numbers spread colors further apart.
+    Abbe {
+        number: f64,
+    },
+}
+
+impl Dispersion {
+    // Refractive index at a wavelength, or at the d line for light without one
+    pub fn refractive_index(&self, base_index: f64, wavelength: Option<f64>) -> f64 {
+        let wavelength = wavelength.unwrap_or(D_LINE);
+        // Micrometers
+        let wavelength2 = (wavelength * 1e-3).powi(2);
+
+        match *self {
+            Dispersion::Cauchy { a, b, c } => a + b / wavelength2 + c / (wavelength2 * wavelength2),
+            Dispersion::Sellmeier { b, c } => (1.0
+                + b.iter()
+                    .zip(&c)
+                    .map(|(b, c)| b * wavelength2 / (wavelength2 - c))
+                    .sum::<f64>())
+            .sqrt(),
+            // Two term Cauchy equation through the index at the d line with the given Abbe number
+            Dispersion::Abbe { number } => {
+                let inverse2 = |wavelength: f64| 1.0 / (wavelength * 1e-3).powi(2);
+                let b = (base_index - 1.0) / (number * (inverse2(F_LINE) - inverse2(C_LINE)));
+
+                base_index + b * (1.0 / wavelength2 - inverse2(D_LINE))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_averages_wavelength_colors_to_white() {
+        let num_samples = 1000;
+        let average = (0..num_samples)
+            .map(|index| {
+                let hero = MIN_WAVELENGTH
+                    + (f64::from(index) + 0.5) / f64::from(num_samples) * WAVELENGTH_RANGE;
+                Wavelengths::Hero(hero)
+                    .split()
+                    .into_iter()
+                    .map(|(_, color)| color)
+                    .sum::<Vector3<f64>>()
+            })
+            .sum::<Vector3<f64>>()
+            / f64::from(num_samples);
+        assert!((average - Vector3::repeat(1.0)).amax() < 1e-3);
+
+        let white = spectrum_to_rgb(|_| 1.0);
+        assert!((white - Vector3::repeat(1.0)).amax() < 1e-2);
+
+        // Long wavelengths are red and short ones blue
+        let red = wavelength_color(650.0);
+        assert!(red.x > red.y && red.x > red.z);
+        let blue = wavelength_color(450.0);
+        assert!(blue.z > blue.x && blue.z > blue.y);
+    }
+
+    #[test]
+    fn it_computes_dispersed_refractive_indices() {
+        // N-BK7 glass
+        let sellmeier = Dispersion::Sellmeier {
+            b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+        };
+        assert!((sellmeier.refractive_index(1.0, None) - 1.5168).abs() < 1e-4);
+        assert!(
+            sellmeier.refractive_index(1.0, Some(450.0))
+                > sellmeier.refractive_index(1.0, Some(650.0))
+        );
+
+        let abbe = Dispersion::Abbe { number: 64.17 };
+        let (n_d, n_f, n_c) = (
+            abbe.refractive_index(1.5168, Some(D_LINE)),
+            abbe.refractive_index(1.5168, Some(F_LINE)),
+            abbe.refractive_index(1.5168, Some(C_LINE)),
+        );
+        assert!((n_d - 1.5168).abs() < 1e-12);
+        assert!(((n_d - 1.0) / (n_f - n_c) - 64.17).abs() < 1e-9);
+
+        let cauchy = Dispersion::Cauchy {
+            a: 1.5,
+            b: 0.004,
+            c: 0.0,
+        };
+        assert!((cauchy.refractive_index(1.0, Some(500.0)) - 1.516).abs() < 1e-12);
+    }
+}
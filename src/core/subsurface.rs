@@ -0,0 +1,66 @@
+use super::HomogeneousMedium;
+use nalgebra::{clamp, Vector3};
+use serde::Deserialize;
+
+// Light scattering around inside of an object before leaving it somewhere else, as in skin, wax and
+// marble. Light which has scattered many times takes on the color of the material.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Subsurface {
+    // Average distance light travels between scattering events in each channel
+    pub mean_free_path: Vector3<f64>,
+    pub asymmetry: f64,
+}
+
+impl Default for Subsurface {
+    fn default() -> Self {
+        Self {
+            mean_free_path: Vector3::repeat(0.1),
+            asymmetry: 0.0,
+        }
+    }
+}
+
+// Single scattering albedo which gives an overall albedo of `color` after many scattering events,
+// from Chiang, Kutz and Burley's fit for random walks
+fn single_scattering_albedo(color: f64) -> f64 {
+    let color = clamp(color, 0.0, 1.0);
+    let t =
+        4.09712 + 4.20863 * color - (9.59217 + 41.6808 * color + 17.7126 * color * color).sqrt();
+
+    1.0 - t * t
+}
+
+impl Subsurface {
+    // Medium filling the object, which scatters light toward the given color
+    pub fn get_medium(&self, color: &Vector3<f64>) -> HomogeneousMedium {
+        let attenuation = self.mean_free_path.map(|path| 1.0 / path.max(1e-6));
+        let albedo = color.map(single_scattering_albedo);
+
+        HomogeneousMedium {
+            absorption: attenuation.component_mul(&(Vector3::repeat(1.0) - albedo)),
+            scattering: attenuation.component_mul(&albedo),
+            asymmetry: self.asymmetry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_inverts_multiple_scattering_albedos() {
+        assert!(single_scattering_albedo(0.0).abs() < 1e-5);
+        assert!(single_scattering_albedo(1.0) > 0.99);
+        assert!(single_scattering_albedo(0.5) > 0.5 && single_scattering_albedo(0.5) < 0.99);
+
+        let medium = Subsurface {
+            mean_free_path: Vector3::new(1.0, 0.5, 0.25),
+            asymmetry: 0.0,
+        }
+        .get_medium(&Vector3::repeat(0.8));
+        let attenuation = medium.absorption + medium.scattering;
+        assert!((attenuation - Vector3::new(1.0, 2.0, 4.0)).amax() < 1e-12);
+    }
+}
@@ -1,6 +1,11 @@
+use crate::ray_intersection::Intersection;
+use crate::utils;
+use image::imageops::{self, FilterType};
 use image::Pixel;
 use image::RgbImage;
-use nalgebra::{clamp, Vector2, Vector3};
+use nalgebra::{clamp, Unit, Vector2, Vector3};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt;
 use std::path::Path;
 
@@ -10,6 +15,8 @@ pub struct Texture {
     width: u32,
     height: u32,
     texture: Option<RgbImage>,
+    // Successively halved copies of the texture, down to a single pixel
+    mip_levels: Vec<RgbImage>,
 }
 
 impl fmt::Debug for Texture {
@@ -29,22 +36,100 @@ impl Texture {
             width: 0,
             height: 0,
             texture: None,
+            mip_levels: Vec::new(),
         }
     }
 
+    pub fn from_image(texture_path: &str, texture: RgbImage) -> Self {
+        Self {
+            texture_path: texture_path.to_string(),
+            width: texture.width(),
+            height: texture.height(),
+            mip_levels: Self::build_mip_levels(&texture),
+            texture: Some(texture),
+        }
+    }
+
+    fn build_mip_levels(texture: &RgbImage) -> Vec<RgbImage> {
+        let mut mip_levels: Vec<RgbImage> = Vec::new();
+        let (mut width, mut height) = texture.dimensions();
+        while width > 1 || height > 1 {
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+
+            let previous = mip_levels.last().unwrap_or(texture);
+            mip_levels.push(imageops::resize(
+                previous,
+                width,
+                height,
+                FilterType::Triangle,
+            ));
+        }
+
+        mip_levels
+    }
+
     pub fn load(&mut self, asset_base: &Path) -> Result<(), image::ImageError> {
         assert!(self.texture.is_none());
 
         let texture = image::open(asset_base.join(self.texture_path.clone()))?.to_rgb();
         self.width = texture.width();
         self.height = texture.height();
+        self.mip_levels = Self::build_mip_levels(&texture);
         self.texture = Some(texture);
 
         Ok(())
     }
 
+    pub fn texel_size(&self) -> Vector2<f64> {
+        Vector2::new(1.0 / f64::from(self.width), 1.0 / f64::from(self.height))
+    }
+
     pub fn get_color(&self, uv: Vector2<f64>) -> Vector3<f64> {
-        let (w, h) = (self.width - 1, self.height - 1);
+        Self::sample_image(self.texture.as_ref().expect("texture not loaded"), uv)
+    }
+
+    // Color averaged over the footprint of a pixel, given the change in texture coordinates
+    // between neighboring pixels. Blends between the two mip levels whose texels are closest to
+    // the footprint in size.
+    pub fn get_filtered_color(
+        &self,
+        uv: Vector2<f64>,
+        uv_derivatives: Option<(Vector2<f64>, Vector2<f64>)>,
+    ) -> Vector3<f64> {
+        let level = uv_derivatives.map_or(0.0, |(duvdx, duvdy)| {
+            let size = Vector2::new(f64::from(self.width), f64::from(self.height));
+            let footprint = duvdx
+                .component_mul(&size)
+                .magnitude()
+                .max(duvdy.component_mul(&size).magnitude());
+
+            footprint.log2().max(0.0).min(self.mip_levels.len() as f64)
+        });
+        if level <= 0.0 {
+            return self.get_color(uv);
+        }
+
+        let lower = level.floor();
+        let (lower_level, upper_level) = (lower as usize, level.ceil() as usize);
+        let lower_color = self.get_level_color(lower_level, uv);
+        if lower_level == upper_level {
+            return lower_color;
+        }
+
+        lower_color.lerp(&self.get_level_color(upper_level, uv), level - lower)
+    }
+
+    fn get_level_color(&self, level: usize, uv: Vector2<f64>) -> Vector3<f64> {
+        if level == 0 {
+            self.get_color(uv)
+        } else {
+            Self::sample_image(&self.mip_levels[level - 1], uv)
+        }
+    }
+
+    fn sample_image(image: &RgbImage, uv: Vector2<f64>) -> Vector3<f64> {
+        let (w, h) = (image.width() - 1, image.height() - 1);
 
         let (x, y) = (uv.x % 1.0, uv.y % 1.0);
         let x = if x < 0.0 { x + 1.0 } else { x };
@@ -53,11 +138,7 @@ impl Texture {
         let (x, y) = (x * f64::from(w), (1.0 - y) * f64::from(h));
         let (x, y) = (clamp(x as u32, 0, w), clamp(y as u32, 0, h));
 
-        let pixel = self
-            .texture
-            .as_ref()
-            .expect("texture not loaded")
-            .get_pixel(x, y);
+        let pixel = image.get_pixel(x, y);
         let channels = pixel.channels();
 
         let norm = f64::from(std::u8::MAX);
@@ -68,3 +149,354 @@ impl Texture {
         )
     }
 }
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum TextureMapping {
+    #[default]
+    Uv,
+    Triplanar,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum TriplanarSpace {
+    #[default]
+    Object,
+    World,
+}
+
+// Channel used when a texture provides a single value (e.g. roughness)
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum TextureChannel {
+    R,
+    G,
+    B,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextureMapOptions {
+    path: String,
+    #[serde(default)]
+    channel: Option<TextureChannel>,
+    #[serde(default)]
+    mapping: TextureMapping,
+    #[serde(default)]
+    triplanar_space: TriplanarSpace,
+    #[serde(default = "TextureMap::default_triplanar_sharpness")]
+    triplanar_sharpness: f64,
+    #[serde(default = "TextureMap::default_uv_scale")]
+    uv_scale: Vector2<f64>,
+    #[serde(default = "Vector2::zeros")]
+    uv_offset: Vector2<f64>,
+    #[serde(default)]
+    uv_rotation: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextureMapDefinition {
+    Path(String),
+    Options(TextureMapOptions),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(from = "TextureMapDefinition")]
+pub struct TextureMap {
+    pub path: String,
+    pub channel: Option<TextureChannel>,
+    pub mapping: TextureMapping,
+    pub triplanar_space: TriplanarSpace,
+    pub triplanar_sharpness: f64,
+    pub uv_scale: Vector2<f64>,
+    pub uv_offset: Vector2<f64>,
+    pub uv_rotation: f64,
+}
+
+impl From<TextureMapDefinition> for TextureMap {
+    fn from(definition: TextureMapDefinition) -> Self {
+        match definition {
+            TextureMapDefinition::Path(path) => Self::new(&path),
+            TextureMapDefinition::Options(options) => Self {
+                path: options.path,
+                channel: options.channel,
+                mapping: options.mapping,
+                triplanar_space: options.triplanar_space,
+                triplanar_sharpness: options.triplanar_sharpness,
+                uv_scale: options.uv_scale,
+                uv_offset: options.uv_offset,
+                uv_rotation: options.uv_rotation,
+            },
+        }
+    }
+}
+
+impl TextureMap {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            channel: None,
+            mapping: TextureMapping::default(),
+            triplanar_space: TriplanarSpace::default(),
+            triplanar_sharpness: Self::default_triplanar_sharpness(),
+            uv_scale: Self::default_uv_scale(),
+            uv_offset: Vector2::zeros(),
+            uv_rotation: 0.0,
+        }
+    }
+
+    fn default_triplanar_sharpness() -> f64 {
+        4.0
+    }
+
+    fn default_uv_scale() -> Vector2<f64> {
+        Vector2::from([1.0, 1.0])
+    }
+
+    // Scale, then rotate (in degrees) and offset texture coordinates
+    pub fn transform_uv(&self, uv: Vector2<f64>) -> Vector2<f64> {
+        let uv = uv.component_mul(&self.uv_scale);
+        let (sin, cos) = self.uv_rotation.to_radians().sin_cos();
+
+        Vector2::new(uv.x * cos - uv.y * sin, uv.x * sin + uv.y * cos) + self.uv_offset
+    }
+
+    // Transforms a change in texture coordinates, which is scaled and rotated but not offset
+    fn transform_uv_offset(&self, offset: Vector2<f64>) -> Vector2<f64> {
+        self.transform_uv(offset) - self.uv_offset
+    }
+
+    pub fn get_color(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Vector3<f64> {
+        let texture = textures.get(&self.path).expect("texture not loaded");
+        let differentials = intersection.get_differentials();
+
+        match self.mapping {
+            TextureMapping::Uv => texture.get_filtered_color(
+                self.transform_uv(intersection.get_uv()),
+                differentials.map(|differentials| {
+                    (
+                        self.transform_uv_offset(differentials.duvdx),
+                        self.transform_uv_offset(differentials.duvdy),
+                    )
+                }),
+            ),
+            TextureMapping::Triplanar => {
+                let (position, normal, position_derivatives) = match self.triplanar_space {
+                    TriplanarSpace::Object => (
+                        intersection.get_object_hit_point(),
+                        intersection.get_object_normal(),
+                        differentials.map(|differentials| {
+                            (differentials.object_dpdx, differentials.object_dpdy)
+                        }),
+                    ),
+                    TriplanarSpace::World => (
+                        intersection.get_hit_point(),
+                        intersection.get_normal(),
+                        differentials.map(|differentials| (differentials.dpdx, differentials.dpdy)),
+                    ),
+                };
+
+                let weights = normal.map(|c| c.abs().powf(self.triplanar_sharpness));
+                let weights = weights / weights.sum();
+
+                // Projects positions and their derivatives onto the plane of two axes
+                let project = |a: usize, b: usize| {
+                    texture.get_filtered_color(
+                        self.transform_uv(Vector2::new(position[a], position[b])),
+                        position_derivatives.map(|(dpdx, dpdy)| {
+                            (
+                                self.transform_uv_offset(Vector2::new(dpdx[a], dpdx[b])),
+                                self.transform_uv_offset(Vector2::new(dpdy[a], dpdy[b])),
+                            )
+                        }),
+                    )
+                };
+
+                let x = project(2, 1);
+                let y = project(0, 2);
+                let z = project(0, 1);
+
+                x * weights.x + y * weights.y + z * weights.z
+            }
+        }
+    }
+
+    pub fn get_value(
+        &self,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> f64 {
+        let color = self.get_color(intersection, textures);
+
+        match self.channel {
+            Some(TextureChannel::R) => color.x,
+            Some(TextureChannel::G) => color.y,
+            Some(TextureChannel::B) => color.z,
+            None => color.mean(),
+        }
+    }
+
+    // Perturb the shading normal using this texture as a height map
+    pub fn get_bump_normal(
+        &self,
+        bump_scale: f64,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Unit<Vector3<f64>> {
+        let texture = textures.get(&self.path).expect("texture not loaded");
+        let normal = intersection.get_normal();
+        let (dpdu, dpdv) = intersection.get_tangents();
+
+        let uv = intersection.get_uv();
+        let delta = texture.texel_size().min() / self.uv_scale.abs().max().max(1e-10);
+        let height =
+            |uv: Vector2<f64>| bump_scale * texture.get_color(self.transform_uv(uv)).mean();
+
+        let h = height(uv);
+        let dh_du = (height(uv + Vector2::new(delta, 0.0)) - h) / delta;
+        let dh_dv = (height(uv + Vector2::new(0.0, delta)) - h) / delta;
+
+        let dpdu = dpdu + dh_du * normal.into_inner();
+        let dpdv = dpdv + dh_dv * normal.into_inner();
+        let bumped = dpdu.cross(&dpdv);
+
+        if bumped.magnitude_squared() == 0.0 {
+            normal
+        } else if bumped.dot(&normal) < 0.0 {
+            Unit::new_normalize(-bumped)
+        } else {
+            Unit::new_normalize(bumped)
+        }
+    }
+
+    // Perturb the shading normal using this texture as a tangent-space normal map
+    pub fn get_mapped_normal(
+        &self,
+        normal_scale: f64,
+        intersection: &Intersection,
+        textures: &HashMap<String, Texture>,
+    ) -> Unit<Vector3<f64>> {
+        let normal = intersection.get_normal();
+        let (dpdu, dpdv) = intersection.get_tangents();
+
+        let Some((tangent, bitangent)) = utils::tangent_frame(&normal, &dpdu, &dpdv) else {
+            return normal;
+        };
+
+        let mapped = self.get_color(intersection, textures) * 2.0 - Vector3::repeat(1.0);
+        Unit::new_normalize(
+            tangent * mapped.x * normal_scale
+                + bitangent * mapped.y * normal_scale
+                + normal.into_inner() * mapped.z,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{Material, MediumStack, Transform};
+    use crate::primitives::{Object3D, Sphere};
+    use crate::ray_intersection::{Ray, RayType};
+    use image::Rgb;
+    use nalgebra::Point3;
+
+    #[test]
+    fn it_averages_minified_textures() {
+        let checkerboard = RgbImage::from_fn(16, 16, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        });
+        let texture = Texture::from_image("checkerboard", checkerboard);
+        let uv = Vector2::new(0.25, 0.25);
+
+        let unfiltered = texture.get_filtered_color(uv, None);
+        assert!(unfiltered.x < 0.01 || unfiltered.x > 0.99);
+
+        // A footprint of a single texel samples the full resolution texture
+        let texel = Some((Vector2::new(1.0 / 16.0, 0.0), Vector2::new(0.0, 1.0 / 16.0)));
+        assert!((texture.get_filtered_color(uv, texel) - unfiltered).magnitude() < 1e-12);
+
+        let whole_texture = Some((Vector2::new(1.0, 0.0), Vector2::new(0.0, 1.0)));
+        let filtered = texture.get_filtered_color(uv, whole_texture);
+        assert!((filtered.x - 0.5).abs() < 0.05, "{:?}", filtered);
+    }
+
+    // Samples a triplanar mapped gradient where a ray toward the center of a sphere hits it
+    fn sample_triplanar(
+        center: Vector3<f64>,
+        direction: Vector3<f64>,
+    ) -> (Vector3<f64>, [Vector3<f64>; 3]) {
+        let gradient = RgbImage::from_fn(8, 8, |x, y| Rgb([x as u8 * 32, y as u8 * 32, 0]));
+        let texture = Texture::from_image("gradient", gradient);
+        let texture_map = TextureMap {
+            mapping: TextureMapping::Triplanar,
+            triplanar_space: TriplanarSpace::World,
+            ..TextureMap::new("gradient")
+        };
+
+        let sphere = Object3D::Sphere(Box::new(Sphere::new(
+            0.5,
+            Transform::default().translate(center),
+            Material::default(),
+        )))
+        .flatten_to_world(&Transform::default());
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::from(center - 5.0 * direction),
+            direction,
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+        let mut intersection = sphere[0].intersect(&ray, None).unwrap();
+        intersection.compute_data(&ray);
+
+        let p = intersection.get_hit_point();
+        let projections = [
+            texture.get_color(Vector2::new(p.z, p.y)),
+            texture.get_color(Vector2::new(p.x, p.z)),
+            texture.get_color(Vector2::new(p.x, p.y)),
+        ];
+        let mut textures = HashMap::new();
+        textures.insert("gradient".to_string(), texture);
+
+        (texture_map.get_color(&intersection, &textures), projections)
+    }
+
+    #[test]
+    fn it_projects_triplanar_textures_along_the_normal() {
+        // Each face only sees the projection along its own axis
+        for axis in 0..3 {
+            let mut direction = Vector3::zeros();
+            direction[axis] = -1.0;
+            let (color, projections) = sample_triplanar(Vector3::repeat(0.25), direction);
+
+            assert!((color - projections[axis]).magnitude() < 1e-12);
+            for (other_axis, projection) in projections.iter().enumerate() {
+                if other_axis != axis {
+                    assert!((color - projection).magnitude() > 0.1);
+                }
+            }
+        }
+
+        // Corners blend all three projections equally
+        let (color, projections) = sample_triplanar(
+            Vector3::new(0.1, 0.3, 0.6),
+            -Vector3::repeat(1.0).normalize(),
+        );
+        let blended = (projections[0] + projections[1] + projections[2]) / 3.0;
+        assert!((color - blended).magnitude() < 1e-12);
+        assert!((projections[0] - projections[1]).magnitude() > 0.1);
+        assert!((projections[1] - projections[2]).magnitude() > 0.1);
+    }
+}
@@ -0,0 +1,41 @@
+use super::spectrum;
+use crate::utils;
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+// Film of another dielectric over a surface, thin enough for light reflected off of its top and
+// bottom to interfere, as in soap bubbles and oil slicks. The color of the film is found from its
+// reflectance over the whole visible spectrum, so it doesn't depend on spectral rendering.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThinFilm {
+    // Nanometers
+    pub thickness: f64,
+    pub refractive_index: f64,
+}
+
+impl Default for ThinFilm {
+    fn default() -> Self {
+        Self {
+            thickness: 500.0,
+            refractive_index: 1.33,
+        }
+    }
+}
+
+impl ThinFilm {
+    // Fraction of light coming from a medium with index `eta_i` reflected by the film over a
+    // surface with index `eta_t`
+    pub fn reflectance(&self, cos_theta_i: f64, eta_i: f64, eta_t: f64) -> Vector3<f64> {
+        spectrum::spectrum_to_rgb(|wavelength| {
+            utils::fresnel_thin_film(
+                cos_theta_i,
+                eta_i,
+                self.refractive_index,
+                eta_t,
+                self.thickness,
+                wavelength,
+            )
+        })
+    }
+}
@@ -18,7 +18,10 @@ mod ray_intersection;
 mod render;
 mod utils;
 
-pub use crate::core::{Material, PhongMaterial, PhysicalMaterial, Transform};
+pub use crate::core::{
+    AcceleratorCache, AcceleratorStats, AcceleratorType, KdTreeConstructionOptions, Material,
+    NodePrecision, PhongMaterial, PhysicalMaterial, Transform,
+};
 pub use crate::lights::{AmbientLight, Light, PointLight};
-pub use crate::primitives::{Cube, Group, Mesh, Object3D, Plane, Sphere, Triangle};
+pub use crate::primitives::{Cube, Gltf, Group, Instance, Mesh, Object3D, Plane, Sphere, Triangle};
 pub use crate::render::{Camera, CastStats, RenderOptions, Scene};
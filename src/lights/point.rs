@@ -29,6 +29,15 @@ impl PointLight {
         }
     }
 
+    // The same light placed within the space of a parent transform
+    #[must_use]
+    pub fn transformed(&self, transform: &Transform) -> Self {
+        Self {
+            transform: transform * &self.transform,
+            ..self.clone()
+        }
+    }
+
     pub fn get_color(&self, distance: f64) -> Vector3<f64> {
         (self.intensity * self.color / distance.powi(2)).map(|c| clamp(c, 0.0, 1.0))
     }
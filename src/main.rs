@@ -1,7 +1,7 @@
 #![deny(clippy::all)]
 
 use clap::{App, Arg};
-use raytrace::Scene;
+use raytrace::{AcceleratorCache, Scene};
 use std::fs::File;
 use std::path::Path;
 use std::time::Instant;
@@ -31,26 +31,60 @@ fn main() {
                 .long("no-progress")
                 .help("Hide progress bar"),
         )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .help(
+                    "Reuse loaded meshes and acceleration structures from a cache file\n\
+                     The file is created or updated if the scene's geometry changed",
+                ),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Print acceleration structure statistics"),
+        )
         .get_matches();
 
     let scene_path = Path::new(matches.value_of("scene").unwrap());
     let scene_file = File::open(scene_path).expect("file not found");
     let output_filename = matches.value_of("output");
     let use_progress = !matches.is_present("noprogress");
+    let print_stats = matches.is_present("stats");
+    let mut cache = matches
+        .value_of("cache")
+        .map_or_else(AcceleratorCache::disabled, |cache_path| {
+            AcceleratorCache::load(Path::new(cache_path))
+        });
 
     let mut scene: Scene = serde_json::from_reader(scene_file).expect("failed to parse scene");
 
     let now = Instant::now();
-    scene.load_assets(scene_path.parent().unwrap_or_else(|| Path::new("")));
+    scene.load_assets_with_cache(
+        scene_path.parent().unwrap_or_else(|| Path::new("")),
+        &mut cache,
+    );
     println!("Took {:?} to load assets.", now.elapsed());
 
     let now = Instant::now();
-    let scene = scene.build_raytracing_scene();
+    let scene = scene.build_raytracing_scene_with_cache(&mut cache);
     println!(
         "Took {:?} to pre-process scene and construct bounding boxes for {} primitives.",
         now.elapsed(),
         scene.get_num_objects()
     );
+    if cache.is_enabled() {
+        println!(
+            "Reused {} of {} cached meshes and acceleration structures.",
+            cache.get_num_hits(),
+            cache.get_num_hits() + cache.get_num_misses()
+        );
+        cache.save().expect("unable to write acceleration cache");
+    }
+    if print_stats {
+        println!("{}", scene.get_accelerator_stats());
+    }
 
     if let Some(filename) = output_filename {
         let (image, duration, _) = scene.raytrace_to_image(use_progress);
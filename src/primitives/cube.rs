@@ -29,6 +29,12 @@ impl Default for Cube {
     }
 }
 
+impl Transformed for Cube {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
 impl Cube {
     pub fn new(size: f64, transform: Transform, material: Material) -> Self {
         Self {
@@ -238,4 +244,34 @@ impl Primitive for RaytracingCube {
             _ => unreachable!(),
         }
     }
+
+    fn surface_tangents(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _object_normal: &Unit<Vector3<f64>>,
+        intermediate: IntermediateData,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        let (dpdu, dpdv) = match intermediate {
+            IntermediateData::CubeHitFace(axis_direction) => {
+                let AxisDirection(axis, positive) = axis_direction;
+
+                if positive {
+                    match axis {
+                        Axis::X => (-Vector3::z(), Vector3::y()),
+                        Axis::Y => (Vector3::x(), -Vector3::z()),
+                        Axis::Z => (Vector3::x(), Vector3::y()),
+                    }
+                } else {
+                    match axis {
+                        Axis::X => (Vector3::z(), Vector3::y()),
+                        Axis::Y => (Vector3::x(), Vector3::z()),
+                        Axis::Z => (-Vector3::x(), Vector3::y()),
+                    }
+                }
+            }
+            _ => unreachable!(),
+        };
+
+        (dpdu * self.size, dpdv * self.size)
+    }
 }
@@ -0,0 +1,553 @@
+use super::mesh::MeshData;
+use super::{Group, Mesh, Object3D, RaytracingObject};
+use crate::core::{
+    Material, MaterialSide, PhysicalMaterial, Texture, TextureChannel, TextureMap, Transform,
+    Transformed,
+};
+use crate::lights::{Light, PointLight};
+use crate::render::Camera;
+use ::gltf::image::Format;
+use ::gltf::khr_lights_punctual::Kind;
+use ::gltf::material::AlphaMode;
+use ::gltf::mesh::Mode;
+use image::RgbImage;
+use nalgebra::{Affine3, Matrix4, Point3, Unit, Vector2, Vector3};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+fn image_key(file: &str, index: usize) -> String {
+    format!("{}#image{}", file, index)
+}
+
+fn to_rgb_image(data: &::gltf::image::Data) -> RgbImage {
+    let (channels, bytes_per_channel, bgr) = match data.format {
+        Format::R8 => (1, 1, false),
+        Format::R8G8 => (2, 1, false),
+        Format::R8G8B8 => (3, 1, false),
+        Format::R8G8B8A8 => (4, 1, false),
+        Format::B8G8R8 => (3, 1, true),
+        Format::B8G8R8A8 => (4, 1, true),
+        Format::R16 => (1, 2, false),
+        Format::R16G16 => (2, 2, false),
+        Format::R16G16B16 => (3, 2, false),
+        Format::R16G16B16A16 => (4, 2, false),
+    };
+
+    // Use the most significant byte of 16 bit (little endian) channels
+    let channel = |pixel: usize, channel: usize| {
+        data.pixels[(pixel * channels + channel) * bytes_per_channel + bytes_per_channel - 1]
+    };
+
+    let mut pixels = Vec::with_capacity((data.width * data.height * 3) as usize);
+    for pixel in 0..(data.width * data.height) as usize {
+        let rgb = if channels < 3 {
+            [channel(pixel, 0); 3]
+        } else if bgr {
+            [channel(pixel, 2), channel(pixel, 1), channel(pixel, 0)]
+        } else {
+            [channel(pixel, 0), channel(pixel, 1), channel(pixel, 2)]
+        };
+        pixels.extend_from_slice(&rgb);
+    }
+
+    RgbImage::from_raw(data.width, data.height, pixels).expect("failed to convert image")
+}
+
+fn to_transform(matrix: [[f32; 4]; 4]) -> Transform {
+    let columns: Vec<f64> = matrix.iter().flatten().copied().map(f64::from).collect();
+
+    Transform::new(Affine3::from_matrix_unchecked(Matrix4::from_column_slice(
+        &columns,
+    )))
+}
+
+#[derive(Debug)]
+struct GltfCamera {
+    yfov: f64,
+    transform: Transform,
+}
+
+struct GltfImporter<'a> {
+    file: &'a str,
+    buffers: &'a [::gltf::buffer::Data],
+    materials: Vec<Material>,
+    default_material: Material,
+
+    import_camera: bool,
+    import_lights: bool,
+    camera: Option<GltfCamera>,
+    lights: Vec<PointLight>,
+}
+
+impl GltfImporter<'_> {
+    fn texture_map(&self, texture: &::gltf::texture::Texture) -> TextureMap {
+        TextureMap::new(&image_key(self.file, texture.source().index()))
+    }
+
+    // Map a glTF metallic-roughness material onto a physical material
+    fn convert_material(&self, material: &::gltf::Material) -> Material {
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor();
+        let metallic_roughness = pbr
+            .metallic_roughness_texture()
+            .map(|info| self.texture_map(&info.texture()));
+
+        Material::Physical(PhysicalMaterial {
+            side: if material.double_sided() {
+                MaterialSide::Both
+            } else {
+                MaterialSide::Front
+            },
+            color: Vector3::new(r, g, b).map(f64::from),
+            opacity: match material.alpha_mode() {
+                AlphaMode::Blend => f64::from(alpha),
+                AlphaMode::Opaque | AlphaMode::Mask => 1.0,
+            },
+            emissive: Vector3::from(material.emissive_factor()).map(f64::from),
+            roughness: f64::from(pbr.roughness_factor()),
+            metalness: f64::from(pbr.metallic_factor()),
+            texture: pbr
+                .base_color_texture()
+                .map(|info| self.texture_map(&info.texture())),
+            roughness_texture: metallic_roughness.clone().map(|texture| TextureMap {
+                channel: Some(TextureChannel::G),
+                ..texture
+            }),
+            metalness_texture: metallic_roughness.map(|texture| TextureMap {
+                channel: Some(TextureChannel::B),
+                ..texture
+            }),
+            emissive_texture: material
+                .emissive_texture()
+                .map(|info| self.texture_map(&info.texture())),
+            normal_texture: material
+                .normal_texture()
+                .map(|normal| self.texture_map(&normal.texture())),
+            normal_scale: material
+                .normal_texture()
+                .map_or(1.0, |normal| f64::from(normal.scale())),
+            ..PhysicalMaterial::default()
+        })
+    }
+
+    fn load_primitive(&self, primitive: &::gltf::Primitive) -> Option<Mesh> {
+        if primitive.mode() != Mode::Triangles {
+            return None;
+        }
+
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+        let positions: Vec<Point3<f64>> = reader
+            .read_positions()?
+            .map(|position| Point3::from(Vector3::from(position).map(f64::from)))
+            .collect();
+        let normals = reader.read_normals().map_or_else(Vec::new, |normals| {
+            normals
+                .map(|normal| Unit::new_normalize(Vector3::from(normal).map(f64::from)))
+                .collect()
+        });
+        // glTF texture coordinates have their origin at the top left of the image
+        let texcoords = reader
+            .read_tex_coords(0)
+            .map_or_else(Vec::new, |texcoords| {
+                texcoords
+                    .into_f32()
+                    .map(|uv| Vector2::new(f64::from(uv[0]), 1.0 - f64::from(uv[1])))
+                    .collect()
+            });
+        let colors = reader.read_colors(0).map_or_else(Vec::new, |colors| {
+            colors
+                .into_rgb_f32()
+                .map(|color| Vector3::from(color).map(f64::from))
+                .collect()
+        });
+        let indices = reader.read_indices().map_or_else(
+            || (0..positions.len()).collect(),
+            |indices| indices.into_u32().map(|index| index as usize).collect(),
+        );
+
+        let material = primitive
+            .material()
+            .index()
+            .map_or(&self.default_material, |index| &self.materials[index]);
+
+        Some(Mesh::from_mesh_data(
+            MeshData {
+                positions,
+                normals,
+                texcoords,
+                colors,
+                indices,
+                material_id: None,
+            },
+            material.clone(),
+        ))
+    }
+
+    fn load_node(&mut self, node: &::gltf::Node, parent_transform: &Transform) -> Object3D {
+        let transform = to_transform(node.transform().matrix());
+        let world_transform = parent_transform * &transform;
+
+        let mut group = Group::new(transform);
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                if let Some(mesh) = self.load_primitive(&primitive) {
+                    group.add_child(Object3D::Mesh(Box::new(mesh)));
+                }
+            }
+        }
+
+        if let Some(camera) = node.camera() {
+            if self.import_camera && self.camera.is_none() {
+                if let ::gltf::camera::Projection::Perspective(perspective) = camera.projection() {
+                    self.camera = Some(GltfCamera {
+                        yfov: f64::from(perspective.yfov()),
+                        transform: world_transform.clone(),
+                    });
+                }
+            }
+        }
+
+        // The renderer only supports point lights, so spot lights lose their cone and directional
+        // lights are skipped
+        if let Some(light) = node.light() {
+            if self.import_lights {
+                match light.kind() {
+                    Kind::Point | Kind::Spot { .. } => {
+                        self.lights.push(PointLight::new(
+                            Vector3::from(light.color()).map(f64::from),
+                            f64::from(light.intensity()),
+                            world_transform.clone(),
+                        ));
+                    }
+                    Kind::Directional => eprintln!(
+                        "Warning: skipping directional light on glTF node {} ({})",
+                        node.index(),
+                        node.name().unwrap_or("unnamed")
+                    ),
+                }
+            }
+        }
+
+        for child in node.children() {
+            let child = self.load_node(&child, &world_transform);
+            group.add_child(child);
+        }
+
+        Object3D::Group(Box::new(group))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Gltf {
+    file: String,
+    #[serde(default)]
+    transform: Transform,
+    // Replaces every material in the file if set
+    #[serde(default)]
+    pub material: Option<Material>,
+    #[serde(default)]
+    import_camera: bool,
+    #[serde(default)]
+    import_lights: bool,
+
+    #[serde(skip)]
+    camera: Option<GltfCamera>,
+    #[serde(skip)]
+    lights: Vec<PointLight>,
+
+    #[serde(default)]
+    pub children: Option<Vec<Object3D>>,
+}
+
+impl Transformed for Gltf {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
+impl Gltf {
+    pub fn new(file: String, transform: Transform) -> Self {
+        Self {
+            file,
+            transform,
+            material: None,
+            import_camera: false,
+            import_lights: false,
+            camera: None,
+            lights: Vec::new(),
+            children: None,
+        }
+    }
+
+    pub fn add_child(&mut self, object: Object3D) {
+        if let Some(children) = self.children.as_mut() {
+            children.push(object);
+        }
+    }
+
+    pub fn flatten_to_world(self, transform: &Transform) -> Vec<Box<dyn RaytracingObject>> {
+        let transform = transform * self.transform;
+
+        let mut objects: Vec<Box<dyn RaytracingObject>> = Vec::new();
+
+        if let Some(children) = self.children {
+            for child in children {
+                let child_objects: Vec<Box<dyn RaytracingObject>> =
+                    child.flatten_to_world(&transform);
+                objects.extend(child_objects);
+            }
+        }
+
+        objects
+    }
+
+    // Imported lights, placed by the transform of this object's parent
+    pub fn get_lights<'a>(&'a self, transform: &'a Transform) -> impl Iterator<Item = Light> + 'a {
+        self.lights
+            .iter()
+            .map(move |light| Light::Point(Box::new(light.transformed(transform))))
+    }
+
+    // Imported camera, placed by the transform of this object's parent. Our field of view applies
+    // to the larger image dimension while glTF's is always vertical.
+    pub fn get_camera(&self, transform: &Transform, aspect: f64) -> Option<Camera> {
+        self.camera.as_ref().map(|camera| {
+            let transform = transform * &camera.transform;
+            let fov = if aspect > 1.0 {
+                2.0 * ((camera.yfov / 2.0).tan() * aspect).atan()
+            } else {
+                camera.yfov
+            };
+
+            let position = transform.matrix() * Point3::origin();
+            Camera {
+                fov: fov.to_degrees(),
+                position,
+                target: position + transform.matrix() * -Vector3::z(),
+                up: Unit::new_normalize(transform.matrix() * Vector3::y()),
+            }
+        })
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the glTF file or any of its buffers and images fail to load.
+    pub fn load_assets(&mut self, asset_base: &Path, textures: &mut HashMap<String, Texture>) {
+        let path = asset_base.join(&self.file);
+        let (document, buffers, images) = ::gltf::import(&path).unwrap_or_else(|err| {
+            panic!(
+                "failed to load glTF at path \"{}\": {}",
+                path.display(),
+                err
+            )
+        });
+
+        self.import(&document, &buffers, &images, textures);
+    }
+
+    fn import(
+        &mut self,
+        document: &::gltf::Document,
+        buffers: &[::gltf::buffer::Data],
+        images: &[::gltf::image::Data],
+        textures: &mut HashMap<String, Texture>,
+    ) {
+        for (index, image) in images.iter().enumerate() {
+            let key = image_key(&self.file, index);
+            textures.insert(key.clone(), Texture::from_image(&key, to_rgb_image(image)));
+        }
+
+        let mut importer = GltfImporter {
+            file: &self.file,
+            buffers,
+            materials: Vec::new(),
+            default_material: self
+                .material
+                .clone()
+                .unwrap_or_else(|| Material::Physical(PhysicalMaterial::default())),
+            import_camera: self.import_camera,
+            import_lights: self.import_lights,
+            camera: None,
+            lights: Vec::new(),
+        };
+        importer.materials = document
+            .materials()
+            .map(|material| {
+                self.material
+                    .clone()
+                    .unwrap_or_else(|| importer.convert_material(&material))
+            })
+            .collect();
+
+        let mut children = Vec::new();
+        if let Some(scene) = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+        {
+            for node in scene.nodes() {
+                children.push(importer.load_node(&node, &self.transform));
+            }
+        }
+
+        let GltfImporter { camera, lights, .. } = importer;
+        self.camera = camera;
+        self.lights = lights;
+        self.children = Some(children);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{Accelerator, AcceleratorCache};
+    use crate::core::{KdTreeAccelerator, KdTreeConstructionOptions, MediumStack, Transformed};
+    use crate::primitives::{Imports, Instance};
+    use crate::ray_intersection::{Ray, RayType};
+    use serde_json::json;
+
+    // Binary glTF holding a triangle, its material, a camera and a point light
+    fn test_glb() -> Vec<u8> {
+        let document = json!({
+            "asset": { "version": "2.0" },
+            "scene": 0,
+            "scenes": [{ "nodes": [0, 1, 2] }],
+            "nodes": [
+                { "mesh": 0, "translation": [0, 0, -2] },
+                { "camera": 0, "translation": [0, 1, 0] },
+                { "extensions": { "KHR_lights_punctual": { "light": 0 } }, "translation": [1, 2, 3] }
+            ],
+            "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "material": 0 }] }],
+            "materials": [{
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": [0.5, 0.25, 1, 1],
+                    "metallicFactor": 0.75,
+                    "roughnessFactor": 0.125
+                },
+                "emissiveFactor": [1, 0, 0],
+                "doubleSided": true
+            }],
+            "cameras": [{ "type": "perspective", "perspective": { "yfov": 0.8, "znear": 0.1 } }],
+            "extensionsUsed": ["KHR_lights_punctual"],
+            "extensions": {
+                "KHR_lights_punctual": {
+                    "lights": [{ "type": "point", "color": [1, 0.5, 0.25], "intensity": 20 }]
+                }
+            },
+            "accessors": [{
+                "bufferView": 0,
+                "componentType": 5126,
+                "count": 3,
+                "type": "VEC3",
+                "min": [0, 0, 0],
+                "max": [1, 1, 0]
+            }],
+            "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+            "buffers": [{ "byteLength": 36 }]
+        });
+
+        let mut json = serde_json::to_vec(&document).unwrap();
+        json.resize((json.len() + 3) / 4 * 4, b' ');
+        let mut bin = Vec::new();
+        for coordinate in &[0_f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            bin.extend_from_slice(&coordinate.to_le_bytes());
+        }
+
+        let mut glb = b"glTF".to_vec();
+        glb.extend_from_slice(&2_u32.to_le_bytes());
+        glb.extend_from_slice(&((12 + 8 + json.len() + 8 + bin.len()) as u32).to_le_bytes());
+        for (chunk_type, chunk) in &[(b"JSON", &json), (b"BIN\0", &bin)] {
+            glb.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            glb.extend_from_slice(*chunk_type);
+            glb.extend_from_slice(chunk);
+        }
+
+        glb
+    }
+
+    fn import_test_glb(import_camera: bool, import_lights: bool) -> Gltf {
+        let (document, buffers, images) = ::gltf::import_slice(test_glb()).unwrap();
+        let mut gltf = Gltf::new("test.glb".to_owned(), Transform::default());
+        gltf.import_camera = import_camera;
+        gltf.import_lights = import_lights;
+        gltf.import(&document, &buffers, &images, &mut HashMap::new());
+
+        gltf
+    }
+
+    #[test]
+    fn it_imports_meshes_and_materials() {
+        let gltf = import_test_glb(false, false);
+        assert!(gltf.lights.is_empty() && gltf.camera.is_none());
+
+        let tree = KdTreeAccelerator::new(
+            gltf.flatten_to_world(&Transform::default()),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::new(0.25, 0.25, 5.0),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+        let intersection = tree.raycast(&ray).unwrap();
+        assert!((intersection.distance - 7.0).abs() < 1e-10);
+
+        match intersection.object.get_material() {
+            Material::Physical(material) => {
+                assert_eq!(material.side, MaterialSide::Both);
+                assert_eq!(material.color, Vector3::new(0.5, 0.25, 1.0));
+                assert_eq!(material.emissive, Vector3::new(1.0, 0.0, 0.0));
+                assert!((material.metalness - 0.75).abs() < 1e-12);
+                assert!((material.roughness - 0.125).abs() < 1e-12);
+            }
+            _ => panic!("expected a physical material"),
+        }
+    }
+
+    #[test]
+    fn it_imports_lights_and_cameras_under_parent_transforms() {
+        let mut group = Group::new(Transform::default().translate(Vector3::new(10.0, 0.0, 0.0)));
+        group.add_child(Object3D::Gltf(Box::new(import_test_glb(true, true))));
+        let mut prototypes = HashMap::new();
+        prototypes.insert(
+            "lamp".to_owned(),
+            Object3D::Gltf(Box::new(import_test_glb(false, true))),
+        );
+        let instance = Instance::new(
+            "lamp".to_owned(),
+            Transform::default().translate(Vector3::new(0.0, 5.0, 0.0)),
+        );
+
+        let mut imports = Imports::new(1.0);
+        for object in &[
+            Object3D::Group(Box::new(group)),
+            Object3D::Instance(Box::new(instance)),
+        ] {
+            object.collect_imports(&Transform::default(), &prototypes, &mut imports);
+        }
+
+        let positions: Vec<Point3<f64>> = imports
+            .lights
+            .iter()
+            .map(|light| match light {
+                Light::Point(light) => light.get_position(),
+                Light::Ambient(_) => panic!("expected a point light"),
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![Point3::new(11.0, 2.0, 3.0), Point3::new(1.0, 7.0, 3.0)]
+        );
+
+        let camera = imports.camera.unwrap();
+        assert!((camera.fov - 0.8_f64.to_degrees()).abs() < 1e-5);
+        assert!((camera.position - Point3::new(10.0, 1.0, 0.0)).norm() < 1e-10);
+        assert!((camera.target - Point3::new(10.0, 1.0, -1.0)).norm() < 1e-10);
+    }
+}
@@ -1,5 +1,5 @@
 use super::{Object3D, RaytracingObject};
-use crate::core::Transform;
+use crate::core::{Transform, Transformed};
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
@@ -11,6 +11,12 @@ pub struct Group {
     pub children: Vec<Object3D>,
 }
 
+impl Transformed for Group {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
 impl Group {
     pub fn new(transform: Transform) -> Self {
         Self {
@@ -0,0 +1,215 @@
+use super::{HasMaterial, Object3D, Primitive, RaytracingObject};
+use crate::core::{Accelerator, Material, ObjectWithBounds, Transform, Transformed};
+use crate::ray_intersection::{IntermediateData, Intersectable, Intersection, Ray};
+use nalgebra::{Point3, Unit, Vector2, Vector3};
+use serde::Deserialize;
+use std::sync::Arc;
+
+// A placement of a named scene prototype. The prototype's objects are flattened and accelerated
+// once and shared by every instance of it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Instance {
+    prototype: String,
+    #[serde(default)]
+    transform: Transform,
+    // Replaces every material in the prototype if set
+    #[serde(default)]
+    pub material: Option<Material>,
+
+    #[serde(skip)]
+    prototype_tree: Option<Arc<dyn Accelerator>>,
+
+    #[serde(default)]
+    pub children: Option<Vec<Object3D>>,
+}
+
+impl Transformed for Instance {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
+impl Instance {
+    pub fn new(prototype: String, transform: Transform) -> Self {
+        Self {
+            prototype,
+            transform,
+            material: None,
+            prototype_tree: None,
+            children: None,
+        }
+    }
+
+    pub fn get_prototype(&self) -> &str {
+        &self.prototype
+    }
+
+    pub fn set_prototype_tree(&mut self, prototype_tree: Arc<dyn Accelerator>) {
+        self.prototype_tree = Some(prototype_tree);
+    }
+
+    pub fn add_child(&mut self, object: Object3D) {
+        if let Some(children) = self.children.as_mut() {
+            children.push(object);
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the instance's prototype tree has not been set.
+    pub fn flatten_to_world(self, transform: &Transform) -> Vec<Box<dyn RaytracingObject>> {
+        let transform = transform * self.transform;
+
+        let mut objects: Vec<Box<dyn RaytracingObject>> = Vec::new();
+
+        if let Some(children) = self.children {
+            for child in children {
+                let child_objects: Vec<Box<dyn RaytracingObject>> =
+                    child.flatten_to_world(&transform);
+                objects.extend(child_objects);
+            }
+        }
+
+        let prototype = self.prototype;
+        let prototype_tree = self.prototype_tree.unwrap_or_else(|| {
+            panic!(
+                "prototype \"{}\" was not built before flattening its instance",
+                prototype
+            )
+        });
+        objects.push(Box::new(RaytracingInstance {
+            prototype_tree,
+            world_transform: transform,
+            material: self.material,
+        }));
+
+        objects
+    }
+}
+
+// Rays reaching an instance have already been transformed into its prototype's space, so
+// intersections come straight from the shared prototype tree
+#[derive(Debug)]
+pub struct RaytracingInstance {
+    prototype_tree: Arc<dyn Accelerator>,
+    world_transform: Transform,
+    material: Option<Material>,
+}
+
+impl HasMaterial for RaytracingInstance {
+    fn get_material(&self) -> &Material {
+        unreachable!("instances are never returned as the intersected object")
+    }
+}
+
+impl Transformed for RaytracingInstance {
+    fn get_transform(&self) -> &Transform {
+        &self.world_transform
+    }
+}
+
+impl Intersectable for RaytracingInstance {
+    fn intersect(&self, ray: &Ray, max_distance: Option<f64>) -> Option<Intersection> {
+        let mut intersection = self.prototype_tree.intersect(ray, max_distance)?;
+        intersection.add_instance(&self.world_transform, self.material.as_ref());
+
+        Some(intersection)
+    }
+}
+
+impl Primitive for RaytracingInstance {
+    fn into_bounded_object(self: Box<Self>) -> ObjectWithBounds {
+        match self.prototype_tree.get_bounding_volume() {
+            Some(bounding_volume) => {
+                let bounding_volume = bounding_volume.transform(self.get_transform());
+                ObjectWithBounds::bounded(self, bounding_volume)
+            }
+            None => ObjectWithBounds::unbounded(self),
+        }
+    }
+
+    fn surface_normal(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _intermediate: IntermediateData,
+    ) -> Unit<Vector3<f64>> {
+        unreachable!("instances are never returned as the intersected object")
+    }
+
+    fn uv(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _object_normal: &Unit<Vector3<f64>>,
+        _intermediate: IntermediateData,
+    ) -> Vector2<f64> {
+        unreachable!("instances are never returned as the intersected object")
+    }
+
+    fn surface_tangents(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _object_normal: &Unit<Vector3<f64>>,
+        _intermediate: IntermediateData,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        unreachable!("instances are never returned as the intersected object")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        AcceleratorCache, KdTreeAccelerator, KdTreeConstructionOptions, MediumStack,
+        PhysicalMaterial,
+    };
+    use crate::primitives::Sphere;
+    use crate::ray_intersection::RayType;
+
+    #[test]
+    fn it_intersects_transformed_prototypes_with_material_override() {
+        let prototype = Object3D::Sphere(Box::new(Sphere::new(
+            1.0,
+            Transform::default(),
+            Material::default(),
+        )));
+        let prototype_tree = Arc::new(KdTreeAccelerator::new(
+            prototype.flatten_to_world(&Transform::default()),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        ));
+
+        let mut instance = Instance::new(
+            "sphere".to_string(),
+            Transform::default().translate(Vector3::new(5.0, 0.0, 0.0)),
+        );
+        instance.material = Some(Material::Physical(PhysicalMaterial::default()));
+        instance.set_prototype_tree(prototype_tree);
+        let tree = KdTreeAccelerator::new(
+            instance.flatten_to_world(&Transform::default()),
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        );
+
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::new(5.0, 0.0, 5.0),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+        let mut intersection = tree.raycast(&ray).unwrap();
+        intersection.compute_data(&ray);
+        assert!((intersection.distance - 4.0).abs() < 1e-10);
+        assert!((intersection.get_hit_point() - Point3::new(5.0, 0.0, 1.0)).norm() < 1e-10);
+        assert!((intersection.get_normal().into_inner() - Vector3::z()).norm() < 1e-10);
+        assert!(matches!(intersection.get_material(), Material::Physical(_)));
+
+        let ray = Ray {
+            origin: Point3::new(0.0, 0.0, 5.0),
+            ..ray
+        };
+        assert!(tree.raycast(&ray).is_none());
+    }
+}
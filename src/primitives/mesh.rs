@@ -1,10 +1,354 @@
-use super::{Object3D, RaytracingObject, Triangle};
-use crate::core::{Material, Transform};
+mod ply;
+mod stl;
+
+use super::{Object3D, RaytracingObject, TriangleMesh};
+use crate::core::{
+    AcceleratorCache, Material, PhongMaterial, PhysicalMaterial, PrincipledMaterial, Texture,
+    TextureMap, Transform, Transformed,
+};
 use nalgebra::{Point3, Unit, Vector2, Vector3};
-use num_traits::identities::Zero;
-use serde::Deserialize;
-use std::path::Path;
-use tobj::load_obj;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+// Indexed vertex data shared by all mesh formats. Each attribute is either empty or has one
+// entry per position.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(super) struct MeshData {
+    pub(super) positions: Vec<Point3<f64>>,
+    pub(super) normals: Vec<Unit<Vector3<f64>>>,
+    pub(super) texcoords: Vec<Vector2<f64>>,
+    pub(super) colors: Vec<Vector3<f64>>,
+    pub(super) indices: Vec<usize>,
+    // Cached meshes find their materials again by name, since the MTL libraries may have changed
+    #[serde(skip)]
+    pub(super) material_id: Option<usize>,
+}
+
+// Processed vertex data of a mesh file as it's stored in the cache, with the name of each part's
+// material and the MTL libraries the materials are read from
+#[derive(Deserialize, Serialize)]
+struct CachedMesh {
+    meshes: Vec<(MeshData, Option<String>)>,
+    material_libraries: Vec<PathBuf>,
+}
+
+impl MeshData {
+    fn from_obj(mesh: &tobj::Mesh) -> Self {
+        let to_f64 =
+            |values: &[f32]| -> Vec<f64> { values.iter().copied().map(f64::from).collect() };
+
+        Self {
+            positions: to_f64(&mesh.positions)
+                .chunks_exact(3)
+                .map(|position| Point3::new(position[0], position[1], position[2]))
+                .collect(),
+            normals: to_f64(&mesh.normals)
+                .chunks_exact(3)
+                .map(|normal| Unit::new_normalize(Vector3::new(normal[0], normal[1], normal[2])))
+                .collect(),
+            texcoords: to_f64(&mesh.texcoords)
+                .chunks_exact(2)
+                .map(|texcoords| Vector2::new(texcoords[0], texcoords[1]))
+                .collect(),
+            colors: Vec::new(),
+            indices: mesh.indices.iter().map(|&index| index as usize).collect(),
+            material_id: mesh.material_id,
+        }
+    }
+
+    fn flip_winding(&mut self) {
+        for face in self.indices.chunks_exact_mut(3) {
+            face.swap(1, 2);
+        }
+        for normal in &mut self.normals {
+            *normal = -*normal;
+        }
+    }
+
+    // Replaces the vertex normals with the angle-weighted average of the normals of every face
+    // sharing the vertex position, excluding faces meeting at more than `crease_angle` degrees.
+    // Vertices are split wherever a crease gives a position more than one normal.
+    fn smooth_normals(&mut self, crease_angle: f64) {
+        let position_key = |position: &Point3<f64>| position.coords.map(f64::to_bits);
+        let cos_crease_angle = crease_angle.to_radians().cos();
+
+        let mut face_normals = Vec::with_capacity(self.indices.len() / 3);
+        let mut corner_angles = Vec::with_capacity(self.indices.len());
+        for face in self.indices.chunks_exact(3) {
+            let positions = [
+                self.positions[face[0]],
+                self.positions[face[1]],
+                self.positions[face[2]],
+            ];
+            let normal = (positions[1] - positions[0]).cross(&(positions[2] - positions[0]));
+            face_normals.push(normal.try_normalize(0.0).unwrap_or_else(Vector3::zeros));
+
+            for corner in 0..3 {
+                let edge1 = positions[(corner + 1) % 3] - positions[corner];
+                let edge2 = positions[(corner + 2) % 3] - positions[corner];
+                corner_angles.push(edge1.angle(&edge2));
+            }
+        }
+
+        // Formats like OBJ duplicate vertices with differing attributes, so faces are matched by
+        // position rather than by index
+        let mut corners_by_position: HashMap<_, Vec<usize>> = HashMap::new();
+        for (corner, &index) in self.indices.iter().enumerate() {
+            corners_by_position
+                .entry(position_key(&self.positions[index]))
+                .or_default()
+                .push(corner);
+        }
+
+        let mut smoothed = Self {
+            material_id: self.material_id,
+            ..Self::default()
+        };
+        let mut vertices = HashMap::new();
+        for (corner, &index) in self.indices.iter().enumerate() {
+            let face_normal = face_normals[corner / 3];
+            let normal = corners_by_position[&position_key(&self.positions[index])]
+                .iter()
+                .filter(|&&other| face_normals[other / 3].dot(&face_normal) >= cos_crease_angle)
+                .fold(Vector3::zeros(), |normal, &other| {
+                    normal + face_normals[other / 3] * corner_angles[other]
+                });
+            let normal = Unit::try_new(normal, 0.0).unwrap_or_else(Vector3::z_axis);
+
+            let vertex = *vertices
+                .entry((index, normal.map(f64::to_bits)))
+                .or_insert_with(|| {
+                    smoothed.positions.push(self.positions[index]);
+                    smoothed.normals.push(normal);
+                    if !self.texcoords.is_empty() {
+                        smoothed.texcoords.push(self.texcoords[index]);
+                    }
+                    if !self.colors.is_empty() {
+                        smoothed.colors.push(self.colors[index]);
+                    }
+
+                    smoothed.positions.len() - 1
+                });
+            smoothed.indices.push(vertex);
+        }
+
+        *self = smoothed;
+    }
+}
+
+fn parse_floats(value: &str) -> Vec<f64> {
+    value
+        .split_whitespace()
+        .filter_map(|value| value.parse().ok())
+        .collect()
+}
+
+fn parse_color(value: &str) -> Option<Vector3<f64>> {
+    match parse_floats(value)[..] {
+        [r, g, b, ..] => Some(Vector3::new(r, g, b)),
+        [c] => Some(Vector3::repeat(c)),
+        _ => None,
+    }
+}
+
+// Parses an MTL texture statement such as `-bm 0.5 -s 2 2 1 bump.png`, returning the texture
+// (relative to the scene) and the bump multiplier if one was given
+fn parse_texture_map(texture_base: &Path, value: &str) -> Option<(TextureMap, Option<f64>)> {
+    let tokens: Vec<&str> = value.split_whitespace().collect();
+    let (file, options) = tokens.split_last()?;
+
+    let path = texture_base.join(file);
+    let mut texture = TextureMap::new(&path.to_string_lossy());
+    let mut bump_multiplier = None;
+
+    let mut index = 0;
+    while index < options.len() {
+        let arguments: Vec<f64> = options[index + 1..]
+            .iter()
+            .map_while(|argument| argument.parse().ok())
+            .collect();
+
+        match options[index] {
+            "-bm" if !arguments.is_empty() => bump_multiplier = Some(arguments[0]),
+            "-s" if arguments.len() >= 2 => {
+                texture.uv_scale = Vector2::new(arguments[0], arguments[1]);
+            }
+            "-o" if arguments.len() >= 2 => {
+                texture.uv_offset = Vector2::new(arguments[0], arguments[1]);
+            }
+            _ => {}
+        }
+
+        index += 1 + arguments.len();
+    }
+
+    Some((texture, bump_multiplier))
+}
+
+// Keys set by each material of an MTL library, in the order tobj loads them. tobj fills in its own
+// defaults for any key a material leaves out, which mustn't replace values of the base material.
+fn defined_mtl_keys(source: &str) -> Vec<HashSet<String>> {
+    let mut defined_keys: Vec<HashSet<String>> = Vec::new();
+    for line in source.lines() {
+        match line.split_whitespace().next() {
+            Some("newmtl") => defined_keys.push(HashSet::new()),
+            Some(key) => {
+                if let Some(keys) = defined_keys.last_mut() {
+                    keys.insert(key.to_string());
+                }
+            }
+            None => {}
+        }
+    }
+
+    defined_keys
+}
+
+fn load_mtl_library(path: &Path) -> Result<(Vec<tobj::Material>, Vec<HashSet<String>>), String> {
+    let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let (materials, _) =
+        tobj::load_mtl_buf(&mut source.as_bytes()).map_err(|err| err.to_string())?;
+
+    Ok((materials, defined_mtl_keys(&source)))
+}
+
+struct MtlMaterial<'a> {
+    mtl: &'a tobj::Material,
+    defined_keys: &'a HashSet<String>,
+    texture_base: &'a Path,
+}
+
+impl<'a> MtlMaterial<'a> {
+    fn defines(&self, key: &str) -> bool {
+        self.defined_keys.contains(key)
+    }
+
+    fn param(&self, key: &str) -> Option<&'a str> {
+        self.mtl.unknown_param.get(key).map(String::as_str)
+    }
+
+    fn scalar(&self, key: &str) -> Option<f64> {
+        self.param(key)
+            .and_then(|value| parse_floats(value).first().copied())
+    }
+
+    fn texture(&self, value: &str) -> Option<(TextureMap, Option<f64>)> {
+        if value.is_empty() {
+            None
+        } else {
+            parse_texture_map(self.texture_base, value)
+        }
+    }
+
+    fn is_physical(&self) -> bool {
+        ["Pr", "Pm", "map_Pr", "map_Pm"]
+            .iter()
+            .any(|key| self.param(key).is_some())
+    }
+
+    fn diffuse(&self) -> Vector3<f64> {
+        Vector3::from(self.mtl.diffuse).map(f64::from)
+    }
+
+    fn emissive(&self) -> Option<Vector3<f64>> {
+        self.param("Ke").and_then(parse_color)
+    }
+
+    fn into_phong(self, base: &PhongMaterial) -> PhongMaterial {
+        let mut material = base.clone();
+        if self.defines("Kd") {
+            material.color = self.diffuse();
+        }
+        if self.defines("Ks") {
+            material.specular = Vector3::from(self.mtl.specular).map(f64::from);
+        }
+        if self.defines("Ns") {
+            material.shininess = f64::from(self.mtl.shininess);
+        }
+        material.emissive = self.emissive().unwrap_or(material.emissive);
+
+        if let Some((texture, _)) = self.texture(&self.mtl.diffuse_texture) {
+            material.texture = Some(texture);
+        }
+        if let Some((texture, _)) = self.texture(&self.mtl.specular_texture) {
+            material.specular_texture = Some(texture);
+        }
+        if let Some((texture, _)) = self.texture(&self.mtl.shininess_texture) {
+            material.shininess_texture = Some(texture);
+        }
+        if let Some((texture, bump_multiplier)) = self.texture(&self.mtl.normal_texture) {
+            material.bump_texture = Some(texture);
+            material.bump_scale = bump_multiplier.unwrap_or(material.bump_scale);
+        }
+
+        material
+    }
+
+    fn into_physical(self, base: PhysicalMaterial) -> PhysicalMaterial {
+        let mut material = base;
+        if self.defines("Kd") {
+            material.color = self.diffuse();
+        }
+        if self.defines("d") {
+            material.opacity = f64::from(self.mtl.dissolve);
+        }
+        if self.defines("Ni") {
+            material.refractive_index = f64::from(self.mtl.optical_density);
+        }
+        material.emissive = self.emissive().unwrap_or(material.emissive);
+
+        // Convert the Blinn-Phong exponent if no PBR roughness is given
+        if let Some(roughness) = self.scalar("Pr") {
+            material.roughness = roughness;
+        } else if self.defines("Ns") {
+            material.roughness = (2.0 / (f64::from(self.mtl.shininess) + 2.0)).sqrt();
+        }
+        material.metalness = self.scalar("Pm").unwrap_or(material.metalness);
+
+        if let Some((texture, _)) = self.texture(&self.mtl.diffuse_texture) {
+            material.texture = Some(texture);
+        }
+        if let Some((texture, _)) = self.param("map_Pr").and_then(|value| self.texture(value)) {
+            material.roughness_texture = Some(texture);
+        } else if let Some((texture, _)) = self.texture(&self.mtl.shininess_texture) {
+            material.shininess_texture = Some(texture);
+        }
+        if let Some((texture, _)) = self.param("map_Pm").and_then(|value| self.texture(value)) {
+            material.metalness_texture = Some(texture);
+        }
+        if let Some((texture, bump_multiplier)) = self.texture(&self.mtl.normal_texture) {
+            material.bump_texture = Some(texture);
+            material.bump_scale = bump_multiplier.unwrap_or(material.bump_scale);
+        }
+
+        material
+    }
+
+    // Convert to a renderer material, using `base` for any values the MTL file doesn't define.
+    // A physical material is produced if `base` is physical or the MTL material uses the PBR
+    // extensions (`Pr`, `Pm`, `map_Pr`, `map_Pm`).
+    fn into_material(self, base: &Material) -> Material {
+        match base {
+            Material::Physical(base) => Material::Physical(self.into_physical(base.clone())),
+            Material::Principled(base) => Material::Principled(PrincipledMaterial {
+                base: self.into_physical(base.base.clone()),
+                ..base.clone()
+            }),
+            Material::Phong(base) if self.is_physical() => {
+                Material::Physical(self.into_physical(PhysicalMaterial {
+                    side: base.side,
+                    ..PhysicalMaterial::default()
+                }))
+            }
+            Material::Phong(base) => Material::Phong(self.into_phong(base)),
+        }
+    }
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -14,21 +358,61 @@ pub struct Mesh {
     transform: Transform,
     #[serde(default)]
     pub material: Material,
+    #[serde(default)]
+    use_mtl: bool,
+    #[serde(default)]
+    material_overrides: HashMap<String, Material>,
+    // Replace vertex normals with smoothed normals, keeping edges sharper than the crease angle
+    // (in degrees) hard
+    #[serde(default)]
+    smooth: bool,
+    #[serde(default = "Mesh::default_crease_angle")]
+    crease_angle: f64,
+    // Reverse the winding order and normals of every face
+    #[serde(default)]
+    flip_normals: bool,
+
+    #[serde(skip)]
+    meshes: Vec<(MeshData, Material)>,
 
     #[serde(default)]
     pub children: Option<Vec<Object3D>>,
 }
 
+impl Transformed for Mesh {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
 impl Mesh {
     pub fn new(file: String, transform: Transform, material: Material) -> Self {
         Self {
             file,
             transform,
             material,
+            use_mtl: false,
+            material_overrides: HashMap::new(),
+            smooth: false,
+            crease_angle: Self::default_crease_angle(),
+            flip_normals: false,
+            meshes: Vec::new(),
             children: None,
         }
     }
 
+    // A mesh built from already loaded vertex data, such as a glTF primitive
+    pub(super) fn from_mesh_data(mesh_data: MeshData, material: Material) -> Self {
+        Self {
+            meshes: vec![(mesh_data, material.clone())],
+            ..Self::new(String::new(), Transform::default(), material)
+        }
+    }
+
+    fn default_crease_angle() -> f64 {
+        180.0
+    }
+
     pub fn add_child(&mut self, object: Object3D) {
         if let Some(children) = self.children.as_mut() {
             children.push(object);
@@ -48,93 +432,393 @@ impl Mesh {
             }
         }
 
+        for (mesh_data, material) in self.meshes {
+            objects
+                .extend(TriangleMesh::new(mesh_data, transform.clone(), material).into_triangles());
+        }
+
         objects
     }
 
-    pub fn load_assets(&mut self, asset_base: &Path) {
-        let (models, _) = load_obj(&asset_base.join(&self.file), true).unwrap_or_else(|err| {
-            panic!(format!(
+    fn convert_mtl_materials(
+        &self,
+        mtl_materials: &[tobj::Material],
+        defined_keys: &[HashSet<String>],
+    ) -> Vec<Material> {
+        let texture_base = Path::new(&self.file)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+
+        mtl_materials
+            .iter()
+            .zip(defined_keys)
+            .map(|(mtl, defined_keys)| {
+                self.material_overrides.get(&mtl.name).map_or_else(
+                    || {
+                        if self.use_mtl {
+                            MtlMaterial {
+                                mtl,
+                                defined_keys,
+                                texture_base,
+                            }
+                            .into_material(&self.material)
+                        } else {
+                            self.material.clone()
+                        }
+                    },
+                    Material::clone,
+                )
+            })
+            .collect()
+    }
+
+    // Parses the mesh file and applies the winding and normal processing to it. Materials from
+    // MTL libraries are only loaded afterwards, so they can change without invalidating the cache.
+    fn parse_mesh_file(&self, path: &Path, bytes: &[u8]) -> Result<CachedMesh, String> {
+        let extension = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .map(str::to_lowercase);
+
+        let (mut meshes, material_libraries) = match extension.as_deref() {
+            Some("ply") => (
+                vec![(ply::parse_ply(bytes).map_err(|err| err.to_string())?, None)],
+                Vec::new(),
+            ),
+            Some("stl") => (
+                vec![(stl::parse_stl(bytes).map_err(|err| err.to_string())?, None)],
+                Vec::new(),
+            ),
+            _ => {
+                let library_base = path.parent().unwrap_or_else(|| Path::new(""));
+                let material_libraries = RefCell::new(Vec::new());
+                let (models, materials) =
+                    tobj::load_obj_buf(&mut Cursor::new(bytes), true, |library| {
+                        material_libraries.borrow_mut().push(library.to_path_buf());
+                        tobj::load_mtl(library_base.join(library))
+                    })
+                    .map_err(|err| err.to_string())?;
+
+                let meshes = models
+                    .iter()
+                    .map(|model| {
+                        let material_name = model
+                            .mesh
+                            .material_id
+                            .map(|material_id| materials[material_id].name.clone());
+                        (MeshData::from_obj(&model.mesh), material_name)
+                    })
+                    .collect();
+                (meshes, material_libraries.into_inner())
+            }
+        };
+
+        for (mesh, _) in &mut meshes {
+            if self.flip_normals {
+                mesh.flip_winding();
+            }
+            if self.smooth {
+                mesh.smooth_normals(self.crease_angle);
+            }
+        }
+
+        Ok(CachedMesh {
+            meshes,
+            material_libraries,
+        })
+    }
+
+    fn load_mesh_data(
+        &self,
+        path: &Path,
+        cache: &mut AcceleratorCache,
+    ) -> Result<(Vec<MeshData>, Vec<Material>), String> {
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        let cache_key = if cache.is_enabled() {
+            Some(AcceleratorCache::file_key(
+                "mesh",
+                &bytes,
+                &[
+                    f64::from(u8::from(self.smooth)),
+                    self.crease_angle,
+                    f64::from(u8::from(self.flip_normals)),
+                ],
+            ))
+        } else {
+            None
+        };
+
+        let cached_mesh: Option<CachedMesh> = cache_key.and_then(|cache_key| cache.get(cache_key));
+        let mesh = if let Some(mesh) = cached_mesh {
+            mesh
+        } else {
+            let mesh = self.parse_mesh_file(path, &bytes)?;
+            if let Some(cache_key) = cache_key {
+                cache.insert(cache_key, &mesh);
+            }
+            mesh
+        };
+
+        // Material indices count through every library in the order they're included
+        let library_base = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut mtl_materials = Vec::new();
+        let mut defined_keys = Vec::new();
+        for library in &mesh.material_libraries {
+            let (mut materials, mut keys) = load_mtl_library(&library_base.join(library))
+                .map_err(|err| format!("{} in \"{}\"", err, library.display()))?;
+            mtl_materials.append(&mut materials);
+            defined_keys.append(&mut keys);
+        }
+
+        let meshes = mesh
+            .meshes
+            .into_iter()
+            .map(|(mut mesh, material_name)| {
+                mesh.material_id = material_name.and_then(|name| {
+                    mtl_materials
+                        .iter()
+                        .position(|material| material.name == name)
+                });
+                mesh
+            })
+            .collect();
+
+        Ok((
+            meshes,
+            self.convert_mtl_materials(&mtl_materials, &defined_keys),
+        ))
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the mesh file fails to load.
+    pub fn load_assets(
+        &mut self,
+        asset_base: &Path,
+        textures: &mut HashMap<String, Texture>,
+        cache: &mut AcceleratorCache,
+    ) {
+        // Meshes built from already loaded vertex data have no file to load
+        if !self.meshes.is_empty() {
+            return;
+        }
+
+        let path = asset_base.join(&self.file);
+        let (meshes, materials) = self.load_mesh_data(&path, cache).unwrap_or_else(|err| {
+            panic!(
                 "failed to load object at path \"{}\": {}",
-                &asset_base.join(&self.file).display(),
+                path.display(),
                 err
-            ))
+            )
         });
 
-        let mut children: Vec<Object3D> = Vec::new();
-        for model in &models {
-            let mesh = &model.mesh;
+        for mesh in meshes {
+            let material = mesh
+                .material_id
+                .and_then(|material_id| materials.get(material_id))
+                .unwrap_or(&self.material)
+                .clone();
+            material.load_textures(asset_base, textures);
 
-            let positions: Vec<Point3<f64>> = mesh
-                .positions
-                .chunks_exact(3)
-                .map(|position| {
-                    Point3::new(
-                        f64::from(position[0]),
-                        f64::from(position[1]),
-                        f64::from(position[2]),
-                    )
-                })
-                .collect();
-            let normals: Vec<Unit<Vector3<f64>>> = mesh
-                .normals
-                .chunks_exact(3)
-                .map(|normal| {
-                    Unit::new_normalize(Vector3::new(
-                        f64::from(normal[0]),
-                        f64::from(normal[1]),
-                        f64::from(normal[2]),
-                    ))
-                })
-                .collect();
-            let texcoords: Vec<Vector2<f64>> = mesh
-                .texcoords
-                .chunks_exact(2)
-                .map(|texcoords| Vector2::new(f64::from(texcoords[0]), f64::from(texcoords[1])))
-                .collect();
-
-            for face_indices in mesh.indices.chunks_exact(3) {
-                let (idx0, idx1, idx2) = (
-                    face_indices[0] as usize,
-                    face_indices[1] as usize,
-                    face_indices[2] as usize,
-                );
+            self.meshes.push((mesh, material));
+        }
+    }
+}
 
-                let p0 = positions[idx0];
-                let p1 = positions[idx1];
-                let p2 = positions[idx2];
-
-                let normals = if mesh.normals.is_empty() {
-                    [Triangle::compute_normal([p0, p1, p2]); 3]
-                } else {
-                    let n0 = normals[idx0];
-                    let n1 = normals[idx1];
-                    let n2 = normals[idx2];
-
-                    [n0, n1, n2]
-                };
-
-                let texcoords = if mesh.texcoords.is_empty() {
-                    [Vector2::zero(); 3]
-                } else {
-                    let uv0 = texcoords[idx0];
-                    let uv1 = texcoords[idx1];
-                    let uv2 = texcoords[idx2];
-
-                    [uv0, uv1, uv2]
-                };
-
-                let face = Triangle::new(
-                    [p0, p1, p2],
-                    normals,
-                    texcoords,
-                    Transform::default(),
-                    self.material.clone(),
-                );
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufReader;
+
+    fn convert_mtl(source: &str, base: &Material) -> Material {
+        let (materials, _) = tobj::load_mtl_buf(&mut BufReader::new(source.as_bytes()))
+            .expect("failed to parse mtl");
+        MtlMaterial {
+            mtl: &materials[0],
+            defined_keys: &defined_mtl_keys(source)[0],
+            texture_base: Path::new(""),
+        }
+        .into_material(base)
+    }
+
+    #[test]
+    fn it_parses_mtl_texture_options() {
+        let (texture, bump_multiplier) =
+            parse_texture_map(Path::new("models"), "-bm 0.5 -s 2 3 1 -o 0.25 0 0 bump.png")
+                .unwrap();
+
+        assert_eq!(Path::new(&texture.path), Path::new("models/bump.png"));
+        assert_eq!(texture.uv_scale, Vector2::new(2.0, 3.0));
+        assert_eq!(texture.uv_offset, Vector2::new(0.25, 0.0));
+        assert_eq!(bump_multiplier, Some(0.5));
+    }
 
-                children.push(Object3D::Triangle(Box::new(face)));
+    #[test]
+    fn it_converts_mtl_materials() {
+        let material = convert_mtl(
+            "newmtl Red\nKd 1 0 0\nKs 0.5 0.5 0.5\nNs 50\nKe 0 0 0.5\nmap_Kd red.png\n",
+            &Material::default(),
+        );
+
+        match material {
+            Material::Phong(material) => {
+                assert_eq!(material.color, Vector3::new(1.0, 0.0, 0.0));
+                assert_eq!(material.specular, Vector3::repeat(0.5));
+                assert_eq!(material.shininess, 50.0);
+                assert_eq!(material.emissive, Vector3::new(0.0, 0.0, 0.5));
+                assert_eq!(material.texture, Some(TextureMap::new("red.png")));
+            }
+            _ => panic!("expected a phong material"),
+        }
+
+        let material = convert_mtl(
+            "newmtl Gold\nKd 1 0.8 0.3\nd 0.5\nNi 1.5\nPr 0.2\nPm 1\n",
+            &Material::default(),
+        );
+
+        match material {
+            Material::Physical(material) => {
+                assert_eq!(material.opacity, 0.5);
+                assert_eq!(material.refractive_index, 1.5);
+                assert_eq!(material.roughness, 0.2);
+                assert_eq!(material.metalness, 1.0);
+            }
+            _ => panic!("expected a physical material"),
+        }
+    }
+
+    #[test]
+    fn it_keeps_base_values_the_mtl_file_does_not_define() {
+        let base = Material::Physical(PhysicalMaterial {
+            color: Vector3::new(0.2, 0.4, 0.6),
+            refractive_index: 1.5,
+            roughness: 0.3,
+            ..PhysicalMaterial::default()
+        });
+        let material = convert_mtl("newmtl Glow\nKe 1 1 1\nmap_Ns rough.png\n", &base);
+
+        match material {
+            Material::Physical(material) => {
+                assert_eq!(material.color, Vector3::new(0.2, 0.4, 0.6));
+                assert_eq!(material.opacity, 1.0);
+                assert_eq!(material.refractive_index, 1.5);
+                assert_eq!(material.roughness, 0.3);
+                assert_eq!(material.emissive, Vector3::repeat(1.0));
+                assert_eq!(
+                    material.shininess_texture,
+                    Some(TextureMap::new("rough.png"))
+                );
             }
+            _ => panic!("expected a physical material"),
         }
 
-        self.children = Some(children);
+        match convert_mtl("newmtl Plain\nillum 2\n", &Material::default()) {
+            Material::Phong(material) => assert_eq!(material, PhongMaterial::default()),
+            _ => panic!("expected a phong material"),
+        }
+    }
+
+    // Two faces folded 90 degrees along the edge between (0, 0, 0) and (1, 0, 0)
+    fn folded_mesh() -> MeshData {
+        MeshData {
+            positions: vec![
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(0.0, 1.0, 0.0),
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(0.0, 0.0, 1.0),
+            ],
+            indices: vec![0, 1, 2, 3, 5, 4],
+            ..MeshData::default()
+        }
+    }
+
+    #[test]
+    fn it_smooths_normals_across_shared_positions() {
+        let mut mesh = folded_mesh();
+        mesh.smooth_normals(180.0);
+
+        let expected = Vector3::new(0.0, 1.0, 1.0).normalize();
+        assert_eq!(mesh.indices.len(), 6);
+        assert_eq!(mesh.normals.len(), mesh.positions.len());
+        assert!((mesh.normals[mesh.indices[0]].into_inner() - expected).norm() < 1e-10);
+        assert!((mesh.normals[mesh.indices[3]].into_inner() - expected).norm() < 1e-10);
+        assert_eq!(mesh.normals[mesh.indices[2]], Vector3::z_axis());
+    }
+
+    #[test]
+    fn it_keeps_creases_sharp() {
+        let mut mesh = folded_mesh();
+        mesh.smooth_normals(60.0);
+
+        assert_eq!(mesh.normals[mesh.indices[0]], Vector3::z_axis());
+        assert_eq!(mesh.normals[mesh.indices[1]], Vector3::z_axis());
+        assert_eq!(mesh.normals[mesh.indices[3]], Vector3::y_axis());
+        assert_eq!(mesh.normals[mesh.indices[4]], Vector3::y_axis());
+    }
+
+    #[test]
+    fn it_flips_winding() {
+        let mut mesh = folded_mesh();
+        mesh.normals = vec![Vector3::z_axis(); 6];
+        mesh.flip_winding();
+
+        assert_eq!(mesh.indices, vec![0, 2, 1, 3, 4, 5]);
+        assert_eq!(mesh.normals[0], -Vector3::z_axis());
+    }
+
+    fn load_cached_mesh(directory: &Path, cache_path: &Path) -> (Mesh, AcceleratorCache) {
+        let mut cache = AcceleratorCache::load(cache_path);
+        let mut mesh = Mesh::new(
+            "triangle.obj".to_string(),
+            Transform::default(),
+            Material::default(),
+        );
+        mesh.use_mtl = true;
+        mesh.load_assets(directory, &mut HashMap::new(), &mut cache);
+        (mesh, cache)
+    }
+
+    fn get_mesh_color(mesh: &Mesh) -> Vector3<f64> {
+        match &mesh.meshes[0].1 {
+            Material::Phong(material) => material.color,
+            _ => panic!("expected a phong material"),
+        }
+    }
+
+    #[test]
+    fn it_caches_loaded_meshes_by_file_contents() {
+        let directory =
+            std::env::temp_dir().join(format!("raytrace_mesh_cache_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let cache_path = directory.join("cache.bin");
+        let obj = "mtllib triangle.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl Red\nf 1 2 3\n";
+        fs::write(directory.join("triangle.obj"), obj).unwrap();
+        fs::write(directory.join("triangle.mtl"), "newmtl Red\nKd 1 0 0\n").unwrap();
+
+        let (_, cache) = load_cached_mesh(&directory, &cache_path);
+        assert_eq!(cache.get_num_misses(), 1);
+        cache.save().unwrap();
+
+        // Editing only the materials still reuses the cached geometry
+        fs::write(directory.join("triangle.mtl"), "newmtl Red\nKd 0 1 0\n").unwrap();
+        let (mesh, cache) = load_cached_mesh(&directory, &cache_path);
+        assert_eq!(cache.get_num_hits(), 1);
+        assert_eq!(mesh.meshes[0].0.positions.len(), 3);
+        assert_eq!(get_mesh_color(&mesh), Vector3::new(0.0, 1.0, 0.0));
+        cache.save().unwrap();
+
+        fs::write(
+            directory.join("triangle.obj"),
+            obj.replace("v 0 1 0", "v 0 2 0"),
+        )
+        .unwrap();
+        let (mesh, cache) = load_cached_mesh(&directory, &cache_path);
+        assert_eq!(cache.get_num_hits(), 0);
+        assert_eq!(cache.get_num_misses(), 1);
+        assert_eq!(mesh.meshes[0].0.positions[2], Point3::new(0.0, 2.0, 0.0));
+
+        fs::remove_dir_all(&directory).unwrap();
     }
 }
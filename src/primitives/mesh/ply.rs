@@ -0,0 +1,381 @@
+use super::MeshData;
+use nalgebra::{Point3, Unit, Vector2, Vector3};
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ScalarType {
+    Char,
+    UChar,
+    Short,
+    UShort,
+    Int,
+    UInt,
+    Float,
+    Double,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "char" | "int8" => Ok(Self::Char),
+            "uchar" | "uint8" => Ok(Self::UChar),
+            "short" | "int16" => Ok(Self::Short),
+            "ushort" | "uint16" => Ok(Self::UShort),
+            "int" | "int32" => Ok(Self::Int),
+            "uint" | "uint32" => Ok(Self::UInt),
+            "float" | "float32" => Ok(Self::Float),
+            "double" | "float64" => Ok(Self::Double),
+            _ => Err(invalid_data(format!(
+                "unknown PLY property type \"{}\"",
+                name
+            ))),
+        }
+    }
+
+    // Scale factor mapping the type's range onto [0, 1], used for colors
+    fn normalizer(self) -> f64 {
+        match self {
+            Self::Char => f64::from(i8::MAX),
+            Self::UChar => f64::from(u8::MAX),
+            Self::Short => f64::from(i16::MAX),
+            Self::UShort => f64::from(u16::MAX),
+            Self::Int => f64::from(i32::MAX),
+            Self::UInt => f64::from(u32::MAX),
+            Self::Float | Self::Double => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PropertyType {
+    Scalar(ScalarType),
+    List(ScalarType, ScalarType),
+}
+
+#[derive(Debug)]
+struct Property {
+    name: String,
+    property_type: PropertyType,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn property_index(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|property| names.contains(&property.name.as_str()))
+    }
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+}
+
+fn parse_header(lines: &mut dyn Iterator<Item = &str>) -> Result<Header> {
+    if lines.next().map(str::trim) != Some("ply") {
+        return Err(invalid_data("missing PLY magic number".to_owned()));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens[..] {
+            ["format", name, _] => {
+                format = Some(match name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(invalid_data(format!("unknown PLY format \"{}\"", name))),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_owned(),
+                count: count
+                    .parse()
+                    .map_err(|_| invalid_data(format!("invalid element count \"{}\"", count)))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_type, item_type, name] => {
+                let element = elements
+                    .last_mut()
+                    .ok_or_else(|| invalid_data("property defined before element".to_owned()))?;
+                element.properties.push(Property {
+                    name: name.to_owned(),
+                    property_type: PropertyType::List(
+                        ScalarType::parse(count_type)?,
+                        ScalarType::parse(item_type)?,
+                    ),
+                });
+            }
+            ["property", scalar_type, name] => {
+                let element = elements
+                    .last_mut()
+                    .ok_or_else(|| invalid_data("property defined before element".to_owned()))?;
+                element.properties.push(Property {
+                    name: name.to_owned(),
+                    property_type: PropertyType::Scalar(ScalarType::parse(scalar_type)?),
+                });
+            }
+            ["end_header"] => {
+                let format = format.ok_or_else(|| invalid_data("missing PLY format".to_owned()))?;
+                return Ok(Header { format, elements });
+            }
+            _ => {}
+        }
+    }
+
+    Err(invalid_data("missing end_header in PLY file".to_owned()))
+}
+
+// Sequential reader over the body of a PLY file, yielding every value as an f64
+struct BodyReader<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    offset: usize,
+    tokens: std::str::SplitWhitespace<'a>,
+}
+
+impl<'a> BodyReader<'a> {
+    fn new(format: Format, bytes: &'a [u8]) -> Result<Self> {
+        let text = if format == Format::Ascii {
+            std::str::from_utf8(bytes)
+                .map_err(|err| invalid_data(format!("invalid ASCII PLY body: {}", err)))?
+        } else {
+            ""
+        };
+
+        Ok(Self {
+            format,
+            bytes,
+            offset: 0,
+            tokens: text.split_whitespace(),
+        })
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + N)
+            .ok_or_else(|| invalid_data("unexpected end of PLY file".to_owned()))?;
+        self.offset += N;
+
+        let mut bytes: [u8; N] = bytes.try_into().expect("slice has the requested length");
+        if self.format == Format::BinaryBigEndian {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+
+    fn read(&mut self, scalar_type: ScalarType) -> Result<f64> {
+        if self.format == Format::Ascii {
+            let token = self
+                .tokens
+                .next()
+                .ok_or_else(|| invalid_data("unexpected end of PLY file".to_owned()))?;
+            return token
+                .parse()
+                .map_err(|_| invalid_data(format!("invalid PLY value \"{}\"", token)));
+        }
+
+        Ok(match scalar_type {
+            ScalarType::Char => f64::from(i8::from_le_bytes(self.read_bytes()?)),
+            ScalarType::UChar => f64::from(u8::from_le_bytes(self.read_bytes()?)),
+            ScalarType::Short => f64::from(i16::from_le_bytes(self.read_bytes()?)),
+            ScalarType::UShort => f64::from(u16::from_le_bytes(self.read_bytes()?)),
+            ScalarType::Int => f64::from(i32::from_le_bytes(self.read_bytes()?)),
+            ScalarType::UInt => f64::from(u32::from_le_bytes(self.read_bytes()?)),
+            ScalarType::Float => f64::from(f32::from_le_bytes(self.read_bytes()?)),
+            ScalarType::Double => f64::from_le_bytes(self.read_bytes()?),
+        })
+    }
+
+    fn read_property(&mut self, property: &Property, values: &mut Vec<f64>) -> Result<()> {
+        values.clear();
+        match property.property_type {
+            PropertyType::Scalar(scalar_type) => values.push(self.read(scalar_type)?),
+            PropertyType::List(count_type, item_type) => {
+                let count = self.read(count_type)? as usize;
+                for _ in 0..count {
+                    values.push(self.read(item_type)?);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn find_body(bytes: &[u8]) -> Result<usize> {
+    let marker = b"end_header";
+    let position = bytes
+        .windows(marker.len())
+        .position(|window| window == marker)
+        .ok_or_else(|| invalid_data("missing end_header in PLY file".to_owned()))?;
+
+    // The header ends at the first newline after end_header (either \n or \r\n)
+    bytes[position..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map(|newline| position + newline + 1)
+        .ok_or_else(|| invalid_data("missing end_header in PLY file".to_owned()))
+}
+
+pub fn parse_ply(bytes: &[u8]) -> Result<MeshData> {
+    let body_start = find_body(bytes)?;
+    let header_text = String::from_utf8_lossy(&bytes[..body_start]);
+    let header = parse_header(&mut header_text.lines())?;
+
+    let mut reader = BodyReader::new(header.format, &bytes[body_start..])?;
+    let mut mesh = MeshData::default();
+    let mut values = Vec::new();
+
+    for element in &header.elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let position = [
+                    element.property_index(&["x"]),
+                    element.property_index(&["y"]),
+                    element.property_index(&["z"]),
+                ];
+                let normal = [
+                    element.property_index(&["nx"]),
+                    element.property_index(&["ny"]),
+                    element.property_index(&["nz"]),
+                ];
+                let texcoords = [
+                    element.property_index(&["u", "s", "texture_u", "texture_s"]),
+                    element.property_index(&["v", "t", "texture_v", "texture_t"]),
+                ];
+                let color = [
+                    element.property_index(&["red", "r", "diffuse_red"]),
+                    element.property_index(&["green", "g", "diffuse_green"]),
+                    element.property_index(&["blue", "b", "diffuse_blue"]),
+                ];
+
+                let mut vertex = vec![0.0; element.properties.len()];
+                for _ in 0..element.count {
+                    for (value, property) in vertex.iter_mut().zip(&element.properties) {
+                        reader.read_property(property, &mut values)?;
+                        *value = values.first().copied().unwrap_or_default();
+                    }
+
+                    if let [Some(x), Some(y), Some(z)] = position {
+                        mesh.positions
+                            .push(Point3::new(vertex[x], vertex[y], vertex[z]));
+                    }
+                    if let [Some(x), Some(y), Some(z)] = normal {
+                        mesh.normals.push(Unit::new_normalize(Vector3::new(
+                            vertex[x], vertex[y], vertex[z],
+                        )));
+                    }
+                    if let [Some(u), Some(v)] = texcoords {
+                        mesh.texcoords.push(Vector2::new(vertex[u], vertex[v]));
+                    }
+                    if let [Some(r), Some(g), Some(b)] = color {
+                        let color = Vector3::new(vertex[r], vertex[g], vertex[b]);
+                        let normalizer = match element.properties[r].property_type {
+                            PropertyType::Scalar(scalar_type) => scalar_type.normalizer(),
+                            PropertyType::List(..) => 1.0,
+                        };
+                        mesh.colors.push(color / normalizer);
+                    }
+                }
+            }
+            "face" => {
+                let vertex_indices = element
+                    .property_index(&["vertex_indices", "vertex_index"])
+                    .ok_or_else(|| invalid_data("PLY faces have no vertex indices".to_owned()))?;
+
+                for _ in 0..element.count {
+                    for (index, property) in element.properties.iter().enumerate() {
+                        reader.read_property(property, &mut values)?;
+                        if index != vertex_indices {
+                            continue;
+                        }
+
+                        // Triangulate polygons as a fan around their first vertex
+                        let face: Vec<usize> = values.iter().map(|&index| index as usize).collect();
+                        for edge in face.get(1..).unwrap_or_default().windows(2) {
+                            mesh.indices.extend_from_slice(&[face[0], edge[0], edge[1]]);
+                        }
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        reader.read_property(property, &mut values)?;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(&index) = mesh
+        .indices
+        .iter()
+        .find(|&&index| index >= mesh.positions.len())
+    {
+        return Err(invalid_data(format!(
+            "PLY face index {} is out of range",
+            index
+        )));
+    }
+
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_ascii_ply() {
+        let ply = b"ply\nformat ascii 1.0\ncomment quad\nelement vertex 4\n\
+                    property float x\nproperty float y\nproperty float z\n\
+                    property uchar red\nproperty uchar green\nproperty uchar blue\n\
+                    element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+                    0 0 0 255 0 0\n1 0 0 0 255 0\n1 1 0 0 0 255\n0 1 0 255 255 255\n\
+                    4 0 1 2 3\n";
+        let mesh = parse_ply(ply).unwrap();
+
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.positions[2], Point3::new(1.0, 1.0, 0.0));
+        assert!(mesh.normals.is_empty());
+        assert_eq!(mesh.colors[1], Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn it_reads_binary_values() {
+        let bytes = [0x00, 0x00, 0x80, 0x3f, 0x01, 0x02];
+
+        let mut reader = BodyReader::new(Format::BinaryLittleEndian, &bytes).unwrap();
+        assert_eq!(reader.read(ScalarType::Float).unwrap(), 1.0);
+        assert_eq!(reader.read(ScalarType::UShort).unwrap(), 513.0);
+
+        let bytes = [0x3f, 0x80, 0x00, 0x00];
+        let mut reader = BodyReader::new(Format::BinaryBigEndian, &bytes).unwrap();
+        assert_eq!(reader.read(ScalarType::Float).unwrap(), 1.0);
+    }
+}
@@ -0,0 +1,51 @@
+use super::MeshData;
+use nalgebra::Point3;
+use std::io::{Cursor, Result};
+
+// STL stores every triangle's corners separately, and only has face normals, which are frequently
+// wrong in exported files. The reader welds corners with identical positions into shared vertices,
+// so normals are left to be computed from the faces around each of them.
+pub fn parse_stl(bytes: &[u8]) -> Result<MeshData> {
+    let stl = stl_io::read_stl(&mut Cursor::new(bytes))?;
+
+    Ok(MeshData {
+        positions: stl
+            .vertices
+            .iter()
+            .map(|vertex| {
+                Point3::new(
+                    f64::from(vertex[0]),
+                    f64::from(vertex[1]),
+                    f64::from(vertex[2]),
+                )
+            })
+            .collect(),
+        indices: stl.faces.iter().flat_map(|face| face.vertices).collect(),
+        ..MeshData::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_welds_shared_stl_vertices() {
+        let stl = b"solid quad\n\
+                   facet normal 0 0 1\nouter loop\n\
+                   vertex 0 0 0\nvertex 1 0 0\nvertex 1 1 0\n\
+                   endloop\nendfacet\n\
+                   facet normal 0 0 1\nouter loop\n\
+                   vertex 0 0 0\nvertex 1 1 0\nvertex 0 1 0\n\
+                   endloop\nendfacet\n\
+                   endsolid quad\n";
+        let mesh = parse_stl(stl).unwrap();
+
+        assert_eq!(mesh.positions.len(), 4);
+        assert!(mesh.normals.is_empty());
+        assert_eq!(mesh.indices.len(), 6);
+        assert_eq!(mesh.indices[0], mesh.indices[3]);
+        assert_eq!(mesh.indices[2], mesh.indices[4]);
+        assert_eq!(mesh.positions[mesh.indices[5]], Point3::new(0.0, 1.0, 0.0));
+    }
+}
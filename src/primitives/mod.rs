@@ -1,25 +1,38 @@
 mod cube;
+mod gltf;
 mod group;
+mod instance;
 mod mesh;
 mod plane;
 mod sphere;
 mod triangle;
+mod triangle_mesh;
+mod volume;
 
-use crate::core::{Material, ObjectWithBounds, Texture, Transform, Transformed};
+use crate::core::{
+    Accelerator, AcceleratorCache, Material, ObjectWithBounds, Texture, Transform, Transformed,
+};
+use crate::lights::Light;
 use crate::ray_intersection::{IntermediateData, Intersectable};
+use crate::render::Camera;
 use nalgebra::{Point3, Unit, Vector2, Vector3};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::marker::{Send, Sync};
 use std::path::Path;
+use std::sync::Arc;
 
+pub use self::gltf::Gltf;
 pub use cube::{Cube, RaytracingCube};
 pub use group::Group;
+pub use instance::{Instance, RaytracingInstance};
 pub use mesh::Mesh;
 pub use plane::{Plane, RaytracingPlane};
 pub use sphere::{RaytracingSphere, Sphere};
 pub use triangle::{RaytracingTriangle, Triangle};
+pub use triangle_mesh::{RaytracingMeshTriangle, TriangleMesh};
+pub use volume::Volume;
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields, tag = "type", rename_all = "lowercase")]
@@ -29,7 +42,33 @@ pub enum Object3D {
     Sphere(Box<Sphere>),
     Triangle(Box<Triangle>),
     Mesh(Box<Mesh>),
+    Gltf(Box<Gltf>),
+    Instance(Box<Instance>),
     Group(Box<Group>),
+    Volume(Box<Volume>),
+}
+
+// Lights and the camera imported from files anywhere in the scene
+#[derive(Debug)]
+pub struct Imports {
+    // Width over height of the image imported cameras are fit to
+    aspect: f64,
+    // Prototypes whose imports are being collected, which stops instances of a prototype within
+    // itself from being followed forever
+    prototype_stack: Vec<String>,
+    pub lights: Vec<Light>,
+    pub camera: Option<Camera>,
+}
+
+impl Imports {
+    pub fn new(aspect: f64) -> Self {
+        Self {
+            aspect,
+            prototype_stack: Vec::new(),
+            lights: Vec::new(),
+            camera: None,
+        }
+    }
 }
 
 impl Object3D {
@@ -37,9 +76,13 @@ impl Object3D {
         object: &mut Object3D,
         asset_base: &Path,
         textures: &mut HashMap<String, Texture>,
+        cache: &mut AcceleratorCache,
     ) {
-        if let Object3D::Mesh(mesh) = object {
-            mesh.load_assets(asset_base);
+        match object {
+            Object3D::Mesh(mesh) => mesh.load_assets(asset_base, textures, cache),
+            Object3D::Gltf(gltf) => gltf.load_assets(asset_base, textures),
+            Object3D::Volume(volume) => volume.load_assets(asset_base),
+            _ => {}
         }
 
         let material = match object {
@@ -48,7 +91,9 @@ impl Object3D {
             Object3D::Sphere(sphere) => Some(&sphere.material),
             Object3D::Triangle(triangle) => Some(&triangle.material),
             Object3D::Mesh(mesh) => Some(&mesh.material),
-            Object3D::Group(_) => None,
+            Object3D::Gltf(gltf) => gltf.material.as_ref(),
+            Object3D::Instance(instance) => instance.material.as_ref(),
+            Object3D::Group(_) | Object3D::Volume(_) => None,
         };
         if let Some(material) = material {
             material.load_textures(asset_base, textures);
@@ -56,7 +101,7 @@ impl Object3D {
 
         if let Some(children) = object.get_children_mut() {
             for child in children {
-                Object3D::load_assets(child, asset_base, textures);
+                Object3D::load_assets(child, asset_base, textures, cache);
             }
         }
     }
@@ -68,7 +113,38 @@ impl Object3D {
             Object3D::Plane(plane) => plane.add_child(object),
             Object3D::Sphere(sphere) => sphere.add_child(object),
             Object3D::Mesh(mesh) => mesh.add_child(object),
+            Object3D::Gltf(gltf) => gltf.add_child(object),
+            Object3D::Instance(instance) => instance.add_child(object),
             Object3D::Group(group) => group.add_child(object),
+            Object3D::Volume(volume) => volume.add_child(object),
+        }
+    }
+
+    fn get_transform(&self) -> &Transform {
+        match self {
+            Object3D::Cube(cube) => cube.get_transform(),
+            Object3D::Triangle(triangle) => triangle.get_transform(),
+            Object3D::Plane(plane) => plane.get_transform(),
+            Object3D::Sphere(sphere) => sphere.get_transform(),
+            Object3D::Mesh(mesh) => mesh.get_transform(),
+            Object3D::Gltf(gltf) => gltf.get_transform(),
+            Object3D::Instance(instance) => instance.get_transform(),
+            Object3D::Group(group) => group.get_transform(),
+            Object3D::Volume(volume) => volume.get_transform(),
+        }
+    }
+
+    fn get_children(&self) -> Option<&Vec<Object3D>> {
+        match self {
+            Object3D::Cube(cube) => cube.children.as_ref(),
+            Object3D::Triangle(triangle) => triangle.children.as_ref(),
+            Object3D::Plane(plane) => plane.children.as_ref(),
+            Object3D::Sphere(sphere) => sphere.children.as_ref(),
+            Object3D::Mesh(mesh) => mesh.children.as_ref(),
+            Object3D::Gltf(gltf) => gltf.children.as_ref(),
+            Object3D::Instance(instance) => instance.children.as_ref(),
+            Object3D::Group(group) => Some(&group.children),
+            Object3D::Volume(volume) => volume.children.as_ref(),
         }
     }
 
@@ -79,7 +155,65 @@ impl Object3D {
             Object3D::Plane(plane) => plane.children.as_mut(),
             Object3D::Sphere(sphere) => sphere.children.as_mut(),
             Object3D::Mesh(mesh) => mesh.children.as_mut(),
+            Object3D::Gltf(gltf) => gltf.children.as_mut(),
+            Object3D::Instance(instance) => instance.children.as_mut(),
             Object3D::Group(group) => Some(&mut group.children),
+            Object3D::Volume(volume) => volume.children.as_mut(),
+        }
+    }
+
+    // Gives every instance in the object hierarchy the accelerated tree of its prototype
+    pub fn resolve_instances(
+        &mut self,
+        build_prototype: &mut dyn FnMut(&str) -> Arc<dyn Accelerator>,
+    ) {
+        if let Object3D::Instance(instance) = self {
+            let prototype_tree = build_prototype(instance.get_prototype());
+            instance.set_prototype_tree(prototype_tree);
+        }
+
+        if let Some(children) = self.get_children_mut() {
+            for child in children {
+                child.resolve_instances(build_prototype);
+            }
+        }
+    }
+
+    // Collects the lights and camera imported by files in the object hierarchy, placed by the
+    // transforms of every object above them. Instances import whatever their prototype does.
+    pub fn collect_imports(
+        &self,
+        transform: &Transform,
+        prototypes: &HashMap<String, Object3D>,
+        imports: &mut Imports,
+    ) {
+        if let Object3D::Gltf(gltf) = self {
+            imports.lights.extend(gltf.get_lights(transform));
+            if let Some(camera) = gltf.get_camera(transform, imports.aspect) {
+                imports.camera = Some(camera);
+            }
+        }
+
+        let transform = transform * self.get_transform();
+        if let Object3D::Instance(instance) = self {
+            let name = instance.get_prototype();
+            if let Some(prototype) = prototypes.get(name) {
+                if !imports
+                    .prototype_stack
+                    .iter()
+                    .any(|visited| visited == name)
+                {
+                    imports.prototype_stack.push(name.to_owned());
+                    prototype.collect_imports(&transform, prototypes, imports);
+                    imports.prototype_stack.pop();
+                }
+            }
+        }
+
+        if let Some(children) = self.get_children() {
+            for child in children {
+                child.collect_imports(&transform, prototypes, imports);
+            }
         }
     }
 
@@ -90,7 +224,10 @@ impl Object3D {
             Object3D::Plane(plane) => plane.flatten_to_world(transform),
             Object3D::Sphere(sphere) => sphere.flatten_to_world(transform),
             Object3D::Mesh(mesh) => mesh.flatten_to_world(transform),
+            Object3D::Gltf(gltf) => gltf.flatten_to_world(transform),
+            Object3D::Instance(instance) => instance.flatten_to_world(transform),
             Object3D::Group(group) => group.flatten_to_world(transform),
+            Object3D::Volume(volume) => volume.flatten_to_world(transform),
         }
     }
 }
@@ -113,6 +250,35 @@ pub trait Primitive: Transformed {
         object_normal: &Unit<Vector3<f64>>,
         intermediate: IntermediateData,
     ) -> Vector2<f64>;
+    // Partial derivatives of the surface position with respect to uv (dp/du, dp/dv)
+    fn surface_tangents(
+        &self,
+        object_hit_point: &Point3<f64>,
+        object_normal: &Unit<Vector3<f64>>,
+        intermediate: IntermediateData,
+    ) -> (Vector3<f64>, Vector3<f64>);
+    // Interpolated per-vertex color, multiplied into the material color
+    fn vertex_color(&self, _intermediate: IntermediateData) -> Option<Vector3<f64>> {
+        None
+    }
+    // Normal of the actual surface, which rays leaving it are offset along. Differs from the
+    // surface normal where normals are interpolated.
+    fn geometric_normal(
+        &self,
+        object_hit_point: &Point3<f64>,
+        intermediate: IntermediateData,
+    ) -> Unit<Vector3<f64>> {
+        self.surface_normal(object_hit_point, intermediate)
+    }
+    // Hit point recomputed from the surface itself along with a bound on the error of each
+    // coordinate, for primitives where that's more accurate than stepping along the ray
+    fn refine_hit_point(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _intermediate: IntermediateData,
+    ) -> Option<(Point3<f64>, Vector3<f64>)> {
+        None
+    }
 }
 
 pub trait RaytracingObject:
@@ -121,6 +287,8 @@ pub trait RaytracingObject:
 }
 
 impl RaytracingObject for RaytracingCube {}
+impl RaytracingObject for RaytracingInstance {}
 impl RaytracingObject for RaytracingPlane {}
 impl RaytracingObject for RaytracingSphere {}
 impl RaytracingObject for RaytracingTriangle {}
+impl RaytracingObject for RaytracingMeshTriangle {}
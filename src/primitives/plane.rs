@@ -27,6 +27,12 @@ impl Default for Plane {
     }
 }
 
+impl Transformed for Plane {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
 impl Plane {
     pub fn new(normal: Unit<Vector3<f64>>, transform: Transform, material: Material) -> Self {
         Self {
@@ -140,4 +146,15 @@ impl Primitive for RaytracingPlane {
 
         Vector2::new(p.x, p.z)
     }
+
+    fn surface_tangents(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        object_normal: &Unit<Vector3<f64>>,
+        _intermediate: IntermediateData,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        let rotation = Rotation3::rotation_between(&Vector3::y_axis(), object_normal).unwrap();
+
+        (rotation * Vector3::x(), rotation * Vector3::z())
+    }
 }
@@ -6,7 +6,7 @@ use crate::ray_intersection::{IntermediateData, Intersectable, Intersection, Ray
 use crate::utils;
 use nalgebra::{Point3, Unit, Vector2, Vector3};
 use serde::Deserialize;
-use std::f64::consts::FRAC_1_PI;
+use std::f64::consts::{FRAC_1_PI, PI, TAU};
 
 #[derive(Debug, Deserialize)]
 #[serde(default, deny_unknown_fields)]
@@ -30,6 +30,12 @@ impl Default for Sphere {
     }
 }
 
+impl Transformed for Sphere {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
 impl Sphere {
     pub fn new(radius: f64, transform: Transform, material: Material) -> Self {
         Self {
@@ -161,4 +167,35 @@ impl Primitive for RaytracingSphere {
             hit_point.y.asin() * FRAC_1_PI + 0.5,
         )
     }
+
+    fn surface_tangents(
+        &self,
+        object_hit_point: &Point3<f64>,
+        object_normal: &Unit<Vector3<f64>>,
+        _intermediate: IntermediateData,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        let p = object_hit_point.coords;
+        let rho = (p.x * p.x + p.z * p.z).sqrt();
+
+        // The parametrization degenerates at the poles
+        if rho < 1e-10 {
+            return utils::orthonormal_basis(object_normal);
+        }
+
+        let dpdu = TAU * Vector3::new(p.z, 0.0, -p.x);
+        let dpdv = PI * Vector3::new(-p.y * p.x / rho, rho, -p.y * p.z / rho);
+
+        (dpdu, dpdv)
+    }
+
+    // Projects the hit point back onto the sphere
+    fn refine_hit_point(
+        &self,
+        object_hit_point: &Point3<f64>,
+        _intermediate: IntermediateData,
+    ) -> Option<(Point3<f64>, Vector3<f64>)> {
+        let hit_point = object_hit_point * (self.radius / object_hit_point.coords.magnitude());
+
+        Some((hit_point, utils::error_bound(5) * hit_point.coords.abs()))
+    }
 }
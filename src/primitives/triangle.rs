@@ -3,10 +3,162 @@ use crate::core::{
     BoundingVolume, Material, MaterialSide, ObjectWithBounds, Transform, Transformed,
 };
 use crate::ray_intersection::{IntermediateData, Intersectable, Intersection, Ray, RayType};
+use crate::utils;
 use nalgebra::{Point3, Unit, Vector2, Vector3};
 use num_traits::identities::Zero;
 use serde::Deserialize;
-use std::f64::EPSILON;
+
+// Watertight intersection of an object space ray with a triangle, returning the distance and
+// barycentric coordinates of the hit. The triangle is moved into a space where the ray starts at
+// the origin and points down +z, so the edge tests only depend on the vertices they share and
+// rays through a shared edge or vertex can't slip between neighboring triangles.
+pub(super) fn intersect_triangle(
+    positions: &[Point3<f64>; 3],
+    side: MaterialSide,
+    ray: &Ray,
+    max_distance: Option<f64>,
+) -> Option<(f64, IntermediateData)> {
+    let edge1 = positions[1] - positions[0];
+    let edge2 = positions[2] - positions[0];
+    let facing = -ray.direction.dot(&edge1.cross(&edge2));
+
+    if match (side, ray.ray_type) {
+        (MaterialSide::Both, _) | (_, RayType::Shadow) => false,
+        (MaterialSide::Front, _) => facing <= 0.0,
+        (MaterialSide::Back, _) => facing >= 0.0,
+    } {
+        return None;
+    }
+
+    // Permute the axes so that z is the largest component of the ray direction
+    let kz = ray.direction.iamax();
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+    let permute = |v: Vector3<f64>| Vector3::new(v[kx], v[ky], v[kz]);
+
+    let direction = permute(ray.direction);
+    let shear_x = -direction.x / direction.z;
+    let shear_y = -direction.y / direction.z;
+    let shear_z = 1.0 / direction.z;
+
+    let transform = |position: &Point3<f64>| {
+        let p = permute(position - ray.origin);
+        Vector3::new(p.x + shear_x * p.z, p.y + shear_y * p.z, p.z)
+    };
+    let (p0, p1, p2) = (
+        transform(&positions[0]),
+        transform(&positions[1]),
+        transform(&positions[2]),
+    );
+
+    // Twice the signed area of the triangle formed by the ray and each edge
+    let e0 = p1.x * p2.y - p1.y * p2.x;
+    let e1 = p2.x * p0.y - p2.y * p0.x;
+    let e2 = p0.x * p1.y - p0.y * p1.x;
+
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+        return None;
+    }
+
+    let det = e0 + e1 + e2;
+    if det == 0.0 {
+        return None;
+    }
+
+    // Distance scaled by the determinant, which avoids a division for rays which miss
+    let (z0, z1, z2) = (p0.z * shear_z, p1.z * shear_z, p2.z * shear_z);
+    let scaled_distance = e0 * z0 + e1 * z1 + e2 * z2;
+    let max_distance = max_distance.unwrap_or(f64::INFINITY);
+    if (det < 0.0 && (scaled_distance >= 0.0 || scaled_distance < max_distance * det))
+        || (det > 0.0 && (scaled_distance <= 0.0 || scaled_distance > max_distance * det))
+    {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let (b0, b1, b2) = (e0 * inv_det, e1 * inv_det, e2 * inv_det);
+    let distance = scaled_distance * inv_det;
+
+    // Reject hits so close to the origin that their distance is within its rounding error
+    let max_z = z0.abs().max(z1.abs()).max(z2.abs());
+    let max_x = p0.x.abs().max(p1.x.abs()).max(p2.x.abs());
+    let max_y = p0.y.abs().max(p1.y.abs()).max(p2.y.abs());
+    let delta_z = utils::error_bound(3) * max_z;
+    let delta_x = utils::error_bound(5) * (max_x + max_z);
+    let delta_y = utils::error_bound(5) * (max_y + max_z);
+    let max_e = e0.abs().max(e1.abs()).max(e2.abs());
+    let delta_e = 2.0 * (utils::error_bound(2) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+    let delta_distance = 3.0
+        * (utils::error_bound(3) * max_e * max_z + delta_e * max_z + delta_z * max_e)
+        * inv_det.abs();
+    if distance <= delta_distance {
+        return None;
+    }
+
+    Some((distance, IntermediateData::Barycentric(b1, b2, b0)))
+}
+
+// Hit point interpolated from the triangle's vertices, which is much more accurate than stepping
+// along the ray, along with a bound on its error
+pub(super) fn triangle_hit_point(
+    positions: &[Point3<f64>; 3],
+    intermediate: IntermediateData,
+) -> (Point3<f64>, Vector3<f64>) {
+    let (u, v, w) = match intermediate {
+        IntermediateData::Barycentric(u, v, w) => (u, v, w),
+        _ => unreachable!(),
+    };
+
+    let (c0, c1, c2) = (
+        w * positions[0].coords,
+        u * positions[1].coords,
+        v * positions[2].coords,
+    );
+    let error = utils::error_bound(7) * (c0.abs() + c1.abs() + c2.abs());
+
+    (Point3::from(c0 + c1 + c2), error)
+}
+
+pub(super) fn triangle_bounding_volume(
+    positions: &[Point3<f64>; 3],
+    transform: &Transform,
+) -> BoundingVolume {
+    let mut min = positions[0];
+    let mut max = min;
+    for position in positions[1..].iter() {
+        min.x = min.x.min(position.x);
+        min.y = min.y.min(position.y);
+        min.z = min.z.min(position.z);
+
+        max.x = max.x.max(position.x);
+        max.y = max.y.max(position.y);
+        max.z = max.z.max(position.z);
+    }
+
+    BoundingVolume::from_bounds_and_transform(min, max, transform)
+}
+
+pub(super) fn triangle_tangents(
+    positions: &[Point3<f64>; 3],
+    texcoords: &[Vector2<f64>; 3],
+    object_normal: &Unit<Vector3<f64>>,
+) -> (Vector3<f64>, Vector3<f64>) {
+    let edge1 = positions[1] - positions[0];
+    let edge2 = positions[2] - positions[0];
+    let duv1 = texcoords[1] - texcoords[0];
+    let duv2 = texcoords[2] - texcoords[0];
+
+    let det = duv1.x * duv2.y - duv1.y * duv2.x;
+    if det.abs() < f64::EPSILON {
+        return utils::orthonormal_basis(object_normal);
+    }
+
+    let inv_det = 1.0 / det;
+    let dpdu = (edge1 * duv2.y - edge2 * duv1.y) * inv_det;
+    let dpdv = (edge2 * duv1.x - edge1 * duv2.x) * inv_det;
+
+    (dpdu, dpdv)
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -38,6 +190,7 @@ enum VertexData {
 pub struct Triangle {
     #[serde(alias = "vertices")]
     vertex_data: VertexData,
+    colors: Option<[Vector3<f64>; 3]>,
     transform: Transform,
     pub material: Material,
 
@@ -52,6 +205,7 @@ impl Default for Triangle {
                 Point3::origin(),
                 Point3::origin(),
             ]),
+            colors: None,
             transform: Transform::default(),
             material: Material::default(),
 
@@ -60,6 +214,12 @@ impl Default for Triangle {
     }
 }
 
+impl Transformed for Triangle {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
 impl Triangle {
     pub fn new(
         positions: [Point3<f64>; 3],
@@ -76,6 +236,7 @@ impl Triangle {
 
         Self {
             vertex_data,
+            colors: None,
             transform,
             material,
 
@@ -83,6 +244,12 @@ impl Triangle {
         }
     }
 
+    #[must_use]
+    pub fn with_colors(mut self, colors: [Vector3<f64>; 3]) -> Self {
+        self.colors = Some(colors);
+        self
+    }
+
     pub fn new_with_positions(
         positions: [Point3<f64>; 3],
         transform: Transform,
@@ -90,6 +257,7 @@ impl Triangle {
     ) -> Self {
         Self {
             vertex_data: VertexData::Position(positions),
+            colors: None,
             transform,
             material,
 
@@ -127,6 +295,7 @@ impl Triangle {
             VertexData::PNT(vertex_data) => {
                 objects.push(Box::new(RaytracingTriangle::new(
                     vertex_data,
+                    self.colors,
                     transform,
                     self.material,
                 )));
@@ -134,6 +303,7 @@ impl Triangle {
             VertexData::Position(vertices) => {
                 objects.push(Box::new(RaytracingTriangle::new_with_positions(
                     vertices,
+                    self.colors,
                     transform,
                     self.material,
                 )));
@@ -147,14 +317,21 @@ impl Triangle {
 #[derive(Debug)]
 pub struct RaytracingTriangle {
     vertex_data: [VertexPNT; 3],
+    colors: Option<[Vector3<f64>; 3]>,
     world_transform: Transform,
     material: Material,
 }
 
 impl RaytracingTriangle {
-    fn new(vertex_data: [VertexPNT; 3], world_transform: Transform, material: Material) -> Self {
+    fn new(
+        vertex_data: [VertexPNT; 3],
+        colors: Option<[Vector3<f64>; 3]>,
+        world_transform: Transform,
+        material: Material,
+    ) -> Self {
         Self {
             vertex_data,
+            colors,
             world_transform,
             material,
         }
@@ -162,6 +339,7 @@ impl RaytracingTriangle {
 
     fn new_with_positions(
         positions: [Point3<f64>; 3],
+        colors: Option<[Vector3<f64>; 3]>,
         world_transform: Transform,
         material: Material,
     ) -> Self {
@@ -174,7 +352,7 @@ impl RaytracingTriangle {
             VertexPNT::new(positions[2], normals[2], texcoords[2]),
         ];
 
-        Self::new(vertex_data, world_transform, material)
+        Self::new(vertex_data, colors, world_transform, material)
     }
 }
 
@@ -192,60 +370,26 @@ impl Transformed for RaytracingTriangle {
 
 impl Intersectable for RaytracingTriangle {
     fn intersect(&self, ray: &Ray, max_distance: Option<f64>) -> Option<Intersection> {
-        let edge1 = self.vertex_data[1].position - self.vertex_data[0].position;
-        let edge2 = self.vertex_data[2].position - self.vertex_data[0].position;
-        let p_vec = ray.direction.cross(&edge2);
-        let det = edge1.dot(&p_vec);
-
-        if match (self.material.side(), ray.ray_type) {
-            (MaterialSide::Both, _) | (_, RayType::Shadow) => det.abs() < EPSILON,
-            (MaterialSide::Front, _) => det < EPSILON,
-            (MaterialSide::Back, _) => -det < EPSILON,
-        } {
-            return None;
-        }
-
-        let t_vec = ray.origin - self.vertex_data[0].position;
-        let u = t_vec.dot(&p_vec) / det;
-        if u < 0.0 || 1.0 < u {
-            return None;
-        }
-
-        let q_vec = t_vec.cross(&edge1);
-        let v = ray.direction.dot(&q_vec) / det;
-        if v < 0.0 || 1.0 < u + v {
-            return None;
-        }
-
-        let distance = edge2.dot(&q_vec) / det;
-
-        if distance < 0.0 || (max_distance.is_some() && max_distance.unwrap() < distance) {
-            return None;
-        }
+        let positions = [
+            self.vertex_data[0].position,
+            self.vertex_data[1].position,
+            self.vertex_data[2].position,
+        ];
 
-        Some(Intersection::new_with_data(
-            self,
-            distance,
-            IntermediateData::Barycentric(u, v, 1.0 - u - v),
-        ))
+        intersect_triangle(&positions, self.material.side(), ray, max_distance).map(
+            |(distance, intermediate)| Intersection::new_with_data(self, distance, intermediate),
+        )
     }
 }
 
 impl Primitive for RaytracingTriangle {
     fn into_bounded_object(self: Box<Self>) -> ObjectWithBounds {
-        let mut min = self.vertex_data[0].position;
-        let mut max = min;
-        for VertexPNT { position, .. } in self.vertex_data[1..].iter() {
-            min.x = min.x.min(position.x);
-            min.y = min.y.min(position.y);
-            min.z = min.z.min(position.z);
-
-            max.x = max.x.max(position.x);
-            max.y = max.y.max(position.y);
-            max.z = max.z.max(position.z);
-        }
-        let bounding_volume =
-            BoundingVolume::from_bounds_and_transform(min, max, self.get_transform());
+        let positions = [
+            self.vertex_data[0].position,
+            self.vertex_data[1].position,
+            self.vertex_data[2].position,
+        ];
+        let bounding_volume = triangle_bounding_volume(&positions, self.get_transform());
 
         ObjectWithBounds::bounded(self, bounding_volume)
     }
@@ -282,4 +426,191 @@ impl Primitive for RaytracingTriangle {
             + u * self.vertex_data[1].texcoords
             + v * self.vertex_data[2].texcoords
     }
+
+    fn surface_tangents(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        object_normal: &Unit<Vector3<f64>>,
+        _intermediate: IntermediateData,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        triangle_tangents(
+            &[
+                self.vertex_data[0].position,
+                self.vertex_data[1].position,
+                self.vertex_data[2].position,
+            ],
+            &[
+                self.vertex_data[0].texcoords,
+                self.vertex_data[1].texcoords,
+                self.vertex_data[2].texcoords,
+            ],
+            object_normal,
+        )
+    }
+
+    fn vertex_color(&self, intermediate: IntermediateData) -> Option<Vector3<f64>> {
+        let (u, v, w) = match intermediate {
+            IntermediateData::Barycentric(u, v, w) => (u, v, w),
+            _ => unreachable!(),
+        };
+
+        self.colors
+            .map(|colors| w * colors[0] + u * colors[1] + v * colors[2])
+    }
+
+    fn geometric_normal(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _intermediate: IntermediateData,
+    ) -> Unit<Vector3<f64>> {
+        Triangle::compute_normal([
+            self.vertex_data[0].position,
+            self.vertex_data[1].position,
+            self.vertex_data[2].position,
+        ])
+    }
+
+    fn refine_hit_point(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        intermediate: IntermediateData,
+    ) -> Option<(Point3<f64>, Vector3<f64>)> {
+        let positions = [
+            self.vertex_data[0].position,
+            self.vertex_data[1].position,
+            self.vertex_data[2].position,
+        ];
+
+        Some(triangle_hit_point(&positions, intermediate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::MediumStack;
+    use std::f64::consts::TAU;
+
+    fn triangle(positions: [Point3<f64>; 3]) -> RaytracingTriangle {
+        RaytracingTriangle::new_with_positions(
+            positions,
+            None,
+            Transform::default(),
+            Material::default(),
+        )
+    }
+
+    fn ray_to(target: &Point3<f64>, direction: Vector3<f64>) -> Ray {
+        Ray {
+            ray_type: RayType::Primary,
+            origin: target - direction * 3.7,
+            direction,
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        }
+    }
+
+    #[test]
+    fn it_hits_points_on_shared_edges() {
+        let (a, b) = (Point3::new(0.1, 0.3, -0.7), Point3::new(1.3, 0.9, 0.2));
+        let triangles = [
+            triangle([a, b, Point3::new(0.2, 1.7, 0.1)]),
+            triangle([b, a, Point3::new(1.1, -0.6, -0.3)]),
+        ];
+        let direction = Vector3::new(0.31, -0.17, -1.13);
+
+        for i in 1..1000 {
+            let target = a + (b - a) * (f64::from(i) / 1000.0);
+            let ray = ray_to(&target, direction);
+            let hits = triangles
+                .iter()
+                .filter(|triangle| triangle.intersect(&ray, None).is_some())
+                .count();
+            assert!(
+                hits > 0,
+                "ray through {:?} slipped between triangles",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn it_hits_shared_vertices() {
+        let center = Point3::new(0.3, -0.2, 0.1);
+        let ring: Vec<Point3<f64>> = (0..7)
+            .map(|i| {
+                let angle = f64::from(i) * TAU / 7.0;
+                center + Vector3::new(angle.cos(), angle.sin(), 0.1 * angle.sin())
+            })
+            .collect();
+        let triangles: Vec<RaytracingTriangle> = (0..7)
+            .map(|i| triangle([center, ring[i], ring[(i + 1) % 7]]))
+            .collect();
+
+        for j in 0..50 {
+            let angle = f64::from(j) * 0.37;
+            let direction = Vector3::new(0.4 * angle.cos(), 0.4 * angle.sin(), -1.0);
+            let ray = ray_to(&center, direction);
+            let intersection = triangles
+                .iter()
+                .find_map(|triangle| triangle.intersect(&ray, None));
+            assert!(intersection.is_some(), "ray {} missed the shared vertex", j);
+
+            let mut intersection = intersection.unwrap();
+            intersection.compute_data(&ray);
+            assert!((intersection.get_hit_point() - center).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn it_offsets_spawned_rays_off_distant_surfaces() {
+        let offset = Vector3::new(1e5, -3e4, 2e5);
+        let positions = [
+            Point3::new(0.0, 0.0, 0.0) + offset,
+            Point3::new(100.0, 0.0, 3.0) + offset,
+            Point3::new(0.0, 100.0, -7.0) + offset,
+        ];
+        let triangle = triangle(positions);
+
+        for i in 0..100 {
+            let target = positions[0]
+                + (positions[1] - positions[0]) * (f64::from(i % 10) * 0.05 + 0.01)
+                + (positions[2] - positions[0]) * (f64::from(i / 10) * 0.05 + 0.01);
+            let direction = Vector3::new(-0.2, 0.1, -1.0).normalize();
+            let ray = Ray {
+                ray_type: RayType::Primary,
+                origin: target - direction * 3e5,
+                direction,
+                media: MediumStack::default(),
+                wavelengths: None,
+                differentials: None,
+            };
+
+            let mut intersection = triangle.intersect(&ray, None).unwrap();
+            intersection.compute_data(&ray);
+            let normal = intersection.get_normal();
+
+            let reflection_dir = utils::reflect(&ray.direction, &normal).into_inner();
+            let reflection_ray = Ray {
+                ray_type: RayType::Secondary(1),
+                origin: intersection.get_ray_origin(&reflection_dir),
+                direction: reflection_dir,
+                media: MediumStack::default(),
+                wavelengths: None,
+                differentials: None,
+            };
+            assert!(triangle.intersect(&reflection_ray, None).is_none());
+
+            let shadow_ray = Ray {
+                ray_type: RayType::Shadow,
+                origin: intersection.get_ray_origin(&-ray.direction),
+                direction: -ray.direction,
+                media: MediumStack::default(),
+                wavelengths: None,
+                differentials: None,
+            };
+            assert!(triangle.intersect(&shadow_ray, None).is_none());
+        }
+    }
 }
@@ -0,0 +1,270 @@
+use super::mesh::MeshData;
+use super::triangle::{
+    intersect_triangle, triangle_bounding_volume, triangle_hit_point, triangle_tangents,
+};
+use super::{HasMaterial, Primitive, RaytracingObject, Triangle};
+use crate::core::{Material, ObjectWithBounds, Transform, Transformed};
+use crate::ray_intersection::{IntermediateData, Intersectable, Intersection, Ray};
+use nalgebra::{Point3, Unit, Vector2, Vector3};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+// Vertex and index buffers shared by every face of a mesh. Optional attributes are either empty
+// or have one entry per position.
+#[derive(Debug)]
+pub struct TriangleMesh {
+    positions: Vec<Point3<f64>>,
+    normals: Vec<Unit<Vector3<f64>>>,
+    texcoords: Vec<Vector2<f64>>,
+    colors: Vec<Vector3<f64>>,
+    indices: Vec<u32>,
+
+    world_transform: Transform,
+    material: Material,
+}
+
+impl TriangleMesh {
+    pub(super) fn new(mesh_data: MeshData, world_transform: Transform, material: Material) -> Self {
+        let MeshData {
+            positions,
+            normals,
+            texcoords,
+            colors,
+            indices,
+            ..
+        } = mesh_data;
+
+        Self {
+            positions,
+            normals,
+            texcoords,
+            colors,
+            indices: indices
+                .into_iter()
+                .map(|index| u32::try_from(index).expect("mesh has too many vertices"))
+                .collect(),
+
+            world_transform,
+            material,
+        }
+    }
+
+    pub fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn into_triangles(self) -> Vec<Box<dyn RaytracingObject>> {
+        let mesh = Arc::new(self);
+
+        (0..mesh.num_faces())
+            .map(|face| -> Box<dyn RaytracingObject> {
+                Box::new(RaytracingMeshTriangle {
+                    mesh: Arc::clone(&mesh),
+                    face: u32::try_from(face).expect("mesh has too many faces"),
+                })
+            })
+            .collect()
+    }
+}
+
+// A single face of a `TriangleMesh`
+#[derive(Debug)]
+pub struct RaytracingMeshTriangle {
+    mesh: Arc<TriangleMesh>,
+    face: u32,
+}
+
+impl RaytracingMeshTriangle {
+    fn indices(&self) -> [usize; 3] {
+        let start = self.face as usize * 3;
+        let indices = &self.mesh.indices[start..start + 3];
+
+        [
+            indices[0] as usize,
+            indices[1] as usize,
+            indices[2] as usize,
+        ]
+    }
+
+    fn positions(&self) -> [Point3<f64>; 3] {
+        let [idx0, idx1, idx2] = self.indices();
+        let positions = &self.mesh.positions;
+
+        [positions[idx0], positions[idx1], positions[idx2]]
+    }
+
+    fn texcoords(&self) -> [Vector2<f64>; 3] {
+        if self.mesh.texcoords.is_empty() {
+            return [Vector2::zeros(); 3];
+        }
+
+        let [idx0, idx1, idx2] = self.indices();
+        let texcoords = &self.mesh.texcoords;
+
+        [texcoords[idx0], texcoords[idx1], texcoords[idx2]]
+    }
+}
+
+impl HasMaterial for RaytracingMeshTriangle {
+    fn get_material(&self) -> &Material {
+        &self.mesh.material
+    }
+}
+
+impl Transformed for RaytracingMeshTriangle {
+    fn get_transform(&self) -> &Transform {
+        &self.mesh.world_transform
+    }
+}
+
+impl Intersectable for RaytracingMeshTriangle {
+    fn intersect(&self, ray: &Ray, max_distance: Option<f64>) -> Option<Intersection> {
+        intersect_triangle(
+            &self.positions(),
+            self.mesh.material.side(),
+            ray,
+            max_distance,
+        )
+        .map(|(distance, intermediate)| Intersection::new_with_data(self, distance, intermediate))
+    }
+}
+
+impl Primitive for RaytracingMeshTriangle {
+    fn into_bounded_object(self: Box<Self>) -> ObjectWithBounds {
+        let bounding_volume = triangle_bounding_volume(&self.positions(), self.get_transform());
+
+        ObjectWithBounds::bounded(self, bounding_volume)
+    }
+
+    fn surface_normal(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        intermediate: IntermediateData,
+    ) -> Unit<Vector3<f64>> {
+        let (u, v, w) = match intermediate {
+            IntermediateData::Barycentric(u, v, w) => (u, v, w),
+            _ => unreachable!(),
+        };
+
+        if self.mesh.normals.is_empty() {
+            return Triangle::compute_normal(self.positions());
+        }
+
+        let [idx0, idx1, idx2] = self.indices();
+        let normals = &self.mesh.normals;
+
+        Unit::new_normalize(
+            w * normals[idx0].into_inner()
+                + u * normals[idx1].into_inner()
+                + v * normals[idx2].into_inner(),
+        )
+    }
+
+    fn uv(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _object_normal: &Unit<Vector3<f64>>,
+        intermediate: IntermediateData,
+    ) -> Vector2<f64> {
+        let (u, v, w) = match intermediate {
+            IntermediateData::Barycentric(u, v, w) => (u, v, w),
+            _ => unreachable!(),
+        };
+
+        let texcoords = self.texcoords();
+
+        w * texcoords[0] + u * texcoords[1] + v * texcoords[2]
+    }
+
+    fn surface_tangents(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        object_normal: &Unit<Vector3<f64>>,
+        _intermediate: IntermediateData,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        triangle_tangents(&self.positions(), &self.texcoords(), object_normal)
+    }
+
+    fn vertex_color(&self, intermediate: IntermediateData) -> Option<Vector3<f64>> {
+        let (u, v, w) = match intermediate {
+            IntermediateData::Barycentric(u, v, w) => (u, v, w),
+            _ => unreachable!(),
+        };
+
+        if self.mesh.colors.is_empty() {
+            return None;
+        }
+
+        let [idx0, idx1, idx2] = self.indices();
+        let colors = &self.mesh.colors;
+
+        Some(w * colors[idx0] + u * colors[idx1] + v * colors[idx2])
+    }
+
+    fn geometric_normal(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        _intermediate: IntermediateData,
+    ) -> Unit<Vector3<f64>> {
+        Triangle::compute_normal(self.positions())
+    }
+
+    fn refine_hit_point(
+        &self,
+        _object_hit_point: &Point3<f64>,
+        intermediate: IntermediateData,
+    ) -> Option<(Point3<f64>, Vector3<f64>)> {
+        Some(triangle_hit_point(&self.positions(), intermediate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::MediumStack;
+    use crate::ray_intersection::RayType;
+
+    #[test]
+    fn it_shares_vertex_buffers_between_faces() {
+        let mesh = TriangleMesh::new(
+            MeshData {
+                positions: vec![
+                    Point3::new(0.0, 0.0, 0.0),
+                    Point3::new(1.0, 0.0, 0.0),
+                    Point3::new(1.0, 1.0, 0.0),
+                    Point3::new(0.0, 1.0, 0.0),
+                ],
+                texcoords: vec![
+                    Vector2::new(0.0, 0.0),
+                    Vector2::new(1.0, 0.0),
+                    Vector2::new(1.0, 1.0),
+                    Vector2::new(0.0, 1.0),
+                ],
+                indices: vec![0, 1, 2, 0, 2, 3],
+                ..MeshData::default()
+            },
+            Transform::default(),
+            Material::default(),
+        );
+        assert_eq!(mesh.num_faces(), 2);
+
+        let triangles = mesh.into_triangles();
+        assert_eq!(triangles.len(), 2);
+
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::new(0.25, 0.75, 1.0),
+            direction: -Vector3::z(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        };
+        assert!(triangles[0].intersect(&ray, None).is_none());
+
+        let mut intersection = triangles[1].intersect(&ray, None).unwrap();
+        intersection.compute_data(&ray);
+        assert!((intersection.distance - 1.0).abs() < f64::EPSILON);
+        assert_eq!(intersection.get_normal(), Vector3::z_axis());
+        assert!((intersection.get_uv() - Vector2::new(0.25, 0.75)).norm() < 1e-10);
+    }
+}
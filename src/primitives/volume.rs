@@ -0,0 +1,218 @@
+use super::{Object3D, RaytracingCube, RaytracingObject};
+use crate::core::{
+    DensityField, DensityGrid, HeterogeneousMedium, Material, NoiseDensity, ParticipatingMedium,
+    PhysicalMaterial, Transform, Transformed,
+};
+use nalgebra::Vector3;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum VolumeDensity {
+    // Grid of densities loaded from a file
+    Grid { path: String },
+    Noise(NoiseDensity),
+}
+
+impl Default for VolumeDensity {
+    fn default() -> Self {
+        VolumeDensity::Noise(NoiseDensity::default())
+    }
+}
+
+// Box of participating medium with its density varying over a unit cube centered on the origin.
+// The coefficients are per unit distance at a density of 1.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Volume {
+    transform: Transform,
+    density: VolumeDensity,
+    absorption: Vector3<f64>,
+    scattering: Vector3<f64>,
+    asymmetry: f64,
+
+    pub children: Option<Vec<Object3D>>,
+
+    #[serde(skip)]
+    grid: Option<Arc<DensityField>>,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            transform: Transform::default(),
+            density: VolumeDensity::default(),
+            absorption: Vector3::zeros(),
+            scattering: Vector3::repeat(1.0),
+            asymmetry: 0.0,
+
+            children: None,
+
+            grid: None,
+        }
+    }
+}
+
+impl Transformed for Volume {
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
+impl Volume {
+    pub fn add_child(&mut self, object: Object3D) {
+        if let Some(children) = self.children.as_mut() {
+            children.push(object);
+        }
+    }
+
+    pub fn load_assets(&mut self, asset_base: &Path) {
+        if let VolumeDensity::Grid { path } = &self.density {
+            let path = asset_base.join(path);
+            let grid = DensityGrid::load(&path).unwrap_or_else(|err| {
+                panic!(
+                    "failed to load density grid at path \"{}\": {}",
+                    path.display(),
+                    err
+                )
+            });
+            self.grid = Some(Arc::new(DensityField::Grid(grid)));
+        }
+    }
+
+    // The volume's box is an invisible surface which only lets rays into the medium
+    pub fn flatten_to_world(self, transform: &Transform) -> Vec<Box<dyn RaytracingObject>> {
+        let transform = transform * self.transform;
+
+        let mut objects: Vec<Box<dyn RaytracingObject>> = Vec::new();
+
+        if let Some(children) = self.children {
+            for child in children {
+                let child_objects: Vec<Box<dyn RaytracingObject>> =
+                    child.flatten_to_world(&transform);
+                objects.extend(child_objects);
+            }
+        }
+
+        let density = match self.density {
+            VolumeDensity::Grid { .. } => self
+                .grid
+                .expect("density grids should be loaded with the scene's assets"),
+            VolumeDensity::Noise(noise) => Arc::new(DensityField::Noise(noise)),
+        };
+        let medium = HeterogeneousMedium::new(
+            self.absorption,
+            self.scattering,
+            self.asymmetry,
+            density,
+            &transform,
+        );
+        let material = Material::Physical(PhysicalMaterial {
+            color: Vector3::repeat(1.0),
+            opacity: 0.0,
+            roughness: 0.0,
+            medium: Some(ParticipatingMedium::Heterogeneous(medium)),
+            ..PhysicalMaterial::default()
+        });
+
+        objects.push(Box::new(RaytracingCube::new(1.0, transform, material)));
+
+        objects
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        Accelerator, AcceleratorCache, KdTreeAccelerator, KdTreeConstructionOptions, MediumSample,
+        MediumStack,
+    };
+    use crate::primitives::Instance;
+    use crate::ray_intersection::{Intersectable, Ray, RayType};
+    use nalgebra::Point3;
+
+    fn fog(transform: Transform) -> Volume {
+        Volume {
+            transform,
+            scattering: Vector3::repeat(100.0),
+            density: VolumeDensity::Noise(NoiseDensity {
+                threshold: -1.0,
+                ..NoiseDensity::default()
+            }),
+            ..Volume::default()
+        }
+    }
+
+    fn ray_along_x(origin: Point3<f64>) -> Ray {
+        Ray {
+            ray_type: RayType::Primary,
+            origin,
+            direction: Vector3::x(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: None,
+        }
+    }
+
+    // Light is only ever scattered between the near and far sides of the medium's box
+    fn assert_scatters_within(medium: &ParticipatingMedium, ray: &Ray, near: f64, far: f64) {
+        for _ in 0..100 {
+            if let MediumSample::Scattered { distance, .. } = medium.sample_distance(ray, 100.0) {
+                assert!(distance >= near && distance <= far, "{}", distance);
+            }
+        }
+    }
+
+    fn build_tree(objects: Vec<Box<dyn RaytracingObject>>) -> KdTreeAccelerator {
+        KdTreeAccelerator::new(
+            objects,
+            KdTreeConstructionOptions::default(),
+            &mut AcceleratorCache::disabled(),
+        )
+    }
+
+    #[test]
+    fn it_flattens_to_an_invisible_box_of_medium() {
+        let volume = fog(Transform::default()
+            .scale(Vector3::repeat(2.0))
+            .translate(Vector3::new(1.0, 0.0, 0.0)));
+        let objects = volume.flatten_to_world(&Transform::default());
+        assert_eq!(objects.len(), 1);
+
+        let tree = build_tree(objects);
+        let bounding_volume = tree.get_bounding_volume().unwrap();
+        assert!((bounding_volume.surface_area() - 24.0).abs() < 1e-10);
+        assert!(bounding_volume.intersect(&ray_along_x(Point3::new(-5.0, 0.99, 0.99)), None));
+        assert!(!bounding_volume.intersect(&ray_along_x(Point3::new(-5.0, 1.01, 0.0)), None));
+
+        let ray = ray_along_x(Point3::new(-5.0, 0.0, 0.0));
+        let intersection = tree.intersect(&ray, None).unwrap();
+        assert!((intersection.distance - 5.0).abs() < 1e-10);
+        let material = intersection.get_material().as_physical().unwrap();
+        assert!(material.opacity <= 0.0);
+        let medium = intersection.get_medium(material);
+        assert_scatters_within(medium.interior().unwrap(), &ray, 5.0, 7.0);
+    }
+
+    #[test]
+    fn it_places_media_of_instanced_volumes() {
+        let prototype = Object3D::Volume(Box::new(fog(Transform::default())));
+        let mut instance = Instance::new(
+            "fog".to_string(),
+            Transform::default().translate(Vector3::new(5.0, 0.0, 0.0)),
+        );
+        instance.set_prototype_tree(Arc::new(build_tree(
+            prototype.flatten_to_world(&Transform::default()),
+        )));
+        let tree = build_tree(instance.flatten_to_world(&Transform::default()));
+
+        let ray = ray_along_x(Point3::new(0.0, 0.0, 0.0));
+        let intersection = tree.intersect(&ray, None).unwrap();
+        let material = intersection.get_material().as_physical().unwrap();
+        let medium = intersection.get_medium(material);
+        assert_scatters_within(medium.interior().unwrap(), &ray, 4.5, 5.5);
+    }
+}
@@ -1,5 +1,9 @@
-use crate::core::{AxisDirection, MaterialSide};
+use crate::core::{
+    AxisDirection, Material, MaterialSide, Medium, MediumStack, PhysicalMaterial, Transform,
+    Wavelengths,
+};
 use crate::primitives::RaytracingObject;
+use crate::utils;
 use nalgebra::{Affine3, Point3, Unit, Vector2, Vector3};
 
 pub trait Intersectable {
@@ -13,12 +17,26 @@ pub enum RayType {
     Shadow,
 }
 
+// Origins and directions of two rays offset by a pixel in x and y from a main ray, used to estimate
+// the footprint of the ray on the surfaces it hits
+#[derive(Copy, Clone, Debug)]
+pub struct RayDifferentials {
+    pub rx_origin: Point3<f64>,
+    pub rx_direction: Vector3<f64>,
+    pub ry_origin: Point3<f64>,
+    pub ry_direction: Vector3<f64>,
+}
+
 #[derive(Debug)]
 pub struct Ray {
     pub ray_type: RayType,
     pub origin: Point3<f64>,
     pub direction: Vector3<f64>,
-    pub refractive_index: f64,
+    // Media the ray is travelling through
+    pub media: MediumStack,
+    // Wavelengths the ray carries when rendering spectrally
+    pub wavelengths: Option<Wavelengths>,
+    pub differentials: Option<RayDifferentials>,
 }
 
 impl Ray {
@@ -33,16 +51,65 @@ impl Ray {
     pub fn transform(&self, transform: Affine3<f64>) -> Ray {
         let origin = transform * self.origin;
         let direction = transform * self.direction;
+        let differentials = self.differentials.map(|differentials| RayDifferentials {
+            rx_origin: transform * differentials.rx_origin,
+            rx_direction: transform * differentials.rx_direction,
+            ry_origin: transform * differentials.ry_origin,
+            ry_direction: transform * differentials.ry_direction,
+        });
 
         Ray {
             ray_type: self.ray_type,
             origin,
             direction,
-            refractive_index: self.refractive_index,
+            media: self.media.clone(),
+            wavelengths: self.wavelengths,
+            differentials,
         }
     }
 }
 
+// Changes in the hit point and texture coordinates of an intersection between neighboring pixels
+#[derive(Copy, Clone, Debug)]
+pub struct SurfaceDifferentials {
+    pub dpdx: Vector3<f64>,
+    pub dpdy: Vector3<f64>,
+    pub object_dpdx: Vector3<f64>,
+    pub object_dpdy: Vector3<f64>,
+    pub duvdx: Vector2<f64>,
+    pub duvdy: Vector2<f64>,
+}
+
+// Offset from a hit point to where an offset ray crosses the tangent plane at the hit point
+fn tangent_plane_offset(
+    hit_point: &Point3<f64>,
+    normal: &Vector3<f64>,
+    origin: &Point3<f64>,
+    direction: &Vector3<f64>,
+) -> Option<Vector3<f64>> {
+    let n_dot_d = normal.dot(direction);
+    if n_dot_d.abs() < 1e-12 {
+        return None;
+    }
+
+    let distance = normal.dot(&(hit_point - origin)) / n_dot_d;
+    Some(origin + direction * distance - hit_point)
+}
+
+// Texture coordinate change for a hit point offset, as the least squares solution of
+// `dpdu * du + dpdv * dv = offset`
+fn uv_offset(tangents: &(Vector3<f64>, Vector3<f64>), offset: &Vector3<f64>) -> Vector2<f64> {
+    let (dpdu, dpdv) = tangents;
+    let (a, b, c) = (dpdu.dot(dpdu), dpdu.dot(dpdv), dpdv.dot(dpdv));
+    let determinant = a * c - b * b;
+    if determinant.abs() < 1e-20 {
+        return Vector2::zeros();
+    }
+
+    let (pu, pv) = (dpdu.dot(offset), dpdv.dot(offset));
+    Vector2::new(c * pu - b * pv, a * pv - b * pu) / determinant
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum IntermediateData {
     Empty,
@@ -50,11 +117,43 @@ pub enum IntermediateData {
     Barycentric(f64, f64, f64), // Barycentric coordinates of hit point
 }
 
+// Transforms a point along with a bound on the error of each of its coordinates, adding the
+// rounding error of the transform itself
+fn transform_point_with_error(
+    transform: &Affine3<f64>,
+    point: &Point3<f64>,
+    error: &Vector3<f64>,
+) -> (Point3<f64>, Vector3<f64>) {
+    let matrix = transform.matrix();
+    let mut transformed_error = Vector3::zeros();
+    for row in 0..3 {
+        let mut rounding = matrix[(row, 3)].abs();
+        let mut propagated = 0.0;
+        for column in 0..3 {
+            rounding += (matrix[(row, column)] * point[column]).abs();
+            propagated += matrix[(row, column)].abs() * error[column];
+        }
+        transformed_error[row] =
+            utils::error_bound(3) * rounding + (utils::error_bound(3) + 1.0) * propagated;
+    }
+
+    (transform * point, transformed_error)
+}
+
 #[derive(Debug)]
 struct IntersectionData {
     hit_point: Point3<f64>,
+    hit_point_error: Vector3<f64>,
+    geometric_normal: Unit<Vector3<f64>>,
     normal: Unit<Vector3<f64>>,
+    // Whether the ray hit the outside of the surface
+    front_face: bool,
+    object_hit_point: Point3<f64>,
+    object_normal: Unit<Vector3<f64>>,
     uv: Vector2<f64>,
+    tangents: (Vector3<f64>, Vector3<f64>),
+    vertex_color: Option<Vector3<f64>>,
+    differentials: Option<SurfaceDifferentials>,
 }
 
 #[derive(Debug)]
@@ -62,6 +161,9 @@ pub struct Intersection<'a> {
     pub object: &'a dyn RaytracingObject,
     pub distance: f64,
     intermediate: IntermediateData,
+    // Combined transform and material override of any instances the object was hit through
+    instance_transform: Option<Transform>,
+    material_override: Option<&'a Material>,
     data: Option<IntersectionData>,
 }
 
@@ -75,6 +177,8 @@ impl<'a> Intersection<'a> {
             object,
             distance,
             intermediate,
+            instance_transform: None,
+            material_override: None,
             data: None,
         }
     }
@@ -83,17 +187,79 @@ impl<'a> Intersection<'a> {
         Self::new_with_data(object, distance, IntermediateData::Empty)
     }
 
+    // Places an intersection found in an instance's prototype space into the instance's parent
+    // space. An outer instance's material override takes precedence over inner ones.
+    pub fn add_instance(&mut self, transform: &Transform, material: Option<&'a Material>) {
+        self.instance_transform = Some(match self.instance_transform.take() {
+            Some(instance_transform) => transform * instance_transform,
+            None => transform.clone(),
+        });
+        if material.is_some() {
+            self.material_override = material;
+        }
+    }
+
+    pub fn get_material(&self) -> &'a Material {
+        self.material_override
+            .unwrap_or_else(|| self.object.get_material())
+    }
+
+    // Medium inside the object, placed by any instances it was hit through
+    pub fn get_medium(&self, material: &PhysicalMaterial) -> Medium {
+        let mut medium = Medium::new(material);
+        if let Some(instance_transform) = &self.instance_transform {
+            medium.add_instance(instance_transform);
+        }
+
+        medium
+    }
+
     pub fn compute_data(&mut self, ray: &Ray) {
-        let transform = self.object.get_transform();
+        let instance_object_transform = self
+            .instance_transform
+            .as_ref()
+            .map(|instance_transform| instance_transform * self.object.get_transform());
+        let transform = instance_object_transform
+            .as_ref()
+            .unwrap_or_else(|| self.object.get_transform());
         let hit_point = ray.origin + ray.direction * self.distance;
         let object_hit_point = transform.inverse() * hit_point;
+        let refined_hit_point = self
+            .object
+            .refine_hit_point(&object_hit_point, self.intermediate);
+        let (hit_point, hit_point_error, object_hit_point) =
+            if let Some((object_hit_point, object_error)) = refined_hit_point {
+                let (hit_point, hit_point_error) = transform_point_with_error(
+                    &transform.matrix(),
+                    &object_hit_point,
+                    &object_error,
+                );
+
+                (hit_point, hit_point_error, object_hit_point)
+            } else {
+                // A generous bound on the rounding of the distance and of stepping along the ray,
+                // along with the rounding of the hit point in object space, where the distance
+                // was found
+                let object_error = utils::error_bound(16) * object_hit_point.coords.abs();
+                let (_, transformed_error) = transform_point_with_error(
+                    &transform.matrix(),
+                    &object_hit_point,
+                    &object_error,
+                );
+                let hit_point_error = transformed_error
+                    + utils::error_bound(16)
+                        * (ray.origin.coords.abs() + (ray.direction * self.distance).abs());
+
+                (hit_point, hit_point_error, object_hit_point)
+            };
 
         let object_normal = self
             .object
             .surface_normal(&object_hit_point, self.intermediate);
         let normal =
             Unit::new_normalize(transform.inverse_transpose() * object_normal.into_inner());
-        let normal = match self.object.get_material().side() {
+        let front_face = normal.dot(&ray.direction) < 0.0;
+        let normal = match self.get_material().side() {
             MaterialSide::Both => {
                 if normal.dot(&ray.direction) > 0.0 {
                     -normal
@@ -108,11 +274,57 @@ impl<'a> Intersection<'a> {
         let uv = self
             .object
             .uv(&object_hit_point, &object_normal, self.intermediate);
+        let (dpdu, dpdv) =
+            self.object
+                .surface_tangents(&object_hit_point, &object_normal, self.intermediate);
+        let tangents = (transform.matrix() * dpdu, transform.matrix() * dpdv);
+        let vertex_color = self.object.vertex_color(self.intermediate);
+
+        let geometric_normal = Unit::new_normalize(
+            transform.inverse_transpose()
+                * self
+                    .object
+                    .geometric_normal(&object_hit_point, self.intermediate)
+                    .into_inner(),
+        );
+
+        let differentials = ray.differentials.and_then(|differentials| {
+            let dpdx = tangent_plane_offset(
+                &hit_point,
+                &geometric_normal,
+                &differentials.rx_origin,
+                &differentials.rx_direction,
+            )?;
+            let dpdy = tangent_plane_offset(
+                &hit_point,
+                &geometric_normal,
+                &differentials.ry_origin,
+                &differentials.ry_direction,
+            )?;
+            let inverse_transform = transform.inverse();
+
+            Some(SurfaceDifferentials {
+                dpdx,
+                dpdy,
+                object_dpdx: inverse_transform * dpdx,
+                object_dpdy: inverse_transform * dpdy,
+                duvdx: uv_offset(&tangents, &dpdx),
+                duvdy: uv_offset(&tangents, &dpdy),
+            })
+        });
 
         self.data = Some(IntersectionData {
             hit_point,
+            hit_point_error,
+            geometric_normal,
             normal,
+            front_face,
+            object_hit_point,
+            object_normal,
             uv,
+            tangents,
+            vertex_color,
+            differentials,
         });
     }
 
@@ -128,7 +340,135 @@ impl<'a> Intersection<'a> {
         self.get_data().normal
     }
 
+    pub fn is_front_face(&self) -> bool {
+        self.get_data().front_face
+    }
+
+    // Origin for a ray leaving the hit point in `direction`, offset just far enough from the
+    // surface that the ray can't hit it again
+    pub fn get_ray_origin(&self, direction: &Vector3<f64>) -> Point3<f64> {
+        let data = self.get_data();
+
+        utils::offset_ray_origin(
+            &data.hit_point,
+            &data.hit_point_error,
+            &data.geometric_normal,
+            direction,
+        )
+    }
+
+    pub fn get_object_hit_point(&self) -> Point3<f64> {
+        self.get_data().object_hit_point
+    }
+
+    pub fn get_object_normal(&self) -> Unit<Vector3<f64>> {
+        self.get_data().object_normal
+    }
+
     pub fn get_uv(&self) -> Vector2<f64> {
         self.get_data().uv
     }
+
+    pub fn get_tangents(&self) -> (Vector3<f64>, Vector3<f64>) {
+        self.get_data().tangents
+    }
+
+    pub fn get_vertex_color(&self) -> Option<Vector3<f64>> {
+        self.get_data().vertex_color
+    }
+
+    pub fn get_differentials(&self) -> Option<SurfaceDifferentials> {
+        self.get_data().differentials
+    }
+
+    // Differentials of a ray leaving the hit point in `direction` after a mirror reflection of
+    // `ray` about `normal`. The offset rays are reflected about the same normal, ignoring the
+    // curvature of the surface, and the result is shifted along with `direction` when it's been
+    // perturbed from the mirror direction.
+    pub fn get_reflected_differentials(
+        &self,
+        ray: &Ray,
+        normal: &Vector3<f64>,
+        direction: &Vector3<f64>,
+    ) -> Option<RayDifferentials> {
+        let mirror_direction = utils::reflect(&ray.direction, normal).into_inner();
+
+        self.get_spawned_differentials(ray, direction, |offset_direction| {
+            Some(utils::reflect(offset_direction, normal).into_inner() - mirror_direction)
+        })
+    }
+
+    // Differentials of a ray refracted through the hit point with relative index of refraction
+    // `eta`, or `None` if either offset ray is totally internally reflected
+    pub fn get_refracted_differentials(
+        &self,
+        ray: &Ray,
+        normal: &Vector3<f64>,
+        eta: f64,
+        direction: &Vector3<f64>,
+    ) -> Option<RayDifferentials> {
+        self.get_spawned_differentials(ray, direction, |offset_direction| {
+            utils::refract(offset_direction, normal, eta)
+                .map(|offset_direction| offset_direction.into_inner() - direction)
+        })
+    }
+
+    fn get_spawned_differentials(
+        &self,
+        ray: &Ray,
+        direction: &Vector3<f64>,
+        direction_change: impl Fn(&Vector3<f64>) -> Option<Vector3<f64>>,
+    ) -> Option<RayDifferentials> {
+        let differentials = ray.differentials?;
+        let surface_differentials = self.get_differentials()?;
+        let origin = self.get_ray_origin(direction);
+
+        Some(RayDifferentials {
+            rx_origin: origin + surface_differentials.dpdx,
+            rx_direction: direction + direction_change(&differentials.rx_direction)?,
+            ry_origin: origin + surface_differentials.dpdy,
+            ry_direction: direction + direction_change(&differentials.ry_direction)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::RaytracingPlane;
+
+    #[test]
+    fn it_computes_uv_derivatives_from_ray_differentials() {
+        let plane =
+            RaytracingPlane::new(Vector3::y_axis(), Transform::default(), Material::default());
+        let ray = Ray {
+            ray_type: RayType::Primary,
+            origin: Point3::new(0.0, 2.0, 0.0),
+            direction: -Vector3::y(),
+            media: MediumStack::default(),
+            wavelengths: None,
+            differentials: Some(RayDifferentials {
+                rx_origin: Point3::new(0.0, 2.0, 0.0),
+                rx_direction: Vector3::new(0.01, -1.0, 0.0),
+                ry_origin: Point3::new(0.0, 2.0, 0.0),
+                ry_direction: Vector3::new(0.0, -1.0, 0.01),
+            }),
+        };
+
+        let mut intersection = plane.intersect(&ray, None).unwrap();
+        intersection.compute_data(&ray);
+        let differentials = intersection.get_differentials().unwrap();
+
+        assert!((differentials.duvdx - Vector2::new(0.02, 0.0)).magnitude() < 1e-12);
+        assert!((differentials.duvdy - Vector2::new(0.0, 0.02)).magnitude() < 1e-12);
+
+        // Offset rays keep spreading apart after a mirror reflection
+        let direction = Vector3::y();
+        let reflected = intersection
+            .get_reflected_differentials(&ray, &Vector3::y(), &direction)
+            .unwrap();
+        assert!((reflected.rx_origin.x - 0.02).abs() < 1e-9);
+        assert!((reflected.rx_direction.x - 0.01).abs() < 1e-4);
+        assert!(reflected.rx_direction.y > 0.0);
+    }
 }
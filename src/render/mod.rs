@@ -1,6 +1,7 @@
 mod raytracing_scene;
 mod scene;
 
+use crate::core::{AcceleratorType, KdTreeConstructionOptions, NodePrecision};
 use crate::utils;
 use nalgebra::{clamp, Point3, Unit, Vector3};
 use num_traits::Zero;
@@ -10,7 +11,9 @@ use std::ops::AddAssign;
 pub use scene::Scene;
 
 const GAMMA: f64 = 2.2;
-const BIAS: f64 = 1e-10;
+// Fraction of a shadow ray's length left untested at the surface end, which covers the rounding
+// error of intersecting a ray that's travelled a long way from the light
+const SHADOW_EPSILON: f64 = 1e-9;
 
 pub struct ColorData {
     color: Vector3<f64>,
@@ -104,6 +107,14 @@ pub struct RenderOptions {
     pub max_occlusion_rays: u16,
     pub max_occlusion_distance: f64,
     pub occlusion_blur_radius: u16,
+    // Number of transmissive surfaces light can pass through on its way to a hit point
+    pub max_shadow_transmissions: u8,
+    // Trace a handful of wavelengths with each camera ray so dispersive materials split light into
+    // colors
+    pub spectral: bool,
+    pub accelerator: AcceleratorType,
+    pub acceleration: KdTreeConstructionOptions,
+    pub node_precision: NodePrecision,
 }
 
 impl Default for RenderOptions {
@@ -117,10 +128,26 @@ impl Default for RenderOptions {
             max_occlusion_rays: 16,
             max_occlusion_distance: 1.0,
             occlusion_blur_radius: 2,
+            max_shadow_transmissions: 8,
+            spectral: false,
+            accelerator: AcceleratorType::default(),
+            acceleration: KdTreeConstructionOptions::default(),
+            node_precision: NodePrecision::default(),
         }
     }
 }
 
+impl RenderOptions {
+    // Combinations of options which parse but can't be rendered
+    fn validate(&self) -> Result<(), String> {
+        if !self.accelerator.supports(self.node_precision) {
+            return Err("f32 node precision is only supported by the bvh accelerator".to_string());
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -169,6 +196,38 @@ mod test {
         scene.unwrap().build_raytracing_scene();
     }
 
+    #[test]
+    fn it_builds_a_kd_tree_with_acceleration_options() {
+        let scene_json = json!({
+          "acceleration": { "max_objects": 1, "max_depth": 0 },
+          "objects": [
+            { "type": "cube", "transform": [{ "translate": [-2, 0, 0] }] },
+            { "type": "cube" },
+            { "type": "cube", "transform": [{ "translate": [2, 0, 0] }] }
+          ]
+        });
+
+        let scene: Scene = serde_json::from_value(scene_json).expect("failed to deserialize scene");
+        assert_eq!(scene.render_options.acceleration.max_objects, 1);
+
+        let stats = scene.build_raytracing_scene().get_accelerator_stats();
+        assert_eq!(stats.num_nodes, 1);
+        assert_eq!(stats.max_leaf_objects, 3);
+        assert_eq!(stats.leaf_depths, vec![1]);
+        assert!((stats.sah_cost - 240.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn it_rejects_node_precisions_the_accelerator_does_not_support() {
+        let scene: Result<Scene, _> = serde_json::from_value(json!({ "node_precision": "f32" }));
+        assert!(scene.is_err());
+
+        let scene: Scene =
+            serde_json::from_value(json!({ "accelerator": "bvh", "node_precision": "f32" }))
+                .expect("failed to deserialize scene");
+        assert_eq!(scene.render_options.node_precision, NodePrecision::F32);
+    }
+
     #[test]
     fn it_builds_a_raytracing_scene_from_an_empty_scene() {
         let scene = Scene::new(RenderOptions::default(), Camera::default());
@@ -209,4 +268,47 @@ mod test {
 
         scene.build_raytracing_scene();
     }
+
+    // Color of light from a bright backdrop behind the camera reflected back by a glass cube
+    fn reflected_glass_color(thin_film: serde_json::Value) -> Vector3<f64> {
+        let scene_json = json!({
+          "width": 10,
+          "height": 10,
+          "samples_per_pixel": 1,
+          "camera": { "position": [0, 0, 1], "target": [0, 0, 0] },
+          "objects": [
+            {
+              "type": "cube",
+              "transform": [{ "translate": [0, 0, -2] }],
+              "material": {
+                "type": "physical",
+                "color": [1, 1, 1],
+                "opacity": 0,
+                "refractive_index": 1.5,
+                "thin_film": thin_film
+              }
+            },
+            {
+              "type": "cube",
+              "size": 4,
+              "transform": [{ "translate": [0, 0, 5] }],
+              "material": { "type": "phong", "emissive": [1, 1, 1] }
+            }
+          ]
+        });
+
+        let scene: Scene = serde_json::from_value(scene_json).expect("failed to deserialize scene");
+        let (color_data, _) = scene.build_raytracing_scene().screen_raycast(5, 5);
+        color_data.color
+    }
+
+    #[test]
+    fn it_reflects_films_over_transmissive_surfaces() {
+        let color = reflected_glass_color(serde_json::Value::Null);
+        assert!(color.max() > 0.0);
+        assert!(color.max() - color.min() < 1e-10);
+
+        let color = reflected_glass_color(json!({ "thickness": 300, "refractive_index": 1.33 }));
+        assert!(color.max() - color.min() > 0.05);
+    }
 }
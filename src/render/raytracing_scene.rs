@@ -1,14 +1,16 @@
-use super::{Camera, CastStats, ColorData, RenderOptions, BIAS};
+use super::{Camera, CastStats, ColorData, RenderOptions, SHADOW_EPSILON};
 use crate::core::{
-    KdTreeAccelerator, Material, PhongMaterial, PhysicalMaterial, Texture, Transformed,
+    Accelerator, AcceleratorStats, HomogeneousMedium, Material, MediumSample, MediumStack,
+    ParticipatingMedium, PhongMaterial, PhysicalMaterial, PrincipledMaterial, RayPacket, Texture,
+    ThinFilm, Transformed, Wavelengths,
 };
 use crate::lights::Light;
-use crate::ray_intersection::{Intersection, Ray, RayType};
+use crate::ray_intersection::{Intersection, Ray, RayDifferentials, RayType};
 use crate::utils;
 use image::RgbaImage;
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use indicatif::{ProgressBar, ProgressStyle};
 use minifb::{Key, Window, WindowOptions};
-use nalgebra::{Matrix4, Point3, Unit, Vector3};
+use nalgebra::{clamp, Matrix4, Point3, Unit, Vector3};
 use num_traits::identities::Zero;
 use rand::Rng;
 use rand::{seq::SliceRandom, thread_rng};
@@ -19,6 +21,101 @@ use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
+// Width and height in pixels of the tiles an image is rendered in. The primary rays of a tile and
+// their shadow rays are traced as packets.
+const TILE_SIZE: u32 = 4;
+// Random walks traced for subsurface scattering from a camera ray's hit, which are divided among
+// deeper hits
+const MAX_SUBSURFACE_WALKS: u16 = 4;
+const MAX_SUBSURFACE_BOUNCES: u16 = 256;
+
+#[derive(Copy, Clone, Debug)]
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Tile {
+    // Indexes into the image buffer of the pixels in the tile, row by row
+    fn pixel_indexes(self, image_width: u32) -> impl Iterator<Item = usize> {
+        (self.y..self.y + self.height).flat_map(move |y| {
+            (self.x..self.x + self.width).map(move |x| (y * image_width + x) as usize)
+        })
+    }
+}
+
+// Ray from a point light toward a hit point along with the distance it's traced for. The ray ends
+// just short of the hit point, offset to the light's side of the surface, so that the surface being
+// lit never blocks it.
+fn build_shadow_ray(
+    light_position: Point3<f64>,
+    intersection: &Intersection,
+    light_dir: &Vector3<f64>,
+) -> (Ray, f64) {
+    let target = intersection.get_ray_origin(light_dir);
+    let to_target = target - light_position;
+    let distance = to_target.magnitude();
+    let ray = Ray {
+        ray_type: RayType::Shadow,
+        origin: light_position,
+        direction: to_target / distance,
+        media: MediumStack::default(),
+        wavelengths: None,
+        differentials: None,
+    };
+
+    (ray, distance * (1.0 - SHADOW_EPSILON))
+}
+
+// Media on the far side of a transmissive surface, along with the refractive indices on either
+// side of it unless the surface is hidden inside a higher priority medium. A ray leaving a medium
+// it was never tracked entering, such as one which started inside an object, is taken to leave the
+// material into whichever medium it's in. Dispersive media have different indices for each
+// wavelength.
+fn cross_surface(
+    ray: &Ray,
+    intersection: &Intersection,
+    material: &PhysicalMaterial,
+    wavelength: Option<f64>,
+) -> (MediumStack, Option<(f64, f64)>) {
+    let medium = intersection.get_medium(material);
+    let media = if intersection.is_front_face() {
+        ray.media.entered(medium)
+    } else if ray.media.contains(&medium) {
+        ray.media.exited(&medium)
+    } else {
+        let interface = (
+            material.get_refractive_index(wavelength),
+            ray.media.refractive_index(wavelength),
+        );
+        return (ray.media.clone(), Some(interface));
+    };
+
+    if ray.media.is_interface(&media) {
+        let interface = (
+            ray.media.refractive_index(wavelength),
+            media.refractive_index(wavelength),
+        );
+        (media, Some(interface))
+    } else {
+        (media, None)
+    }
+}
+
+// Ray carrying on through a surface which neither bends nor reflects it, without using up a bounce
+fn pass_through(ray: &Ray, intersection: &Intersection, media: MediumStack) -> Ray {
+    Ray {
+        ray_type: ray.ray_type,
+        origin: intersection.get_ray_origin(&ray.direction),
+        direction: ray.direction,
+        media,
+        wavelengths: ray.wavelengths,
+        differentials: ray.differentials,
+    }
+}
+
 #[derive(Debug)]
 pub struct RaytracingCamera {
     fov: f64,
@@ -45,7 +142,9 @@ pub struct RaytracingScene {
     camera: RaytracingCamera,
     lights: Vec<Light>,
     textures: HashMap<String, Texture>,
-    object_tree: KdTreeAccelerator,
+    object_tree: Box<dyn Accelerator>,
+    // Medium filling the space outside every object
+    medium: Option<ParticipatingMedium>,
 }
 
 impl RaytracingScene {
@@ -54,7 +153,8 @@ impl RaytracingScene {
         camera: RaytracingCamera,
         lights: Vec<Light>,
         textures: HashMap<String, Texture>,
-        object_tree: KdTreeAccelerator,
+        object_tree: Box<dyn Accelerator>,
+        medium: Option<ParticipatingMedium>,
     ) -> Self {
         Self {
             render_options,
@@ -62,6 +162,7 @@ impl RaytracingScene {
             lights,
             textures,
             object_tree,
+            medium,
         }
     }
 
@@ -85,12 +186,50 @@ impl RaytracingScene {
         self.object_tree.get_num_objects()
     }
 
+    pub fn get_accelerator_stats(&self) -> AcceleratorStats {
+        self.object_tree.get_stats()
+    }
+
     fn raycast(&self, ray: &Ray) -> Option<Intersection> {
         self.object_tree.raycast(ray)
     }
 
     fn shadow_cast(&self, ray: &Ray, max_distance: f64) -> bool {
-        self.object_tree.shadow_cast(ray, max_distance - BIAS)
+        self.object_tree.shadow_cast(ray, max_distance)
+    }
+
+    // Fraction of a point light's color which reaches a hit point. Shadows which were already
+    // traced as part of a packet are looked up by light index, and only blocked ones are traced
+    // again to see how much light makes it through transmissive surfaces. Shadow rays start at
+    // the light, which is taken to be outside every object, so unblocked ones only pass through
+    // the scene's medium.
+    fn get_shadow_transmittance(
+        &self,
+        light_index: usize,
+        shadow_ray: &Ray,
+        shadow_distance: f64,
+        packet_shadows: Option<&[bool]>,
+    ) -> Vector3<f64> {
+        let is_blocked = packet_shadows.map_or_else(
+            || self.shadow_cast(shadow_ray, shadow_distance),
+            |packet_shadows| packet_shadows[light_index],
+        );
+
+        if !is_blocked {
+            self.medium.as_ref().map_or_else(
+                || Vector3::repeat(1.0),
+                |medium| medium.transmittance(shadow_ray, shadow_distance),
+            )
+        } else if self.render_options.max_shadow_transmissions == 0 {
+            Vector3::zeros()
+        } else {
+            self.object_tree.shadow_transmittance(
+                shadow_ray,
+                shadow_distance,
+                self.render_options.max_shadow_transmissions,
+                self.medium.as_ref(),
+            )
+        }
     }
 
     fn get_color_phong(
@@ -98,24 +237,32 @@ impl RaytracingScene {
         ray: &Ray,
         intersection: &Intersection,
         material: &PhongMaterial,
+        packet_shadows: Option<&[bool]>,
     ) -> (ColorData, CastStats) {
         let mut cast_stats = CastStats::zero();
         let depth = ray.get_depth();
         let hit_point = intersection.get_hit_point();
 
-        let normal = intersection.get_normal();
+        let normal = material.get_normal(intersection, &self.textures);
 
-        let uv = intersection.get_uv();
-        let material_color = material.get_color(uv, &self.textures);
+        let material_color = material.get_color(intersection, &self.textures);
+        let specular = material.get_specular(intersection, &self.textures);
+        let shininess = material.get_shininess(intersection, &self.textures);
         let emissive = material.emissive;
 
         let reflection = if material.reflectivity > 0.0 {
             let reflection_dir = utils::reflect(&ray.direction, &normal).into_inner();
             let reflection_ray = Ray {
                 ray_type: RayType::Secondary(depth + 1),
-                origin: hit_point + (reflection_dir * BIAS),
+                origin: intersection.get_ray_origin(&reflection_dir),
                 direction: reflection_dir,
-                refractive_index: 1.0,
+                media: ray.media.clone(),
+                wavelengths: ray.wavelengths,
+                differentials: intersection.get_reflected_differentials(
+                    ray,
+                    &normal,
+                    &reflection_dir,
+                ),
             };
             let (mut color_data, stats) = self.get_color(&reflection_ray);
             color_data.color.component_mul_assign(&material_color);
@@ -129,7 +276,7 @@ impl RaytracingScene {
         let mut ambient_light = Vector3::zero();
         let mut irradiance = Vector3::zero();
         if material.reflectivity < 1.0 {
-            for light in &self.lights {
+            for (light_index, light) in self.lights.iter().enumerate() {
                 match light {
                     Light::Ambient(light) => {
                         ambient_light += light.get_color().component_mul(&material_color);
@@ -142,23 +289,27 @@ impl RaytracingScene {
 
                         let n_dot_l = normal.dot(&light_dir);
                         if n_dot_l > 0.0 {
-                            let shadow_ray = Ray {
-                                ray_type: RayType::Shadow,
-                                origin: light_position,
-                                direction: -light_dir,
-                                refractive_index: 1.0,
-                            };
+                            let (shadow_ray, shadow_distance) =
+                                build_shadow_ray(light_position, intersection, &light_dir);
 
                             cast_stats.ray_count += 1;
-                            if !self.shadow_cast(&shadow_ray, light_distance) {
-                                let light_color = light.get_color(light_distance);
+                            let transmittance = self.get_shadow_transmittance(
+                                light_index,
+                                &shadow_ray,
+                                shadow_distance,
+                                packet_shadows,
+                            );
+                            if transmittance.max() > 0.0 {
+                                let light_color = light
+                                    .get_color(light_distance)
+                                    .component_mul(&transmittance);
                                 irradiance += light_color.component_mul(&material_color) * n_dot_l;
 
                                 let half_vec = Unit::new_normalize(light_dir - ray.direction);
                                 let n_dot_h = normal.dot(&half_vec);
                                 if n_dot_h > 0.0 {
-                                    irradiance += light_color.component_mul(&material.specular)
-                                        * n_dot_h.powf(material.shininess);
+                                    irradiance += light_color.component_mul(&specular)
+                                        * n_dot_h.powf(shininess);
                                 }
                             }
                         }
@@ -198,52 +349,150 @@ impl RaytracingScene {
         ray: &Ray,
         intersection: &Intersection,
         material: &PhysicalMaterial,
+        layers: Option<&PrincipledMaterial>,
+        packet_shadows: Option<&[bool]>,
     ) -> (ColorData, CastStats) {
+        // Packet shadows are only cast toward the side the normal faces, which thin sheets can be
+        // seen and lit from either side of
+        let packet_shadows = packet_shadows.filter(|_| !material.thin);
+
+        let transmitted_media = if material.opacity < 1.0 && !material.thin {
+            let wavelength = ray.wavelengths.map(Wavelengths::hero);
+            let (media, interface) = cross_surface(ray, intersection, material, wavelength);
+            if let Some(interface) = interface {
+                Some((media, interface))
+            } else {
+                // The ray carries on as if the surface weren't there
+                return self.get_color(&pass_through(ray, intersection, media));
+            }
+        } else {
+            None
+        };
+
         let mut cast_stats = CastStats::zero();
         let depth = ray.get_depth();
         let hit_point = intersection.get_hit_point();
 
-        let normal = intersection.get_normal();
+        let normal = material.get_normal(intersection, &self.textures);
         let view_dir = Unit::new_normalize(-ray.direction);
+        // Thin sheets are shaded from whichever side they're seen from
+        let normal = if material.thin && normal.dot(&view_dir) < 0.0 {
+            -normal
+        } else {
+            normal
+        };
         let n_dot_v = normal.dot(&view_dir).max(0.0);
 
-        let uv = intersection.get_uv();
-        let material_color = material.get_color(uv, &self.textures);
+        let material_color = material.get_color(intersection, &self.textures);
 
-        let roughness = material.roughness.max(0.04);
-        let base_reflectivity = Vector3::repeat(0.04).lerp(&material_color, material.metalness);
-        let f = utils::fresnel(n_dot_v, base_reflectivity);
-        let k_s = f;
-        let k_d = (Vector3::repeat(1.0) - k_s) * (1.0 - material.metalness);
+        let material_roughness = material.get_roughness(intersection, &self.textures);
 
-        let emissive = material.emissive;
+        let roughness = material_roughness.max(0.04);
+        let (f, k_d) = if let Some(conductor) = material.conductor {
+            // Conductors absorb all of the light they don't reflect
+            let f = conductor.reflectance(n_dot_v, ray.media.refractive_index(None));
+            (f, Vector3::zeros())
+        } else {
+            let metalness = material.get_metalness(intersection, &self.textures);
+            let specular_color = layers.map_or(Vector3::repeat(1.0), |layers| {
+                layers.get_specular_color(&material_color)
+            });
+            let base_reflectivity = (0.04 * specular_color).lerp(&material_color, metalness);
+            let f = if let Some(film) = material.thin_film {
+                // Films over thin sheets have the same medium on either side of them
+                let eta_i = ray.media.refractive_index(None);
+                let eta_t = if material.thin {
+                    eta_i
+                } else {
+                    material.refractive_index
+                };
+                film.reflectance(n_dot_v, eta_i, eta_t)
+                    .lerp(&utils::fresnel(n_dot_v, material_color), metalness)
+            } else {
+                utils::fresnel(n_dot_v, base_reflectivity)
+            };
+            let k_s = f;
+            (f, (Vector3::repeat(1.0) - k_s) * (1.0 - metalness))
+        };
+
+        let emissive = material.get_emissive(intersection, &self.textures);
 
-        let reflection = if self.render_options.max_reflected_rays > 0 {
+        // Anisotropic surfaces are shaded in terms of their tangent frame
+        let anisotropic_frame = if material.anisotropy > 0.0 {
+            let (tangent, bitangent) =
+                material.get_tangent_frame(intersection, &normal, &self.textures);
+            let (alpha_t, alpha_b) = utils::anisotropic_alpha(roughness, material.anisotropy);
+            Some((tangent, bitangent, alpha_t, alpha_b))
+        } else {
+            None
+        };
+        let to_local =
+            |direction: &Vector3<f64>, tangent: &Vector3<f64>, bitangent: &Vector3<f64>| {
+                Vector3::new(
+                    direction.dot(tangent),
+                    direction.dot(bitangent),
+                    direction.dot(&normal),
+                )
+            };
+
+        let reflection = if self.render_options.max_reflected_rays > 0 && material.opacity > 0.0 {
             let d = 8_u16.pow(depth.into());
             let reflected_rays = (self.render_options.max_reflected_rays / d).max(1);
 
-            let max_angle = FRAC_PI_2 * material.roughness;
+            let max_angle = FRAC_PI_2 * material_roughness;
             let reflection_dir = utils::reflect(&ray.direction, &normal);
 
             let mut reflection = (0..reflected_rays).fold(ColorData::zero(), |mut acc, _| {
-                let direction = utils::uniform_sample_cone(&reflection_dir, max_angle).into_inner();
+                let (direction, microfacet_normal, weight) =
+                    if let Some((tangent, bitangent, alpha_t, alpha_b)) = anisotropic_frame {
+                        // Microfacet normals are importance sampled, leaving the masking of the
+                        // reflected ray as its weight
+                        let half = utils::sample_anisotropic_ggx(alpha_t, alpha_b);
+                        let half =
+                            tangent * half.x + bitangent * half.y + normal.into_inner() * half.z;
+                        let direction = utils::reflect(&ray.direction, &half).into_inner();
+
+                        let n_dot_l = normal.dot(&direction);
+                        let v_dot_h = view_dir.dot(&half);
+                        if n_dot_l <= 0.0 || v_dot_h <= 0.0 || n_dot_v <= 0.0 {
+                            return acc;
+                        }
+                        let g = utils::anisotropic_geometry_function(
+                            &to_local(&view_dir, &tangent, &bitangent),
+                            &to_local(&direction, &tangent, &bitangent),
+                            alpha_t,
+                            alpha_b,
+                        );
+
+                        (direction, half, g * v_dot_h / (n_dot_v * normal.dot(&half)))
+                    } else {
+                        let direction =
+                            utils::uniform_sample_cone(&reflection_dir, max_angle).into_inner();
+                        (direction, normal.into_inner(), FRAC_PI_2)
+                    };
                 let reflection_ray = Ray {
                     ray_type: RayType::Secondary(depth + 1),
-                    origin: hit_point + (direction * BIAS),
+                    origin: intersection.get_ray_origin(&direction),
                     direction,
-                    refractive_index: 1.0,
+                    media: ray.media.clone(),
+                    wavelengths: ray.wavelengths,
+                    differentials: intersection.get_reflected_differentials(
+                        ray,
+                        &microfacet_normal,
+                        &direction,
+                    ),
                 };
                 let (color_data, stats) = self.get_color(&reflection_ray);
                 cast_stats += stats;
 
-                acc.color += color_data.color;
+                acc.color += color_data.color * weight;
                 acc.ambient_occlusion += color_data.ambient_occlusion;
 
                 acc
             });
             reflection
                 .color
-                .component_mul_assign(&(f * FRAC_PI_2 / f64::from(reflected_rays)));
+                .component_mul_assign(&(f / f64::from(reflected_rays)));
             reflection.ambient_occlusion /= f64::from(reflected_rays);
 
             Some(reflection)
@@ -251,29 +500,110 @@ impl RaytracingScene {
             None
         };
 
-        let refraction = if material.opacity < 1.0 {
-            let eta = ray.refractive_index / material.refractive_index;
-            utils::refract(&ray.direction, &normal, eta).map(|refraction_dir| {
-                let refraction_dir = refraction_dir.into_inner();
-                let refraction_ray = Ray {
-                    ray_type: RayType::Secondary(depth + 1),
-                    origin: hit_point + (refraction_dir * BIAS),
-                    direction: refraction_dir,
-                    refractive_index: material.refractive_index,
+        let transmission = if material.thin && material.opacity < 1.0 {
+            let (color, stats) = self.get_thin_sheet_color(ray, intersection, &normal, material);
+            cast_stats += stats;
+
+            Some(color.component_mul(&material_color))
+        } else {
+            transmitted_media.map(|(media, interface)| {
+                let (eta_i, eta_t) = interface;
+                let color = match ray.wavelengths {
+                    // Each wavelength is bent by a different amount, so they're traced apart from here
+                    Some(wavelengths) if material.dispersion.is_some() => wavelengths
+                        .split()
+                        .into_iter()
+                        .map(|(wavelength, weight)| {
+                            let (_, dispersed_interface) =
+                                cross_surface(ray, intersection, material, Some(wavelength));
+                            let (color, stats) = self.get_dielectric_color(
+                                ray,
+                                intersection,
+                                &normal,
+                                media.clone(),
+                                dispersed_interface.unwrap_or(interface),
+                                material.thin_film,
+                                Some(Wavelengths::Single(wavelength)),
+                            );
+                            cast_stats += stats;
+
+                            color.component_mul(&weight)
+                        })
+                        .sum(),
+                    // Boundaries between media with the same refractive index, such as those of
+                    // volumes, are crossed straight through
+                    _ if (eta_i - eta_t).abs() < f64::EPSILON && material.thin_film.is_none() => {
+                        let (color_data, stats) =
+                            self.get_color(&pass_through(ray, intersection, media));
+                        cast_stats += stats;
+
+                        color_data.color
+                    }
+                    _ => {
+                        let (color, stats) = self.get_dielectric_color(
+                            ray,
+                            intersection,
+                            &normal,
+                            media,
+                            interface,
+                            material.thin_film,
+                            ray.wavelengths,
+                        );
+                        cast_stats += stats;
+
+                        color
+                    }
                 };
-                let (color_data, stats) = self.get_color(&refraction_ray);
-                cast_stats += stats;
 
-                color_data.color.component_mul(&material_color)
+                color.component_mul(&material_color)
             })
-        } else {
-            None
         };
 
+        // Fully transmissive surfaces, such as the boundaries of liquids and fog, scatter none of
+        // the light reaching them
+        if material.opacity <= 0.0 {
+            if let Some(transmission) = transmission {
+                return (
+                    ColorData::new(transmission, material_color, emissive),
+                    cast_stats,
+                );
+            }
+        }
+
+        let sheen_color = layers.map_or(Vector3::zeros(), |layers| {
+            layers.get_sheen_color(&material_color)
+        });
+        // Normal, roughness and reflectance of the coat, if there is one
+        let clearcoat = layers
+            .filter(|layers| layers.clearcoat > 0.0)
+            .map(|layers| {
+                let normal = layers.get_clearcoat_normal(intersection, &self.textures);
+                let n_dot_v = normal.dot(&view_dir).max(0.0);
+                let f = layers.clearcoat * utils::fresnel(n_dot_v, Vector3::repeat(0.04)).x;
+                (normal, layers.clearcoat_roughness, f)
+            });
+
         let mut ambient_light = Vector3::zero();
         let mut irradiance = Vector3::zero();
-        let diffuse = FRAC_1_PI * k_d.component_mul(&material_color);
-        for light in &self.lights {
+        let mut clearcoat_irradiance = Vector3::zero();
+        // Light entering materials with subsurface scattering leaves from somewhere else instead
+        // of being reflected diffusely
+        let subsurface_medium = material
+            .subsurface
+            .map(|subsurface| subsurface.get_medium(&material_color));
+        let diffuse = if subsurface_medium.is_some() {
+            Vector3::zeros()
+        } else {
+            FRAC_1_PI * k_d.component_mul(&material_color)
+        };
+        // Thin sheets let some of their diffuse light through to their other side
+        let (diffuse, transmitted_diffuse) = if material.thin {
+            let transmission = clamp(material.diffuse_transmission, 0.0, 1.0);
+            (diffuse * (1.0 - transmission), diffuse * transmission)
+        } else {
+            (diffuse, Vector3::zeros())
+        };
+        for (light_index, light) in self.lights.iter().enumerate() {
             match light {
                 Light::Ambient(light) => {
                     ambient_light += light.get_color().component_mul(&material_color);
@@ -286,33 +616,96 @@ impl RaytracingScene {
 
                     let n_dot_l = normal.dot(&light_dir);
                     if n_dot_l > 0.0 {
-                        let shadow_ray = Ray {
-                            ray_type: RayType::Shadow,
-                            origin: light_position,
-                            direction: -light_dir,
-                            refractive_index: 1.0,
-                        };
+                        let (shadow_ray, shadow_distance) =
+                            build_shadow_ray(light_position, intersection, &light_dir);
 
                         cast_stats.ray_count += 1;
-                        if !self.shadow_cast(&shadow_ray, light_distance) {
+                        let transmittance = self.get_shadow_transmittance(
+                            light_index,
+                            &shadow_ray,
+                            shadow_distance,
+                            packet_shadows,
+                        );
+                        if transmittance.max() > 0.0 {
                             let half_vec = Unit::new_normalize(light_dir - ray.direction);
                             let n_dot_h = normal.dot(&half_vec).max(0.0);
 
-                            let light_color = light.get_color(light_distance);
+                            let light_color = light
+                                .get_color(light_distance)
+                                .component_mul(&transmittance);
                             let radiance = light_color * n_dot_l;
 
-                            let ndf = utils::ndf(n_dot_h, roughness);
-                            let g = utils::geometry_function(n_dot_v, n_dot_l, roughness);
+                            let (ndf, g) = if let Some((tangent, bitangent, alpha_t, alpha_b)) =
+                                anisotropic_frame
+                            {
+                                let view = to_local(&view_dir, &tangent, &bitangent);
+                                let light = to_local(&light_dir, &tangent, &bitangent);
+                                (
+                                    utils::anisotropic_ndf(
+                                        &to_local(&half_vec, &tangent, &bitangent),
+                                        alpha_t,
+                                        alpha_b,
+                                    ),
+                                    utils::anisotropic_geometry_function(
+                                        &view, &light, alpha_t, alpha_b,
+                                    ),
+                                )
+                            } else {
+                                (
+                                    utils::ndf(n_dot_h, roughness),
+                                    utils::geometry_function(n_dot_v, n_dot_l, roughness),
+                                )
+                            };
 
+                            // Sheen brightens the diffuse light at grazing angles
+                            let sheen = sheen_color.component_mul(&k_d)
+                                * (1.0 - light_dir.dot(&half_vec)).powi(5);
                             let diffuse_specular = if n_dot_v == 0.0 {
-                                diffuse
+                                diffuse + sheen
                             } else {
                                 let specular = ndf * g * f / (4.0 * n_dot_v * n_dot_l);
-                                diffuse + specular
+                                diffuse + sheen + specular
                             };
 
                             irradiance += diffuse_specular.component_mul(&radiance) * n_dot_l;
+
+                            if let Some((coat_normal, coat_roughness, coat_f)) = clearcoat {
+                                let coat_n_dot_v = coat_normal.dot(&view_dir);
+                                let coat_n_dot_l = coat_normal.dot(&light_dir);
+                                if coat_n_dot_v > 0.0 && coat_n_dot_l > 0.0 {
+                                    let coat_roughness = coat_roughness.max(0.04);
+                                    let ndf = utils::ndf(
+                                        coat_normal.dot(&half_vec).max(0.0),
+                                        coat_roughness,
+                                    );
+                                    let g = utils::geometry_function(
+                                        coat_n_dot_v,
+                                        coat_n_dot_l,
+                                        coat_roughness,
+                                    );
+                                    clearcoat_irradiance += light_color
+                                        * (coat_f * ndf * g * coat_n_dot_l / (4.0 * coat_n_dot_v));
+                                }
+                            }
                         }
+                    } else if n_dot_l < 0.0 && transmitted_diffuse.max() > 0.0 {
+                        // Light shining through the back of a thin sheet
+                        let (shadow_ray, shadow_distance) =
+                            build_shadow_ray(light_position, intersection, &light_dir);
+
+                        cast_stats.ray_count += 1;
+                        let transmittance = self.get_shadow_transmittance(
+                            light_index,
+                            &shadow_ray,
+                            shadow_distance,
+                            None,
+                        );
+                        let radiance = light
+                            .get_color(light_distance)
+                            .component_mul(&transmittance)
+                            * -n_dot_l;
+
+                        irradiance += transmitted_diffuse.component_mul(&radiance) * -n_dot_l;
                     }
                 }
             };
@@ -338,13 +731,330 @@ impl RaytracingScene {
             );
         }
 
-        if let Some(refraction) = refraction {
-            color_data.color = color_data.color.lerp(&refraction, material.opacity);
+        if let Some(medium) = subsurface_medium {
+            let (subsurface, stats) =
+                self.get_subsurface_color(ray, intersection, &normal, &medium);
+            cast_stats += stats;
+
+            color_data.color += k_d.component_mul(&subsurface);
+        }
+
+        // The coat reflects some of the light, and lets the rest through to the base and back
+        if let Some((coat_normal, coat_roughness, coat_f)) = clearcoat {
+            let (coat_reflection, stats) =
+                self.get_clearcoat_reflection(ray, intersection, &coat_normal, coat_roughness);
+            cast_stats += stats;
+
+            color_data.color =
+                color_data.color * (1.0 - coat_f) + clearcoat_irradiance + coat_reflection * coat_f;
+        }
+
+        // Light not scattered by the surface passes into a smooth dielectric
+        if let Some(transmission) = transmission {
+            color_data.color = color_data.color.lerp(&transmission, 1.0 - material.opacity);
         }
 
         (color_data, cast_stats)
     }
 
+    // Light scattered out of an object after entering it at a hit point, estimated by random walks
+    // through the medium inside. Each walk enters diffusely and ends where it next reaches the
+    // surface, which is lit like a diffuse surface by the lights outside.
+    fn get_subsurface_color(
+        &self,
+        ray: &Ray,
+        intersection: &Intersection,
+        normal: &Unit<Vector3<f64>>,
+        medium: &HomogeneousMedium,
+    ) -> (Vector3<f64>, CastStats) {
+        let mut cast_stats = CastStats::zero();
+        let depth = ray.get_depth();
+        let d = 4_u16.pow(depth.into());
+        let walks = (MAX_SUBSURFACE_WALKS / d).max(1);
+        let medium = ParticipatingMedium::Homogeneous(*medium);
+
+        let mut color = Vector3::zero();
+        for _ in 0..walks {
+            let mut direction = utils::cosine_sample_hemisphere(&-*normal).into_inner();
+            let mut origin = intersection.get_ray_origin(&direction);
+            let mut throughput = Vector3::repeat(1.0);
+            for _ in 0..MAX_SUBSURFACE_BOUNCES {
+                let walk_ray = Ray {
+                    ray_type: RayType::Secondary(depth + 1),
+                    origin,
+                    direction,
+                    media: ray.media.clone(),
+                    wavelengths: ray.wavelengths,
+                    differentials: None,
+                };
+                cast_stats.ray_count += 1;
+                // Walks escaping an object which isn't closed are lost
+                let mut exit = if let Some(exit) = self.raycast(&walk_ray) {
+                    exit
+                } else {
+                    break;
+                };
+
+                match medium.sample_distance(&walk_ray, exit.distance) {
+                    MediumSample::Scattered { distance, weight } => {
+                        throughput.component_mul_assign(&weight);
+                        origin = walk_ray.origin + direction * distance;
+                        direction = utils::sample_henyey_greenstein(
+                            &Unit::new_normalize(direction),
+                            medium.asymmetry(),
+                        )
+                        .into_inner();
+                    }
+                    MediumSample::Passed { weight } => {
+                        throughput.component_mul_assign(&weight);
+                        exit.compute_data(&walk_ray);
+                        let (exit_color, stats) = self.get_subsurface_exit_color(&exit, &direction);
+                        cast_stats += stats;
+
+                        color += throughput.component_mul(&exit_color);
+                        break;
+                    }
+                }
+            }
+        }
+
+        (color / f64::from(walks), cast_stats)
+    }
+
+    // Light from point lights reaching the point where a subsurface walk leaves an object
+    fn get_subsurface_exit_color(
+        &self,
+        exit: &Intersection,
+        direction: &Vector3<f64>,
+    ) -> (Vector3<f64>, CastStats) {
+        let mut cast_stats = CastStats::zero();
+        let hit_point = exit.get_hit_point();
+        let normal = exit.get_normal();
+        let normal = if normal.dot(direction) < 0.0 {
+            -normal.into_inner()
+        } else {
+            normal.into_inner()
+        };
+
+        let mut color = Vector3::zero();
+        for (light_index, light) in self.lights.iter().enumerate() {
+            if let Light::Point(light) = light {
+                let light_position = light.get_position();
+                let light_dir = light_position - hit_point;
+                let light_distance = light_dir.magnitude();
+                let light_dir = light_dir / light_distance;
+
+                let n_dot_l = normal.dot(&light_dir);
+                if n_dot_l > 0.0 {
+                    let (shadow_ray, shadow_distance) =
+                        build_shadow_ray(light_position, exit, &light_dir);
+
+                    cast_stats.ray_count += 1;
+                    let transmittance = self.get_shadow_transmittance(
+                        light_index,
+                        &shadow_ray,
+                        shadow_distance,
+                        None,
+                    );
+                    let radiance = light
+                        .get_color(light_distance)
+                        .component_mul(&transmittance)
+                        * n_dot_l;
+                    color += FRAC_1_PI * radiance * n_dot_l;
+                }
+            }
+        }
+
+        (color, cast_stats)
+    }
+
+    // Light reflected off of a clear coat, before weighting by its reflectance
+    fn get_clearcoat_reflection(
+        &self,
+        ray: &Ray,
+        intersection: &Intersection,
+        normal: &Unit<Vector3<f64>>,
+        roughness: f64,
+    ) -> (Vector3<f64>, CastStats) {
+        let mut cast_stats = CastStats::zero();
+        if self.render_options.max_reflected_rays == 0 {
+            return (Vector3::zeros(), cast_stats);
+        }
+
+        let depth = ray.get_depth();
+        let d = 8_u16.pow(depth.into());
+        let reflected_rays = (self.render_options.max_reflected_rays / d).max(1);
+
+        let max_angle = FRAC_PI_2 * roughness;
+        let reflection_dir = utils::reflect(&ray.direction, normal);
+
+        let reflection = (0..reflected_rays)
+            .map(|_| {
+                let direction = utils::uniform_sample_cone(&reflection_dir, max_angle).into_inner();
+                let reflection_ray = Ray {
+                    ray_type: RayType::Secondary(depth + 1),
+                    origin: intersection.get_ray_origin(&direction),
+                    direction,
+                    media: ray.media.clone(),
+                    wavelengths: ray.wavelengths,
+                    differentials: intersection
+                        .get_reflected_differentials(ray, normal, &direction),
+                };
+                let (color_data, stats) = self.get_color(&reflection_ray);
+                cast_stats += stats;
+
+                color_data.color
+            })
+            .sum::<Vector3<f64>>();
+
+        (
+            reflection * FRAC_PI_2 / f64::from(reflected_rays),
+            cast_stats,
+        )
+    }
+
+    // Light reflected and refracted at a smooth boundary between two dielectrics, weighted by
+    // Fresnel reflectance, or by the reflectance of the film over the boundary if it has one
+    #[allow(clippy::too_many_arguments)]
+    fn get_dielectric_color(
+        &self,
+        ray: &Ray,
+        intersection: &Intersection,
+        normal: &Unit<Vector3<f64>>,
+        transmitted_media: MediumStack,
+        (eta_i, eta_t): (f64, f64),
+        thin_film: Option<ThinFilm>,
+        wavelengths: Option<Wavelengths>,
+    ) -> (Vector3<f64>, CastStats) {
+        let mut cast_stats = CastStats::zero();
+        let depth = ray.get_depth();
+
+        let normal = if normal.dot(&ray.direction) > 0.0 {
+            -normal.into_inner()
+        } else {
+            normal.into_inner()
+        };
+        let cos_theta_i = -normal.dot(&ray.direction);
+
+        let eta = eta_i / eta_t;
+        let refraction_dir = utils::refract(&ray.direction, &normal, eta);
+        let reflectance = match (refraction_dir, thin_film) {
+            (None, _) => Vector3::repeat(1.0),
+            (Some(_), Some(film)) => film.reflectance(cos_theta_i, eta_i, eta_t),
+            (Some(_), None) => {
+                Vector3::repeat(utils::fresnel_dielectric(cos_theta_i, eta_i, eta_t))
+            }
+        };
+
+        // Boundaries between media with the same refractive index and no film reflect nothing
+        let mut color = Vector3::zeros();
+        if reflectance.max() > 0.0 {
+            let reflection_dir = utils::reflect(&ray.direction, &normal).into_inner();
+            let reflection_ray = Ray {
+                ray_type: RayType::Secondary(depth + 1),
+                origin: intersection.get_ray_origin(&reflection_dir),
+                direction: reflection_dir,
+                media: ray.media.clone(),
+                wavelengths,
+                differentials: intersection.get_reflected_differentials(
+                    ray,
+                    &normal,
+                    &reflection_dir,
+                ),
+            };
+            let (reflection, stats) = self.get_color(&reflection_ray);
+            cast_stats += stats;
+            color += reflection.color.component_mul(&reflectance);
+        }
+
+        if let Some(refraction_dir) = refraction_dir {
+            let refraction_dir = refraction_dir.into_inner();
+            let refraction_ray = Ray {
+                ray_type: RayType::Secondary(depth + 1),
+                origin: intersection.get_ray_origin(&refraction_dir),
+                direction: refraction_dir,
+                media: transmitted_media,
+                wavelengths,
+                differentials: intersection.get_refracted_differentials(
+                    ray,
+                    &normal,
+                    eta,
+                    &refraction_dir,
+                ),
+            };
+            let (refraction, stats) = self.get_color(&refraction_ray);
+            cast_stats += stats;
+            color += refraction
+                .color
+                .component_mul(&(Vector3::repeat(1.0) - reflectance));
+        }
+
+        (color, cast_stats)
+    }
+
+    // Light reflected off of and passing straight through a thin sheet, which reflects like a slab
+    // of the material, or like its film if it has one
+    fn get_thin_sheet_color(
+        &self,
+        ray: &Ray,
+        intersection: &Intersection,
+        normal: &Unit<Vector3<f64>>,
+        material: &PhysicalMaterial,
+    ) -> (Vector3<f64>, CastStats) {
+        let mut cast_stats = CastStats::zero();
+        let depth = ray.get_depth();
+
+        let cos_theta_i = normal.dot(&ray.direction).abs();
+        let eta_i = ray.media.refractive_index(None);
+        let reflectance = if let Some(film) = material.thin_film {
+            film.reflectance(cos_theta_i, eta_i, eta_i)
+        } else {
+            // Light bouncing back and forth between both sides of the slab
+            let reflectance = utils::fresnel_dielectric(
+                cos_theta_i,
+                eta_i,
+                material.get_refractive_index(ray.wavelengths.map(Wavelengths::hero)),
+            );
+            Vector3::repeat(2.0 * reflectance / (1.0 + reflectance))
+        };
+
+        let mut color = Vector3::zeros();
+        if reflectance.max() > 0.0 {
+            let reflection_dir = utils::reflect(&ray.direction, normal).into_inner();
+            let reflection_ray = Ray {
+                ray_type: RayType::Secondary(depth + 1),
+                origin: intersection.get_ray_origin(&reflection_dir),
+                direction: reflection_dir,
+                media: ray.media.clone(),
+                wavelengths: ray.wavelengths,
+                differentials: intersection.get_reflected_differentials(
+                    ray,
+                    normal,
+                    &reflection_dir,
+                ),
+            };
+            let (reflection, stats) = self.get_color(&reflection_ray);
+            cast_stats += stats;
+            color += reflection.color.component_mul(&reflectance);
+        }
+
+        let transmission_ray = Ray {
+            ray_type: RayType::Secondary(depth + 1),
+            origin: intersection.get_ray_origin(&ray.direction),
+            direction: ray.direction,
+            media: ray.media.clone(),
+            wavelengths: ray.wavelengths,
+            differentials: ray.differentials,
+        };
+        let (transmission, stats) = self.get_color(&transmission_ray);
+        cast_stats += stats;
+        color += transmission
+            .color
+            .component_mul(&(Vector3::repeat(1.0) - reflectance));
+
+        (color, cast_stats)
+    }
+
     fn compute_ambient_occlusion(
         &self,
         intersection: &Intersection,
@@ -360,9 +1070,11 @@ impl RaytracingScene {
                 utils::uniform_sample_cone(&intersection.get_normal(), FRAC_PI_2).into_inner();
             let occlusion_ray = Ray {
                 ray_type: RayType::Secondary(depth + 1),
-                origin: intersection.get_hit_point() + (direction * BIAS),
+                origin: intersection.get_ray_origin(&direction),
                 direction,
-                refractive_index: 1.0,
+                media: MediumStack::default(),
+                wavelengths: None,
+                differentials: None,
             };
             cast_stats.ray_count += 1;
             if !self.shadow_cast(&occlusion_ray, self.render_options.max_occlusion_distance) {
@@ -376,6 +1088,110 @@ impl RaytracingScene {
         )
     }
 
+    fn shade(
+        &self,
+        ray: &Ray,
+        intersection: &Intersection,
+        packet_shadows: Option<&[bool]>,
+    ) -> (ColorData, CastStats) {
+        let material = intersection.get_material();
+        let (color_data, cast_stats) = match material {
+            Material::Phong(material) => {
+                self.get_color_phong(ray, intersection, material, packet_shadows)
+            }
+            Material::Physical(material) => {
+                self.get_color_physical(ray, intersection, material, None, packet_shadows)
+            }
+            Material::Principled(material) => self.get_color_physical(
+                ray,
+                intersection,
+                &material.base,
+                Some(material),
+                packet_shadows,
+            ),
+        };
+
+        (color_data.clamp(), cast_stats)
+    }
+
+    // Light scattered toward the start of a ray at a point inside a participating medium. Lights
+    // are sampled directly, and one more ray is traced in a direction sampled from the phase
+    // function for light scattered in from everywhere else.
+    fn get_scattered_color(
+        &self,
+        ray: &Ray,
+        medium: &ParticipatingMedium,
+        distance: f64,
+    ) -> (ColorData, CastStats) {
+        let mut cast_stats = CastStats::zero();
+        let depth = ray.get_depth();
+        let scatter_point = ray.origin + ray.direction * distance;
+
+        let mut color = Vector3::zero();
+        for (light_index, light) in self.lights.iter().enumerate() {
+            match light {
+                Light::Ambient(light) => {
+                    color += light.get_color();
+                }
+                Light::Point(light) => {
+                    let light_position = light.get_position();
+                    let light_dir = light_position - scatter_point;
+                    let light_distance = light_dir.magnitude();
+                    let light_dir = light_dir / light_distance;
+
+                    let shadow_ray = Ray {
+                        ray_type: RayType::Shadow,
+                        origin: light_position,
+                        direction: -light_dir,
+                        media: MediumStack::default(),
+                        wavelengths: None,
+                        differentials: None,
+                    };
+
+                    cast_stats.ray_count += 1;
+                    let transmittance = self.get_shadow_transmittance(
+                        light_index,
+                        &shadow_ray,
+                        light_distance * (1.0 - SHADOW_EPSILON),
+                        None,
+                    );
+                    if transmittance.max() > 0.0 {
+                        let phase = utils::henyey_greenstein(
+                            light_dir.dot(&ray.direction),
+                            medium.asymmetry(),
+                        );
+                        color += light
+                            .get_color(light_distance)
+                            .component_mul(&transmittance)
+                            * phase;
+                    }
+                }
+            }
+        }
+
+        let scattered_dir = utils::sample_henyey_greenstein(
+            &Unit::new_normalize(ray.direction),
+            medium.asymmetry(),
+        )
+        .into_inner();
+        let scattered_ray = Ray {
+            ray_type: RayType::Secondary(depth + 1),
+            origin: scatter_point,
+            direction: scattered_dir,
+            media: ray.media.clone(),
+            wavelengths: ray.wavelengths,
+            differentials: None,
+        };
+        let (scattered, stats) = self.get_color(&scattered_ray);
+        cast_stats += stats;
+        color += scattered.color;
+
+        (
+            ColorData::new(color, medium.albedo(), Vector3::zero()),
+            cast_stats,
+        )
+    }
+
     #[allow(clippy::option_if_let_else)]
     fn get_color(&self, ray: &Ray) -> (ColorData, CastStats) {
         let mut cast_stats = CastStats::zero();
@@ -385,32 +1201,162 @@ impl RaytracingScene {
         }
 
         cast_stats.ray_count += 1;
-        if let Some(mut intersection) = self.raycast(&ray) {
-            intersection.compute_data(&ray);
-
-            let material = intersection.object.get_material();
-            let (color_data, material_stats) = match material {
-                Material::Phong(material) => self.get_color_phong(&ray, &intersection, material),
-                Material::Physical(material) => {
-                    self.get_color_physical(&ray, &intersection, material)
-                }
+        let intersection = self.raycast(&ray);
+
+        // Light traveling through a medium may be scattered before it reaches the surface
+        let medium = ray.media.participating_medium(self.medium.as_ref());
+        let sample = medium.map(|medium| {
+            let max_distance = intersection
+                .as_ref()
+                .map_or(f64::INFINITY, |intersection| intersection.distance);
+
+            medium.sample_distance(ray, max_distance)
+        });
+        let (mut color_data, weight) = match (medium, sample) {
+            (Some(medium), Some(MediumSample::Scattered { distance, weight })) => {
+                let (color_data, scattered_stats) = self.get_scattered_color(ray, medium, distance);
+                cast_stats += scattered_stats;
+
+                (color_data, weight)
+            }
+            (_, sample) => {
+                let color_data = if let Some(mut intersection) = intersection {
+                    intersection.compute_data(&ray);
+
+                    let (color_data, material_stats) = self.shade(ray, &intersection, None);
+                    cast_stats += material_stats;
+
+                    color_data
+                } else {
+                    ColorData::black()
+                };
+                let weight = if let Some(MediumSample::Passed { weight }) = sample {
+                    weight
+                } else {
+                    Vector3::repeat(1.0)
+                };
+
+                (color_data, weight)
+            }
+        };
+        color_data.color.component_mul_assign(&weight);
+
+        (color_data.clamp(), cast_stats)
+    }
+
+    // Traces shadow rays from each point light to the hit points facing it as one packet per
+    // light. The result holds, for each hit, whether each light is blocked.
+    fn cast_packet_shadows(&self, hits: &[Option<Intersection>]) -> Vec<Vec<bool>> {
+        let mut packet_shadows = vec![vec![false; self.lights.len()]; hits.len()];
+        let normals: Vec<Option<Unit<Vector3<f64>>>> = hits
+            .iter()
+            .map(|intersection| {
+                intersection
+                    .as_ref()
+                    .map(|intersection| match intersection.get_material() {
+                        Material::Phong(material) => {
+                            material.get_normal(intersection, &self.textures)
+                        }
+                        Material::Physical(material) => {
+                            material.get_normal(intersection, &self.textures)
+                        }
+                        Material::Principled(material) => {
+                            material.base.get_normal(intersection, &self.textures)
+                        }
+                    })
+            })
+            .collect();
+
+        for (light_index, light) in self.lights.iter().enumerate() {
+            let light = match light {
+                Light::Point(light) => light,
+                Light::Ambient(_) => continue,
             };
-            cast_stats += material_stats;
+            let light_position = light.get_position();
+
+            let mut hit_indexes = Vec::new();
+            let mut shadow_rays = Vec::new();
+            let mut max_distances = Vec::new();
+            for (hit_index, (intersection, normal)) in hits.iter().zip(&normals).enumerate() {
+                if let (Some(intersection), Some(normal)) = (intersection, normal) {
+                    let light_dir = (light_position - intersection.get_hit_point()).normalize();
+
+                    // Hits facing away from the light are lit by nothing and cast no shadow ray
+                    if normal.dot(&light_dir) > 0.0 {
+                        hit_indexes.push(hit_index);
+                        let (shadow_ray, shadow_distance) =
+                            build_shadow_ray(light_position, intersection, &light_dir);
+                        shadow_rays.push(shadow_ray);
+                        max_distances.push(shadow_distance);
+                    }
+                }
+            }
 
-            (color_data.clamp(), cast_stats)
-        } else {
-            (ColorData::black(), cast_stats)
+            if shadow_rays.is_empty() {
+                continue;
+            }
+
+            let shadows = self
+                .object_tree
+                .shadow_cast_packet(&RayPacket::new(shadow_rays), &max_distances);
+            for (hit_index, shadow) in hit_indexes.into_iter().zip(shadows) {
+                packet_shadows[hit_index][light_index] = shadow;
+            }
+        }
+
+        packet_shadows
+    }
+
+    // Colors of a packet of primary rays. The rays and the shadow rays from their hit points are
+    // traced as packets, while any secondary rays are traced one at a time. Rays through a scene
+    // filled with a medium can scatter before reaching anything, so they're all traced on their own.
+    fn get_packet_colors(&self, rays: Vec<Ray>) -> Vec<(ColorData, CastStats)> {
+        if self.render_options.max_depth == 0 {
+            return rays
+                .iter()
+                .map(|_| (ColorData::black(), CastStats::zero()))
+                .collect();
+        }
+        if self.medium.is_some() {
+            return rays.iter().map(|ray| self.get_color(ray)).collect();
         }
+
+        let packet = RayPacket::new(rays);
+        let mut hits = self.object_tree.raycast_packet(&packet);
+        for (ray, intersection) in packet.rays().iter().zip(hits.iter_mut()) {
+            if let Some(intersection) = intersection {
+                intersection.compute_data(ray);
+            }
+        }
+        let packet_shadows = self.cast_packet_shadows(&hits);
+
+        packet
+            .rays()
+            .iter()
+            .zip(&hits)
+            .zip(&packet_shadows)
+            .map(|((ray, intersection), shadows)| {
+                let mut cast_stats = CastStats { ray_count: 1 };
+                let color_data =
+                    intersection
+                        .as_ref()
+                        .map_or_else(ColorData::black, |intersection| {
+                            let (color_data, material_stats) =
+                                self.shade(ray, intersection, Some(shadows));
+                            cast_stats += material_stats;
+
+                            color_data
+                        });
+
+                (color_data, cast_stats)
+            })
+            .collect()
     }
 
     fn build_camera_rays(&self, x: u32, y: u32) -> Vec<Ray> {
         assert!(x < self.get_width() && y < self.get_height());
 
         let samples = self.render_options.samples_per_pixel;
-        let (width, height) = (f64::from(self.get_width()), f64::from(self.get_height()));
-        let aspect = self.get_aspect();
-        let fov = self.compute_screen_to_fov();
-
         let (x, y) = (f64::from(x), f64::from(y));
 
         let mut ray_pixel_positions = Vec::with_capacity(samples.into());
@@ -423,59 +1369,139 @@ impl RaytracingScene {
             ray_pixel_positions.push((x + rx, y + ry));
         }
 
+        // Differentials span the distance between samples rather than whole pixels when a pixel
+        // is sampled more than once
+        let differential_scale = 1.0 / f64::from(samples).sqrt();
+
         ray_pixel_positions
             .into_iter()
             .map(|(x, y)| {
-                let (x, y) = (
-                    utils::remap_value(x, (0.0, width), (-1.0, 1.0)),
-                    utils::remap_value(y, (0.0, height), (1.0, -1.0)),
-                );
-
-                // Apply fov and scale to aspect ratio
-                let (x, y) = if width < height {
-                    (x * aspect, y)
+                let direction = self.pixel_direction(x, y);
+                let rx_direction = self.pixel_direction(x + 1.0, y);
+                let ry_direction = self.pixel_direction(x, y + 1.0);
+                let wavelengths = if self.render_options.spectral {
+                    Some(Wavelengths::sample())
                 } else {
-                    (x, y / aspect)
+                    None
                 };
-                let (x, y) = (x * fov, y * fov);
-
-                let direction = Vector3::from([x, y, -1.0]).normalize();
-                let direction = (self.camera.camera_to_world * direction.to_homogeneous()).xyz();
 
                 Ray {
                     ray_type: RayType::Primary,
                     origin: self.camera.position,
                     direction,
-                    refractive_index: 1.0,
+                    media: MediumStack::default(),
+                    wavelengths,
+                    differentials: Some(RayDifferentials {
+                        rx_origin: self.camera.position,
+                        rx_direction: direction + (rx_direction - direction) * differential_scale,
+                        ry_origin: self.camera.position,
+                        ry_direction: direction + (ry_direction - direction) * differential_scale,
+                    }),
                 }
             })
             .collect()
     }
 
-    pub fn screen_raycast(&self, x: u32, y: u32) -> (ColorData, CastStats) {
-        let samples = self.render_options.samples_per_pixel;
-        let rays = self.build_camera_rays(x, y);
+    // World space direction of a camera ray through a position in pixel coordinates
+    fn pixel_direction(&self, x: f64, y: f64) -> Vector3<f64> {
+        let (width, height) = (f64::from(self.get_width()), f64::from(self.get_height()));
+        let aspect = self.get_aspect();
+        let fov = self.compute_screen_to_fov();
 
-        let (color_data, stats) = if samples == 1 {
-            self.get_color(rays.first().unwrap())
+        let (x, y) = (
+            utils::remap_value(x, (0.0, width), (-1.0, 1.0)),
+            utils::remap_value(y, (0.0, height), (1.0, -1.0)),
+        );
+
+        // Apply fov and scale to aspect ratio
+        let (x, y) = if width < height {
+            (x * aspect, y)
         } else {
-            let (mut color_data, mut cast_stats) = self.get_color(rays.first().unwrap());
+            (x, y / aspect)
+        };
+        let (x, y) = (x * fov, y * fov);
 
-            for ray in &rays[1..] {
-                let (data, stats) = self.get_color(ray);
+        let direction = Vector3::from([x, y, -1.0]).normalize();
+        (self.camera.camera_to_world * direction.to_homogeneous()).xyz()
+    }
+
+    // Averages the colors of every sample of a pixel
+    fn combine_samples(
+        &self,
+        samples: impl Iterator<Item = (ColorData, CastStats)>,
+    ) -> (ColorData, CastStats) {
+        let num_samples = self.render_options.samples_per_pixel;
+        let mut samples = samples;
+        let (mut color_data, mut cast_stats) = samples.next().unwrap();
+
+        if num_samples > 1 {
+            for (data, stats) in samples {
                 color_data.color += data.color;
                 color_data.ambient_occlusion += data.ambient_occlusion;
                 cast_stats += stats;
             }
 
-            let inv_samples = 1.0 / f64::from(samples);
+            let inv_samples = 1.0 / f64::from(num_samples);
             color_data.color *= inv_samples;
             color_data.ambient_occlusion *= inv_samples;
+            color_data = color_data.clamp();
+        }
 
-            (color_data.clamp(), cast_stats)
-        };
+        (color_data.gamma_correct(), cast_stats)
+    }
 
-        (color_data.gamma_correct(), stats)
+    pub fn screen_raycast(&self, x: u32, y: u32) -> (ColorData, CastStats) {
+        let rays = self.build_camera_rays(x, y);
+
+        self.combine_samples(rays.iter().map(|ray| self.get_color(ray)))
+    }
+
+    // Renders every pixel in a tile, row by row. Each sample index across the tile's pixels is
+    // traced as one packet.
+    fn tile_raycast(&self, tile: Tile) -> Vec<(ColorData, CastStats)> {
+        let samples = self.render_options.samples_per_pixel;
+        let mut pixel_rays: Vec<std::vec::IntoIter<Ray>> = (tile.y..tile.y + tile.height)
+            .flat_map(|y| (tile.x..tile.x + tile.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.build_camera_rays(x, y).into_iter())
+            .collect();
+
+        let mut pixel_samples: Vec<Vec<(ColorData, CastStats)>> = pixel_rays
+            .iter()
+            .map(|_| Vec::with_capacity(samples.into()))
+            .collect();
+        for _ in 0..samples {
+            let rays = pixel_rays
+                .iter_mut()
+                .map(|rays| rays.next().unwrap())
+                .collect();
+            for (samples, sample) in pixel_samples.iter_mut().zip(self.get_packet_colors(rays)) {
+                samples.push(sample);
+            }
+        }
+
+        pixel_samples
+            .into_iter()
+            .map(|samples| self.combine_samples(samples.into_iter()))
+            .collect()
+    }
+
+    // Tiles covering the image in a random order
+    fn build_tiles(&self) -> Vec<Tile> {
+        let (width, height) = (self.get_width(), self.get_height());
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(TILE_SIZE as usize) {
+            for x in (0..width).step_by(TILE_SIZE as usize) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(width - x),
+                    height: TILE_SIZE.min(height - y),
+                });
+            }
+        }
+        tiles.shuffle(&mut thread_rng());
+
+        tiles
     }
 
     fn post_process_pass(&self, color_data_buffer_lock: &RwLock<Vec<ColorData>>) {
@@ -531,45 +1557,44 @@ impl RaytracingScene {
         let cast_stats = CastStats::zero();
         let cast_stats_lock = RwLock::new(cast_stats);
 
-        let process_pixel = |&index| {
-            let (color_data, stats) =
-                self.screen_raycast((index % width) as u32, (index / width) as u32);
-            {
-                let mut cast_stats = cast_stats_lock.write().unwrap();
-                *cast_stats += stats;
-            }
+        let process_tile = |&tile: &Tile| {
+            let pixels = self.tile_raycast(tile);
+            for (index, (color_data, stats)) in tile.pixel_indexes(width as u32).zip(pixels) {
+                {
+                    let mut cast_stats = cast_stats_lock.write().unwrap();
+                    *cast_stats += stats;
+                }
 
-            let buffer_index = index * 4;
-            {
-                let mut image_buffer = image_buffer_lock.write().unwrap();
-                image_buffer[buffer_index] = (color_data.color.x * 255.0) as u8;
-                image_buffer[buffer_index + 1] = (color_data.color.y * 255.0) as u8;
-                image_buffer[buffer_index + 2] = (color_data.color.z * 255.0) as u8;
-                image_buffer[buffer_index + 3] = 255;
-            }
+                let buffer_index = index * 4;
+                {
+                    let mut image_buffer = image_buffer_lock.write().unwrap();
+                    image_buffer[buffer_index] = (color_data.color.x * 255.0) as u8;
+                    image_buffer[buffer_index + 1] = (color_data.color.y * 255.0) as u8;
+                    image_buffer[buffer_index + 2] = (color_data.color.z * 255.0) as u8;
+                    image_buffer[buffer_index + 3] = 255;
+                }
 
-            let mut color_data_buffer = color_data_buffer_lock.write().unwrap();
-            color_data_buffer[index] = color_data;
+                let mut color_data_buffer = color_data_buffer_lock.write().unwrap();
+                color_data_buffer[index] = color_data;
+            }
         };
 
-        let mut indexes: Vec<usize> = (0..width * height).collect();
-        indexes.shuffle(&mut thread_rng());
+        let tiles = self.build_tiles();
+        let indexes: Vec<usize> = (0..width * height).collect();
 
         let start = Instant::now();
         if use_progress {
             let progress = self.build_progress_bar();
 
-            indexes
-                .par_iter()
-                .progress_with(progress.clone())
-                .inspect(|_| {
-                    progress.set_message(&cast_stats_lock.read().unwrap().ray_count.to_string())
-                })
-                .for_each(process_pixel);
+            tiles.par_iter().for_each(|tile| {
+                process_tile(tile);
+                progress.inc(u64::from(tile.width * tile.height));
+                progress.set_message(&cast_stats_lock.read().unwrap().ray_count.to_string());
+            });
 
             progress.finish_with_message(&cast_stats_lock.read().unwrap().ray_count.to_string());
         } else {
-            indexes.par_iter().for_each(process_pixel);
+            tiles.par_iter().for_each(process_tile);
         }
 
         self.post_process_pass(&color_data_buffer_lock);
@@ -631,41 +1656,40 @@ impl RaytracingScene {
             let cast_stats = CastStats::zero();
             let cast_stats_lock = RwLock::new(cast_stats);
 
-            let process_pixel = |&index| {
-                let (color_data, stats) =
-                    self.screen_raycast((index % width) as u32, (index / width) as u32);
-                {
-                    let mut cast_stats = cast_stats_lock.write().unwrap();
-                    *cast_stats += stats;
-                }
+            let process_tile = |&tile: &Tile| {
+                let pixels = self.tile_raycast(tile);
+                for (index, (color_data, stats)) in tile.pixel_indexes(width as u32).zip(pixels) {
+                    {
+                        let mut cast_stats = cast_stats_lock.write().unwrap();
+                        *cast_stats += stats;
+                    }
 
-                {
-                    let mut image_buffer = ray_image_buffer_lock.write().unwrap();
-                    image_buffer[index] = utils::to_argb_u32(color_data.color);
-                }
+                    {
+                        let mut image_buffer = ray_image_buffer_lock.write().unwrap();
+                        image_buffer[index] = utils::to_argb_u32(color_data.color);
+                    }
 
-                let mut color_data_buffer = color_data_buffer_lock.write().unwrap();
-                color_data_buffer[index] = color_data;
+                    let mut color_data_buffer = color_data_buffer_lock.write().unwrap();
+                    color_data_buffer[index] = color_data;
+                }
             };
 
-            let mut indexes: Vec<usize> = (0..width * height).collect();
-            indexes.shuffle(&mut thread_rng());
+            let tiles = self.build_tiles();
+            let indexes: Vec<usize> = (0..width * height).collect();
 
             if use_progress {
                 let progress = self.build_progress_bar();
 
-                indexes
-                    .par_iter()
-                    .progress_with(progress.clone())
-                    .inspect(|_| {
-                        progress.set_message(&cast_stats_lock.read().unwrap().ray_count.to_string())
-                    })
-                    .for_each(process_pixel);
+                tiles.par_iter().for_each(|tile| {
+                    process_tile(tile);
+                    progress.inc(u64::from(tile.width * tile.height));
+                    progress.set_message(&cast_stats_lock.read().unwrap().ray_count.to_string());
+                });
 
                 progress
                     .finish_with_message(&cast_stats_lock.read().unwrap().ray_count.to_string());
             } else {
-                indexes.par_iter().for_each(process_pixel);
+                tiles.par_iter().for_each(process_tile);
             }
 
             self.post_process_pass(&color_data_buffer_lock);
@@ -1,26 +1,41 @@
 use super::raytracing_scene::RaytracingScene;
 use super::{Camera, RenderOptions};
-use crate::core::{KdTreeAccelerator, Texture, Transform};
+use crate::core::{Accelerator, AcceleratorCache, ParticipatingMedium, Texture, Transform};
 use crate::lights::Light;
-use crate::primitives::Object3D;
-use serde::Deserialize;
+use crate::primitives::{Imports, Object3D};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Debug, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Scene {
-    #[serde(flatten)]
+    #[serde(flatten, deserialize_with = "deserialize_render_options")]
     pub render_options: RenderOptions,
     loaded: bool,
     camera: Camera,
     lights: Vec<Light>,
     objects: Vec<Object3D>,
+    // Named objects which are only rendered through instances
+    prototypes: HashMap<String, Object3D>,
+    // Medium filling the space outside every object
+    medium: Option<ParticipatingMedium>,
 
     #[serde(skip)]
     textures: HashMap<String, Texture>,
 }
 
+fn deserialize_render_options<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<RenderOptions, D::Error> {
+    let render_options = RenderOptions::deserialize(deserializer)?;
+    render_options.validate().map_err(D::Error::custom)?;
+
+    Ok(render_options)
+}
+
 impl Default for Scene {
     fn default() -> Self {
         Self {
@@ -29,6 +44,8 @@ impl Default for Scene {
             camera: Camera::default(),
             lights: Vec::new(),
             objects: Vec::new(),
+            prototypes: HashMap::new(),
+            medium: None,
 
             textures: HashMap::new(),
         }
@@ -56,30 +73,120 @@ impl Scene {
         self.objects.push(object)
     }
 
+    pub fn add_prototype(&mut self, name: String, object: Object3D) {
+        if self.loaded {
+            panic!("prototypes cannot be added after scene assets have loaded")
+        }
+
+        self.prototypes.insert(name, object);
+    }
+
     pub fn load_assets(&mut self, asset_base: &Path) {
+        self.load_assets_with_cache(asset_base, &mut AcceleratorCache::disabled());
+    }
+
+    // Reuses loaded meshes from the cache where their files haven't changed, adding any that had
+    // to be loaded
+    pub fn load_assets_with_cache(&mut self, asset_base: &Path, cache: &mut AcceleratorCache) {
         if self.loaded {
             panic!("assets are already loaded for scene")
         }
 
         for object in &mut self.objects {
-            Object3D::load_assets(object, asset_base, &mut self.textures);
+            Object3D::load_assets(object, asset_base, &mut self.textures, cache);
+        }
+        for prototype in self.prototypes.values_mut() {
+            Object3D::load_assets(prototype, asset_base, &mut self.textures, cache);
+        }
+
+        let aspect = f64::from(self.render_options.width) / f64::from(self.render_options.height);
+        let mut imports = Imports::new(aspect);
+        for object in &self.objects {
+            object.collect_imports(&Transform::default(), &self.prototypes, &mut imports);
+        }
+        self.lights.append(&mut imports.lights);
+        if let Some(camera) = imports.camera {
+            self.camera = camera;
         }
         self.loaded = true;
     }
 
     pub fn build_raytracing_scene(self) -> RaytracingScene {
-        RaytracingScene::from_scene(self)
+        RaytracingScene::from_scene(self, &mut AcceleratorCache::disabled())
+    }
+
+    // Reuses acceleration structures from the cache where the scene's geometry hasn't changed,
+    // adding any that had to be built
+    pub fn build_raytracing_scene_with_cache(
+        self,
+        cache: &mut AcceleratorCache,
+    ) -> RaytracingScene {
+        RaytracingScene::from_scene(self, cache)
+    }
+}
+
+// Flattens and accelerates a prototype in its own space, building the prototypes of any instances
+// it contains first
+fn build_prototype(
+    name: &str,
+    render_options: &RenderOptions,
+    prototypes: &mut HashMap<String, Object3D>,
+    prototype_trees: &mut HashMap<String, Arc<dyn Accelerator>>,
+    cache: &mut AcceleratorCache,
+) -> Arc<dyn Accelerator> {
+    if let Some(prototype_tree) = prototype_trees.get(name) {
+        return Arc::clone(prototype_tree);
     }
+
+    // Prototypes are removed while they are being built, so a missing prototype is either
+    // undefined or instanced within itself
+    let mut prototype = prototypes.remove(name).unwrap_or_else(|| {
+        panic!(
+            "prototype \"{}\" is not defined or contains an instance of itself",
+            name
+        )
+    });
+    prototype.resolve_instances(&mut |name| {
+        build_prototype(name, render_options, prototypes, prototype_trees, cache)
+    });
+
+    let prototype_tree: Arc<dyn Accelerator> = Arc::from(render_options.accelerator.build(
+        prototype.flatten_to_world(&Transform::default()),
+        render_options.acceleration,
+        render_options.node_precision,
+        cache,
+    ));
+    prototype_trees.insert(name.to_owned(), Arc::clone(&prototype_tree));
+
+    prototype_tree
 }
 
 impl RaytracingScene {
-    fn from_scene(scene: Scene) -> Self {
+    fn from_scene(scene: Scene, cache: &mut AcceleratorCache) -> Self {
+        let render_options = &scene.render_options;
+        let mut prototypes = scene.prototypes;
+        let mut prototype_trees = HashMap::new();
+
         let root_transform = Transform::default();
         let mut objects = Vec::new();
-        for object in scene.objects {
+        for mut object in scene.objects {
+            object.resolve_instances(&mut |name| {
+                build_prototype(
+                    name,
+                    render_options,
+                    &mut prototypes,
+                    &mut prototype_trees,
+                    cache,
+                )
+            });
             objects.append(&mut object.flatten_to_world(&root_transform));
         }
-        let object_tree = KdTreeAccelerator::new(objects);
+        let object_tree = render_options.accelerator.build(
+            objects,
+            render_options.acceleration,
+            render_options.node_precision,
+            cache,
+        );
 
         RaytracingScene::new(
             scene.render_options,
@@ -87,6 +194,7 @@ impl RaytracingScene {
             scene.lights,
             scene.textures,
             object_tree,
+            scene.medium,
         )
     }
 }
@@ -0,0 +1,41 @@
+// Bound on the relative error of `n` successive floating point operations, as γ(n) in PBRT
+pub fn error_bound(n: u32) -> f64 {
+    let n_epsilon = f64::from(n) * f64::EPSILON * 0.5;
+
+    n_epsilon / (1.0 - n_epsilon)
+}
+
+// Smallest float greater than `value`
+pub fn next_float_up(value: f64) -> f64 {
+    if value.is_infinite() && value > 0.0 {
+        return value;
+    }
+
+    // Negative zero steps up the same way as positive zero
+    let value = if value == 0.0 { 0.0 } else { value };
+    let bits = value.to_bits();
+    let bits = if value >= 0.0 { bits + 1 } else { bits - 1 };
+
+    f64::from_bits(bits)
+}
+
+// Largest float less than `value`
+pub fn next_float_down(value: f64) -> f64 {
+    -next_float_up(-value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_steps_to_adjacent_floats() {
+        assert!(next_float_up(1.0) > 1.0);
+        assert_eq!(next_float_up(1.0), 1.0 + f64::EPSILON);
+        assert!(next_float_down(1.0) < 1.0);
+        assert_eq!(next_float_down(next_float_up(-3.5)), -3.5);
+        assert_eq!(next_float_up(0.0), f64::from_bits(1));
+        assert_eq!(next_float_up(-0.0), f64::from_bits(1));
+        assert_eq!(next_float_down(0.0), -f64::from_bits(1));
+    }
+}
@@ -1,13 +1,20 @@
+mod floating_point;
 mod physical_material_equations;
 mod rays;
 mod sampling;
 
-use nalgebra::Vector3;
+use nalgebra::{Unit, Vector3};
 use num_traits::Float;
 
-pub use physical_material_equations::{fresnel, geometry_function, ndf};
-pub use rays::{reflect, refract};
-pub use sampling::{cosine_sample_hemisphere, uniform_sample_cone};
+pub use floating_point::{error_bound, next_float_down, next_float_up};
+pub use physical_material_equations::{
+    anisotropic_alpha, anisotropic_geometry_function, anisotropic_ndf, fresnel, fresnel_conductor,
+    fresnel_dielectric, fresnel_thin_film, geometry_function, henyey_greenstein, ndf,
+};
+pub use rays::{offset_ray_origin, reflect, refract};
+pub use sampling::{
+    cosine_sample_hemisphere, sample_anisotropic_ggx, sample_henyey_greenstein, uniform_sample_cone,
+};
 
 const ALPHA_BIT_MASK: u32 = 255 << 24;
 const BOX_BLUR_ITERATIONS: usize = 3;
@@ -33,6 +40,40 @@ pub fn remap_value<F: Float>(num: F, domain: (F, F), range: (F, F)) -> F {
     (num - domain.0) * (range.1 - range.0) / (domain.1 - domain.0) + range.0
 }
 
+// Build two vectors perpendicular to the given direction and to each other
+pub fn orthonormal_basis(direction: &Unit<Vector3<f64>>) -> (Vector3<f64>, Vector3<f64>) {
+    let u = if direction.x.abs() > 0.9 {
+        direction.cross(&Vector3::y_axis()).normalize()
+    } else {
+        direction.cross(&Vector3::x_axis()).normalize()
+    };
+    let v = direction.cross(&u);
+
+    (u, v)
+}
+
+// Tangent and bitangent of a surface around its shading normal, pointing the ways its texture
+// coordinates increase in. The bitangent is flipped where texture coordinates are mirrored.
+pub fn tangent_frame(
+    normal: &Unit<Vector3<f64>>,
+    dpdu: &Vector3<f64>,
+    dpdv: &Vector3<f64>,
+) -> Option<(Vector3<f64>, Vector3<f64>)> {
+    let tangent = dpdu - normal.into_inner() * normal.dot(dpdu);
+    if tangent.magnitude_squared() <= 0.0 {
+        return None;
+    }
+    let tangent = tangent.normalize();
+    let bitangent = normal.cross(&tangent);
+    let bitangent = if bitangent.dot(dpdv) < 0.0 {
+        -bitangent
+    } else {
+        bitangent
+    };
+
+    Some((tangent, bitangent))
+}
+
 pub fn quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
     let discriminant = b * b - 4.0 * a * c;
     if discriminant < 0.0 {
@@ -140,6 +181,24 @@ mod test {
         assert_eq!(remap_value(2.0, (0.0, 1.0), (0.0, 10.0)), 20.0);
     }
 
+    #[test]
+    fn it_flips_bitangents_of_mirrored_texture_coordinates() {
+        let normal = Vector3::z_axis();
+        let dpdu = Vector3::new(2.0, 0.0, 0.5);
+
+        let (tangent, bitangent) =
+            tangent_frame(&normal, &dpdu, &Vector3::new(0.0, 1.0, 0.0)).unwrap();
+        assert_eq!(tangent, Vector3::x());
+        assert_eq!(bitangent, Vector3::y());
+
+        let (tangent, bitangent) =
+            tangent_frame(&normal, &dpdu, &Vector3::new(0.0, -1.0, 0.0)).unwrap();
+        assert_eq!(tangent, Vector3::x());
+        assert_eq!(bitangent, -Vector3::y());
+
+        assert_eq!(tangent_frame(&normal, &Vector3::z(), &Vector3::y()), None);
+    }
+
     #[test]
     fn it_solves_quadratic_eqs() {
         assert_eq!(quadratic(1.0, 2.0, 1.0), Some((-1.0, -1.0)));
@@ -1,5 +1,5 @@
-use nalgebra::Vector3;
-use std::f64::consts::PI;
+use nalgebra::{clamp, Vector3};
+use std::f64::consts::{FRAC_1_PI, PI};
 
 // Trowbridge-Reitz GGX normal distribution function
 pub fn ndf(n_dot_h: f64, roughness: f64) -> f64 {
@@ -13,6 +13,46 @@ pub fn ndf(n_dot_h: f64, roughness: f64) -> f64 {
     a2 / denom
 }
 
+// GGX roughness along and across a tangent direction, for surfaces like brushed metals which are
+// smoother along the tangent than across it. Anisotropy of 1 stretches highlights ten times
+// further across the tangent than along it.
+pub fn anisotropic_alpha(roughness: f64, anisotropy: f64) -> (f64, f64) {
+    let alpha = roughness * roughness;
+    let aspect = (1.0 - 0.9 * clamp(anisotropy, 0.0, 1.0)).sqrt();
+
+    (alpha * aspect, alpha / aspect)
+}
+
+// Anisotropic Trowbridge-Reitz GGX normal distribution function, for a half vector given in terms
+// of the tangent, bitangent and normal
+pub fn anisotropic_ndf(half: &Vector3<f64>, alpha_t: f64, alpha_b: f64) -> f64 {
+    let stretched = Vector3::new(half.x / alpha_t, half.y / alpha_b, half.z);
+    let denom = stretched.magnitude_squared();
+
+    FRAC_1_PI / (alpha_t * alpha_b * denom * denom)
+}
+
+// Ratio of hidden to visible microfacet area seen from a direction
+fn anisotropic_lambda(direction: &Vector3<f64>, alpha_t: f64, alpha_b: f64) -> f64 {
+    let cos2 = direction.z * direction.z;
+    let alpha2_tan2 = ((direction.x * alpha_t).powi(2) + (direction.y * alpha_b).powi(2)) / cos2;
+
+    0.5 * ((1.0 + alpha2_tan2).sqrt() - 1.0)
+}
+
+// Height-correlated Smith masking-shadowing function for anisotropic GGX, with directions given in
+// terms of the tangent, bitangent and normal
+pub fn anisotropic_geometry_function(
+    view: &Vector3<f64>,
+    light: &Vector3<f64>,
+    alpha_t: f64,
+    alpha_b: f64,
+) -> f64 {
+    1.0 / (1.0
+        + anisotropic_lambda(view, alpha_t, alpha_b)
+        + anisotropic_lambda(light, alpha_t, alpha_b))
+}
+
 // Smith's Schlick-GGX geometry function
 pub fn geometry_function(n_dot_v: f64, n_dot_l: f64, roughness: f64) -> f64 {
     let r = roughness + 1.0;
@@ -28,3 +68,203 @@ pub fn geometry_function(n_dot_v: f64, n_dot_l: f64, roughness: f64) -> f64 {
 pub fn fresnel(n_dot_v: f64, base_reflectivity: Vector3<f64>) -> Vector3<f64> {
     base_reflectivity + (Vector3::repeat(1.0) - base_reflectivity) * (1.0 - n_dot_v).powf(5.0)
 }
+
+// Fraction of unpolarized light reflected at a smooth boundary between dielectrics, going from a
+// medium with refractive index `eta_i` into one with `eta_t`. Light which would refract past the
+// critical angle is totally internally reflected.
+pub fn fresnel_dielectric(cos_theta_i: f64, eta_i: f64, eta_t: f64) -> f64 {
+    let cos_theta_i = clamp(cos_theta_i, 0.0, 1.0);
+    let sin_theta_i = (1.0 - cos_theta_i * cos_theta_i).sqrt();
+    let sin_theta_t = eta_i / eta_t * sin_theta_i;
+    if sin_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = (1.0 - sin_theta_t * sin_theta_t).sqrt();
+
+    let parallel =
+        (eta_t * cos_theta_i - eta_i * cos_theta_t) / (eta_t * cos_theta_i + eta_i * cos_theta_t);
+    let perpendicular =
+        (eta_i * cos_theta_i - eta_t * cos_theta_t) / (eta_i * cos_theta_i + eta_t * cos_theta_t);
+
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+// Fraction of unpolarized light of a wavelength reflected by a film with refractive index
+// `eta_film` and a thickness in nanometers, lying between media with indices `eta_i` and `eta_t`.
+// Light reflected off of the top and bottom of the film interferes, depending on how much further
+// the light reflected off of the bottom travels.
+pub fn fresnel_thin_film(
+    cos_theta_i: f64,
+    eta_i: f64,
+    eta_film: f64,
+    eta_t: f64,
+    thickness: f64,
+    wavelength: f64,
+) -> f64 {
+    let cos_theta_i = clamp(cos_theta_i, 0.0, 1.0);
+    let sin2_theta_i = 1.0 - cos_theta_i * cos_theta_i;
+    let sin2_theta_film = (eta_i / eta_film).powi(2) * sin2_theta_i;
+    let sin2_theta_t = (eta_i / eta_t).powi(2) * sin2_theta_i;
+    // Totally internally reflected
+    if sin2_theta_film >= 1.0 || sin2_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_film = (1.0 - sin2_theta_film).sqrt();
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+
+    // Amplitudes of the light reflected off of each side of the film for each polarization
+    let reflected_amplitudes = |eta_1: f64, cos_1: f64, eta_2: f64, cos_2: f64| {
+        (
+            (eta_1 * cos_1 - eta_2 * cos_2) / (eta_1 * cos_1 + eta_2 * cos_2),
+            (eta_2 * cos_1 - eta_1 * cos_2) / (eta_2 * cos_1 + eta_1 * cos_2),
+        )
+    };
+    let (top_s, top_p) = reflected_amplitudes(eta_i, cos_theta_i, eta_film, cos_theta_film);
+    let (bottom_s, bottom_p) = reflected_amplitudes(eta_film, cos_theta_film, eta_t, cos_theta_t);
+
+    let cos_phase = (4.0 * PI * eta_film * thickness * cos_theta_film / wavelength).cos();
+    let airy = |top: f64, bottom: f64| {
+        (top * top + bottom * bottom + 2.0 * top * bottom * cos_phase)
+            / (1.0 + top * top * bottom * bottom + 2.0 * top * bottom * cos_phase)
+    };
+
+    0.5 * (airy(top_s, bottom_s) + airy(top_p, bottom_p))
+}
+
+// Fraction of unpolarized light reflected off of a conductor with a complex refractive index of
+// `eta + ik` relative to the medium the light comes from, computed separately for each channel
+pub fn fresnel_conductor(cos_theta_i: f64, eta: &Vector3<f64>, k: &Vector3<f64>) -> Vector3<f64> {
+    let cos_theta_i = clamp(cos_theta_i, 0.0, 1.0);
+    let cos2 = cos_theta_i * cos_theta_i;
+    let sin2 = 1.0 - cos2;
+
+    Vector3::from_fn(|channel, _| {
+        let (eta2, k2) = (eta[channel] * eta[channel], k[channel] * k[channel]);
+
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+
+        let t1 = a2_plus_b2 + cos2;
+        let t2 = 2.0 * cos_theta_i * a;
+        let perpendicular = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+
+        0.5 * (parallel + perpendicular)
+    })
+}
+
+// Henyey-Greenstein phase function, giving the density of light scattered by a medium at an angle
+// to the direction it was traveling in
+pub fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+
+    0.25 * FRAC_1_PI * (1.0 - g * g) / (denom * denom.sqrt())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    #[test]
+    fn it_normalizes_the_anisotropic_ndf() {
+        let (alpha_t, alpha_b) = anisotropic_alpha(0.6, 0.8);
+        assert!(alpha_t < alpha_b);
+
+        // The projected area of every microfacet covers the surface exactly once
+        let steps = 400;
+        let (d_theta, d_phi) = (FRAC_PI_2 / f64::from(steps), 2.0 * PI / f64::from(steps));
+        let mut projected_area = 0.0;
+        for i in 0..steps {
+            let theta = (f64::from(i) + 0.5) * d_theta;
+            for j in 0..steps {
+                let phi = (f64::from(j) + 0.5) * d_phi;
+                let half = Vector3::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                projected_area += anisotropic_ndf(&half, alpha_t, alpha_b)
+                    * theta.cos()
+                    * theta.sin()
+                    * d_theta
+                    * d_phi;
+            }
+        }
+        assert!((projected_area - 1.0).abs() < 1e-3);
+
+        // Without anisotropy the distribution matches the isotropic one
+        let half = Vector3::new(0.3, 0.2, 1.0).normalize();
+        let (alpha, _) = anisotropic_alpha(0.6, 0.0);
+        assert!((anisotropic_ndf(&half, alpha, alpha) - ndf(half.z, 0.6)).abs() < 1e-12);
+        assert!(
+            (anisotropic_geometry_function(&Vector3::z(), &Vector3::z(), alpha_t, alpha_b) - 1.0)
+                .abs()
+                < 1e-12
+        );
+    }
+
+    #[test]
+    fn it_computes_dielectric_reflectance() {
+        // ((1.5 - 1) / (1.5 + 1))^2 at normal incidence
+        assert!((fresnel_dielectric(1.0, 1.0, 1.5) - 0.04).abs() < 1e-12);
+        assert!((fresnel_dielectric(1.0, 1.5, 1.0) - 0.04).abs() < 1e-12);
+        assert!((fresnel_dielectric(0.0, 1.0, 1.5) - 1.0).abs() < 1e-12);
+
+        // The critical angle going from glass into air is about 41.8 degrees
+        assert!(fresnel_dielectric(40_f64.to_radians().cos(), 1.5, 1.0) < 1.0);
+        assert!((fresnel_dielectric(45_f64.to_radians().cos(), 1.5, 1.0) - 1.0).abs() < 1e-12);
+        assert!(fresnel_dielectric(45_f64.to_radians().cos(), 1.0, 1.5) < 0.1);
+    }
+
+    #[test]
+    fn it_computes_thin_film_reflectance() {
+        // Films with no thickness reflect like the boundary between the media on either side
+        let cos_theta_i = 30_f64.to_radians().cos();
+        assert!(
+            (fresnel_thin_film(cos_theta_i, 1.0, 1.33, 1.5, 0.0, 550.0)
+                - fresnel_dielectric(cos_theta_i, 1.0, 1.5))
+            .abs()
+                < 1e-12
+        );
+
+        // A quarter wavelength film cancels reflections from either side of it out, while a half
+        // wavelength film reflects like it isn't there
+        let eta_film = 1.5_f64.sqrt();
+        let quarter_wave = 550.0 / (4.0 * eta_film);
+        assert!(fresnel_thin_film(1.0, 1.0, eta_film, 1.5, quarter_wave, 550.0) < 1e-12);
+        assert!(
+            (fresnel_thin_film(1.0, 1.0, eta_film, 1.5, 2.0 * quarter_wave, 550.0) - 0.04).abs()
+                < 1e-12
+        );
+        assert!((fresnel_thin_film(1.0, 1.5, 1.33, 1.0, 100.0, 550.0) - 1.0).abs() > 0.5);
+        assert!((fresnel_thin_film(0.2, 1.5, 1.33, 1.0, 100.0, 550.0) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn it_computes_conductor_reflectance() {
+        let eta = Vector3::new(0.2, 1.5, 3.0);
+        let k = Vector3::new(3.9, 0.0, 3.3);
+
+        // ((eta - 1)^2 + k^2) / ((eta + 1)^2 + k^2) at normal incidence
+        let normal = fresnel_conductor(1.0, &eta, &k);
+        let expected = Vector3::from_fn(|c, _| {
+            ((eta[c] - 1.0).powi(2) + k[c] * k[c]) / ((eta[c] + 1.0).powi(2) + k[c] * k[c])
+        });
+        assert!((normal - expected).amax() < 1e-12);
+        assert!((fresnel_conductor(0.0, &eta, &k) - Vector3::repeat(1.0)).amax() < 1e-12);
+
+        // Without extinction, conductors reflect like dielectrics
+        let cos_theta_i = 30_f64.to_radians().cos();
+        assert!(
+            (fresnel_conductor(cos_theta_i, &eta, &k).y
+                - fresnel_dielectric(cos_theta_i, 1.0, 1.5))
+            .abs()
+                < 1e-12
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use nalgebra::{Unit, Vector3};
+use super::{next_float_down, next_float_up};
+use nalgebra::{Point3, Unit, Vector3};
 
 pub fn reflect(incident: &Vector3<f64>, normal: &Vector3<f64>) -> Unit<Vector3<f64>> {
     Unit::new_normalize(incident - 2.0 * incident.dot(&normal) * normal)
@@ -24,3 +25,31 @@ pub fn refract(
         ))
     }
 }
+
+// Moves a point on a surface just far enough along the geometric normal that a ray leaving it in
+// `direction` can't hit the same surface again, given a bound on the error of each coordinate of
+// the point. The offset point is then rounded away from the surface.
+pub fn offset_ray_origin(
+    point: &Point3<f64>,
+    error: &Vector3<f64>,
+    geometric_normal: &Vector3<f64>,
+    direction: &Vector3<f64>,
+) -> Point3<f64> {
+    let distance = geometric_normal.abs().dot(error);
+    let offset = if direction.dot(geometric_normal) < 0.0 {
+        -distance * geometric_normal
+    } else {
+        distance * geometric_normal
+    };
+
+    let mut origin = point + offset;
+    for axis in 0..3 {
+        if offset[axis] > 0.0 {
+            origin[axis] = next_float_up(origin[axis]);
+        } else if offset[axis] < 0.0 {
+            origin[axis] = next_float_down(origin[axis]);
+        }
+    }
+
+    origin
+}
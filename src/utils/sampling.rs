@@ -1,4 +1,4 @@
-use nalgebra::{Point2, Point3, Unit, Vector2, Vector3};
+use nalgebra::{clamp, Point2, Point3, Unit, Vector2, Vector3};
 use rand::Rng;
 use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, TAU};
 use std::f64::EPSILON;
@@ -20,7 +20,6 @@ fn concentric_sample_disk() -> Point2<f64> {
 }
 
 // Sample a hemisphere with a cosine weight in the direction of the given direction using Malley's method
-#[allow(dead_code)]
 pub fn cosine_sample_hemisphere(direction: &Unit<Vector3<f64>>) -> Unit<Vector3<f64>> {
     let p = concentric_sample_disk();
     let p = Point3::from([p.x, p.y, (1.0 - p.x * p.x - p.y * p.y).sqrt()]);
@@ -70,6 +69,45 @@ pub fn uniform_sample_cone(direction: &Unit<Vector3<f64>>, max_angle: f64) -> Un
     Unit::new_normalize(u * radius * phi.cos() + v * radius * phi.sin() + w * z)
 }
 
+// Sample a scattered direction from the Henyey-Greenstein phase function with the given
+// asymmetry, relative to the direction light was traveling in
+pub fn sample_henyey_greenstein(direction: &Unit<Vector3<f64>>, g: f64) -> Unit<Vector3<f64>> {
+    let mut rng = rand::thread_rng();
+
+    let u: f64 = rng.gen();
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u
+    } else {
+        let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * u);
+        (1.0 + g * g - s * s) / (2.0 * g)
+    };
+    let cos_theta = clamp(cos_theta, -1.0, 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let phi = rng.gen::<f64>() * TAU;
+
+    let (u, v) = super::orthonormal_basis(direction);
+    Unit::new_normalize(
+        u * sin_theta * phi.cos() + v * sin_theta * phi.sin() + direction.into_inner() * cos_theta,
+    )
+}
+
+// Sample a microfacet normal from an anisotropic GGX distribution in proportion to its projected
+// area, given in terms of the tangent, bitangent and normal. GGX slopes are distributed like unit
+// roughness slopes stretched by the roughness along each tangent.
+pub fn sample_anisotropic_ggx(alpha_t: f64, alpha_b: f64) -> Unit<Vector3<f64>> {
+    let mut rng = rand::thread_rng();
+
+    let u: f64 = rng.gen();
+    let slope = (u / (1.0 - u)).sqrt();
+    let phi = rng.gen::<f64>() * TAU;
+
+    Unit::new_normalize(Vector3::new(
+        alpha_t * slope * phi.cos(),
+        alpha_b * slope * phi.sin(),
+        1.0,
+    ))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -89,6 +127,19 @@ mod test {
         }
     }
 
+    #[test]
+    fn it_samples_anisotropic_ggx() {
+        let (mut spread_t, mut spread_b) = (0.0_f64, 0.0_f64);
+        for _ in 0..10_000 {
+            let half = sample_anisotropic_ggx(0.1, 0.5);
+            assert!(half.z > 0.0);
+            spread_t += half.x * half.x;
+            spread_b += half.y * half.y;
+        }
+
+        assert!(spread_t * 4.0 < spread_b);
+    }
+
     #[test]
     fn it_samples_a_cone() {
         let mut rng = rand::thread_rng();
@@ -189,4 +240,20 @@ mod test {
             assert_le!(dot.min(1.0).acos(), FRAC_PI_2 + PRECISION);
         }
     }
+
+    #[test]
+    fn it_samples_henyey_greenstein_with_the_given_asymmetry() {
+        let direction = Unit::new_normalize(Vector3::new(1.0, -2.0, 0.5));
+
+        // The average cosine of the scattering angle is the asymmetry parameter
+        for &g in &[-0.7, 0.0, 0.3, 0.9] {
+            let num_samples = 100_000;
+            let mean_cos = (0..num_samples)
+                .map(|_| sample_henyey_greenstein(&direction, g).dot(&direction))
+                .sum::<f64>()
+                / f64::from(num_samples);
+
+            assert_le!((mean_cos - g).abs(), 0.01);
+        }
+    }
 }